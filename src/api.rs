@@ -1,19 +1,50 @@
-use csv_db::Database;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
 use itertools::Itertools;
-use rocket::{State, http::Status, serde::json::Json, tokio::sync::Mutex};
+use rocket::{
+    Data, Request, Response, State,
+    fairing::{Fairing, Info, Kind},
+    form::Form,
+    http::{Accept, Header, Status},
+    request::{FromRequest, Outcome},
+    response::{self, Responder},
+    serde::json::{Json, Value, json},
+    tokio::sync::Mutex,
+};
+use serde::Serialize;
 
 use crate::{
-    models::{ApiScoredGuess, Guess, User},
-    store::Store,
+    config::Config,
+    models::{
+        Admin, ApiLeaderboardEntry, ApiScoredGuess, CrowdGuess, Driver, Guess, GuessForm,
+        InviteCode, LeaderboardEntry, LeaderboardRow, ParticipationStat, Points, PositionAccuracy,
+        PublicUser, RivalInfo, ScoreMatrix, ScoredGuessOwned, User, UserDataExport,
+    },
+    store::{Store, StoreError},
 };
 
 #[derive(Responder)]
 pub enum LeaderboardResponse {
-    Json(Json<Vec<(String, u16)>>),
+    Json(Json<Vec<LeaderboardRow>>),
+    WithGuesses(Json<Vec<ApiLeaderboardEntry>>),
+    Matrix(Json<ScoreMatrix>),
     PlainText(String),
     Irc(String),
+    Csv(CsvLeaderboard),
+    #[response(content_type = "text/tab-separated-values; charset=utf-8")]
+    Tsv(String),
 }
 
+#[derive(Responder)]
+#[response(content_type = "text/csv; charset=utf-8")]
+pub struct CsvLeaderboard(String);
+
 #[derive(Responder)]
 pub enum GuessesResponse {
     Json(Json<Vec<Guess>>),
@@ -30,28 +61,46 @@ pub enum ScoredGuessesResponse {
 
 #[derive(Responder)]
 pub enum UsersResponse {
-    Json(Json<Vec<User>>),
+    Json(Json<Vec<PublicUser>>),
     PlainText(String),
     Irc(String),
 }
 
-#[get("/guesses?<username>&<format>")]
+/// Reply to a guess submitted through the API, scored right away if the race already has a result.
+#[derive(Serialize)]
+pub struct PlayResponse {
+    pub message: String,
+    pub guess: Guess,
+    pub preview_score: Option<Points>,
+}
+
+#[derive(Responder)]
+pub enum PlayError {
+    Message((Status, &'static str)),
+    #[response(status = 400)]
+    Invalid(String),
+    #[response(status = 503)]
+    OffSeason(Value),
+}
+
+#[get("/guesses?<username>&<format>&<category>")]
 pub async fn guesses(
-    db: &State<Mutex<Database<&str>>>,
+    store: &State<Store>,
+    config: &State<Config>,
     username: Option<&str>,
     format: Option<&str>,
+    category: Option<&str>,
 ) -> Result<GuessesResponse, Status> {
-    let store = Store::new(db);
-
-    let current_event = &store
-        .next_event()
-        .await
-        .expect("The next event should be available on the database");
+    let category = config.category(category).ok_or(Status::NotFound)?;
 
-    let guesses = store
-        .get_guesses(username, Some(&current_event.name))
-        .await
-        .unwrap_or_default();
+    // Without an upcoming event there are no current guesses to list.
+    let guesses = match store.next_event(category).await {
+        Ok(Some(current_event)) => store
+            .get_guesses(username, Some(&current_event.name))
+            .await
+            .unwrap_or_default(),
+        Ok(None) | Err(_) => Vec::new(),
+    };
 
     match format {
         Some(kind) => match kind {
@@ -59,7 +108,7 @@ pub async fn guesses(
             "irc" | "IRC" => {
                 let irc_guesses = guesses
                     .iter()
-                    .map(|g| format!("{}: {} {} {} {} {}", g.race, g.p1, g.p2, g.p3, g.p4, g.p5))
+                    .map(|g| format!("{}: {}", g.race, g.picks().iter().join(" ")))
                     .join("\n");
 
                 Ok(GuessesResponse::Irc(irc_guesses))
@@ -67,38 +116,38 @@ pub async fn guesses(
             "text" | "TEXT" => {
                 let text_guesses = guesses
                     .iter()
-                    .map(|g| {
-                        format!(
-                            "{} {} {} {} {} {} {}",
-                            g.race, g.username, g.p1, g.p2, g.p3, g.p4, g.p5
-                        )
-                    })
+                    .map(|g| format!("{} {} {}", g.race, g.username, g.picks().iter().join(" ")))
                     .join("\n");
 
                 Ok(GuessesResponse::PlainText(text_guesses))
             }
-            _ => return Err(Status::InternalServerError),
+            _ => Err(Status::InternalServerError),
         },
         None => Ok(GuessesResponse::Json(Json(guesses))),
     }
 }
 
-#[get("/scored_guesses?<format>")]
-pub async fn scored_guesses<'a>(
-    db: &State<Mutex<Database<&str>>>,
+#[get("/scored_guesses?<format>&<category>")]
+pub async fn scored_guesses(
+    store: &State<Store>,
+    config: &State<Config>,
     format: Option<&str>,
+    category: Option<&str>,
 ) -> Result<ScoredGuessesResponse, Status> {
-    let store = Store::new(db);
+    let category = config.category(category).ok_or(Status::NotFound)?;
 
     let normalized_results = store
         .normalized_results()
         .await
         .map_err(|_| Status::InternalServerError)?;
 
-    let guesses = store
+    let guesses: Vec<Guess> = store
         .get_guesses(None, None)
         .await
-        .map_err(|_| Status::InternalServerError)?;
+        .map_err(|_| Status::InternalServerError)?
+        .into_iter()
+        .filter(|g| config.belongs_to(&g.category, category))
+        .collect();
     let scored_guesses = store.scored_guesses(&guesses, &normalized_results).await;
     let api_scored_guesses: Vec<ApiScoredGuess> = scored_guesses
         .iter()
@@ -111,24 +160,21 @@ pub async fn scored_guesses<'a>(
     match format {
         Some("json" | "JSON") => Ok(ScoredGuessesResponse::Json(Json(api_scored_guesses))),
         Some("irc" | "IRC") => {
-            let current_event = &store
-                .next_event()
-                .await
-                .expect("The next event should be available on the database");
+            let current_event = store.next_event(category).await.ok().flatten();
 
             let irc_guesses = api_scored_guesses
                 .iter()
-                .filter(|sg| sg.guess.race.eq_ignore_ascii_case(&current_event.name))
+                .filter(|sg| {
+                    current_event
+                        .as_ref()
+                        .is_some_and(|e| sg.guess.race.eq_ignore_ascii_case(&e.name))
+                })
                 .map(|sg| {
                     format!(
-                        "{}: {} {} {} {} {} {} {}",
+                        "{}: {} {} {}",
                         sg.guess.race,
                         sg.guess.username,
-                        sg.guess.p1,
-                        sg.guess.p2,
-                        sg.guess.p3,
-                        sg.guess.p4,
-                        sg.guess.p5,
+                        sg.guess.picks().iter().join(" "),
                         sg.points,
                     )
                 })
@@ -141,14 +187,10 @@ pub async fn scored_guesses<'a>(
                 .iter()
                 .map(|sg| {
                     format!(
-                        "{}: {} {} {} {} {} {} {}",
+                        "{}: {} {} {}",
                         sg.guess.race,
                         sg.guess.username,
-                        sg.guess.p1,
-                        sg.guess.p2,
-                        sg.guess.p3,
-                        sg.guess.p4,
-                        sg.guess.p5,
+                        sg.guess.picks().iter().join(" "),
                         sg.points,
                     )
                 })
@@ -161,121 +203,427 @@ pub async fn scored_guesses<'a>(
     }
 }
 
-#[get("/leaderboard?<format>")]
+#[allow(clippy::too_many_arguments)]
+#[get("/leaderboard?<format>&<season>&<category>&<from>&<to>&<q>&<include_guesses>&<sort>")]
 pub async fn leaderboard(
-    db: &State<Mutex<Database<&str>>>,
+    _limit: WithinRateLimit,
+    user: Option<User>,
+    store: &State<Store>,
+    config: &State<Config>,
+    accept: Option<&Accept>,
     format: Option<&str>,
+    season: Option<u16>,
+    category: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+    q: Option<&str>,
+    include_guesses: Option<bool>,
+    sort: Option<&str>,
 ) -> Result<LeaderboardResponse, Status> {
-    let store = Store::new(db);
+    let category = config.category(category).ok_or(Status::NotFound)?;
+    let include_guesses = include_guesses.unwrap_or_default();
+    let by_wins = match sort {
+        None | Some("points") => false,
+        Some("wins") => true,
+        Some(_) => return Err(Status::BadRequest),
+    };
 
-    let normalized_results = store
-        .normalized_results()
-        .await
-        .map_err(|_| Status::InternalServerError)?;
+    // Guesses for the upcoming event are only shown to players.
+    if include_guesses && user.is_none() {
+        return Err(Status::Unauthorized);
+    }
 
-    let guesses = store
-        .get_guesses(None, None)
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-    let scored_guesses = store.scored_guesses(&guesses, &normalized_results).await;
-    let grouped_guesses = scored_guesses
-        .iter()
-        .into_group_map_by(|g| &g.guess.username);
+    // A leaderboard of only some rounds takes both ends of its date range, in RFC 3339.
+    let date_range = match (from, to) {
+        (None, None) => None,
+        (Some(from), Some(to)) => {
+            let parse = |datetime| {
+                DateTime::parse_from_rfc3339(datetime)
+                    .map(|datetime| datetime.with_timezone(&Utc))
+                    .map_err(|_| Status::BadRequest)
+            };
+            let (from, to) = (parse(from)?, parse(to)?);
+
+            if from >= to {
+                return Err(Status::BadRequest);
+            }
+
+            Some((from, to))
+        }
+        _ => return Err(Status::BadRequest),
+    };
 
-    let leaderboard = store.leaderboard(grouped_guesses).await;
+    // The format query parameter takes priority over the Accept header.
+    let format = format.or_else(|| {
+        accept.and_then(|accept| match accept.preferred().media_type() {
+            media_type if media_type.is_plain() => Some("text"),
+            media_type if media_type.is_json() => Some("json"),
+            _ => None,
+        })
+    });
+
+    let include_constructor = config.scoring.include_constructor(season);
+    let mut leaderboard = match date_range {
+        Some((from, to)) => {
+            let scored_guesses: Vec<ScoredGuessOwned> = store
+                .get_all_scored_guesses()
+                .await
+                .map_err(|_| Status::InternalServerError)?
+                .iter()
+                .filter(|sg| config.belongs_to(&sg.guess.category, category))
+                .cloned()
+                .collect();
+
+            store
+                .leaderboard_for_date_range(
+                    category,
+                    from,
+                    to,
+                    &scored_guesses,
+                    include_constructor,
+                )
+                .await
+                .map_err(|_| Status::InternalServerError)?
+        }
+        None => store
+            .leaderboard_for_season(
+                category,
+                config.is_primary(category),
+                season,
+                include_constructor,
+            )
+            .await
+            .map_err(|_| Status::InternalServerError)?,
+    };
+
+    // The sort is stable, so players with as many wins stay in the order of their points.
+    if by_wins {
+        leaderboard.sort_by_key(|(_, _, wins)| Reverse(*wins));
+    }
+
+    // Searching keeps the overall rank of the players found.
+    let q = q.unwrap_or_default();
+    let rows: Vec<LeaderboardRow> = LeaderboardRow::rank(leaderboard)
+        .into_iter()
+        .filter(|row| LeaderboardEntry::name_matches(&row.name, q))
+        .collect();
+
+    if include_guesses && matches!(format, None | Some("json" | "JSON")) {
+        let mut current_guesses = store
+            .get_current_guesses(category)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+        let entries = rows
+            .into_iter()
+            .map(|row| ApiLeaderboardEntry {
+                current_guess: current_guesses.remove(&row.username),
+                rank: row.rank,
+                username: row.username,
+                points: row.points,
+            })
+            .collect();
+
+        return Ok(LeaderboardResponse::WithGuesses(Json(entries)));
+    }
 
     match format {
         Some(kind) => match kind {
-            "json" | "JSON" => Ok(LeaderboardResponse::Json(Json(leaderboard))),
-            "irc" | "IRC" => {
-                let irc_leaderboard: String = leaderboard
+            "json" | "JSON" => Ok(LeaderboardResponse::Json(Json(rows))),
+            "matrix" | "MATRIX" => {
+                let guesses: Vec<Guess> = store
+                    .get_all_scored_guesses()
+                    .await
+                    .map_err(|_| Status::InternalServerError)?
                     .iter()
-                    .enumerate()
-                    .map(|r| {
-                        let code: String =
-                            r.1.0
-                                .chars()
-                                .filter(|c| c.is_alphanumeric())
-                                .take(3)
-                                .collect();
-
-                        format!("{}. {} {}", r.0 + 1, code.to_ascii_uppercase(), r.1.1)
-                    })
-                    .join(" | ");
+                    .filter(|sg| config.belongs_to(&sg.guess.category, category))
+                    .map(|sg| sg.guess.clone())
+                    .collect();
+                let normalized_results = store
+                    .normalized_results()
+                    .await
+                    .map_err(|_| Status::InternalServerError)?;
+                let matrix = store
+                    .score_matrix(
+                        category,
+                        season,
+                        &guesses,
+                        &normalized_results,
+                        config.max_matrix_cells,
+                    )
+                    .await
+                    .map_err(|_| Status::InternalServerError)?
+                    .ok_or(Status::InternalServerError)?;
 
-                Ok(LeaderboardResponse::Irc(irc_leaderboard))
+                Ok(LeaderboardResponse::Matrix(Json(matrix)))
             }
-            "text" | "TEXT" => {
-                let text_leaderboard: String = leaderboard
-                    .iter()
-                    .enumerate()
-                    .map(|r| format!("{}. {} {}", r.0 + 1, r.1.0, r.1.1))
-                    .join("\n");
+            "irc" | "IRC" => Ok(LeaderboardResponse::Irc(
+                rows.iter().map(|row| row.format(true)).join(" | "),
+            )),
+            "text" | "TEXT" => Ok(LeaderboardResponse::PlainText(
+                rows.iter().map(|row| row.format(false)).join("\n"),
+            )),
+            "csv" | "CSV" | "tsv" | "TSV" => {
+                let tsv = kind.eq_ignore_ascii_case("tsv");
+                let body = LeaderboardRow::to_delimited(&rows, if tsv { b'\t' } else { b',' })
+                    .map_err(|_| Status::InternalServerError)?;
 
-                Ok(LeaderboardResponse::PlainText(text_leaderboard))
+                if tsv {
+                    Ok(LeaderboardResponse::Tsv(body))
+                } else {
+                    Ok(LeaderboardResponse::Csv(CsvLeaderboard(body)))
+                }
             }
-            _ => return Err(Status::InternalServerError),
+            _ => Err(Status::InternalServerError),
         },
-        None => Ok(LeaderboardResponse::Json(Json(leaderboard))),
+        None => Ok(LeaderboardResponse::Json(Json(rows))),
     }
 }
 
-#[post("/play", data = "<post_data>")]
+/// Players ranked by how many times they earned the parlay bonus.
+#[get("/leaderboard/parlays")]
+pub async fn parlay_leaderboard(store: &State<Store>) -> Result<Json<Vec<(String, u32)>>, Status> {
+    store
+        .parlay_leaderboard()
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+// Ranked after `play_form_encoded`, so that anything not form-encoded is still read as JSON, even
+// without a `Content-Type`.
+#[post("/play?<category>", data = "<post_data>", rank = 2)]
 pub async fn play(
     user: User,
-    db: &State<Mutex<Database<&str>>>,
-    post_data: Json<Guess>,
-) -> Result<String, (Status, &'static str)> {
-    let store = Store::new(db);
-
-    let drivers = store.all_drivers().await.ok().unwrap_or_default();
-    let current_event = &store
-        .next_event()
-        .await
-        .expect("The next event should be available on the database");
+    store: &State<Store>,
+    config: &State<Config>,
+    category: Option<&str>,
+    post_data: Json<GuessForm>,
+) -> Result<Json<PlayResponse>, PlayError> {
+    submit_guess(user, store, config, category, &post_data).await
+}
+
+/// Same as `play`, for clients that post the guess form-encoded rather than as JSON.
+#[post("/play?<category>", format = "form", data = "<post_data>")]
+pub async fn play_form_encoded(
+    user: User,
+    store: &State<Store>,
+    config: &State<Config>,
+    category: Option<&str>,
+    post_data: Form<GuessForm>,
+) -> Result<Json<PlayResponse>, PlayError> {
+    submit_guess(user, store, config, category, &post_data).await
+}
+
+/// Validates and stores a guess posted to the API, whatever its encoding.
+async fn submit_guess(
+    user: User,
+    store: &State<Store>,
+    config: &State<Config>,
+    category: Option<&str>,
+    post_data: &GuessForm,
+) -> Result<Json<PlayResponse>, PlayError> {
+    let category = config
+        .category(category)
+        .ok_or(PlayError::Message((Status::NotFound, "Unknown category.")))?;
+
+    let current_event = &match store.next_event(category).await {
+        Ok(Some(current_event)) => current_event,
+        Ok(None) => {
+            return Err(PlayError::OffSeason(json!({
+                "error": "There are no upcoming events to guess."
+            })));
+        }
+        Err(_) => {
+            return Err(PlayError::Message((
+                Status::InternalServerError,
+                "Could not get the next event.",
+            )));
+        }
+    };
 
-    let mut guess = post_data.into_inner();
+    let mut guess =
+        Guess::try_from(post_data).map_err(|error| PlayError::Invalid(error.to_string()))?;
 
     if !guess.username.eq_ignore_ascii_case(&user.username) {
-        return Err((
+        return Err(PlayError::Message((
             Status::Unauthorized,
             "Guess username does not match authenticated user.",
-        ));
+        )));
     }
 
     guess.race = current_event.name.clone();
+    guess.race_id = current_event.id.clone();
+    guess.category = category.name.clone();
 
     guess.normalize();
 
-    if !guess.valid(&drivers) {
-        return Err((
-            Status::InternalServerError,
-            "Your guess must contain 5 different driver codes.",
-        ));
+    let drivers: Vec<Driver> = store
+        .all_drivers()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|d| d.active && config.belongs_to(&d.category, category))
+        .collect();
+
+    if let Err(error) = guess.valid(&drivers) {
+        return Err(PlayError::Invalid(error.to_string()));
     }
 
-    match store.update_guess(guess.clone(), &current_event.name).await {
-        Ok(_) => Ok(format!(
-            "Your guess for the {} was successfully updated.",
-            current_event.name
-        )),
-        Err(_) => Err((Status::InternalServerError, "Could not update your guess.")),
+    // Clients that don't send the version of their guess keep replacing the stored one.
+    if guess.version == 0 {
+        guess.version = store
+            .get_guesses(Some(&guess.username), Some(&guess.race))
+            .await
+            .unwrap_or_default()
+            .first()
+            .map_or(0, |g| g.version);
     }
+
+    let (message, guess) = if store.is_stored_guess(&guess).await.unwrap_or_default() {
+        ("Your guess is already up to date.".to_string(), guess)
+    } else {
+        match store.update_guess(guess, &current_event.name).await {
+            Ok(guess) => (
+                format!(
+                    "Your guess for the {} was successfully updated.",
+                    current_event.name
+                ),
+                guess,
+            ),
+            Err(StoreError::Conflict) => {
+                return Err(PlayError::Message((
+                    Status::Conflict,
+                    "Your guess was modified elsewhere, please refresh.",
+                )));
+            }
+            Err(_) => {
+                return Err(PlayError::Message((
+                    Status::InternalServerError,
+                    "Could not update your guess.",
+                )));
+            }
+        }
+    };
+
+    let normalized_results = store.normalized_results().await.unwrap_or_default();
+    // Play is closed once the race starts, so this only scores guesses made by admins or in tests.
+    let (message, preview_score) = if guess.result_in(&normalized_results).is_some() {
+        let breakdown = store.score_breakdown(&guess, &normalized_results).await;
+        let points = breakdown.total();
+        let message = match breakdown.bonuses().as_slice() {
+            [] => format!("{message} It scores {}.", points.label()),
+            bonuses => format!(
+                "{message} It scores {} ({}).",
+                points.label(),
+                bonuses.join(", ")
+            ),
+        };
+
+        (message, Some(points))
+    } else {
+        (message, None)
+    };
+
+    Ok(Json(PlayResponse {
+        message,
+        guess,
+        preview_score,
+    }))
+}
+
+#[get("/stats/participation")]
+pub async fn participation(store: &State<Store>) -> Result<Json<Vec<ParticipationStat>>, Status> {
+    store
+        .participation()
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+/// The most popular picks for a race, only shown once its result is in so they can't sway
+/// anyone's guess.
+#[get("/stats/crowd?<race>")]
+pub async fn crowd(store: &State<Store>, race: &str) -> Result<Json<CrowdGuess>, Status> {
+    if !store
+        .race_has_result(race)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+    {
+        return Err(Status::Forbidden);
+    }
+
+    store
+        .most_popular_guesses_for_race(race)
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[derive(Serialize)]
+pub struct UserStats {
+    pub username: String,
+    pub rival: Option<RivalInfo>,
+    /// Past races of the primary category guessed in a row.
+    pub streak: u32,
+    pub parlay_count: u32,
+    pub position_accuracy: [PositionAccuracy; 5],
+}
+
+#[get("/stats/user/<username>")]
+pub async fn user_stats(
+    store: &State<Store>,
+    config: &State<Config>,
+    username: &str,
+) -> Result<Json<UserStats>, Status> {
+    let user = store
+        .get_users(None)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .into_iter()
+        .find(|u| u.username.eq_ignore_ascii_case(username))
+        .ok_or(Status::NotFound)?;
+    let normalized_results = store
+        .normalized_results()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let rival = store
+        .find_rival(&user.username, &normalized_results)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let streak = store
+        .guess_streak(&user.username, config.primary_category())
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let parlay_count = store
+        .parlay_count(&user.username, &normalized_results)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let position_accuracy = store
+        .guess_accuracy_by_position(&user.username, &normalized_results)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    Ok(Json(UserStats {
+        username: user.username,
+        rival,
+        streak,
+        parlay_count,
+        position_accuracy,
+    }))
 }
 
 #[get("/users?<username>&<format>")]
 pub async fn users(
-    db: &State<Mutex<Database<&str>>>,
+    store: &State<Store>,
     username: Option<&str>,
     format: Option<&str>,
 ) -> Result<UsersResponse, Status> {
-    let store = Store::new(db);
-
     let users = store.get_users(username).await.unwrap_or_default();
+    let public_users = || users.iter().map(PublicUser::from).collect();
 
     match format {
         Some(kind) => match kind {
-            "json" | "JSON" => Ok(UsersResponse::Json(Json(users))),
+            "json" | "JSON" => Ok(UsersResponse::Json(Json(public_users()))),
             "irc" | "IRC" => {
                 let irc_users = users
                     .iter()
@@ -289,8 +637,552 @@ pub async fn users(
 
                 Ok(UsersResponse::PlainText(text_users))
             }
-            _ => return Err(Status::InternalServerError),
+            _ => Err(Status::InternalServerError),
         },
-        None => Ok(UsersResponse::Json(Json(users))),
+        None => Ok(UsersResponse::Json(Json(public_users()))),
+    }
+}
+
+/// Every guess of a user, including when each one was last submitted.
+#[get("/users/<username>/guesses")]
+pub async fn user_guesses(
+    store: &State<Store>,
+    username: &str,
+) -> Result<Json<Vec<Guess>>, Status> {
+    store
+        .get_guesses(Some(username), None)
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[get("/me")]
+pub async fn me(user: User) -> Json<PublicUser> {
+    Json(PublicUser::from(&user))
+}
+
+/// How the stored password hashes compare to the configured Argon2 variant.
+#[derive(Serialize)]
+pub struct RehashReport {
+    pub variant: String,
+    /// Users whose hash was made with another variant, converted the next time they login.
+    pub outdated: Vec<String>,
+}
+
+/// Lists the password hashes left to convert after changing the Argon2 variant. Hashes can't be
+/// rehashed without the passwords they were made from, so nothing is changed here: each one is
+/// rehashed when its user logs in.
+#[post("/admin/rehash")]
+pub async fn rehash(
+    _admin: Admin,
+    store: &State<Store>,
+    config: &State<Config>,
+) -> Result<Json<RehashReport>, Status> {
+    let outdated = store
+        .outdated_password_hashes()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    Ok(Json(RehashReport {
+        variant: config.argon2.variant.clone(),
+        outdated,
+    }))
+}
+
+/// Creates an invite code, for admins to hand out while registration is invite only.
+#[post("/admin/invite-codes")]
+pub async fn invite_codes(admin: Admin, store: &State<Store>) -> Result<Json<InviteCode>, Status> {
+    store
+        .create_invite_code(&admin.0.username)
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+/// How often each user can download their data.
+const EXPORT_INTERVAL: TimeDelta = TimeDelta::hours(1);
+
+/// When each user, by lowercase username, last downloaded their data.
+#[derive(Default)]
+pub struct ExportLimiter {
+    exported_at: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl ExportLimiter {
+    /// Records an export by `username` at `now`, unless they already exported within the interval.
+    pub async fn allow(&self, username: &str, now: DateTime<Utc>) -> bool {
+        let mut exported_at = self.exported_at.lock().await;
+        let username = username.to_lowercase();
+
+        if exported_at
+            .get(&username)
+            .is_some_and(|last| now - *last < EXPORT_INTERVAL)
+        {
+            return false;
+        }
+
+        exported_at.insert(username, now);
+
+        true
+    }
+}
+
+/// How many requests each client can make to the leaderboard per window.
+const RATE_LIMIT: u32 = 100;
+/// How long a rate limit window lasts, from the first request of the client in it.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Where the rate limit window of a request's client stands, kept for its response.
+#[derive(Clone, Copy)]
+struct RateLimitStatus {
+    remaining: u32,
+    reset_in: Duration,
+    exceeded: bool,
+}
+
+impl RateLimitStatus {
+    /// Seconds until the window resets, rounded up so clients retrying on time find the new one.
+    fn reset_in_secs(&self) -> u64 {
+        self.reset_in.as_secs() + u64::from(self.reset_in.subsec_nanos() > 0)
+    }
+}
+
+/// Counts the requests to the leaderboard, which bots poll, by client IP. Every response tells
+/// the client its limit, how many requests it has left and when the window resets. Turning away
+/// the requests over the limit is left to the [`WithinRateLimit`] guard.
+#[derive(Default)]
+pub struct RateLimitInfo {
+    requests: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+}
+
+impl RateLimitInfo {
+    fn limited(request: &Request<'_>) -> bool {
+        request.uri().path() == "/api/leaderboard"
+    }
+
+    /// Counts a request from `ip` at `now` in its client's window, starting a new one if it's over.
+    async fn count(&self, ip: IpAddr, now: Instant) -> RateLimitStatus {
+        let mut requests = self.requests.lock().await;
+
+        requests.retain(|_, (_, started)| now.duration_since(*started) < RATE_LIMIT_WINDOW);
+
+        let (count, started) = requests.entry(ip).or_insert((0, now));
+        *count = count.saturating_add(1);
+
+        RateLimitStatus {
+            remaining: RATE_LIMIT.saturating_sub(*count),
+            reset_in: RATE_LIMIT_WINDOW.saturating_sub(now.duration_since(*started)),
+            exceeded: *count > RATE_LIMIT,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimitInfo {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit info",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        if !Self::limited(req) {
+            return;
+        }
+
+        if let Some(ip) = req.client_ip() {
+            let status = self.count(ip, Instant::now()).await;
+            req.local_cache(|| Some(status));
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Some(status) = *req.local_cache(|| None::<RateLimitStatus>) else {
+            return;
+        };
+
+        let reset_at = Utc::now().timestamp() + status.reset_in_secs() as i64;
+
+        res.set_header(Header::new("X-RateLimit-Limit", RATE_LIMIT.to_string()));
+        res.set_header(Header::new(
+            "X-RateLimit-Remaining",
+            status.remaining.to_string(),
+        ));
+        res.set_header(Header::new("X-RateLimit-Reset", reset_at.to_string()));
+    }
+}
+
+/// Guards the rate limited routes, failing with a 429 before the handler runs once the client went
+/// over its limit, as counted by [`RateLimitInfo`].
+pub struct WithinRateLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WithinRateLimit {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.local_cache(|| None::<RateLimitStatus>) {
+            Some(status) if status.exceeded => Outcome::Error((Status::TooManyRequests, ())),
+            _ => Outcome::Success(Self),
+        }
+    }
+}
+
+/// An empty 429, telling clients over their rate limit when to retry if it's known.
+pub struct RetryLater(Option<u64>);
+
+impl<'r> Responder<'r, 'static> for RetryLater {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.status(Status::TooManyRequests);
+
+        if let Some(seconds) = self.0 {
+            response.header(Header::new("Retry-After", seconds.to_string()));
+        }
+
+        response.ok()
+    }
+}
+
+#[catch(429)]
+pub fn too_many_requests(req: &Request) -> RetryLater {
+    RetryLater(
+        req.local_cache(|| None::<RateLimitStatus>)
+            .filter(|status| status.exceeded)
+            .map(|status| status.reset_in_secs()),
+    )
+}
+
+/// A JSON document offered as a download rather than shown in the browser.
+#[derive(Responder)]
+pub struct JsonDownload {
+    body: Json<UserDataExport>,
+    disposition: Header<'static>,
+}
+
+#[get("/me/export")]
+pub async fn me_export(
+    user: User,
+    store: &State<Store>,
+    limiter: &State<ExportLimiter>,
+) -> Result<JsonDownload, Status> {
+    if !limiter.allow(&user.username, Utc::now()).await {
+        return Err(Status::TooManyRequests);
+    }
+
+    let export = store
+        .export_user_data(&user.username)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    Ok(JsonDownload {
+        body: Json(export),
+        disposition: Header::new(
+            "Content-Disposition",
+            "attachment; filename=\"my_data.json\"",
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{
+        http::{ContentType, Header},
+        local::blocking::Client,
+    };
+    use std::net::SocketAddr;
+
+    fn client() -> Client {
+        let rocket = rocket::build()
+            .manage(Store::new("test_data/leaderboard_accept/"))
+            .manage(Config::default())
+            .attach(RateLimitInfo::default())
+            .mount("/api", routes![leaderboard, users])
+            .register("/api", catchers![too_many_requests]);
+
+        Client::tracked(rocket).expect("The test rocket instance should be valid")
+    }
+
+    #[test]
+    fn leaderboard_accept_text() {
+        let client = client();
+        let response = client
+            .get("/api/leaderboard")
+            .header(Header::new("Accept", "text/plain"))
+            .dispatch();
+
+        assert!(response.status() == Status::Ok);
+        assert!(response.content_type() == Some(ContentType::Plain));
+    }
+
+    #[test]
+    fn leaderboard_accept_json() {
+        let client = client();
+        let response = client
+            .get("/api/leaderboard")
+            .header(Header::new("Accept", "application/json"))
+            .dispatch();
+
+        assert!(response.status() == Status::Ok);
+        assert!(response.content_type() == Some(ContentType::JSON));
+
+        let response = client
+            .get("/api/leaderboard?format=text")
+            .header(Header::new("Accept", "application/json"))
+            .dispatch();
+
+        assert!(response.content_type() == Some(ContentType::Plain));
+    }
+
+    fn remote(ip: &str) -> SocketAddr {
+        SocketAddr::new(
+            ip.parse().expect("The test IP address should be valid"),
+            8000,
+        )
+    }
+
+    #[test]
+    fn leaderboard_sort() {
+        let client = client();
+
+        assert!(client.get("/api/leaderboard?sort=wins").dispatch().status() == Status::Ok);
+        assert!(
+            client
+                .get("/api/leaderboard?sort=points")
+                .dispatch()
+                .status()
+                == Status::Ok
+        );
+        assert!(client.get("/api/leaderboard?sort=rank").dispatch().status() == Status::BadRequest);
+    }
+
+    #[test]
+    fn leaderboard_rate_limit_headers() {
+        let client = client();
+        let response = client
+            .get("/api/leaderboard")
+            .remote(remote("192.0.2.1"))
+            .dispatch();
+        let header = |name| response.headers().get_one(name).unwrap_or_default();
+
+        assert!(response.status() == Status::Ok);
+        assert!(header("X-RateLimit-Limit") == "100");
+        assert!(header("X-RateLimit-Remaining") == "99");
+        assert!(
+            header("X-RateLimit-Reset")
+                .parse::<i64>()
+                .is_ok_and(|reset| reset > Utc::now().timestamp())
+        );
+
+        // Only the leaderboard is rate limited.
+        let response = client
+            .get("/api/users")
+            .remote(remote("192.0.2.1"))
+            .dispatch();
+
+        assert!(response.headers().get_one("X-RateLimit-Limit").is_none());
+    }
+
+    #[test]
+    fn leaderboard_rate_limit() {
+        let client = client();
+
+        for remaining in (0..RATE_LIMIT).rev() {
+            let response = client
+                .get("/api/leaderboard?format=text")
+                .remote(remote("192.0.2.1"))
+                .dispatch();
+
+            assert!(response.status() == Status::Ok);
+            assert!(
+                response.headers().get_one("X-RateLimit-Remaining")
+                    == Some(remaining.to_string().as_str())
+            );
+        }
+
+        let response = client
+            .get("/api/leaderboard?format=text")
+            .remote(remote("192.0.2.1"))
+            .dispatch();
+
+        assert!(response.status() == Status::TooManyRequests);
+        assert!(response.headers().get_one("X-RateLimit-Remaining") == Some("0"));
+        assert!(
+            response
+                .headers()
+                .get_one("Retry-After")
+                .and_then(|seconds| seconds.parse::<u64>().ok())
+                .is_some_and(|seconds| (1..=60).contains(&seconds))
+        );
+        assert!(response.into_string().unwrap_or_default().is_empty());
+
+        // The limit is checked before the handler runs, which would reject the sort.
+        let response = client
+            .get("/api/leaderboard?sort=rank")
+            .remote(remote("192.0.2.1"))
+            .dispatch();
+
+        assert!(response.status() == Status::TooManyRequests);
+
+        // Other clients have their own window.
+        let response = client
+            .get("/api/leaderboard?format=text")
+            .remote(remote("192.0.2.2"))
+            .dispatch();
+
+        assert!(response.status() == Status::Ok);
+        assert!(response.headers().get_one("X-RateLimit-Remaining") == Some("99"));
+    }
+
+    #[test]
+    fn users_json_hides_password() {
+        let path = "test_data/users_json_hides_password/";
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}users.csv"),
+                "token,username,password,country\n\
+                 secret-token,test,$argon2id$v=19$secret-hash,PT\n",
+            )
+            .is_ok()
+        );
+
+        let rocket = rocket::build()
+            .manage(Store::new(path))
+            .mount("/api", routes![users]);
+        let client = Client::tracked(rocket).expect("The test rocket instance should be valid");
+        let body = client
+            .get("/api/users?format=json")
+            .dispatch()
+            .into_string()
+            .unwrap_or_default();
+
+        assert!(body.contains("\"username\":\"test\""));
+        assert!(!body.contains("secret-hash"));
+        assert!(!body.contains("secret-token"));
+    }
+
+    fn play_client(path: &'static str) -> Client {
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}users.csv"),
+                "token,username,password,country\n\
+                 test-token,test,,PT\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],Test GP,Race,2099-03-01 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}drivers.csv"),
+                "number,code,name\n\
+                 4,NOR,Lando Norris\n\
+                 1,VER,Max Verstappen\n\
+                 81,PIA,Oscar Piastri\n\
+                 63,RUS,George Russell\n\
+                 16,LEC,Charles Leclerc\n",
+            )
+            .is_ok()
+        );
+        assert!(std::fs::write(format!("{path}guesses.csv"), "").is_ok());
+
+        let rocket = rocket::build()
+            .manage(Store::new(path))
+            .manage(Config::default())
+            .mount("/api", routes![play, play_form_encoded]);
+
+        Client::tracked(rocket).expect("The test rocket instance should be valid")
+    }
+
+    #[test]
+    fn play_json() {
+        let client = play_client("test_data/play_json/");
+        let response = client
+            .post("/api/play")
+            .header(ContentType::JSON)
+            .header(Header::new("x-api-key", "test-token"))
+            .body(
+                r#"{"race":"","username":"test","p1":"NOR","p2":"VER","p3":"PIA","p4":"RUS","p5":"LEC"}"#,
+            )
+            .dispatch();
+
+        assert!(response.status() == Status::Ok);
+        assert!(
+            response
+                .into_string()
+                .is_some_and(|body| body.contains("\"race\":\"TEST GP\""))
+        );
+    }
+
+    #[test]
+    fn play_form_encoded() {
+        let client = play_client("test_data/play_form_encoded/");
+        let response = client
+            .post("/api/play")
+            .header(ContentType::Form)
+            .header(Header::new("x-api-key", "test-token"))
+            .body("race=&username=test&p1=NOR&p2=VER&p3=PIA&p4=RUS&p5=LEC")
+            .dispatch();
+
+        assert!(response.status() == Status::Ok);
+        assert!(
+            response
+                .into_string()
+                .is_some_and(|body| body.contains("\"race\":\"TEST GP\""))
+        );
+
+        // Form-encoded guesses go through the same validation as JSON ones.
+        let response = client
+            .post("/api/play")
+            .header(ContentType::Form)
+            .header(Header::new("x-api-key", "test-token"))
+            .body("race=&username=test&p1=NOR&p2=NOR&p3=PIA&p4=RUS&p5=LEC")
+            .dispatch();
+
+        assert!(response.status() == Status::BadRequest);
+    }
+
+    #[test]
+    fn leaderboard_csv() {
+        let client = client();
+        let response = client.get("/api/leaderboard?format=csv").dispatch();
+
+        assert!(response.status() == Status::Ok);
+        assert!(response.headers().get_one("Content-Type") == Some("text/csv; charset=utf-8"));
+        assert!(response.into_string().as_deref() == Some("rank,username,points,wins\n"));
+
+        let response = client.get("/api/leaderboard?format=tsv").dispatch();
+
+        assert!(
+            response.headers().get_one("Content-Type")
+                == Some("text/tab-separated-values; charset=utf-8")
+        );
+    }
+
+    #[test]
+    fn leaderboard_include_guesses_unauthorized() {
+        let client = client();
+        let response = client
+            .get("/api/leaderboard?include_guesses=true")
+            .dispatch();
+
+        assert!(response.status() == Status::Unauthorized);
+
+        let response = client
+            .get("/api/leaderboard?include_guesses=false")
+            .dispatch();
+
+        assert!(response.status() == Status::Ok);
     }
 }
@@ -0,0 +1,509 @@
+use argon2::Algorithm;
+use serde::{Deserialize, Deserializer, Serialize, de::Error};
+
+use crate::models::Event;
+use crate::store::{CONSTRUCTOR_CORRECT, CORRECT_FIVE, CORRECT_PODIUM, PARLAY, WRONG_PLACE};
+
+const CATEGORY: &str = "formula 1";
+const CHANNEL: &str = "#formula1";
+const MAX_MATRIX_CELLS: usize = 10_000;
+const LEADERBOARD_SIZE: usize = 10;
+const REMINDER_MINUTES: i64 = 60;
+const MAX_WIDGET_SIZE: usize = 50;
+const ARGON2_VARIANT: &str = "argon2id";
+const DOUBLE_POINTS_MULTIPLIER: u16 = 2;
+const SPRINT_MULTIPLIER: u16 = 1;
+
+/// Application settings read from the `Rocket.toml` file or `ROCKET_*` environment variables.
+///
+/// Every field has a default, so the application runs without any configuration at all.
+/// For example, to include constructor guesses in the leaderboard and run a MotoGP game
+/// alongside the primary Formula 1 one:
+///
+/// ```toml
+/// [default.scoring]
+/// include_constructor = true
+///
+/// [[default.scoring.seasons]]
+/// season = 2025
+/// include_constructor = false
+///
+/// [[default.categories]]
+/// name = "formula 1"
+/// channel = "#formula1"
+///
+/// [[default.categories]]
+/// name = "motogp"
+/// channel = "#motogp"
+///
+/// [default]
+/// admins = ["vasco"]
+///
+/// [default.argon2]
+/// variant = "argon2id"
+///
+/// [default.registration]
+/// invite_only = true
+/// ```
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub scoring: ScoringConfig,
+    /// At least one category, the first being the primary one.
+    #[serde(
+        default = "default_categories",
+        deserialize_with = "deserialize_categories"
+    )]
+    pub categories: Vec<Category>,
+    /// Usernames allowed to access the admin pages.
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// Upper bound on the number of cells of the leaderboard score matrix.
+    #[serde(default = "default_max_matrix_cells")]
+    pub max_matrix_cells: usize,
+    /// Number of leaderboard entries shown on the index page, before linking to the standings.
+    #[serde(default = "default_leaderboard_size")]
+    pub leaderboard_size: usize,
+    /// How many minutes before an event flagged with `notify` a reminder is logged.
+    #[serde(default = "default_reminder_minutes")]
+    pub reminder_minutes: i64,
+    /// Origins allowed to embed the widgets in a frame, for instance `https://club.example.com`.
+    /// When empty, any origin may embed them.
+    #[serde(default)]
+    pub widget_frame_ancestors: Vec<String>,
+    #[serde(default)]
+    pub argon2: Argon2Config,
+    #[serde(default)]
+    pub registration: RegistrationConfig,
+}
+
+impl Config {
+    /// The first configured category is the primary one, used whenever none is requested.
+    pub fn primary_category(&self) -> &Category {
+        &self.categories[0]
+    }
+
+    /// Whether `category` is the primary category, which values without a category belong to.
+    pub fn is_primary(&self, category: &Category) -> bool {
+        category.name == self.primary_category().name
+    }
+
+    /// Finds a category by name, falling back to the primary category when `name` is `None`.
+    pub fn category(&self, name: Option<&str>) -> Option<&Category> {
+        match name {
+            Some(name) => self
+                .categories
+                .iter()
+                .find(|c| c.name.eq_ignore_ascii_case(name)),
+            None => Some(self.primary_category()),
+        }
+    }
+
+    /// Value of the `Content-Security-Policy` header sent with the widgets.
+    pub fn widget_frame_policy(&self) -> String {
+        if self.widget_frame_ancestors.is_empty() {
+            "frame-ancestors *".to_string()
+        } else {
+            format!("frame-ancestors {}", self.widget_frame_ancestors.join(" "))
+        }
+    }
+
+    /// Number of leaderboard entries shown by a widget, within sensible bounds.
+    pub fn widget_size(&self, limit: Option<usize>) -> usize {
+        limit
+            .unwrap_or(self.leaderboard_size)
+            .clamp(1, MAX_WIDGET_SIZE)
+    }
+
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admins.iter().any(|a| a.eq_ignore_ascii_case(username))
+    }
+
+    /// Whether a category stored on a guess or driver is the given category.
+    /// Rows stored before categories existed have an empty category and belong to the primary one.
+    pub fn belongs_to(&self, value: &str, category: &Category) -> bool {
+        if value.is_empty() {
+            self.is_primary(category)
+        } else {
+            value.eq_ignore_ascii_case(&category.name)
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            scoring: ScoringConfig::default(),
+            categories: default_categories(),
+            admins: Vec::new(),
+            max_matrix_cells: default_max_matrix_cells(),
+            leaderboard_size: default_leaderboard_size(),
+            reminder_minutes: default_reminder_minutes(),
+            widget_frame_ancestors: Vec::new(),
+            argon2: Argon2Config::default(),
+            registration: RegistrationConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ScoringConfig {
+    #[serde(default)]
+    pub include_constructor: bool,
+    /// Consolation points for each pick finishing from P6 to P10. None are awarded by default.
+    #[serde(default)]
+    pub top_ten_points: u16,
+    /// How many times the points of races tagged `double-points` count. Twice by default.
+    #[serde(default = "default_double_points_multiplier")]
+    pub double_points_multiplier: u16,
+    /// How many times the points of races tagged `sprint` count. Once by default, like any race.
+    #[serde(default = "default_sprint_multiplier")]
+    pub sprint_multiplier: u16,
+    /// Scoring that applied to given seasons, when it differs from the current one.
+    #[serde(default)]
+    pub seasons: Vec<SeasonScoringConfig>,
+}
+
+impl Default for ScoringConfig {
+    fn default() -> Self {
+        Self {
+            include_constructor: false,
+            top_ten_points: 0,
+            double_points_multiplier: default_double_points_multiplier(),
+            sprint_multiplier: default_sprint_multiplier(),
+            seasons: Vec::new(),
+        }
+    }
+}
+
+impl ScoringConfig {
+    /// Whether constructor guesses count in `season`, or in the current scoring when `None`.
+    pub fn include_constructor(&self, season: Option<u16>) -> bool {
+        season
+            .and_then(|season| self.seasons.iter().find(|s| s.season == season))
+            .map_or(self.include_constructor, |s| s.include_constructor)
+    }
+
+    /// Consolation points for picks from P6 to P10 in `season`, or in the current scoring when
+    /// `None`.
+    pub fn top_ten_points(&self, season: Option<u16>) -> u16 {
+        season
+            .and_then(|season| self.seasons.iter().find(|s| s.season == season))
+            .map_or(self.top_ten_points, |s| s.top_ten_points)
+    }
+
+    /// How many times the points of a race count, from the tags of its event. A race tagged both
+    /// `double-points` and `sprint` gets both multipliers.
+    pub fn multiplier(&self, event: &Event) -> u16 {
+        let mut multiplier: u16 = 1;
+
+        if event.has_tag(Event::DOUBLE_POINTS_TAG) {
+            multiplier = multiplier.saturating_mul(self.double_points_multiplier);
+        }
+        if event.has_tag(Event::SPRINT_TAG) {
+            multiplier = multiplier.saturating_mul(self.sprint_multiplier);
+        }
+
+        multiplier
+    }
+
+    /// Lists the scoring rules in effect for `season`, leaving out the disabled ones.
+    pub fn rules(&self, season: Option<u16>) -> Vec<ScoringRule> {
+        let mut rules = vec![
+            ScoringRule {
+                name: "Correct driver in the correct position (P1-P3)",
+                points: CORRECT_PODIUM,
+                description: "For each podium position guessed exactly.",
+            },
+            ScoringRule {
+                name: "Correct driver in the correct position (P4-P5)",
+                points: CORRECT_FIVE,
+                description: "For each of the remaining top 5 positions guessed exactly.",
+            },
+            ScoringRule {
+                name: "Correct driver but in the wrong position",
+                points: WRONG_PLACE,
+                description: "For each driver finishing anywhere else in the top 5.",
+            },
+            ScoringRule {
+                name: "Driver not present in the actual top 5",
+                points: 0,
+                description: "For each driver finishing outside the top 5.",
+            },
+            ScoringRule {
+                name: "All drivers in the correct position (P1-P5)",
+                points: PARLAY,
+                description: "Bonus on top of the points above for a perfect guess.",
+            },
+        ];
+
+        let top_ten_points = self.top_ten_points(season);
+
+        if top_ten_points > 0 {
+            rules.insert(
+                3,
+                ScoringRule {
+                    name: "Driver finishing from P6 to P10",
+                    points: top_ten_points,
+                    description: "For each driver finishing in the rest of the top 10.",
+                },
+            );
+        }
+
+        if self.include_constructor(season) {
+            rules.push(ScoringRule {
+                name: "Correct constructor in the correct position (C1-C2)",
+                points: CONSTRUCTOR_CORRECT,
+                description: "For each of the two best constructors guessed exactly.",
+            });
+        }
+
+        rules
+    }
+}
+
+/// Scoring overrides for a single season, for instance one played without constructor guesses.
+#[derive(Clone, Deserialize)]
+pub struct SeasonScoringConfig {
+    pub season: u16,
+    #[serde(default)]
+    pub include_constructor: bool,
+    #[serde(default)]
+    pub top_ten_points: u16,
+}
+
+/// How new passwords are hashed.
+///
+/// Stored hashes name the variant they were made with, so passwords hashed with a previous variant
+/// still verify. They can't be rehashed without the password itself though, so changing the variant
+/// only converts a user's hash the next time they login.
+#[derive(Deserialize)]
+pub struct Argon2Config {
+    /// One of `argon2i`, `argon2d` or `argon2id`.
+    #[serde(
+        default = "default_argon2_variant",
+        deserialize_with = "deserialize_argon2_variant"
+    )]
+    pub variant: String,
+}
+
+impl Argon2Config {
+    pub fn algorithm(&self) -> Algorithm {
+        self.variant.parse().unwrap_or_default()
+    }
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            variant: default_argon2_variant(),
+        }
+    }
+}
+
+/// Who can register.
+#[derive(Default, Deserialize)]
+pub struct RegistrationConfig {
+    /// Only people with an unused invite code, created by an admin, can register.
+    #[serde(default)]
+    pub invite_only: bool,
+}
+
+/// A scoring rule as explained on the rules page.
+#[derive(Serialize)]
+pub struct ScoringRule {
+    pub name: &'static str,
+    pub points: u16,
+    pub description: &'static str,
+}
+
+/// A racing category with its own events, drivers, guesses and leaderboard.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Category {
+    /// Lowercase name matched against the category of events.
+    pub name: String,
+    /// Channel the events of this category are announced on.
+    pub channel: String,
+}
+
+fn default_categories() -> Vec<Category> {
+    vec![Category {
+        name: CATEGORY.to_string(),
+        channel: CHANNEL.to_string(),
+    }]
+}
+
+fn default_max_matrix_cells() -> usize {
+    MAX_MATRIX_CELLS
+}
+
+fn default_double_points_multiplier() -> u16 {
+    DOUBLE_POINTS_MULTIPLIER
+}
+
+fn default_sprint_multiplier() -> u16 {
+    SPRINT_MULTIPLIER
+}
+
+fn default_leaderboard_size() -> usize {
+    LEADERBOARD_SIZE
+}
+
+fn default_reminder_minutes() -> i64 {
+    REMINDER_MINUTES
+}
+
+fn default_argon2_variant() -> String {
+    ARGON2_VARIANT.to_string()
+}
+
+/// Refuses to start with an unknown variant, rather than hashing passwords with another one.
+fn deserialize_argon2_variant<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    let variant = String::deserialize(deserializer)?.to_lowercase();
+
+    match variant.parse::<Algorithm>() {
+        Ok(_) => Ok(variant),
+        Err(_) => Err(D::Error::custom(format!(
+            "unknown Argon2 variant '{variant}', expected argon2i, argon2d or argon2id"
+        ))),
+    }
+}
+
+fn deserialize_categories<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Category>, D::Error> {
+    let categories = Vec::<Category>::deserialize(deserializer)?;
+
+    if categories.is_empty() {
+        return Err(D::Error::custom("expected at least one category"));
+    }
+
+    Ok(categories)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::figment::{
+        Figment,
+        providers::{Format, Toml},
+    };
+
+    #[test]
+    fn scoring_rules() {
+        let scoring = ScoringConfig {
+            include_constructor: true,
+            top_ten_points: 1,
+            seasons: vec![SeasonScoringConfig {
+                season: 2025,
+                include_constructor: false,
+                top_ten_points: 0,
+            }],
+            ..Default::default()
+        };
+
+        let constructor_rule = |rules: Vec<ScoringRule>| {
+            rules
+                .iter()
+                .any(|rule| rule.points == CONSTRUCTOR_CORRECT && rule.name.contains("constructor"))
+        };
+
+        assert!(constructor_rule(scoring.rules(None)));
+        assert!(constructor_rule(scoring.rules(Some(2026))));
+        assert!(!constructor_rule(scoring.rules(Some(2025))));
+
+        // A season overriding the scoring doesn't inherit the consolation points either.
+        let top_ten_rule =
+            |rules: Vec<ScoringRule>| rules.iter().any(|rule| rule.name.contains("P6"));
+
+        assert!(top_ten_rule(scoring.rules(None)) && top_ten_rule(scoring.rules(Some(2026))));
+        assert!(!top_ten_rule(scoring.rules(Some(2025))));
+    }
+
+    #[test]
+    fn scoring_multiplier() {
+        let scoring = |toml: &str| {
+            Figment::from(Toml::string(toml))
+                .extract::<Config>()
+                .map(|config| config.scoring)
+                .unwrap_or_default()
+        };
+        let event = |tags: &str| Event {
+            category: "[Formula 1]".to_string(),
+            name: "Test GP".to_string(),
+            description: "Race".to_string(),
+            datetime: chrono::Utc::now(),
+            channel: "#formula1".to_string(),
+            tags: tags.to_string(),
+            notify: true,
+            id: String::new(),
+        };
+
+        // Double points races count twice by default, while sprints count like any other race.
+        let default = scoring("");
+
+        assert!(default.multiplier(&event("")) == 1);
+        assert!(default.multiplier(&event("double-points")) == 2);
+        assert!(default.multiplier(&event("Sprint")) == 1);
+
+        let configured = scoring("[scoring]\ndouble_points_multiplier = 3\nsprint_multiplier = 2");
+
+        assert!(configured.multiplier(&event("no-game")) == 1);
+        assert!(configured.multiplier(&event("double-points")) == 3);
+        assert!(configured.multiplier(&event("sprint")) == 2);
+        assert!(configured.multiplier(&event("sprint;double-points")) == 6);
+    }
+
+    #[test]
+    fn argon2_variant() {
+        let algorithm = |toml: &str| {
+            Figment::from(Toml::string(toml))
+                .extract::<Config>()
+                .ok()
+                .map(|config| config.argon2.algorithm())
+        };
+
+        assert!(algorithm("") == Some(Algorithm::Argon2id));
+        assert!(algorithm("[argon2]\nvariant = \"Argon2i\"") == Some(Algorithm::Argon2i));
+        assert!(algorithm("[argon2]\nvariant = \"argon2x\"").is_none());
+    }
+
+    #[test]
+    fn categories() {
+        let primary = |toml: &str| {
+            Figment::from(Toml::string(toml))
+                .extract::<Config>()
+                .ok()
+                .map(|config| config.primary_category().name.clone())
+        };
+
+        assert!(primary("").as_deref() == Some(CATEGORY));
+        assert!(
+            primary("[[categories]]\nname = \"motogp\"\nchannel = \"#motogp\"").as_deref()
+                == Some("motogp")
+        );
+        assert!(primary("categories = []").is_none());
+    }
+
+    #[test]
+    fn widget() {
+        let mut config = Config::default();
+
+        assert!(config.widget_frame_policy() == "frame-ancestors *");
+        assert!(config.widget_size(None) == LEADERBOARD_SIZE);
+        assert!(config.widget_size(Some(0)) == 1);
+        assert!(config.widget_size(Some(1000)) == MAX_WIDGET_SIZE);
+
+        config.widget_frame_ancestors = vec![
+            "https://club.example.com".to_string(),
+            "https://example.org".to_string(),
+        ];
+
+        assert!(
+            config.widget_frame_policy()
+                == "frame-ancestors https://club.example.com https://example.org"
+        );
+    }
+}
@@ -1,194 +1,703 @@
-use csv_db::Database;
+use std::{cmp::Reverse, collections::HashMap};
+
+use chrono::{DateTime, Datelike, Utc};
+use chrono_tz::{TZ_VARIANTS, Tz};
+use csv_db::DbError;
 use itertools::Itertools;
 use rocket::{
     Request, State,
-    form::Form,
-    http::{Cookie, CookieJar, SameSite},
-    request::FlashMessage,
+    form::{self, Contextual, Form},
+    fs::TempFile,
+    http::{Cookie, CookieJar, Header, SameSite, Status},
+    request::{FlashMessage, FromRequest, Outcome},
     response::{Flash, Redirect},
+    serde::{
+        Serialize,
+        json::{Json, Value, json},
+    },
     time::{Duration, OffsetDateTime},
-    tokio::sync::Mutex,
+    tokio::{fs, io::AsyncReadExt, join},
     uri,
 };
 use rocket_dyn_templates::{Template, context};
 
-use crate::models::{Guess, Profile, Registration, ScoredGuess, User};
-use crate::store::{CORRECT_FIVE, CORRECT_PODIUM, PARLAY, Store, WRONG_PLACE};
+use crate::config::Config;
+use crate::models::{
+    AVATAR_DIR, Admin, ConstructorGuess, Country, CsrfToken, DEFAULT_AVATAR, Driver, DriverCode,
+    DriverForm, DriverKey, Event, EventForm, EventKey, FormTimestamp, Guess, GuessForm,
+    GuessHistoryRow, HIDDEN_PICK, HistoryEvent, LeaderboardEntry, Login, MAX_AVATAR_SIZE,
+    PrefsForm, Profile, PublicUser, RaceResult, RaceResultForm, Registration, ScoredGuess,
+    ScoredGuessOwned, SeasonSummary, UiPrefs, User, UserHistorySummary, avatar_extension,
+    avatar_file_name, avatar_path, time_ago,
+};
+use crate::store::{
+    CONSTRUCTOR_CORRECT, CORRECT_FIVE, CORRECT_PODIUM, PARLAY, Store, StoreError, WRONG_PLACE,
+};
+
+const LATEST_LIMIT: usize = 20;
+const MAX_LATEST_LIMIT: usize = 100;
+
+/// Parses the season query parameter, where "all" selects every season and anything else falls
+/// back to the season of the current event, or the latest season once it's over.
+fn selected_season(
+    season: Option<&str>,
+    current_event: Option<&Event>,
+    seasons: &[u16],
+) -> Option<u16> {
+    let current_season = match current_event {
+        Some(current_event) => current_event.datetime.year() as u16,
+        None => seasons.first().copied().unwrap_or(Utc::now().year() as u16),
+    };
+
+    match season {
+        Some("all") => None,
+        Some(season) => season.parse().ok().or(Some(current_season)),
+        None => Some(current_season),
+    }
+}
+
+/// Describes when the last result was entered, for instance "2 hours ago".
+async fn standings_updated(store: &Store) -> String {
+    match store.results_updated_at().await {
+        Ok(Some(entered_at)) => time_ago(entered_at, Utc::now()),
+        _ => "unknown".to_string(),
+    }
+}
 
-#[get("/")]
-pub async fn index(cookies: &CookieJar<'_>, db: &State<Mutex<Database<&str>>>) -> Template {
+#[get("/?<season>&<category>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn index(
+    layout: Layout,
+    cookies: &CookieJar<'_>,
+    flash: Option<FlashMessage<'_>>,
+    user: Option<User>,
+    store: &State<Store>,
+    config: &State<Config>,
+    season: Option<&str>,
+    category: Option<&str>,
+) -> Template {
     let logged_in = cookies.get_private("session").is_some();
+    let flash = flash.map(|flash| flash.message().to_string());
 
-    let store = Store::new(db);
+    let category = config
+        .category(category)
+        .unwrap_or(config.primary_category());
+    let categories = &config.categories;
 
-    let normalized_results = match store.normalized_results().await {
-        Ok(normalized_results) => normalized_results,
+    let (scored_guesses, current_event, seasons) = join!(
+        store.get_all_scored_guesses(),
+        store.next_event(category),
+        store.seasons(category),
+    );
+
+    let scored_guesses = match scored_guesses {
+        Ok(scored_guesses) => scored_guesses,
         Err(_) => {
-            return Template::render(
-                "history",
-                context! { error: "Could not get event results.", logged_in },
+            return layout.render(
+                "index",
+                context! { error: "Could not get guesses.", logged_in },
             );
         }
     };
+    let in_category = |sg: &&ScoredGuessOwned| config.belongs_to(&sg.guess.category, category);
 
-    let guesses = match store.get_guesses(None, None).await {
-        Ok(guesses) => guesses,
-        Err(_) => {
-            return Template::render(
-                "history",
-                context! { error: "Could not get guesses.", logged_in },
+    // Between the season finale and the next calendar there is no upcoming event, in which case
+    // the page shows the final standings of the last season instead.
+    let current_event = current_event.ok().flatten();
+    let seasons = seasons.unwrap_or_default();
+
+    let season = selected_season(season, current_event.as_ref(), &seasons);
+
+    let leaderboard = store
+        .leaderboard_for_season(
+            category,
+            config.is_primary(category),
+            season,
+            config.scoring.include_constructor(season),
+        )
+        .await
+        .unwrap_or_default();
+    let leaderboard_count = leaderboard.len();
+    let avatars = store.avatars().await.unwrap_or_default();
+    let mut leaderboard = LeaderboardEntry::rank(
+        leaderboard,
+        user.as_ref().map(|u| u.username.as_str()),
+        &avatars,
+    );
+
+    if let Ok(Some(previous)) = store
+        .previous_leaderboard_for_season(
+            category,
+            config.is_primary(category),
+            season,
+            config.scoring.include_constructor(season),
+        )
+        .await
+    {
+        LeaderboardEntry::track_changes(&mut leaderboard, &previous);
+    }
+
+    // Keep the page short with the top of the leaderboard, plus the row of the user wherever it is.
+    let leaderboard: Vec<LeaderboardEntry> = leaderboard
+        .into_iter()
+        .filter(|entry| entry.rank <= config.leaderboard_size || entry.current_user)
+        .collect();
+
+    // Anonymous visitors get no summary at all.
+    let summary = match &user {
+        Some(user) => {
+            let (history, results) = join!(
+                store.event_history(&user.username, category),
+                store.normalized_results(),
             );
+            let history = history.unwrap_or_default();
+            let results = results.unwrap_or_default();
+            let submitted = scored_guesses.iter().filter(in_category).any(|sg| {
+                sg.guess.username.eq_ignore_ascii_case(&user.username)
+                    && current_event
+                        .as_ref()
+                        .is_some_and(|e| sg.guess.race.eq_ignore_ascii_case(&e.name))
+            });
+
+            Some(SeasonSummary::new(
+                leaderboard.iter().find(|entry| entry.current_user),
+                &history,
+                &results,
+                submitted,
+            ))
         }
+        None => None,
     };
-    let scored_guesses = store.scored_guesses(&guesses, &normalized_results).await;
-    let grouped_guesses = scored_guesses
-        .iter()
-        .into_group_map_by(|g| &g.guess.username);
 
-    let leaderboard = store.leaderboard(grouped_guesses).await;
+    // Only the number of guesses for the current event is exposed, never the guesses themselves.
+    let submitted_count = scored_guesses
+        .iter()
+        .filter(in_category)
+        .filter(|sg| {
+            current_event
+                .as_ref()
+                .is_some_and(|e| sg.guess.race.eq_ignore_ascii_case(&e.name))
+        })
+        .count();
+    let (players_count, standings_updated) = join!(store.users_count(), standings_updated(store));
+    let players_count = players_count.unwrap_or_default();
+
+    // The round of the current event is shown as the races before it plus one.
+    let (events_remaining, season_total) = match &current_event {
+        Some(current_event) => (
+            store
+                .events_remaining_count(category, current_event.datetime)
+                .await
+                .unwrap_or_default(),
+            store
+                .upcoming_events_count(category, current_event.datetime.year() as u16)
+                .await
+                .unwrap_or_default(),
+        ),
+        None => (0, 0),
+    };
 
-    let current_event = &store
-        .next_event()
+    // The player who earned the parlay bonus the most times, across every season.
+    let hall_of_fame = store
+        .parlay_leaderboard()
         .await
-        .expect("The next event should be available on the database");
+        .ok()
+        .and_then(|leaderboard| leaderboard.into_iter().next());
 
-    Template::render("index", context! { logged_in, current_event, leaderboard})
+    layout.render(
+        "index",
+        context! { logged_in, flash, summary, current_event, leaderboard, leaderboard_count, season, seasons, category, categories, submitted_count, players_count, events_remaining, season_total, standings_updated, hall_of_fame },
+    )
 }
 
-#[get("/history")]
-pub async fn history(
+#[get("/standings?<season>&<category>&<q>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn standings(
+    layout: Layout,
     cookies: &CookieJar<'_>,
-    user: User,
-    db: &State<Mutex<Database<&str>>>,
+    user: Option<User>,
+    store: &State<Store>,
+    config: &State<Config>,
+    season: Option<&str>,
+    category: Option<&str>,
+    q: Option<&str>,
 ) -> Template {
     let logged_in = cookies.get_private("session").is_some();
 
-    let store = Store::new(db);
-
-    let normalized_results = match store.normalized_results().await {
-        Ok(normalized_results) => normalized_results,
+    let category = config
+        .category(category)
+        .unwrap_or(config.primary_category());
+    let categories = &config.categories;
+
+    let current_event = store.next_event(category).await.ok().flatten();
+    let seasons = store.seasons(category).await.unwrap_or_default();
+    let season = selected_season(season, current_event.as_ref(), &seasons);
+
+    let leaderboard = match store
+        .leaderboard_for_season(
+            category,
+            config.is_primary(category),
+            season,
+            config.scoring.include_constructor(season),
+        )
+        .await
+    {
+        Ok(leaderboard) => leaderboard,
         Err(_) => {
-            return Template::render(
-                "history",
-                context! { error: "Could not get event results.", logged_in },
+            return layout.render(
+                "standings",
+                context! { error: "Could not get guesses.", logged_in },
             );
         }
     };
+    let avatars = store.avatars().await.unwrap_or_default();
+    let players_count = leaderboard.len();
+    let q = q.unwrap_or_default().trim();
+    let mut leaderboard = LeaderboardEntry::rank(
+        leaderboard,
+        user.as_ref().map(|u| u.username.as_str()),
+        &avatars,
+    );
+
+    if let Ok(Some(previous)) = store
+        .previous_leaderboard_for_season(
+            category,
+            config.is_primary(category),
+            season,
+            config.scoring.include_constructor(season),
+        )
+        .await
+    {
+        LeaderboardEntry::track_changes(&mut leaderboard, &previous);
+    }
 
-    let guesses = match store.get_guesses(Some(&user.username), None).await {
-        Ok(guesses) => guesses,
+    // Searching filters the rows after ranking them, so players keep their overall rank.
+    let leaderboard: Vec<LeaderboardEntry> = leaderboard
+        .into_iter()
+        .filter(|entry| LeaderboardEntry::name_matches(&entry.name, q))
+        .collect();
+    let standings_updated = standings_updated(store).await;
+
+    layout.render(
+        "standings",
+        context! { logged_in, leaderboard, players_count, q, season, seasons, category, categories, standings_updated },
+    )
+}
+
+/// Minimal leaderboard meant to be embedded in other sites, so it needs no session and has no
+/// navigation. The frame headers that allow embedding it are set by a fairing.
+#[get("/widget/leaderboard?<limit>&<theme>&<category>")]
+pub async fn widget_leaderboard(
+    store: &State<Store>,
+    config: &State<Config>,
+    limit: Option<usize>,
+    theme: Option<&str>,
+    category: Option<&str>,
+) -> Template {
+    let theme = match theme {
+        Some("dark") => "dark",
+        _ => "light",
+    };
+    let category = config
+        .category(category)
+        .unwrap_or(config.primary_category());
+
+    let current_event = store.next_event(category).await.ok().flatten();
+    let seasons = store.seasons(category).await.unwrap_or_default();
+    let season = selected_season(None, current_event.as_ref(), &seasons);
+
+    let leaderboard = match store
+        .leaderboard_for_season(
+            category,
+            config.is_primary(category),
+            season,
+            config.scoring.include_constructor(season),
+        )
+        .await
+    {
+        Ok(leaderboard) => leaderboard,
         Err(_) => {
             return Template::render(
-                "history",
-                context! { error: "Could not get your guess.", logged_in },
+                "widget_leaderboard",
+                context! { error: "Could not get guesses.", theme },
             );
         }
     };
-    let scored_guesses: Vec<ScoredGuess<'_>> = store
-        .scored_guesses(&guesses, &normalized_results)
-        .await
+    let avatars = store.avatars().await.unwrap_or_default();
+    let leaderboard: Vec<LeaderboardEntry> = LeaderboardEntry::rank(leaderboard, None, &avatars)
         .into_iter()
-        .rev()
-        .take(24)
+        .take(config.widget_size(limit))
         .collect();
 
-    Template::render("history", context! {scored_guesses, logged_in})
+    Template::render(
+        "widget_leaderboard",
+        context! { leaderboard, season, category, theme },
+    )
+}
+
+/// A CSV file offered as a download rather than shown in the browser.
+#[derive(Responder)]
+#[response(content_type = "text/csv")]
+pub struct CsvDownload {
+    body: String,
+    disposition: Header<'static>,
 }
 
-#[get("/latest")]
-pub async fn latest(cookies: &CookieJar<'_>, db: &State<Mutex<Database<&str>>>) -> Template {
-    let logged_in = cookies.get_private("session").is_some();
+#[get("/history.csv")]
+pub async fn history_csv(user: User, store: &State<Store>) -> Result<CsvDownload, Status> {
+    let rows = store
+        .guess_history(&user.username)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let body = GuessHistoryRow::to_csv(&rows).map_err(|_| Status::InternalServerError)?;
+
+    Ok(CsvDownload {
+        body,
+        disposition: Header::new(
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"{}-history.csv\"",
+                user.username.to_lowercase()
+            ),
+        ),
+    })
+}
+
+/// The history of a user, as a page or, with `format=json`, as their scored guesses.
+#[derive(Responder)]
+pub enum HistoryResponse {
+    Page(Template),
+    Json(Json<Vec<ScoredGuessOwned>>),
+    JsonError((Status, Value)),
+}
 
-    let store = Store::new(db);
+#[get("/history?<race>&<format>")]
+pub async fn history(
+    layout: Layout,
+    cookies: &CookieJar<'_>,
+    user: User,
+    store: &State<Store>,
+    config: &State<Config>,
+    race: Option<&str>,
+    format: Option<&str>,
+) -> HistoryResponse {
+    let logged_in = cookies.get_private("session").is_some();
+    let json = format.is_some_and(|format| format.eq_ignore_ascii_case("json"));
+    let error = |message: &str| {
+        if json {
+            HistoryResponse::JsonError((Status::InternalServerError, json!({ "error": message })))
+        } else {
+            HistoryResponse::Page(layout.render("history", context! { error: message, logged_in }))
+        }
+    };
 
     let normalized_results = match store.normalized_results().await {
         Ok(normalized_results) => normalized_results,
-        Err(_) => {
-            return Template::render(
-                "latest",
-                context! { error: "Could not get event results.", logged_in },
-            );
-        }
+        Err(_) => return error("Could not get event results."),
     };
 
-    let guesses = match store.get_guesses(None, None).await {
+    let guesses = match store.get_guesses(Some(&user.username), None).await {
         Ok(guesses) => guesses,
+        Err(_) => return error("Could not get your guess."),
+    };
+    let scored_guesses: Vec<ScoredGuess<'_>> = match store
+        .scored_guesses_by_date(store.scored_guesses(&guesses, &normalized_results).await)
+        .await
+    {
+        Ok(scored_guesses) => scored_guesses,
+        Err(_) => return error("Could not get events."),
+    };
+
+    // Scripts get every scored guess, of a single race when one is requested, with their
+    // breakdown.
+    if json {
+        let mut owned = Vec::with_capacity(scored_guesses.len());
+
+        for scored_guess in scored_guesses
+            .iter()
+            .filter(|sg| race.is_none_or(|race| sg.guess.race.eq_ignore_ascii_case(race)))
+        {
+            owned.push(
+                store
+                    .scored_guess_owned(scored_guess, &normalized_results)
+                    .await,
+            );
+        }
+
+        return HistoryResponse::Json(Json(owned));
+    }
+    // Summarize every guess, not only the ones listed below.
+    let summary = UserHistorySummary::new(&scored_guesses);
+    let scored_guesses: Vec<ScoredGuess<'_>> = scored_guesses.into_iter().take(24).collect();
+
+    // Every race that took place, played or not, to navigate between them. The latest one is
+    // shown unless another race is requested.
+    let events = store
+        .event_history(&user.username, config.primary_category())
+        .await
+        .unwrap_or_default();
+    let selected = race
+        .and_then(|race| {
+            events
+                .iter()
+                .position(|e| e.name.eq_ignore_ascii_case(race))
+        })
+        .or(events.len().checked_sub(1));
+    let event = selected.map(|i| &events[i]);
+    let previous_event = selected
+        .and_then(|i| i.checked_sub(1))
+        .map(|i| &events[i].name);
+    let next_event = selected.and_then(|i| events.get(i + 1)).map(|e| &e.name);
+    let season_events: Vec<&HistoryEvent> = events
+        .iter()
+        .filter(|e| event.is_some_and(|event| e.datetime.year() == event.datetime.year()))
+        .collect();
+
+    HistoryResponse::Page(layout.render(
+        "history",
+        context! {
+            scored_guesses,
+            summary,
+            event,
+            previous_event,
+            next_event,
+            season_events,
+            logged_in,
+        },
+    ))
+}
+
+#[get("/latest?<race>&<limit>&<category>")]
+pub async fn latest(
+    layout: Layout,
+    cookies: &CookieJar<'_>,
+    store: &State<Store>,
+    config: &State<Config>,
+    race: Option<&str>,
+    limit: Option<usize>,
+    category: Option<&str>,
+) -> Template {
+    let logged_in = cookies.get_private("session").is_some();
+
+    let category = config
+        .category(category)
+        .unwrap_or(config.primary_category());
+
+    // The "All races" option of the race filter submits an empty race.
+    let race = race.filter(|race| !race.is_empty());
+
+    let scored_guesses = match store.get_all_scored_guesses().await {
+        Ok(scored_guesses) => scored_guesses,
         Err(_) => {
-            return Template::render(
+            return layout.render(
                 "latest",
                 context! { error: "Could not get guesses.", logged_in },
             );
         }
     };
-    let scored_guesses: Vec<ScoredGuess<'_>> = store
-        .scored_guesses(&guesses, &normalized_results)
+    let scored_guesses: Vec<&ScoredGuessOwned> = scored_guesses
+        .iter()
+        .filter(|sg| config.belongs_to(&sg.guess.category, category))
+        .collect();
+
+    let race_datetimes = store.race_datetimes(category).await.unwrap_or_default();
+    let race_datetime = |race: &str| {
+        race_datetimes
+            .get(&race.to_uppercase())
+            .copied()
+            .unwrap_or(DateTime::<Utc>::MIN_UTC)
+    };
+
+    let races: Vec<String> = scored_guesses
+        .iter()
+        .map(|sg| sg.guess.race.clone())
+        .unique()
+        .sorted_by_key(|r| Reverse(race_datetime(r)))
+        .collect();
+
+    // Hide the picks for the current event until its deadline, so they can't be copied.
+    let current_event = store.next_event(category).await.ok().flatten();
+
+    let scored_guesses: Vec<ScoredGuessOwned> = store
+        .latest_scored_guesses(
+            scored_guesses
+                .into_iter()
+                .filter(|sg| race.is_none_or(|race| sg.guess.race.eq_ignore_ascii_case(race))),
+            limit.unwrap_or(LATEST_LIMIT).min(MAX_LATEST_LIMIT),
+        )
         .await
+        .unwrap_or_default()
         .into_iter()
-        .rev()
-        .take(20)
+        .map(|mut sg| {
+            if let Some(current_event) = &current_event {
+                sg.hidden = sg.guess.hidden_before_deadline(current_event, Utc::now());
+            }
+            sg
+        })
         .collect();
 
-    Template::render("latest", context! {scored_guesses, logged_in})
+    layout.render(
+        "latest",
+        context! {
+            scored_guesses,
+            races,
+            race,
+            category,
+            avatars: store.avatars().await.unwrap_or_default(),
+            default_avatar: DEFAULT_AVATAR,
+            hidden_pick: HIDDEN_PICK,
+            logged_in,
+        },
+    )
 }
 
-#[get("/play")]
+#[get("/play?<category>")]
 pub async fn play_form(
+    layout: Layout,
     cookies: &CookieJar<'_>,
+    flash: Option<FlashMessage<'_>>,
     user: User,
-    db: &State<Mutex<Database<&str>>>,
+    store: &State<Store>,
+    config: &State<Config>,
+    category: Option<&str>,
 ) -> Template {
     let logged_in = cookies.get_private("session").is_some();
+    let success = flash.map(|flash| flash.message().to_string());
+    let csrf = csrf_token(cookies);
 
-    let store = Store::new(db);
+    let category = config
+        .category(category)
+        .unwrap_or(config.primary_category());
 
-    let drivers = store.all_drivers().await.ok().unwrap_or_default();
-    let current_event = &store
-        .next_event()
-        .await
-        .expect("The next event should be available on the database");
+    let (drivers, current_event, players_count) = join!(
+        store.all_drivers(),
+        store.next_event(category),
+        store.users_count(),
+    );
 
-    let guesses = match store
-        .get_guesses(Some(&user.username), Some(&current_event.name))
-        .await
-    {
+    let drivers: Vec<Driver> = drivers
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|d| d.active && config.belongs_to(&d.category, category))
+        .collect();
+    let current_event = &match current_event {
+        Ok(Some(current_event)) => current_event,
+        Ok(None) => {
+            return layout.render("play", context! { category, success, logged_in });
+        }
+        Err(_) => {
+            return layout.render(
+                "play",
+                context! { category, error: "Could not get the next event.", logged_in },
+            );
+        }
+    };
+
+    // Fetch every guess for the current event once, so the number of submissions can be shown
+    // without a second query. Only the user's own guess is passed to the template.
+    let guesses = match store.get_guesses(None, Some(&current_event.name)).await {
         Ok(guesses) => guesses,
         Err(_) => {
-            return Template::render(
+            return layout.render(
                 "play",
-                context! { current_event, drivers: drivers, guess: Guess::default(), error: "Could not get your guess.", logged_in },
+                context! { csrf, current_event, teams: Driver::by_team(drivers.clone()), drivers, guess: GuessForm::default(), category, error: "Could not get your guess.", logged_in },
             );
         }
     };
-    let guess = guesses.into_iter().next().unwrap_or(Guess {
-        race: current_event.name.to_string(),
-        username: user.username.clone(),
-        ..Default::default()
-    });
+    let submitted_count = guesses.len();
+    let players_count = players_count.unwrap_or_default();
+    let guess = guesses
+        .into_iter()
+        .find(|g| g.username.eq_ignore_ascii_case(&user.username));
+    // Without a guess for this event yet, suggest the picks of the previous one, if any.
+    let (guess, suggested) = match guess {
+        Some(guess) => (GuessForm::from(&guess), false),
+        None => match store.latest_guess(&user.username, category).await {
+            Ok(Some(previous)) => (
+                GuessForm {
+                    race: current_event.name.to_string(),
+                    username: user.username.clone(),
+                    version: 0,
+                    ..GuessForm::from(&previous)
+                },
+                true,
+            ),
+            _ => (
+                GuessForm {
+                    race: current_event.name.to_string(),
+                    username: user.username.clone(),
+                    ..Default::default()
+                },
+                false,
+            ),
+        },
+    };
 
-    Template::render(
+    let event_datetime_local = current_event.local_datetime(&user.timezone);
+    let teams = Driver::by_team(drivers.clone());
+
+    layout.render(
         "play",
-        context! { current_event, drivers, guess, logged_in },
+        context! { csrf, current_event, event_datetime_local, drivers, teams, guess, category, submitted_count, players_count, suggested, success, logged_in },
     )
 }
 
-#[post("/play", data = "<form_data>")]
+#[post("/play?<category>", data = "<form_data>")]
 pub async fn play_submit(
+    layout: Layout,
     cookies: &CookieJar<'_>,
     user: User,
-    db: &State<Mutex<Database<&str>>>,
-    form_data: Form<Guess>,
-) -> Template {
+    store: &State<Store>,
+    config: &State<Config>,
+    category: Option<&str>,
+    form_data: Form<GuessForm>,
+) -> Result<Flash<Redirect>, FormError> {
     let logged_in = cookies.get_private("session").is_some();
 
-    let store = Store::new(db);
+    if !valid_csrf(cookies, &form_data.csrf) {
+        return Err(FormError::Forbidden(forbidden_page(logged_in, &layout)));
+    }
 
-    let drivers = store.all_drivers().await.ok().unwrap_or_default();
-    let current_event = &store
-        .next_event()
-        .await
-        .expect("The next event should be available on the database");
+    let csrf = csrf_token(cookies);
 
-    let mut guess = form_data.into_inner();
+    let category = config
+        .category(category)
+        .unwrap_or(config.primary_category());
+
+    let (drivers, current_event) = join!(store.all_drivers(), store.next_event(category));
+
+    let drivers: Vec<Driver> = drivers
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|d| d.active && config.belongs_to(&d.category, category))
+        .collect();
+    let teams = Driver::by_team(drivers.clone());
+    let current_event = &match current_event {
+        Ok(Some(current_event)) => current_event,
+        Ok(None) => {
+            return Err(FormError::Page(layout.render(
+                "play",
+                context! { category, error: "There are no upcoming events to guess.", logged_in },
+            )));
+        }
+        Err(_) => {
+            return Err(FormError::Page(layout.render(
+                "play",
+                context! { category, error: "Could not get the next event.", logged_in },
+            )));
+        }
+    };
+
+    let form = form_data.into_inner();
+    let mut guess = match Guess::try_from(&form) {
+        Ok(guess) => guess,
+        Err(error) => {
+            return Err(FormError::Page(layout.render(
+                "play",
+                context! { csrf, current_event, drivers, teams, guess: form, category, error: error.to_string(), logged_in },
+            )));
+        }
+    };
 
     // Return early with an authentication error if guess.username differs from user.username.
     // Since user is a User guard, it can only be instanced with a valid private session cookie.
@@ -196,256 +705,1526 @@ pub async fn play_submit(
     // In other words the username in the guess must be from the user creating/updating the guess.
     // Unless a user can guess the encrypted private session cookie from another user, we are safe. :)
     if !guess.username.eq_ignore_ascii_case(&user.username) {
-        return Template::render(
+        return Err(FormError::Page(layout.render(
             "play",
-            context! { current_event, drivers, guess, error: "Unauthenticated.", logged_in },
-        );
+            context! { csrf, current_event, drivers, teams, guess, category, error: "Unauthenticated.", logged_in },
+        )));
     }
 
     // When posting a new guess after its deadline (through guess_submit), which was rendered by guess_form before,
     // if we don't use a new guess.race, the deadline could be abused.
     guess.race = current_event.name.clone();
+    guess.race_id = current_event.id.clone();
+    guess.category = category.name.clone();
 
     // Make sure we always store a guess with consistent case for every field.
     guess.normalize();
 
-    if !guess.valid(&drivers) {
-        return Template::render(
+    if let Err(error) = guess.valid(&drivers) {
+        return Err(FormError::Page(layout.render(
             "play",
             context! {
+                csrf,
                 current_event,
                 drivers,
+                teams,
                 guess,
-                error: "Your guess must contain 5 different driver codes.",
+                category,
+                error: error.to_string(),
                 logged_in,
             },
-        );
-    }
-
-    match store.update_guess(guess.clone(), &current_event.name).await {
-        Ok(_) => Template::render(
-            "play",
-            context! { current_event, drivers, guess, success: "Your guess was successfully updated.", logged_in },
-        ),
-        Err(_) => Template::render(
-            "play",
-            context! { current_event, drivers, guess, error: "Problem updating.", logged_in
-            },
-        ),
+        )));
     }
-}
 
-#[get("/login")]
-pub async fn login_form(flash: Option<FlashMessage<'_>>) -> Template {
-    Template::render(
-        "login",
-        context! { flash: flash.map(|flash| flash.message().to_string()) },
-    )
-}
+    // Redirect after a successful submission, so refreshing the page doesn't post the form again.
+    let redirect = Redirect::to(uri!(play_form(category = Some(&category.name))));
 
-#[post("/login", data = "<form_data>")]
-pub async fn login_submit(
-    cookies: &CookieJar<'_>,
-    db: &State<Mutex<Database<&str>>>,
-    form_data: Form<Registration>,
-) -> Result<Redirect, Template> {
-    let store = Store::new(db);
+    let (stored, previous) = join!(
+        store.is_stored_guess(&guess),
+        store.get_guesses(Some(&guess.username), Some(&guess.race)),
+    );
 
-    let registration = form_data.into_inner();
+    // Resubmitting the stored guess is a no-op, so skip rewriting the guesses.
+    if stored.unwrap_or_default() {
+        return Ok(Flash::success(
+            redirect,
+            "Your guess is already up to date.",
+        ));
+    }
 
-    match store
-        .validate_user(&registration.username, &registration.password)
-        .await
-    {
-        Some(token) => {
-            // Create cookie with the token.
-            let cookie = Cookie::build(("session", token))
-                .http_only(true)
-                .same_site(SameSite::Lax)
-                .secure(true)
-                .expires(OffsetDateTime::now_utc() + Duration::days(365));
+    let previous = previous.unwrap_or_default().into_iter().next();
 
-            cookies.add_private(cookie);
+    // Confirm exactly which picks changed, or that this is the first guess for the event.
+    let message = match previous {
+        Some(previous) => match guess.changes_from(&previous) {
+            changes if changes.is_empty() => "Your guess was saved.".to_string(),
+            changes => format!("Your guess was saved. {}.", changes.join(", ")),
+        },
+        None => "Your new guess was saved.".to_string(),
+    };
 
-            Ok(Redirect::to(uri! { play_form }))
-        }
-        None => Err(Template::render(
-            "login",
-            context! { error: "Login failed." },
-        )),
+    match store.update_guess(guess.clone(), &current_event.name).await {
+        Ok(_) => Ok(Flash::success(redirect, message)),
+        Err(StoreError::Conflict) => Err(FormError::Page(layout.render(
+            "play",
+            context! { csrf, current_event, drivers, teams, guess, category, error: "Your guess was modified elsewhere, please refresh.", logged_in },
+        ))),
+        Err(_) => Err(FormError::Page(layout.render(
+            "play",
+            context! { csrf, current_event, drivers, teams, guess, category, error: "Problem updating.", logged_in
+            },
+        ))),
     }
 }
 
-#[get("/logout")]
-pub async fn logout(cookies: &CookieJar<'_>) -> Redirect {
-    cookies.remove_private("session");
-    Redirect::to(uri!(index))
-}
-
-#[get("/profile?<token>")]
-pub async fn profile_form(
-    token: Option<String>,
+#[get("/constructor")]
+pub async fn constructor_form(
+    layout: Layout,
     cookies: &CookieJar<'_>,
-    db: &State<Mutex<Database<&str>>>,
-) -> Result<Template, Flash<Redirect>> {
+    user: User,
+    store: &State<Store>,
+    config: &State<Config>,
+) -> Template {
     let logged_in = cookies.get_private("session").is_some();
 
-    let token = match token {
-        Some(token) => {
-            // Create cookie with the token.
-            let cookie = Cookie::build(("session", token.clone()))
-                .http_only(true)
-                .same_site(SameSite::Lax)
-                .secure(true)
-                .expires(OffsetDateTime::now_utc() + Duration::days(365));
-
-            cookies.add_private(cookie);
-
-            token
+    let current_event = &match store.next_event(config.primary_category()).await {
+        Ok(Some(current_event)) => current_event,
+        Ok(None) => {
+            return layout.render(
+                "constructor",
+                context! { logged_in, constructor_correct: CONSTRUCTOR_CORRECT },
+            );
+        }
+        Err(_) => {
+            return layout.render(
+                "constructor",
+                context! { error: "Could not get the next event.", logged_in, constructor_correct: CONSTRUCTOR_CORRECT },
+            );
         }
-        None => match cookies.get_private("session") {
-            Some(token) => token.value().to_owned(),
-            None => {
-                return Err(Flash::error(
-                    Redirect::to(uri!(login_form)),
-                    "Please login to continue.",
-                ));
-            }
-        },
     };
 
-    let user = match Store::get_user(&token, db).await {
-        Some(user) => user,
-        None => {
-            return Err(Flash::error(
-                Redirect::to(uri!(login_form)),
-                "Could not find your user.",
-            ));
+    let guesses = match store
+        .get_constructor_guesses(Some(&user.username), Some(&current_event.name))
+        .await
+    {
+        Ok(guesses) => guesses,
+        Err(_) => {
+            return layout.render(
+                "constructor",
+                context! { current_event, guess: ConstructorGuess::default(), error: "Could not get your guess.", logged_in, constructor_correct: CONSTRUCTOR_CORRECT },
+            );
         }
     };
+    let guess = guesses.into_iter().next().unwrap_or(ConstructorGuess {
+        race: current_event.name.to_string(),
+        username: user.username.clone(),
+        ..Default::default()
+    });
 
-    Ok(Template::render(
-        "profile",
-        context! { country: &user.country, logged_in},
-    ))
+    let event_datetime_local = current_event.local_datetime(&user.timezone);
+
+    layout.render(
+        "constructor",
+        context! { current_event, event_datetime_local, guess, logged_in, constructor_correct: CONSTRUCTOR_CORRECT },
+    )
 }
 
-#[post("/profile", data = "<form_data>")]
-pub async fn profile_submit(
+#[post("/constructor", data = "<form_data>")]
+pub async fn constructor_submit(
+    layout: Layout,
     cookies: &CookieJar<'_>,
-    _user: User,
-    db: &State<Mutex<Database<&str>>>,
-    form_data: Form<Profile>,
+    user: User,
+    store: &State<Store>,
+    config: &State<Config>,
+    form_data: Form<ConstructorGuess>,
 ) -> Template {
     let logged_in = cookies.get_private("session").is_some();
 
-    let store = Store::new(db);
+    let current_event = &match store.next_event(config.primary_category()).await {
+        Ok(Some(current_event)) => current_event,
+        Ok(None) => {
+            return layout.render(
+                "constructor",
+                context! { error: "There are no upcoming events to guess.", logged_in, constructor_correct: CONSTRUCTOR_CORRECT },
+            );
+        }
+        Err(_) => {
+            return layout.render(
+                "constructor",
+                context! { error: "Could not get the next event.", logged_in, constructor_correct: CONSTRUCTOR_CORRECT },
+            );
+        }
+    };
 
-    let profile_data = form_data.into_inner();
+    let mut guess = form_data.into_inner();
+
+    // Same reasoning as in play_submit: only the authenticated user can update their own guess,
+    // and always for the current event, so the deadline can't be abused.
+    if !guess.username.eq_ignore_ascii_case(&user.username) {
+        return layout.render(
+            "constructor",
+            context! { current_event, guess, error: "Unauthenticated.", logged_in, constructor_correct: CONSTRUCTOR_CORRECT },
+        );
+    }
+
+    guess.race = current_event.name.clone();
+    guess.race_id = current_event.id.clone();
+    guess.normalize();
+
+    if !guess.valid() {
+        return layout.render(
+            "constructor",
+            context! {
+                current_event,
+                guess,
+                error: "Your guess must contain 2 different constructor codes.",
+                logged_in,
+                constructor_correct: CONSTRUCTOR_CORRECT,
+            },
+        );
+    }
+
+    match store
+        .update_constructor_guess(guess.clone(), &current_event.name)
+        .await
+    {
+        Ok(_) => layout.render(
+            "constructor",
+            context! { current_event, guess, success: "Your guess was successfully updated.", logged_in, constructor_correct: CONSTRUCTOR_CORRECT },
+        ),
+        Err(_) => layout.render(
+            "constructor",
+            context! { current_event, guess, error: "Problem updating.", logged_in, constructor_correct: CONSTRUCTOR_CORRECT },
+        ),
+    }
+}
+
+#[get("/login?<next>")]
+pub async fn login_form(
+    layout: Layout,
+    cookies: &CookieJar<'_>,
+    flash: Option<FlashMessage<'_>>,
+    next: Option<&str>,
+) -> Template {
+    let csrf = csrf_token(cookies);
+
+    layout.render(
+        "login",
+        context! { csrf, flash: flash.map(|flash| flash.message().to_string()), next },
+    )
+}
+
+/// The CSRF token of the visitor, set as a cookie the first time a form is shown.
+/// The cookie is readable by scripts on purpose, as the double submit pattern only relies on
+/// other sites being unable to read it.
+fn csrf_token(cookies: &CookieJar<'_>) -> String {
+    // Pending cookies include a token issued earlier in this request, so every form agrees on it.
+    if let Some(cookie) = cookies.get_pending(CsrfToken::COOKIE) {
+        return cookie.value().to_string();
+    }
+
+    let token = CsrfToken::generate();
+    let cookie = Cookie::build((CsrfToken::COOKIE, token.clone()))
+        .http_only(false)
+        .same_site(SameSite::Strict)
+        .secure(true)
+        .path("/");
+
+    cookies.add(cookie);
+
+    token
+}
+
+/// What every page gets besides its own context, namely the display preferences and the CSRF
+/// token of the preferences form in the footer.
+#[derive(Clone)]
+pub struct Layout {
+    prefs: UiPrefs,
+    csrf: String,
+}
+
+impl Layout {
+    fn new(cookies: &CookieJar<'_>) -> Self {
+        Self {
+            prefs: UiPrefs::from_cookies(cookies),
+            csrf: csrf_token(cookies),
+        }
+    }
+
+    /// Renders a template with the layout added to its context, unless the context sets them.
+    fn render(&self, name: &'static str, context: impl Serialize) -> Template {
+        let mut context = json!(context);
+
+        if let Value::Object(map) = &mut context {
+            map.entry("prefs").or_insert_with(|| json!(self.prefs));
+            map.entry("csrf").or_insert_with(|| json!(self.csrf));
+        }
+
+        Template::render(name, context)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Layout {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self::new(req.cookies()))
+    }
+}
+
+fn valid_csrf(cookies: &CookieJar<'_>, form_token: &str) -> bool {
+    cookies
+        .get(CsrfToken::COOKIE)
+        .is_some_and(|cookie| CsrfToken::validate(cookie.value(), form_token))
+}
+
+/// Maps the name of each invalid form field to the first error found for it.
+fn field_errors<'a, 'v: 'a>(
+    errors: impl Iterator<Item = &'a form::Error<'v>>,
+) -> HashMap<String, String> {
+    let mut field_errors = HashMap::new();
+
+    for error in errors {
+        if let Some(name) = &error.name {
+            field_errors
+                .entry(name.to_string())
+                .or_insert_with(|| error.kind.to_string());
+        }
+    }
+
+    field_errors
+}
+
+/// Remembers the display preferences on this device.
+fn set_ui_prefs_cookie(cookies: &CookieJar<'_>, prefs: UiPrefs) {
+    let cookie = Cookie::build((UiPrefs::COOKIE, prefs.to_cookie()))
+        .same_site(SameSite::Lax)
+        .secure(true)
+        .path("/")
+        .expires(OffsetDateTime::now_utc() + Duration::days(365));
+
+    cookies.add(cookie);
+}
+
+/// Only paths on this site are followed after login, never absolute or protocol relative URLs,
+/// so the login page can't be used to redirect users elsewhere.
+fn is_local_path(path: &str) -> bool {
+    path.starts_with('/') && !path.starts_with("//") && !path.contains('\\')
+}
+
+#[post("/login", data = "<form_data>")]
+pub async fn login_submit(
+    layout: Layout,
+    cookies: &CookieJar<'_>,
+    store: &State<Store>,
+    form_data: Form<Login>,
+) -> Result<Redirect, FormError> {
+    let login = form_data.into_inner();
+
+    if !valid_csrf(cookies, &login.csrf) {
+        return Err(FormError::Forbidden(forbidden_page(false, &layout)));
+    }
+
+    let csrf = csrf_token(cookies);
+
+    match store.validate_user(&login.username, &login.password).await {
+        Some(token) => {
+            set_session_cookie(cookies, &token);
+
+            // Bring the preferences saved with the user to this device.
+            if let Some(user) = store.get_user(&token).await {
+                set_ui_prefs_cookie(cookies, user.ui_prefs());
+            }
+
+            match login.next.filter(|next| is_local_path(next)) {
+                Some(next) => Ok(Redirect::to(next)),
+                None => Ok(Redirect::to(uri! { play_form(category = _) })),
+            }
+        }
+        None => Err(FormError::Page(layout.render(
+            "login",
+            context! { csrf, error: "Login failed.", next: login.next },
+        ))),
+    }
+}
+
+#[get("/logout")]
+pub async fn logout(cookies: &CookieJar<'_>) -> Redirect {
+    cookies.remove_private("session");
+    Redirect::to(uri!(index(season = _, category = _)))
+}
+
+/// Keeps the user logged in on this device with their token.
+fn set_session_cookie(cookies: &CookieJar<'_>, token: &str) {
+    let cookie = Cookie::build(("session", token.to_owned()))
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(true)
+        .expires(OffsetDateTime::now_utc() + Duration::days(365));
+
+    cookies.add_private(cookie);
+}
+
+#[get("/profile?<token>")]
+pub async fn profile_form(
+    layout: Layout,
+    token: Option<String>,
+    cookies: &CookieJar<'_>,
+    flash: Option<FlashMessage<'_>>,
+    store: &State<Store>,
+    config: &State<Config>,
+) -> Result<Template, Flash<Redirect>> {
+    let logged_in = cookies.get_private("session").is_some();
+    let success = flash.map(|flash| flash.message().to_string());
+
+    let token = match token {
+        Some(token) => {
+            set_session_cookie(cookies, &token);
+
+            token
+        }
+        None => match cookies.get_private("session") {
+            Some(token) => token.value().to_owned(),
+            None => {
+                return Err(Flash::error(
+                    Redirect::to(uri!(login_form(next = _))),
+                    "Please login to continue.",
+                ));
+            }
+        },
+    };
+
+    let user = match store.get_user(&token).await {
+        Some(user) => user,
+        None => {
+            return Err(Flash::error(
+                Redirect::to(uri!(login_form(next = _))),
+                "Could not find your user.",
+            ));
+        }
+    };
+
+    let drivers: Vec<Driver> = store
+        .all_drivers()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|d| d.active)
+        .collect();
+
+    let rival = match store.normalized_results().await {
+        Ok(normalized_results) => store
+            .find_rival(&user.username, &normalized_results)
+            .await
+            .ok()
+            .flatten(),
+        Err(_) => None,
+    };
+    let streak = store
+        .guess_streak(&user.username, config.primary_category())
+        .await
+        .unwrap_or_default();
+    let countries = store.all_countries().await.unwrap_or_default();
+
+    Ok(layout.render(
+        "profile",
+        context! { rival, streak, countries, version: user.version, country: &user.country, preferred_driver: &user.preferred_driver, timezone: &user.timezone, timezones: timezones(), avatar: user.avatar_url(), drivers, success, logged_in},
+    ))
+}
+
+#[post("/profile", data = "<form_data>")]
+pub async fn profile_submit(
+    layout: Layout,
+    cookies: &CookieJar<'_>,
+    _user: User,
+    store: &State<Store>,
+    form_data: Form<Profile<'_>>,
+) -> Result<Flash<Redirect>, Template> {
+    let logged_in = cookies.get_private("session").is_some();
+
+    let mut profile_data = form_data.into_inner();
 
     let token = match cookies.get_private("session") {
         Some(token) => token.value().to_owned(),
         None => {
-            return Template::render(
+            return Err(layout.render(
                 "profile",
                 context! { error: "Could not find your user.", logged_in },
-            );
+            ));
         }
     };
 
-    let mut user = match Store::get_user(&token, db).await {
+    let mut user = match store.get_user(&token).await {
         Some(user) => user,
         None => {
-            return Template::render(
+            return Err(layout.render(
                 "profile",
                 context! { error: "Could not find your user.", logged_in },
-            );
+            ));
         }
     };
 
+    let drivers: Vec<Driver> = store
+        .all_drivers()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|d| d.active)
+        .collect();
+    let countries = store.all_countries().await.unwrap_or_default();
+
+    // An empty selection clears the preferred driver.
+    let preferred_code = profile_data
+        .preferred_driver
+        .as_deref()
+        .filter(|code| !code.trim().is_empty())
+        .map(DriverCode::new);
+    let known_driver = match &preferred_code {
+        Some(Ok(code)) => store.validate_drivers_exist(&[code]).await.is_ok(),
+        Some(Err(_)) => false,
+        None => true,
+    };
+
+    if !known_driver {
+        return Err(layout.render(
+            "profile",
+            context! { countries, version: profile_data.version, country: profile_data.country, preferred_driver: profile_data.preferred_driver, timezone: profile_data.timezone, timezones: timezones(), drivers, error: "Unknown preferred driver.", logged_in },
+        ));
+    }
+
+    let preferred_driver = preferred_code.and_then(Result::ok).map(String::from);
+
+    profile_data.country = profile_data.country.trim().to_uppercase();
+
+    if !profile_data.country.is_empty() && !Country::is_known(&countries, &profile_data.country) {
+        return Err(layout.render(
+            "profile",
+            context! { countries, version: profile_data.version, country: profile_data.country, preferred_driver, timezone: profile_data.timezone, timezones: timezones(), drivers, error: "Unknown country.", logged_in },
+        ));
+    }
+
+    let timezone = profile_data.timezone.trim().to_string();
+
+    if !timezone.is_empty() && timezone.parse::<Tz>().is_err() {
+        return Err(layout.render(
+            "profile",
+            context! { countries, version: profile_data.version, country: profile_data.country, preferred_driver, timezone, timezones: timezones(), drivers, error: "Unknown time zone.", logged_in },
+        ));
+    }
+
     user.country = profile_data.country.clone();
+    user.preferred_driver = preferred_driver.clone();
+    user.timezone = timezone.clone();
+    user.set_ui_prefs(UiPrefs {
+        dark_mode: profile_data.dark_mode,
+        compact_tables: profile_data.compact_tables,
+    });
+    let ui_prefs = user.ui_prefs();
 
-    if profile_data.password.len() > 0 {
-        user.password = match Store::hash_password(&profile_data.password).await {
+    if !profile_data.password.is_empty() {
+        user.password = match store.hash_password(&profile_data.password).await {
             Ok(hashed_password) => hashed_password,
             Err(_) => {
-                return Template::render(
+                return Err(layout.render(
                     "profile",
-                    context! { error: "Could not update your profile.", logged_in },
-                );
+                    context! { countries, version: profile_data.version, country: profile_data.country, preferred_driver, timezone: profile_data.timezone, timezones: timezones(), drivers, error: "Could not update your profile.", logged_in },
+                ));
             }
         };
     }
 
-    if store.update_user(user, &token).await.is_err() {
-        return Template::render(
-            "profile",
-            context! { error: "Could not update your profile.", logged_in },
-        );
+    // Browsers send an empty file when no avatar was chosen, which keeps the current one.
+    let mut new_avatar = None;
+
+    if let Some(avatar) = profile_data
+        .avatar
+        .as_mut()
+        .filter(|avatar| avatar.len() > 0)
+    {
+        match save_avatar(&user.username, avatar).await {
+            Ok(url) => new_avatar = Some(url),
+            Err(error) => {
+                return Err(layout.render(
+                    "profile",
+                    context! { countries, version: profile_data.version, country: profile_data.country, preferred_driver, timezone, timezones: timezones(), avatar: user.avatar_url(), drivers, error, logged_in },
+                ));
+            }
+        }
     }
 
-    Template::render(
-        "profile",
-        context! { country: profile_data.country, success: "Profile updated successfully.", logged_in },
-    )
+    // The previous avatar is only deleted once the user points to the new one.
+    let previous_avatar = match &new_avatar {
+        Some(url) => user.avatar.replace(url.clone()),
+        None => None,
+    };
+
+    let username = user.username.clone();
+    user.version = profile_data.version;
+
+    let updated = store.update_user(user, &token).await;
+
+    // Whichever avatar the user didn't end up with is no longer used.
+    let unused_avatar = if updated.is_ok() {
+        &previous_avatar
+    } else {
+        &new_avatar
+    };
+
+    if let Some(url) = unused_avatar {
+        remove_avatar(url).await;
+    }
+
+    match updated {
+        Ok(()) => {}
+        Err(StoreError::Conflict) => {
+            return Err(layout.render(
+                "profile",
+                context! { countries, version: profile_data.version, country: profile_data.country, preferred_driver, timezone: profile_data.timezone, timezones: timezones(), drivers, error: "Profile was modified elsewhere, please reload.", logged_in },
+            ));
+        }
+        Err(_) => {
+            return Err(layout.render(
+                "profile",
+                context! { countries, version: profile_data.version, country: profile_data.country, preferred_driver, timezone: profile_data.timezone, timezones: timezones(), drivers, error: "Could not update your profile.", logged_in },
+            ));
+        }
+    }
+
+    // A new password logs out every other session, keeping this one with the new token.
+    if !profile_data.password.is_empty() {
+        // The profile is saved by now, so the form continues from the version just stored.
+        let saved_version = profile_data.version + 1;
+
+        match store.invalidate_sessions(&username).await {
+            Ok(new_token) => set_session_cookie(cookies, &new_token),
+            Err(_) => {
+                return Err(layout.render(
+                    "profile",
+                    context! { countries, version: saved_version, country: profile_data.country, preferred_driver, timezone: profile_data.timezone, timezones: timezones(), drivers, error: "Your password was changed, but your other sessions could not be logged out.", logged_in },
+                ));
+            }
+        }
+    }
+
+    set_ui_prefs_cookie(cookies, ui_prefs);
+
+    Ok(Flash::success(
+        Redirect::to(uri!(profile_form(token = _))),
+        "Profile updated successfully.",
+    ))
+}
+
+/// Saves the display preferences on this device, and with the user when logged in.
+#[post("/prefs", data = "<form_data>")]
+pub async fn prefs_submit(
+    layout: Layout,
+    cookies: &CookieJar<'_>,
+    user: Option<User>,
+    store: &State<Store>,
+    form_data: Form<PrefsForm>,
+) -> Result<Redirect, FormError> {
+    let form = form_data.into_inner();
+
+    if !valid_csrf(cookies, &form.csrf) {
+        return Err(FormError::Forbidden(forbidden_page(
+            user.is_some(),
+            &layout,
+        )));
+    }
+    let prefs = UiPrefs::from(&form);
+
+    if let Some(mut user) = user {
+        let token = user.token.clone();
+
+        user.set_ui_prefs(prefs);
+
+        if store.update_user(user, &token).await.is_err() {
+            error!("Could not save the display preferences.");
+        }
+    }
+
+    set_ui_prefs_cookie(cookies, prefs);
+
+    Ok(match form.next.filter(|next| is_local_path(next)) {
+        Some(next) => Redirect::to(next),
+        None => Redirect::to(uri!(index(season = _, category = _))),
+    })
+}
+
+/// Validates an uploaded avatar and stores it next to the current one, returning its URL.
+async fn save_avatar(username: &str, avatar: &mut TempFile<'_>) -> Result<String, &'static str> {
+    if avatar.len() > MAX_AVATAR_SIZE {
+        return Err("The avatar must be smaller than 200 KB.");
+    }
+
+    let mut header = [0; 12];
+    let extension = match avatar.open().await {
+        Ok(mut file) => match file.read_exact(&mut header).await {
+            Ok(_) => avatar_extension(&header),
+            Err(_) => None,
+        },
+        Err(_) => None,
+    }
+    .ok_or("The avatar must be a PNG, JPEG or WebP image.")?;
+    let file_name = avatar_file_name(username, Utc::now(), extension)
+        .ok_or("Your username can't be used for an avatar.")?;
+
+    fs::create_dir_all(AVATAR_DIR)
+        .await
+        .map_err(|_| "Could not save your avatar.")?;
+
+    avatar
+        .move_copy_to(format!("{AVATAR_DIR}/{file_name}"))
+        .await
+        .map_err(|_| "Could not save your avatar.")?;
+
+    Ok(format!("/{AVATAR_DIR}/{file_name}"))
+}
+
+/// Deletes the file of an avatar that's no longer used, if it was uploaded.
+async fn remove_avatar(url: &str) {
+    if let Some(path) = avatar_path(url) {
+        let _ = fs::remove_file(path).await;
+    }
+}
+
+/// Names of the IANA time zones users can pick on their profile.
+fn timezones() -> Vec<&'static str> {
+    TZ_VARIANTS.iter().map(|tz| tz.name()).collect()
 }
 
 #[get("/register")]
-pub async fn register_form() -> Template {
-    Template::render("register", context! {})
+pub async fn register_form(
+    layout: Layout,
+    cookies: &CookieJar<'_>,
+    store: &State<Store>,
+    form_timestamp: &State<FormTimestamp>,
+    config: &State<Config>,
+) -> Template {
+    let csrf = csrf_token(cookies);
+    let issued = form_timestamp.issue(Utc::now());
+    let countries = store.all_countries().await.unwrap_or_default();
+    let invite_only = config.registration.invite_only;
+
+    layout.render(
+        "register",
+        context! { csrf, issued, countries, invite_only },
+    )
 }
 
 #[post("/register", data = "<form_data>")]
 pub async fn register_submit(
-    _cookies: &CookieJar<'_>,
-    db: &State<Mutex<Database<&str>>>,
-    form_data: Form<Registration>,
-) -> Result<Flash<Redirect>, Template> {
-    let store = Store::new(db);
+    layout: Layout,
+    cookies: &CookieJar<'_>,
+    store: &State<Store>,
+    form_timestamp: &State<FormTimestamp>,
+    config: &State<Config>,
+    form_data: Form<Contextual<'_, Registration>>,
+) -> Result<Flash<Redirect>, FormError> {
+    let form = form_data.into_inner();
+
+    // An invalid form is only shown back with its errors, without touching any user data.
+    let registration = match form.value {
+        Some(registration) => registration,
+        None => {
+            // The raw field is checked, as the token can't be read from a form that failed to parse.
+            if !valid_csrf(
+                cookies,
+                form.context.field_value("_csrf").unwrap_or_default(),
+            ) {
+                return Err(FormError::Forbidden(forbidden_page(false, &layout)));
+            }
 
-    let registration = form_data.into_inner();
+            let csrf = csrf_token(cookies);
+            let issued = form_timestamp.issue(Utc::now());
+            let countries = store.all_countries().await.unwrap_or_default();
+            let country = form.context.field_value("country");
+            let invite_only = config.registration.invite_only;
+            let field_errors = field_errors(form.context.errors());
+
+            return Err(FormError::Page(layout.render(
+                "register",
+                context! { csrf, issued, countries, country, invite_only, field_errors },
+            )));
+        }
+    };
+
+    if !valid_csrf(cookies, &registration.csrf) {
+        return Err(FormError::Forbidden(forbidden_page(false, &layout)));
+    }
+
+    let csrf = csrf_token(cookies);
+    let issued = form_timestamp.issue(Utc::now());
+    let countries = store.all_countries().await.unwrap_or_default();
+    let invite_only = config.registration.invite_only;
+
+    // Bots get the same answer as any other failure, so they can't tell what gave them away.
+    if registration.looks_automated(form_timestamp, Utc::now()) {
+        return Err(FormError::Page(layout.render(
+            "register",
+            context! { csrf, issued, countries, invite_only, error: "Registration failed." },
+        )));
+    }
+
+    // An empty selection leaves the country unset.
+    let country = registration
+        .country
+        .map(|code| code.trim().to_uppercase())
+        .filter(|code| !code.is_empty());
+
+    if let Some(code) = &country
+        && !Country::is_known(&countries, code)
+    {
+        return Err(FormError::Page(layout.render(
+            "register",
+            context! { csrf, issued, countries, invite_only, error: "Unknown country." },
+        )));
+    }
+
+    if invite_only {
+        let error = |error| {
+            FormError::Page(layout.render(
+                "register",
+                context! { csrf: &csrf, issued: &issued, countries: &countries, country: &country, invite_only, error },
+            ))
+        };
+
+        if registration.invite_code.trim().is_empty() {
+            return Err(error("An invite code is required."));
+        }
+
+        // The code is only used up along with adding the user, so a failed registration keeps it.
+        return match store
+            .add_invited_user(
+                &registration.username,
+                &registration.password,
+                country.clone(),
+                &registration.invite_code,
+            )
+            .await
+        {
+            Ok(_) => Ok(registered()),
+            Err(StoreError::Used | StoreError::Db(DbError::NoMatch)) => {
+                Err(error("Invalid or used invite code."))
+            }
+            Err(_) => Err(error("Registration failed.")),
+        };
+    }
 
     match store
         .add_user(
             &registration.username,
             &registration.password,
-            registration.country,
+            country.clone(),
         )
         .await
     {
-        Ok(_) => Ok(Flash::success(
-            Redirect::to(uri!(login_form)),
-            "Registration successful. You can now login.",
-        )),
-        Err(_) => Err(Template::render(
+        Ok(_) => Ok(registered()),
+        Err(_) => Err(FormError::Page(layout.render(
             "register",
-            context! { error: "Registration failed." },
-        )),
+            context! { csrf, issued, countries, country, invite_only, error: "Registration failed." },
+        ))),
     }
 }
 
-#[get("/rules")]
-pub async fn rules(cookies: &CookieJar<'_>) -> Template {
+/// Sends a player who just registered to log in.
+fn registered() -> Flash<Redirect> {
+    Flash::success(
+        Redirect::to(uri!(login_form(next = _))),
+        "Registration successful. You can now login.",
+    )
+}
+
+#[get("/rules?<season>")]
+pub async fn rules(
+    layout: Layout,
+    cookies: &CookieJar<'_>,
+    store: &State<Store>,
+    config: &State<Config>,
+    season: Option<u16>,
+) -> Template {
     let logged_in = cookies.get_private("session").is_some();
 
-    Template::render(
+    let seasons = store
+        .seasons(config.primary_category())
+        .await
+        .unwrap_or_default();
+    // Show the rules of the latest season by default.
+    let season = season.or(seasons.first().copied());
+    let scoring_rules = config.scoring.rules(season);
+
+    layout.render(
         "rules",
-        context! { logged_in, correct_podium: CORRECT_PODIUM, correct_five: CORRECT_FIVE, wrong_place: WRONG_PLACE, parlay: PARLAY },
+        context! { logged_in, season, seasons, scoring_rules, correct_podium: CORRECT_PODIUM, correct_five: CORRECT_FIVE, wrong_place: WRONG_PLACE, parlay: PARLAY, double_points_multiplier: config.scoring.double_points_multiplier, sprint_multiplier: config.scoring.sprint_multiplier },
     )
 }
 
 #[get("/stats")]
-pub async fn stats(cookies: &CookieJar<'_>) -> Template {
+pub async fn stats(
+    layout: Layout,
+    cookies: &CookieJar<'_>,
+    store: &State<Store>,
+    config: &State<Config>,
+) -> Template {
     let logged_in = cookies.get_private("session").is_some();
 
-    Template::render("stats", context! { logged_in })
+    let site_stats = store
+        .site_stats(config.primary_category())
+        .await
+        .unwrap_or_default();
+
+    layout.render("stats", context! { site_stats, logged_in })
+}
+
+#[get("/driver/<code>")]
+pub async fn driver(
+    layout: Layout,
+    cookies: &CookieJar<'_>,
+    store: &State<Store>,
+    config: &State<Config>,
+    code: &str,
+) -> Option<Template> {
+    let logged_in = cookies.get_private("session").is_some();
+    let code = DriverCode::new(code).ok()?;
+
+    let driver_stats = match store.driver_stats(config.primary_category(), &code).await {
+        Ok(driver_stats) => driver_stats?,
+        Err(_) => {
+            return Some(layout.render(
+                "driver",
+                context! { error: "Could not get the driver stats.", logged_in },
+            ));
+        }
+    };
+
+    Some(layout.render("driver", context! { driver_stats, logged_in }))
 }
 
 #[get("/disclaimer")]
-pub async fn disclaimer(cookies: &CookieJar<'_>) -> Template {
+pub async fn disclaimer(layout: Layout, cookies: &CookieJar<'_>) -> Template {
     let logged_in = cookies.get_private("session").is_some();
 
-    Template::render("disclaimer", context! { logged_in })
+    layout.render("disclaimer", context! { logged_in })
+}
+
+#[get("/admin")]
+pub async fn admin(
+    layout: Layout,
+    admin: Admin,
+    store: &State<Store>,
+    config: &State<Config>,
+) -> Template {
+    let logged_in = true;
+
+    let users = store.get_users(None).await.unwrap_or_default();
+    let guesses_count = store
+        .get_guesses(None, None)
+        .await
+        .unwrap_or_default()
+        .len();
+    let events_count = store.events().await.unwrap_or_default().len();
+    let results_count = store.results().await.unwrap_or_default().len();
+    let current_event = store
+        .next_event(config.primary_category())
+        .await
+        .ok()
+        .flatten();
+    let missing_results = store
+        .races_missing_results(config.primary_category())
+        .await
+        .unwrap_or_default();
+
+    // Users are appended on registration, so the last ones are the most recent.
+    let recent_users: Vec<PublicUser> = users.iter().rev().take(5).map(PublicUser::from).collect();
+
+    layout.render(
+        "admin",
+        context! {
+            logged_in,
+            admin: admin.0.username,
+            users_count: users.len(),
+            guesses_count,
+            events_count,
+            results_count,
+            current_event,
+            missing_results,
+            recent_users,
+        },
+    )
+}
+
+#[get("/admin/results?<race>")]
+pub async fn admin_results_form(
+    layout: Layout,
+    _admin: Admin,
+    store: &State<Store>,
+    config: &State<Config>,
+    race: Option<&str>,
+) -> Template {
+    let logged_in = true;
+
+    let drivers = store.all_drivers().await.unwrap_or_default();
+    let missing_results = store
+        .races_missing_results(config.primary_category())
+        .await
+        .unwrap_or_default();
+    let results = store.results_sorted().await.unwrap_or_default();
+
+    // Editing an existing result pre-fills the form with it.
+    let result = race.map(|race| {
+        results
+            .iter()
+            .find(|r| r.race.eq_ignore_ascii_case(race))
+            .map(RaceResultForm::from)
+            .unwrap_or(RaceResultForm {
+                race: race.to_uppercase(),
+                ..Default::default()
+            })
+    });
+
+    layout.render(
+        "admin_results",
+        context! { logged_in, drivers, missing_results, results, result },
+    )
+}
+
+#[post("/admin/results", data = "<form_data>")]
+pub async fn admin_results_submit(
+    layout: Layout,
+    _admin: Admin,
+    store: &State<Store>,
+    config: &State<Config>,
+    form_data: Form<RaceResultForm>,
+) -> Template {
+    let logged_in = true;
+
+    let drivers = store.all_drivers().await.unwrap_or_default();
+    let missing_results = store
+        .races_missing_results(config.primary_category())
+        .await
+        .unwrap_or_default();
+
+    let form = form_data.into_inner();
+    let mut result = match RaceResult::try_from(&form) {
+        Ok(result) => result,
+        Err(error) => {
+            let results = store.results_sorted().await.unwrap_or_default();
+
+            return layout.render(
+                "admin_results",
+                context! { logged_in, drivers, missing_results, results, result: form, error: error.to_string() },
+            );
+        }
+    };
+
+    result.normalize();
+
+    if !result.valid(&drivers) {
+        let results = store.results_sorted().await.unwrap_or_default();
+
+        return layout.render(
+            "admin_results",
+            context! {
+                logged_in,
+                drivers,
+                missing_results,
+                results,
+                result,
+                error: "The result must contain different, known driver codes.",
+            },
+        );
+    }
+
+    if store.upsert_result(result.clone()).await.is_err() {
+        let results = store.results_sorted().await.unwrap_or_default();
+
+        return layout.render(
+            "admin_results",
+            context! { logged_in, drivers, missing_results, results, result, error: "Could not save the result." },
+        );
+    }
+
+    // Show the standings of the race right away, so they can be checked before announcing them.
+    let standings: Vec<ScoredGuessOwned> = store
+        .get_all_scored_guesses()
+        .await
+        .unwrap_or_default()
+        .iter()
+        .filter(|sg| sg.guess.race.eq_ignore_ascii_case(&result.race))
+        .cloned()
+        .sorted_by_key(|sg| Reverse(sg.points))
+        .collect();
+    let missing_results = store
+        .races_missing_results(config.primary_category())
+        .await
+        .unwrap_or_default();
+    let results = store.results_sorted().await.unwrap_or_default();
+
+    layout.render(
+        "admin_results",
+        context! { logged_in, drivers, missing_results, results, result, standings, success: "Result saved successfully." },
+    )
+}
+
+async fn admin_events_template(
+    store: &Store,
+    layout: &Layout,
+    config: &Config,
+    form: EventForm,
+    error: Option<&str>,
+    success: Option<&str>,
+) -> Template {
+    let logged_in = true;
+
+    let normalized_results = store.normalized_results().await.unwrap_or_default();
+    let events: Vec<_> = store
+        .events()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .sorted_by(|a, b| a.datetime.cmp(&b.datetime))
+        .map(|event| {
+            let is_race = config.categories.iter().any(|c| Store::is_race(&event, c));
+            let status = if event.datetime > Utc::now() {
+                "upcoming"
+            } else if is_race
+                && RaceResult::find(&normalized_results, &event.id, &event.name).is_none()
+            {
+                "missing result"
+            } else {
+                "completed"
+            };
+
+            context! { event, status }
+        })
+        .collect();
+
+    layout.render(
+        "admin_events",
+        context! { logged_in, events, form, error, success },
+    )
+}
+
+#[get("/admin/events?<name>&<description>")]
+pub async fn admin_events_form(
+    layout: Layout,
+    _admin: Admin,
+    store: &State<Store>,
+    config: &State<Config>,
+    name: Option<&str>,
+    description: Option<&str>,
+) -> Template {
+    // Editing an existing event pre-fills the form with it.
+    let form = match (name, description) {
+        (Some(name), Some(description)) => store
+            .events()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .find(|e| e.is(name, description))
+            .map(EventForm::from),
+        _ => None,
+    };
+    let form = form.unwrap_or(EventForm {
+        timezone: "UTC".to_string(),
+        ..Default::default()
+    });
+
+    admin_events_template(store, &layout, config, form, None, None).await
+}
+
+#[post("/admin/events", data = "<form_data>")]
+pub async fn admin_events_submit(
+    layout: Layout,
+    _admin: Admin,
+    store: &State<Store>,
+    config: &State<Config>,
+    form_data: Form<EventForm>,
+) -> Template {
+    let form = form_data.into_inner();
+
+    let datetime = match Event::parse_local_datetime(&form.datetime, &form.timezone) {
+        Some(datetime) => datetime,
+        None => {
+            return admin_events_template(
+                store,
+                &layout,
+                config,
+                form,
+                Some("Could not parse the date, time or time zone."),
+                None,
+            )
+            .await;
+        }
+    };
+
+    let event = Event {
+        category: form.category.trim().to_string(),
+        name: form.name.trim().to_uppercase(),
+        description: form.description.trim().to_string(),
+        datetime,
+        channel: form.channel.trim().to_string(),
+        tags: form.tags.trim().to_string(),
+        notify: form.notify,
+        // Set by the store, from the name of a new event or the stored one when editing.
+        id: String::new(),
+    };
+
+    let result = if form.original_name.is_empty() {
+        store.add_event(event.clone()).await
+    } else {
+        store
+            .update_event(
+                &form.original_name,
+                &form.original_description,
+                event.clone(),
+            )
+            .await
+    };
+
+    match result {
+        Ok(_) => {
+            admin_events_template(
+                store,
+                &layout,
+                config,
+                EventForm::from(&event),
+                None,
+                Some("Event saved successfully."),
+            )
+            .await
+        }
+        Err(StoreError::AlreadyExists) => {
+            admin_events_template(
+                store,
+                &layout,
+                config,
+                form,
+                Some("An event with the same name and description already exists."),
+                None,
+            )
+            .await
+        }
+        Err(_) => {
+            admin_events_template(
+                store,
+                &layout,
+                config,
+                form,
+                Some("Could not save the event."),
+                None,
+            )
+            .await
+        }
+    }
+}
+
+#[post("/admin/events/delete", data = "<form_data>")]
+pub async fn admin_events_delete(
+    layout: Layout,
+    _admin: Admin,
+    store: &State<Store>,
+    config: &State<Config>,
+    form_data: Form<EventKey>,
+) -> Template {
+    let key = form_data.into_inner();
+    let empty_form = EventForm {
+        timezone: "UTC".to_string(),
+        ..Default::default()
+    };
+
+    match store.delete_event(&key.name, &key.description).await {
+        Ok(_) => {
+            admin_events_template(
+                store,
+                &layout,
+                config,
+                empty_form,
+                None,
+                Some("Event deleted successfully."),
+            )
+            .await
+        }
+        Err(StoreError::InUse) => {
+            admin_events_template(
+                store,
+                &layout,
+                config,
+                empty_form,
+                Some("Races with guesses can't be deleted."),
+                None,
+            )
+            .await
+        }
+        Err(_) => {
+            admin_events_template(
+                store,
+                &layout,
+                config,
+                empty_form,
+                Some("Could not delete the event."),
+                None,
+            )
+            .await
+        }
+    }
+}
+
+async fn admin_drivers_template(
+    store: &Store,
+    layout: &Layout,
+    form: DriverForm,
+    error: Option<&str>,
+    success: Option<&str>,
+) -> Template {
+    let logged_in = true;
+
+    let drivers: Vec<Driver> = store
+        .all_drivers()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .sorted_by(|a, b| a.number.cmp(&b.number))
+        .collect();
+
+    layout.render(
+        "admin_drivers",
+        context! { logged_in, drivers, form, error, success },
+    )
+}
+
+#[get("/admin/drivers?<code>")]
+pub async fn admin_drivers_form(
+    layout: Layout,
+    _admin: Admin,
+    store: &State<Store>,
+    code: Option<&str>,
+) -> Template {
+    // Editing an existing driver pre-fills the form with it.
+    let form = match code {
+        Some(code) => store
+            .all_drivers()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .find(|d| DriverCode::new(code).is_ok_and(|code| d.code == code))
+            .map(DriverForm::from),
+        None => None,
+    };
+    let form = form.unwrap_or(DriverForm {
+        active: true,
+        ..Default::default()
+    });
+
+    admin_drivers_template(store, &layout, form, None, None).await
+}
+
+#[post("/admin/drivers", data = "<form_data>")]
+pub async fn admin_drivers_submit(
+    layout: Layout,
+    _admin: Admin,
+    store: &State<Store>,
+    form_data: Form<DriverForm>,
+) -> Template {
+    let form = form_data.into_inner();
+
+    let number = match form.number.trim().parse() {
+        Ok(number) => number,
+        Err(_) => {
+            return admin_drivers_template(
+                store,
+                &layout,
+                form,
+                Some("The driver number must be between 0 and 255."),
+                None,
+            )
+            .await;
+        }
+    };
+
+    let code = match DriverCode::new(&form.code) {
+        Ok(code) => code,
+        Err(error) => {
+            return admin_drivers_template(store, &layout, form, Some(&error.to_string()), None)
+                .await;
+        }
+    };
+
+    let mut driver = Driver {
+        number,
+        code,
+        name: form.name.clone(),
+        category: form.category.clone(),
+        active: form.active,
+        team: form.team.clone(),
+        colour: form.colour.clone(),
+    };
+
+    driver.normalize();
+
+    if !driver.valid() {
+        return admin_drivers_template(
+            store,
+            &layout,
+            form,
+            Some("The name of the driver can't be empty and the colour must look like #rrggbb."),
+            None,
+        )
+        .await;
+    }
+
+    let result = if form.original_code.is_empty() {
+        store.add_driver(driver.clone()).await
+    } else {
+        match DriverCode::new(&form.original_code) {
+            Ok(original_code) => store.update_driver(&original_code, driver.clone()).await,
+            // No driver could have been stored with an invalid code.
+            Err(_) => Err(StoreError::Db(DbError::NoMatch)),
+        }
+    };
+
+    match result {
+        Ok(_) => {
+            admin_drivers_template(
+                store,
+                &layout,
+                DriverForm::from(&driver),
+                None,
+                Some("Driver saved successfully."),
+            )
+            .await
+        }
+        Err(StoreError::DuplicateDriver(conflict)) => {
+            admin_drivers_template(
+                store,
+                &layout,
+                form,
+                Some(&format!("Could not save the driver: {conflict}.")),
+                None,
+            )
+            .await
+        }
+        Err(_) => {
+            admin_drivers_template(
+                store,
+                &layout,
+                form,
+                Some("Could not save the driver."),
+                None,
+            )
+            .await
+        }
+    }
+}
+
+#[post("/admin/drivers/toggle", data = "<form_data>")]
+pub async fn admin_drivers_toggle(
+    layout: Layout,
+    _admin: Admin,
+    store: &State<Store>,
+    form_data: Form<DriverKey>,
+) -> Template {
+    let empty_form = DriverForm {
+        active: true,
+        ..Default::default()
+    };
+
+    match store.toggle_driver(&form_data.code).await {
+        Ok(_) => {
+            admin_drivers_template(
+                store,
+                &layout,
+                empty_form,
+                None,
+                Some("Driver updated successfully."),
+            )
+            .await
+        }
+        Err(StoreError::DuplicateDriver(conflict)) => {
+            admin_drivers_template(
+                store,
+                &layout,
+                empty_form,
+                Some(&format!("Could not activate the driver: {conflict}.")),
+                None,
+            )
+            .await
+        }
+        Err(_) => {
+            admin_drivers_template(
+                store,
+                &layout,
+                empty_form,
+                Some("Could not update the driver."),
+                None,
+            )
+            .await
+        }
+    }
+}
+
+/// A submitted form rendered again with an error, or rejected altogether.
+#[derive(Responder)]
+pub enum FormError {
+    Page(Template),
+    #[response(status = 403)]
+    Forbidden(Template),
+}
+
+fn forbidden_page(logged_in: bool, layout: &Layout) -> Template {
+    layout.render("forbidden", context! { logged_in })
+}
+
+#[derive(Responder)]
+pub enum ErrorResponse {
+    Page(Box<Template>),
+    Json(Value),
+}
+
+/// Requests to the API, or from clients preferring JSON, get errors as JSON instead of pages.
+fn wants_json(req: &Request) -> bool {
+    req.uri().path().starts_with("/api") || req.accept().is_some_and(|a| a.preferred().is_json())
+}
+
+#[catch(404)]
+pub fn not_found(req: &Request) -> ErrorResponse {
+    if wants_json(req) {
+        return ErrorResponse::Json(json!({ "error": "Not found." }));
+    }
+
+    let logged_in = req.cookies().get_private("session").is_some();
+    let layout = Layout::new(req.cookies());
+
+    ErrorResponse::Page(Box::new(layout.render("not_found", context! { logged_in })))
+}
+
+#[catch(500)]
+pub fn internal_error(req: &Request) -> ErrorResponse {
+    error!(
+        "Internal server error while handling {} {}",
+        req.method(),
+        req.uri()
+    );
+
+    if wants_json(req) {
+        return ErrorResponse::Json(json!({ "error": "Internal server error." }));
+    }
+
+    let logged_in = req.cookies().get_private("session").is_some();
+    let layout = Layout::new(req.cookies());
+
+    ErrorResponse::Page(Box::new(
+        layout.render("internal_error", context! { logged_in }),
+    ))
+}
+
+#[catch(403)]
+pub fn forbidden(req: &Request) -> Template {
+    forbidden_page(
+        req.cookies().get_private("session").is_some(),
+        &Layout::new(req.cookies()),
+    )
 }
 
 #[catch(401)]
@@ -453,8 +2232,238 @@ pub fn unauthorized(req: &Request) -> Result<Flash<Redirect>, &'static str> {
     match req.headers().get_one("x-api-key") {
         Some(_) => Err("Unauthorized"),
         None => Ok(Flash::error(
-            Redirect::to(uri!(login_form)),
-            "Please login to continue.",
+            Redirect::to(uri!(login_form(next = Some(req.uri().to_string())))),
+            "You have been logged out or your session expired.",
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{http::ContentType, local::blocking::Client};
+
+    #[test]
+    fn history_json() {
+        let path = "test_data/history_json/";
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}users.csv"),
+                "token,username,password,country\n\
+                 test-token,test,,PT\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2025-02-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Second GP,Race,2025-03-01 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5\n\
+                 First GP,test,NOR,VER,PIA,RUS,LEC\n\
+                 Second GP,test,VER,NOR,PIA,RUS,LEC\n\
+                 First GP,other,NOR,VER,PIA,RUS,LEC\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}results.csv"),
+                "race,p1,p2,p3,p4,p5\n\
+                 First GP,NOR,VER,PIA,RUS,LEC\n",
+            )
+            .is_ok()
+        );
+
+        let rocket = rocket::build()
+            .manage(Store::new(path))
+            .manage(Config::default())
+            .mount("/", routes![history]);
+        let client = Client::tracked(rocket).expect("The test rocket instance should be valid");
+        let response = client
+            .get("/history?format=json")
+            .private_cookie(("session", "test-token"))
+            .dispatch();
+
+        assert!(response.status() == Status::Ok);
+        assert!(response.content_type() == Some(ContentType::JSON));
+
+        let body: Value = response.into_json().unwrap_or_default();
+        let races: Vec<&str> = body
+            .as_array()
+            .map(|guesses| {
+                guesses
+                    .iter()
+                    .filter_map(|sg| sg["guess"]["race"].as_str())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Only the guesses of the user, the latest race first.
+        assert!(races == ["Second GP", "First GP"]);
+        assert!(body[1]["guess"]["username"] == "test");
+        assert!(body[1]["guess"]["p1"] == "NOR" && body[1]["guess"]["p5"] == "LEC");
+        assert!(body[1]["points"] == 25);
+        assert!(body[1]["breakdown"]["parlay"] == PARLAY);
+
+        let body: Value = client
+            .get("/history?format=json&race=first%20gp")
+            .private_cookie(("session", "test-token"))
+            .dispatch()
+            .into_json()
+            .unwrap_or_default();
+
+        assert!(body.as_array().is_some_and(|guesses| guesses.len() == 1));
+        assert!(body[0]["guess"]["race"] == "First GP");
+    }
+
+    #[test]
+    fn prefs_csrf() {
+        let rocket = rocket::build()
+            .manage(Store::new("test_data/prefs_csrf/"))
+            .attach(Template::fairing())
+            .mount("/", routes![prefs_submit]);
+        let client = Client::tracked(rocket).expect("The test rocket instance should be valid");
+        let token = CsrfToken::generate();
+
+        let response = client
+            .post("/prefs")
+            .header(ContentType::Form)
+            .body(format!("_csrf={token}&dark_mode=true&next=/history"))
+            .dispatch();
+
+        assert!(response.status() == Status::Forbidden);
+        assert!(response.cookies().get(UiPrefs::COOKIE).is_none());
+
+        let response = client
+            .post("/prefs")
+            .header(ContentType::Form)
+            .cookie((CsrfToken::COOKIE, token.clone()))
+            .body(format!("_csrf={token}&dark_mode=true&next=/history"))
+            .dispatch();
+
+        assert!(response.status() == Status::SeeOther);
+        assert!(response.headers().get_one("Location") == Some("/history"));
+        assert!(response.cookies().get(UiPrefs::COOKIE).is_some());
+    }
+
+    #[test]
+    fn register_invalid() {
+        let path = "test_data/register_invalid/";
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}countries.csv"),
+                "code,name,flag\n\
+                 PT,Portugal,\n",
+            )
+            .is_ok()
+        );
+
+        let rocket = rocket::build()
+            .manage(Store::new(path))
+            .manage(Config::default())
+            .manage(FormTimestamp::default())
+            .attach(Template::fairing())
+            .mount("/", routes![register_submit]);
+        let client = Client::tracked(rocket).expect("The test rocket instance should be valid");
+        let token = CsrfToken::generate();
+
+        // Too short a username fails to parse, which still needs a valid token.
+        let response = client
+            .post("/register")
+            .header(ContentType::Form)
+            .body("username=ab&password=password&country=PT")
+            .dispatch();
+
+        assert!(response.status() == Status::Forbidden);
+
+        let response = client
+            .post("/register")
+            .header(ContentType::Form)
+            .cookie((CsrfToken::COOKIE, token.clone()))
+            .body(format!(
+                "_csrf={token}&username=ab&password=password&country=PT"
+            ))
+            .dispatch();
+
+        assert!(response.status() == Status::Ok);
+        assert!(
+            response
+                .into_string()
+                .is_some_and(|body| body.contains(r#"<option value="PT" selected>"#))
+        );
+    }
+
+    #[test]
+    fn index_error() {
+        let path = "test_data/index_error/";
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5\n\
+                 First GP,test,NOR\n",
+            )
+            .is_ok()
+        );
+
+        let rocket = rocket::build()
+            .manage(Store::new(path))
+            .manage(Config::default())
+            .attach(Template::fairing())
+            .mount("/", routes![index]);
+        let client = Client::tracked(rocket).expect("The test rocket instance should be valid");
+        let response = client.get("/").dispatch();
+
+        assert!(response.status() == Status::Ok);
+        assert!(response.into_string().is_some_and(|body| {
+            body.contains("Become the world champion") && body.contains("Could not get guesses.")
+        }));
+    }
+
+    #[test]
+    fn rules_multipliers() {
+        let path = "test_data/rules_multipliers/";
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+
+        let rocket = rocket::build()
+            .manage(Store::new(path))
+            .manage(Config::default())
+            .attach(Template::fairing())
+            .mount("/", routes![rules]);
+        let client = Client::tracked(rocket).expect("The test rocket instance should be valid");
+        let response = client.get("/rules").dispatch();
+
+        // Only the multipliers that change anything are mentioned, and sprints count once by
+        // default.
+        assert!(response.status() == Status::Ok);
+        assert!(response.into_string().is_some_and(|body| {
+            body.contains("double points races count <strong>2</strong> times")
+                && !body.contains("sprint races")
+        }));
+    }
+
+    #[test]
+    fn local_path() {
+        assert!(is_local_path("/history"));
+        assert!(is_local_path("/admin/results?race=TEST%20GP"));
+        assert!(!is_local_path("https://example.com/"));
+        assert!(!is_local_path("//example.com/"));
+        assert!(!is_local_path("/\\example.com/"));
+        assert!(!is_local_path("history"));
+        assert!(!is_local_path(""));
+    }
+}
@@ -1,4 +1,5 @@
 mod api;
+mod config;
 mod controllers;
 mod models;
 mod store;
@@ -6,12 +7,62 @@ mod store;
 #[macro_use]
 extern crate rocket;
 
-use csv_db::Database;
-use rocket::{fs::FileServer, tokio::sync::Mutex};
+use std::{collections::HashSet, time::Duration};
+
+use chrono::Utc;
+use rocket::{
+    fairing::AdHoc,
+    fs::FileServer,
+    http::Header,
+    tokio::{self, time},
+};
 use rocket_dyn_templates::Template;
 
 use api::*;
+use config::Config;
 use controllers::*;
+use models::FormTimestamp;
+use store::Store;
+
+const DATA_DIR: &str = "data";
+
+/// Checks every minute for upcoming events flagged with `notify` and logs a reminder once per event.
+///
+/// Managed state doesn't outlive the liftoff fairing, so the task reads the events through a store
+/// detached from the managed one. It only ever reads, leaving every write to the request handlers.
+async fn remind(store: Store, lookahead_minutes: i64) {
+    let mut reminded = HashSet::new();
+    let mut interval = time::interval(Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+
+        // Started events are never returned again, so their keys can go.
+        let now = Utc::now();
+        reminded.retain(|(_, _, datetime)| *datetime >= now);
+
+        let Ok(events) = store.events_needing_notification(lookahead_minutes).await else {
+            continue;
+        };
+
+        for event in events {
+            let key = (
+                event.name.clone(),
+                event.description.clone(),
+                event.datetime,
+            );
+
+            if reminded.insert(key) {
+                let minutes = (event.datetime - Utc::now()).num_minutes();
+
+                info!(
+                    "REMINDER: {} {} starts in {minutes} minutes",
+                    event.name, event.description
+                );
+            }
+        }
+    }
+}
 
 #[launch]
 fn rocket() -> _ {
@@ -19,8 +70,21 @@ fn rocket() -> _ {
         .mount(
             "/",
             routes![
+                admin,
+                admin_drivers_form,
+                admin_drivers_submit,
+                admin_drivers_toggle,
+                admin_events_delete,
+                admin_events_form,
+                admin_events_submit,
+                admin_results_form,
+                admin_results_submit,
+                constructor_form,
+                constructor_submit,
                 disclaimer,
+                driver,
                 history,
+                history_csv,
                 index,
                 latest,
                 login_form,
@@ -28,20 +92,97 @@ fn rocket() -> _ {
                 logout,
                 play_form,
                 play_submit,
+                prefs_submit,
                 profile_form,
                 profile_submit,
                 register_form,
                 register_submit,
                 rules,
+                standings,
                 stats,
+                widget_leaderboard,
             ],
         )
         .mount(
             "/api",
-            routes![guesses, leaderboard, play, scored_guesses, users],
+            routes![
+                crowd,
+                guesses,
+                invite_codes,
+                leaderboard,
+                me,
+                me_export,
+                parlay_leaderboard,
+                participation,
+                play,
+                play_form_encoded,
+                rehash,
+                scored_guesses,
+                user_guesses,
+                user_stats,
+                users
+            ],
+        )
+        .register(
+            "/",
+            catchers![forbidden, internal_error, not_found, unauthorized],
         )
-        .register("/", catchers![unauthorized])
+        .register("/api", catchers![too_many_requests])
         .attach(Template::fairing())
-        .manage(Mutex::new(Database::new("data", None)))
+        .attach(AdHoc::config::<Config>())
+        .attach(AdHoc::try_on_ignite("Store", |rocket| async {
+            let store = Store::new(DATA_DIR).with_driver_cache().with_score_cache();
+            let store = match rocket.state::<Config>() {
+                Some(config) => store
+                    .with_scoring(config.scoring.clone())
+                    .with_argon2(&config.argon2),
+                None => store,
+            };
+
+            let migrated = match store.run_migrations(DATA_DIR).await {
+                Ok(_) => {
+                    for problem in store.verify().await.unwrap_or_default() {
+                        warn!("{problem}");
+                    }
+
+                    store.seed_countries().await
+                }
+                error => error,
+            };
+
+            match migrated {
+                Ok(_) => Ok(rocket.manage(store)),
+                Err(error) => {
+                    error!("Could not migrate the data: {error}");
+                    Err(rocket)
+                }
+            }
+        }))
+        // The default shield only allows framing by the same origin, which the widgets relax.
+        .attach(AdHoc::on_response("Widget frames", |req, res| {
+            Box::pin(async move {
+                if req.uri().path().starts_with("/widget/")
+                    && let Some(config) = req.rocket().state::<Config>()
+                {
+                    res.remove_header("X-Frame-Options");
+                    res.set_header(Header::new(
+                        "Content-Security-Policy",
+                        config.widget_frame_policy(),
+                    ));
+                }
+            })
+        }))
+        .attach(RateLimitInfo::default())
+        .attach(AdHoc::on_liftoff("Event reminders", |rocket| {
+            Box::pin(async move {
+                if let (Some(config), Some(store)) =
+                    (rocket.state::<Config>(), rocket.state::<Store>())
+                {
+                    tokio::spawn(remind(store.detached(), config.reminder_minutes));
+                }
+            })
+        }))
+        .manage(ExportLimiter::default())
+        .manage(FormTimestamp::default())
         .mount("/static", FileServer::from("./static"))
 }
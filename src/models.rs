@@ -1,32 +1,389 @@
-use std::collections::HashSet;
+use std::{cmp::Reverse, collections::HashMap, fmt};
 
-use chrono::{DateTime, Utc};
-use csv_db::Database;
+use chrono::{DateTime, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use country_emoji::code_to_flag;
+use hmac::{Hmac, Mac};
+use itertools::Itertools;
 use rocket::{
     Request, State,
-    http::Status,
+    form::{self, FromFormField, ValueField},
+    fs::TempFile,
+    http::{CookieJar, Status},
     request::{FromRequest, Outcome},
-    tokio::sync::Mutex,
 };
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
 
-use crate::store::Store;
+use crate::{
+    config::Config,
+    store::{CORRECT_FIVE, CORRECT_PODIUM, PARLAY, Store},
+};
+
+/// Placeholder shown instead of the picks of a guess whose deadline hasn't passed yet.
+pub const HIDDEN_PICK: &str = "submitted";
+/// Number of picks of the current game, from P1 to P5, below which a guess is incomplete.
+pub const MIN_PICKS: usize = 5;
+/// Number of picks up to which a guess can be stored, from P1 to P10.
+pub const MAX_PICKS: usize = 10;
+
+/// Protects forms against cross-site request forgery with the double submit cookie pattern.
+/// A form is only accepted when it echoes the random token of a cookie, which other sites can't
+/// read, so they can't forge the form field.
+pub struct CsrfToken;
+
+impl CsrfToken {
+    pub const COOKIE: &str = "csrf_token";
+
+    pub fn generate() -> String {
+        Uuid::new_v4().to_string()
+    }
+
+    pub fn validate(cookie: &str, form: &str) -> bool {
+        !cookie.is_empty() && cookie == form
+    }
+}
+
+/// Signs the time a form was rendered, so a submission can be rejected when it comes back faster
+/// than a person could fill it in, or long after. The key is random, so restarting the server
+/// invalidates forms rendered before.
+pub struct FormTimestamp {
+    key: [u8; 32],
+}
+
+impl Default for FormTimestamp {
+    fn default() -> Self {
+        let mut key = [0; 32];
+        key[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+        key[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+
+        Self { key }
+    }
+}
+
+impl FormTimestamp {
+    pub const MIN_AGE: TimeDelta = TimeDelta::seconds(3);
+    pub const MAX_AGE: TimeDelta = TimeDelta::hours(1);
+    /// Leeway for clocks that disagree slightly, such as behind a load balancer.
+    pub const CLOCK_SKEW: TimeDelta = TimeDelta::seconds(1);
+
+    fn mac(&self, timestamp: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(timestamp.as_bytes());
+        mac
+    }
+
+    /// The Unix timestamp of `now` in milliseconds, followed by its signature in hex.
+    pub fn issue(&self, now: DateTime<Utc>) -> String {
+        let timestamp = now.timestamp_millis().to_string();
+        let signature: String = self
+            .mac(&timestamp)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        format!("{timestamp}.{signature}")
+    }
+
+    /// Whether `value` was issued by `issue` between `MIN_AGE` and `MAX_AGE` before `now`, give
+    /// or take `CLOCK_SKEW`.
+    pub fn validate(&self, value: &str, now: DateTime<Utc>) -> bool {
+        let Some((timestamp, signature)) = value.split_once('.') else {
+            return false;
+        };
+
+        if !signature.is_ascii() || signature.len() != 64 {
+            return false;
+        }
+
+        let Ok(signature) = (0..signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&signature[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+        else {
+            return false;
+        };
+
+        if self.mac(timestamp).verify_slice(&signature).is_err() {
+            return false;
+        }
+
+        let Some(issued) = timestamp
+            .parse()
+            .ok()
+            .and_then(DateTime::from_timestamp_millis)
+        else {
+            return false;
+        };
+        let age = now - issued;
+
+        age >= Self::MIN_AGE - Self::CLOCK_SKEW && age <= Self::MAX_AGE + Self::CLOCK_SKEW
+    }
+}
 
 #[derive(FromForm)]
 pub struct Registration {
+    #[field(validate = len(3..=20).or_else(msg!("The username must have between 3 and 20 characters.")))]
+    #[field(validate = is_alphanumeric_underscore())]
     pub username: String,
+    #[field(validate = len(8..).or_else(msg!("The password must have at least 8 characters.")))]
     pub password: String,
     pub country: Option<String>,
+    /// Only required while registration is invite only.
+    #[field(default = String::new())]
+    pub invite_code: String,
+    #[field(name = "_csrf", default = String::new())]
+    pub csrf: String,
+    /// Hidden from people, so only bots filling in every field send it.
+    #[field(default = String::new())]
+    pub website: String,
+    /// Signed by `FormTimestamp` when the form was rendered.
+    #[field(name = "_issued", default = String::new())]
+    pub issued: String,
+}
+
+/// Form validator accepting only ASCII letters, digits and underscores.
+fn is_alphanumeric_underscore<'v>(value: &str) -> form::Result<'v, ()> {
+    if value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(())
+    } else {
+        Err(form::Error::validation(
+            "The username can only contain letters, digits and underscores.",
+        ))?
+    }
+}
+
+impl Registration {
+    /// Whether the registration looks like it was sent by a bot rather than a person.
+    pub fn looks_automated(&self, form_timestamp: &FormTimestamp, now: DateTime<Utc>) -> bool {
+        !self.website.is_empty() || !form_timestamp.validate(&self.issued, now)
+    }
+}
+
+#[derive(FromForm)]
+pub struct Login {
+    pub username: String,
+    pub password: String,
+    /// Page the user was trying to reach before being asked to login.
+    pub next: Option<String>,
+    #[field(name = "_csrf", default = String::new())]
+    pub csrf: String,
 }
 
 #[derive(FromForm)]
-pub struct Profile {
+pub struct Profile<'r> {
     pub country: String,
     pub password: String,
+    pub preferred_driver: Option<String>,
+    pub timezone: String,
+    pub avatar: Option<TempFile<'r>>,
+    pub dark_mode: bool,
+    pub compact_tables: bool,
+    /// Version of the user the form was rendered with.
+    #[field(default = 0)]
+    pub version: u64,
+}
+
+/// A driver code such as "VER", always made of 3 uppercase ASCII letters. Codes are trimmed and
+/// uppercased when parsed, so they can be compared and hashed as they are.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DriverCode(String);
+
+impl DriverCode {
+    pub fn new(code: &str) -> Result<Self, InvalidDriverCode> {
+        let code = code.trim();
+
+        if code.len() == 3 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+            Ok(Self(code.to_ascii_uppercase()))
+        } else {
+            Err(InvalidDriverCode {
+                code: code.to_string(),
+            })
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for DriverCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::str::FromStr for DriverCode {
+    type Err = InvalidDriverCode;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        Self::new(code)
+    }
+}
+
+impl TryFrom<String> for DriverCode {
+    type Error = InvalidDriverCode;
+
+    fn try_from(code: String) -> Result<Self, Self::Error> {
+        Self::new(&code)
+    }
+}
+
+impl From<DriverCode> for String {
+    fn from(code: DriverCode) -> Self {
+        code.0
+    }
+}
+
+impl<'v> FromFormField<'v> for DriverCode {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        Ok(Self::new(field.value).map_err(|error| form::Error::validation(error.to_string()))?)
+    }
+}
+
+impl PartialEq<str> for DriverCode {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for DriverCode {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+/// A driver code that isn't made of 3 letters, as it was given once trimmed.
+#[derive(Debug, PartialEq)]
+pub struct InvalidDriverCode {
+    pub code: String,
 }
 
-#[derive(Clone, Deserialize, FromForm, PartialEq, Serialize)]
+impl fmt::Display for InvalidDriverCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' is not a 3 letter driver code.", self.code)
+    }
+}
+
+impl std::error::Error for InvalidDriverCode {}
+
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+#[serde(from = "GuessRecord", into = "GuessRecord")]
 pub struct Guess {
+    pub race: String,
+    /// Id of the event of the race, empty for guesses stored before events had ids and not
+    /// matching any event.
+    pub race_id: String,
+    pub username: String,
+    /// The driver codes picked from P1 onwards, P1 to P5 for the current game. Guesses may be
+    /// partial or, for experiments, go up to P10, which `valid` decides about.
+    pub positions: Vec<DriverCode>,
+    pub category: String,
+    /// When the guess was last submitted. Guesses stored before this was tracked get the Unix
+    /// epoch as a placeholder.
+    pub submitted_at: DateTime<Utc>,
+    /// When the guess was first stored, unknown for guesses stored before this was tracked.
+    pub created_at: Option<DateTime<Utc>>,
+    /// When the guess was last stored, unknown for guesses stored before this was tracked.
+    pub updated_at: Option<DateTime<Utc>>,
+    /// Incremented on every update, so an update based on an outdated guess can be rejected.
+    pub version: u64,
+}
+
+/// How a guess is stored and serialized, with a `p1` to `p10` field for each position. Every row
+/// has all ten, left empty past the last pick, since the whole file is written with one header.
+#[derive(Deserialize, Serialize)]
+struct GuessRecord {
+    race: String,
+    username: String,
+    p1: Option<DriverCode>,
+    p2: Option<DriverCode>,
+    p3: Option<DriverCode>,
+    p4: Option<DriverCode>,
+    p5: Option<DriverCode>,
+    #[serde(default)]
+    p6: Option<DriverCode>,
+    #[serde(default)]
+    p7: Option<DriverCode>,
+    #[serde(default)]
+    p8: Option<DriverCode>,
+    #[serde(default)]
+    p9: Option<DriverCode>,
+    #[serde(default)]
+    p10: Option<DriverCode>,
+    #[serde(default)]
+    category: String,
+    #[serde(default)]
+    submitted_at: DateTime<Utc>,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    updated_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    version: u64,
+    #[serde(default)]
+    race_id: String,
+}
+
+impl From<GuessRecord> for Guess {
+    fn from(record: GuessRecord) -> Self {
+        Self {
+            race: record.race,
+            race_id: record.race_id,
+            username: record.username,
+            // The picks end at the first empty position.
+            positions: [
+                record.p1, record.p2, record.p3, record.p4, record.p5, record.p6, record.p7,
+                record.p8, record.p9, record.p10,
+            ]
+            .into_iter()
+            .map_while(|pick| pick)
+            .collect(),
+            category: record.category,
+            submitted_at: record.submitted_at,
+            created_at: record.created_at,
+            updated_at: record.updated_at,
+            version: record.version,
+        }
+    }
+}
+
+impl From<Guess> for GuessRecord {
+    fn from(guess: Guess) -> Self {
+        let mut positions = guess.positions.into_iter();
+
+        Self {
+            race: guess.race,
+            username: guess.username,
+            p1: positions.next(),
+            p2: positions.next(),
+            p3: positions.next(),
+            p4: positions.next(),
+            p5: positions.next(),
+            p6: positions.next(),
+            p7: positions.next(),
+            p8: positions.next(),
+            p9: positions.next(),
+            p10: positions.next(),
+            category: guess.category,
+            submitted_at: guess.submitted_at,
+            created_at: guess.created_at,
+            updated_at: guess.updated_at,
+            version: guess.version,
+            race_id: guess.race_id,
+        }
+    }
+}
+
+/// A guess as submitted through the play form or the API, before its picks are parsed. It's also
+/// rendered back into the form when they can't be.
+#[derive(Default, Deserialize, FromForm, Serialize)]
+pub struct GuessForm {
     pub race: String,
     pub username: String,
     pub p1: String,
@@ -34,50 +391,208 @@ pub struct Guess {
     pub p3: String,
     pub p4: String,
     pub p5: String,
+    /// Version of the guess the form was rendered with.
+    #[field(default = 0)]
+    #[serde(default)]
+    pub version: u64,
+    #[field(name = "_csrf", default = String::new())]
+    #[serde(default, skip_serializing)]
+    pub csrf: String,
+}
+
+impl From<&Guess> for GuessForm {
+    fn from(guess: &Guess) -> Self {
+        let [p1, p2, p3, p4, p5] = std::array::from_fn(|pos| {
+            guess
+                .picks()
+                .get(pos)
+                .map(DriverCode::to_string)
+                .unwrap_or_default()
+        });
+
+        Self {
+            race: guess.race.clone(),
+            username: guess.username.clone(),
+            p1,
+            p2,
+            p3,
+            p4,
+            p5,
+            version: guess.version,
+            csrf: String::new(),
+        }
+    }
+}
+
+/// Parses a pick, reporting its position when it's empty or not a driver code.
+fn parse_pick(position: usize, pick: &str) -> Result<DriverCode, GuessValidationError> {
+    if pick.trim().is_empty() {
+        return Err(GuessValidationError::EmptyPosition { position });
+    }
+
+    DriverCode::new(pick).map_err(|error| GuessValidationError::InvalidCode {
+        position,
+        code: error.code,
+    })
+}
+
+/// Parses the picks from P1 to P5, reporting the first one that can't be.
+fn parse_picks(picks: [&str; 5]) -> Result<[DriverCode; 5], GuessValidationError> {
+    let [p1, p2, p3, p4, p5] = picks;
+
+    Ok([
+        parse_pick(1, p1)?,
+        parse_pick(2, p2)?,
+        parse_pick(3, p3)?,
+        parse_pick(4, p4)?,
+        parse_pick(5, p5)?,
+    ])
+}
+
+impl TryFrom<&GuessForm> for Guess {
+    type Error = GuessValidationError;
+
+    fn try_from(form: &GuessForm) -> Result<Self, Self::Error> {
+        Ok(Self {
+            race: form.race.clone(),
+            race_id: String::new(),
+            username: form.username.clone(),
+            positions: parse_picks(
+                [&form.p1, &form.p2, &form.p3, &form.p4, &form.p5].map(String::as_str),
+            )?
+            .to_vec(),
+            category: String::new(),
+            submitted_at: Utc::now(),
+            created_at: None,
+            updated_at: None,
+            version: form.version,
+        })
+    }
 }
 
 impl Guess {
+    /// Driver codes are normalized when parsed, leaving the race and username.
     pub fn normalize(&mut self) {
-        self.race = self.race.to_uppercase();
-        self.username = self.username.to_lowercase();
-        self.p1 = self.p1.to_uppercase();
-        self.p2 = self.p2.to_uppercase();
-        self.p3 = self.p3.to_uppercase();
-        self.p4 = self.p4.to_uppercase();
-        self.p5 = self.p5.to_uppercase();
+        self.race = normalize_race_name(&self.race);
+        self.username = self.username.trim().to_lowercase();
     }
 
-    pub fn valid(&self, drivers: &[Driver]) -> bool {
-        let driver_codes: HashSet<String> = drivers.iter().map(|d| d.code.to_lowercase()).collect();
-        let guesses = [&self.p1, &self.p2, &self.p3, &self.p4, &self.p5];
+    /// The driver codes picked from P1 onwards.
+    pub fn picks(&self) -> &[DriverCode] {
+        &self.positions
+    }
 
-        let mut seen = HashSet::new();
+    /// The driver code picked for P1, unless the guess is empty.
+    pub fn p1(&self) -> Option<&DriverCode> {
+        self.positions.first()
+    }
 
-        for guess in guesses {
-            let code = guess.to_lowercase();
-            if !driver_codes.contains(&code) {
-                return false;
+    /// Describes each pick that differs from a previous guess, like "P2: NOR → PIA".
+    pub fn changes_from(&self, previous: &Guess) -> Vec<String> {
+        self.picks()
+            .iter()
+            .zip(previous.picks())
+            .enumerate()
+            .filter(|(_, (new, old))| new != old)
+            .map(|(i, (new, old))| format!("P{}: {old} → {new}", i + 1))
+            .collect()
+    }
+
+    /// A guess is valid when it has from 5 to 10 picks, all different codes of the given drivers.
+    /// Reports the first position that breaks this.
+    pub fn valid(&self, drivers: &[Driver]) -> Result<(), GuessValidationError> {
+        if self.positions.len() < MIN_PICKS {
+            return Err(GuessValidationError::EmptyPosition {
+                position: self.positions.len() + 1,
+            });
+        }
+
+        if self.positions.len() > MAX_PICKS {
+            return Err(GuessValidationError::TooManyPicks {
+                count: self.positions.len(),
+            });
+        }
+
+        for (index, code) in self.positions.iter().enumerate() {
+            if !drivers.iter().any(|d| d.code == *code) {
+                return Err(GuessValidationError::UnknownDriver {
+                    position: index + 1,
+                    code: code.to_string(),
+                });
             }
 
-            if !seen.insert(code) {
-                return false;
+            if self.positions[..index].contains(code) {
+                return Err(GuessValidationError::DuplicateDriver {
+                    code: code.to_string(),
+                });
             }
         }
 
-        true
+        Ok(())
+    }
+
+    /// The result of the race of the guess, see `RaceResult::find`.
+    pub fn result_in<'r>(
+        &self,
+        results: &'r HashMap<String, RaceResult>,
+    ) -> Option<&'r RaceResult> {
+        RaceResult::find(results, &self.race_id, &self.race)
+    }
+
+    /// Whether the picks must be hidden, because the guess is for the given event and its deadline
+    /// hasn't passed.
+    pub fn hidden_before_deadline(&self, event: &Event, now: DateTime<Utc>) -> bool {
+        now < event.datetime && self.race.eq_ignore_ascii_case(&event.name)
+    }
+}
+
+/// Why a guess was rejected, with positions counted from 1.
+#[derive(Debug, PartialEq)]
+pub enum GuessValidationError {
+    EmptyPosition { position: usize },
+    InvalidCode { position: usize, code: String },
+    UnknownDriver { position: usize, code: String },
+    DuplicateDriver { code: String },
+    TooManyPicks { count: usize },
+}
+
+impl fmt::Display for GuessValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyPosition { position } => write!(f, "P{position} is empty."),
+            Self::InvalidCode { position, code } => write!(
+                f,
+                "P{position} contains '{code}' which is not a 3 letter driver code."
+            ),
+            Self::UnknownDriver { position, code } => write!(
+                f,
+                "P{position} contains '{code}' which is not a valid driver code."
+            ),
+            Self::DuplicateDriver { code } => write!(f, "'{code}' is picked more than once."),
+            Self::TooManyPicks { count } => write!(
+                f,
+                "{count} drivers are picked, but at most {MAX_PICKS} can be."
+            ),
+        }
     }
 }
 
+impl std::error::Error for GuessValidationError {}
+
 impl Default for Guess {
     fn default() -> Self {
         Self {
             race: "".to_string(),
+            race_id: "".to_string(),
             username: "".to_string(),
-            p1: "NOR".to_string(),
-            p2: "VER".to_string(),
-            p3: "PIA".to_string(),
-            p4: "RUS".to_string(),
-            p5: "LEC".to_string(),
+            positions: ["NOR", "VER", "PIA", "RUS", "LEC"]
+                .map(|code| DriverCode::new(code).expect("The default picks are valid codes"))
+                .to_vec(),
+            category: "".to_string(),
+            submitted_at: DateTime::default(),
+            created_at: None,
+            updated_at: None,
+            version: 0,
         }
     }
 }
@@ -85,76 +600,2661 @@ impl Default for Guess {
 #[derive(Serialize)]
 pub struct ScoredGuess<'a> {
     pub guess: &'a Guess,
-    pub points: u16,
+    pub points: Points,
+    /// The result of the race, or `None` while it's pending.
+    pub result: Option<&'a RaceResult>,
+    /// Whether no event matches the race, so the guess can't be sorted by date and comes last.
+    pub undated: bool,
+}
+
+/// A scored guess that can be sorted by the date of its race, borrowed or owned.
+pub trait RaceDated {
+    fn race(&self) -> &str;
+
+    /// Flags whether no event matches the race.
+    fn set_undated(&mut self, undated: bool);
+}
+
+impl RaceDated for ScoredGuess<'_> {
+    fn race(&self) -> &str {
+        &self.guess.race
+    }
+
+    fn set_undated(&mut self, undated: bool) {
+        self.undated = undated;
+    }
+}
+
+/// Aggregate statistics of the guesses of a user, shown at the top of their history.
+#[derive(Default, PartialEq, Serialize)]
+pub struct UserHistorySummary {
+    pub total_races: usize,
+    pub total_points: Points,
+    pub average_points: f32,
+    /// Race of the highest scoring guess, or `None` while no guess scored any points.
+    pub best_race: Option<String>,
+    pub best_score: Points,
+    /// Number of guesses with every driver in the correct position.
+    pub perfect_count: u16,
+}
+
+impl UserHistorySummary {
+    pub fn new(scored_guesses: &[ScoredGuess]) -> Self {
+        let perfect_score = 3 * CORRECT_PODIUM + 2 * CORRECT_FIVE + PARLAY;
+
+        let total_races = scored_guesses.len();
+        let total_points: Points = scored_guesses.iter().map(|sg| sg.points).sum();
+        let best = scored_guesses
+            .iter()
+            .filter(|sg| sg.points > Points::ZERO)
+            .max_by_key(|sg| sg.points);
+
+        Self {
+            total_races,
+            total_points,
+            average_points: if total_races == 0 {
+                0.0
+            } else {
+                total_points.get() as f32 / total_races as f32
+            },
+            best_race: best.map(|sg| sg.guess.race.clone()),
+            best_score: best.map_or(Points::ZERO, |sg| sg.points),
+            perfect_count: scored_guesses
+                .iter()
+                .filter(|sg| sg.points == perfect_score)
+                .count() as u16,
+        }
+    }
 }
 
+/// A guess of a user as a row of the downloadable history, scored once the race has a result.
 #[derive(Serialize)]
-pub struct ApiScoredGuess {
-    pub guess: Guess,
-    pub points: u16,
+pub struct GuessHistoryRow {
+    pub race: String,
+    /// Date of the race, unknown when it's no longer in the calendar.
+    pub date: Option<DateTime<Utc>>,
+    pub p1: Option<DriverCode>,
+    pub p2: Option<DriverCode>,
+    pub p3: Option<DriverCode>,
+    pub p4: Option<DriverCode>,
+    pub p5: Option<DriverCode>,
+    pub points: Option<Points>,
+    #[serde(serialize_with = "yes_no")]
+    pub scored: bool,
 }
 
-#[derive(Default, Deserialize, PartialEq, Serialize)]
-pub struct User {
-    pub token: String,
-    pub username: String,
-    pub password: String,
-    pub country: String,
+impl GuessHistoryRow {
+    /// Writes the rows as CSV, with a header naming the columns.
+    pub fn to_csv(rows: &[Self]) -> Result<String, csv::Error> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+
+        for row in rows {
+            writer.serialize(row)?;
+        }
+
+        let bytes = writer
+            .into_inner()
+            .map_err(|error| csv::Error::from(error.into_error()))?;
+
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
 }
 
-#[rocket::async_trait]
-impl<'r> FromRequest<'r> for User {
-    type Error = &'static str;
+fn yes_no<S: serde::Serializer>(value: &bool, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(if *value { "yes" } else { "no" })
+}
 
-    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let cookies = req.cookies();
-        let db = match req.guard::<&State<Mutex<Database<&str>>>>().await {
-            Outcome::Success(db) => db,
-            _ => {
-                return Outcome::Error((Status::InternalServerError, "Could not access database."));
-            }
+/// How many players guessed a race, and which share of the registered players that is.
+#[derive(Serialize)]
+pub struct ParticipationStat {
+    pub race: String,
+    pub guesses: usize,
+    pub participation_rate: f32,
+}
+
+/// Aggregate facts about the guesses of every player in a season, shown on the stats page.
+#[derive(Default, Serialize)]
+pub struct SiteStats {
+    pub season: Option<u16>,
+    pub guesses_count: usize,
+    /// Average points of a guess, over the races that already have a result.
+    pub average_points: f32,
+    pub best_score: Points,
+    /// Player and race of the highest scoring guess, or `None` while no guess scored any points.
+    pub best_username: Option<String>,
+    pub best_race: Option<String>,
+    pub parlay_count: usize,
+    pub most_picked: Option<String>,
+    pub most_picked_count: usize,
+    pub least_picked: Option<String>,
+    pub least_picked_count: usize,
+    /// Race with the lowest average points, among the races that already have a result.
+    pub worst_race: Option<String>,
+    pub worst_race_average: f32,
+}
+
+impl SiteStats {
+    pub fn new(
+        season: Option<u16>,
+        scored_guesses: &[ScoredGuessOwned],
+        normalized_results: &HashMap<String, RaceResult>,
+    ) -> Self {
+        let scored: Vec<&ScoredGuessOwned> = scored_guesses
+            .iter()
+            .filter(|sg| sg.guess.result_in(normalized_results).is_some())
+            .collect();
+        let average = |guesses: &[&ScoredGuessOwned]| {
+            guesses.iter().map(|sg| sg.points.get() as f32).sum::<f32>() / guesses.len() as f32
         };
 
-        match cookies.get_private("session") {
-            Some(token) => match Store::get_user(token.value(), db).await {
-                Some(user) => Outcome::Success(user),
-                None => Outcome::Forward(Status::Unauthorized),
-            },
-            None => match req.headers().get_one("x-api-key") {
-                Some(key) => match Store::get_user(key, db).await {
-                    Some(user) => Outcome::Success(user),
-                    None => Outcome::Error((Status::Unauthorized, "Unauthorized")),
-                },
-                None => Outcome::Error((Status::Unauthorized, "Unauthorized")),
+        let best = scored
+            .iter()
+            .filter(|sg| sg.points > Points::ZERO)
+            .max_by_key(|sg| sg.points);
+        // Ties are broken alphabetically, so the stats don't change between page loads.
+        let picks = scored_guesses
+            .iter()
+            .flat_map(|sg| sg.guess.picks())
+            .counts();
+        let most_picked = picks
+            .iter()
+            .max_by_key(|(code, count)| (**count, Reverse(**code)));
+        let least_picked = picks.iter().min_by_key(|(code, count)| (**count, **code));
+        let worst_race = scored
+            .iter()
+            .copied()
+            .into_group_map_by(|sg| sg.guess.race.as_str())
+            .into_iter()
+            .map(|(race, guesses)| (race, average(&guesses)))
+            .min_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(b.0)));
+
+        Self {
+            season,
+            guesses_count: scored_guesses.len(),
+            average_points: if scored.is_empty() {
+                0.0
+            } else {
+                average(&scored)
             },
+            best_score: best.map_or(Points::ZERO, |sg| sg.points),
+            best_username: best.map(|sg| sg.guess.username.clone()),
+            best_race: best.map(|sg| sg.guess.race.clone()),
+            parlay_count: scored
+                .iter()
+                .filter(|sg| sg.breakdown.parlay > Points::ZERO)
+                .count(),
+            most_picked: most_picked.map(|(code, _)| code.to_string()),
+            most_picked_count: most_picked.map_or(0, |(_, count)| *count),
+            least_picked: least_picked.map(|(code, _)| code.to_string()),
+            least_picked_count: least_picked.map_or(0, |(_, count)| *count),
+            worst_race: worst_race.map(|(race, _)| race.to_string()),
+            worst_race_average: worst_race.map_or(0.0, |(_, average)| average),
         }
     }
 }
 
-#[derive(Deserialize, Serialize)]
-pub struct Driver {
-    pub number: u8,
-    pub code: String,
+/// A race that already took place, as seen by one user on their history page.
+#[derive(Serialize)]
+pub struct HistoryEvent {
     pub name: String,
+    pub datetime: DateTime<Utc>,
+    /// The guess of the user, or `None` if they didn't play this race.
+    pub guess: Option<Guess>,
+    /// Points of the guess, or `None` while the race has no result or the user didn't play.
+    pub points: Option<Points>,
+    /// Whether the result of the race is still missing.
+    pub pending: bool,
 }
 
-#[derive(Deserialize, Serialize)]
-pub struct Event {
-    pub category: String,
-    pub name: String,
-    pub description: String,
-    pub datetime: DateTime<Utc>,
-    pub channel: String,
-    tags: String,
-    notify: bool,
+/// The season of the logged in user at a glance, shown on the index page.
+#[derive(Default, Serialize)]
+pub struct SeasonSummary {
+    /// Rank in the leaderboard, or `None` before the user scored in it.
+    pub rank: Option<usize>,
+    pub points: Points,
+    /// Latest race with a result, with the points the user gained in it.
+    pub last_race: Option<String>,
+    pub last_race_points: Option<Points>,
+    /// Whether the user already guessed the upcoming event.
+    pub submitted: bool,
+    /// Number of latest races in a row where the user guessed the winner.
+    pub p1_streak: usize,
 }
 
-#[derive(Deserialize, Serialize)]
-pub struct RaceResult {
-    pub race: String,
-    pub p1: String,
-    pub p2: String,
-    pub p3: String,
-    pub p4: String,
-    pub p5: String,
+impl SeasonSummary {
+    /// Summarizes the ranked leaderboard row of the user and their history, sorted by date.
+    pub fn new(
+        entry: Option<&LeaderboardEntry>,
+        history: &[HistoryEvent],
+        results: &HashMap<String, RaceResult>,
+        submitted: bool,
+    ) -> Self {
+        let mut scored = history.iter().rev().filter(|e| !e.pending);
+        let last_race = scored.clone().next();
+
+        let p1_streak = scored
+            .by_ref()
+            .take_while(|e| {
+                let winner = results
+                    .values()
+                    .find(|result| result.race.eq_ignore_ascii_case(&e.name))
+                    .map(|result| &result.p1);
+
+                e.guess
+                    .as_ref()
+                    .zip(winner)
+                    .is_some_and(|(guess, winner)| guess.p1() == Some(winner))
+            })
+            .count();
+
+        Self {
+            rank: entry.map(|e| e.rank),
+            points: entry.map_or(Points::ZERO, |e| e.points),
+            last_race: last_race.map(|e| e.name.clone()),
+            last_race_points: last_race.map(|e| e.points.unwrap_or_default()),
+            submitted,
+            p1_streak,
+        }
+    }
+}
+
+/// How a driver was picked by the players and how they actually finished in a season.
+#[derive(Serialize)]
+pub struct DriverStats {
+    pub driver: Driver,
+    pub season: Option<u16>,
+    /// Number of guesses picking the driver in each position, from P1 to P5.
+    pub picks: [usize; 5],
+    /// Number of results with the driver in each position, from P1 to P5.
+    pub finishes: [usize; 5],
+    pub top_five_count: usize,
+    pub results_count: usize,
+    /// Players who picked the driver the most, with the number of guesses picking them.
+    pub top_pickers: Vec<(String, usize)>,
+}
+
+impl DriverStats {
+    const TOP_PICKERS: usize = 5;
+
+    pub fn new(
+        driver: Driver,
+        season: Option<u16>,
+        guesses: &[Guess],
+        results: &[RaceResult],
+    ) -> Self {
+        let mut picks = [0; 5];
+        let mut finishes = [0; 5];
+
+        for guess in guesses {
+            if let Some(position) = guess.positions.iter().position(|code| *code == driver.code) {
+                picks[position] += 1;
+            }
+        }
+
+        for result in results {
+            if let Some(position) = [&result.p1, &result.p2, &result.p3, &result.p4, &result.p5]
+                .iter()
+                .position(|code| **code == driver.code)
+            {
+                finishes[position] += 1;
+            }
+        }
+
+        let top_pickers = guesses
+            .iter()
+            .filter(|g| g.positions.contains(&driver.code))
+            .map(|g| g.username.as_str())
+            .counts()
+            .into_iter()
+            .sorted_by_key(|(username, count)| (Reverse(*count), *username))
+            .take(Self::TOP_PICKERS)
+            .map(|(username, count)| (username.to_string(), count))
+            .collect();
+
+        Self {
+            driver,
+            season,
+            picks,
+            finishes,
+            top_five_count: finishes.iter().sum(),
+            results_count: results.len(),
+            top_pickers,
+        }
+    }
+}
+
+/// A number of points, serialized as a plain number. Adding points saturates at the maximum
+/// instead of overflowing, which only a pathological scoring configuration could reach.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+pub struct Points(u16);
+
+impl Points {
+    pub const ZERO: Self = Self(0);
+
+    pub fn get(self) -> u16 {
+        self.0
+    }
+
+    /// The points with their unit, like "25 pts" or "1 pt".
+    pub fn label(self) -> String {
+        match self.0 {
+            1 => "1 pt".to_string(),
+            points => format!("{points} pts"),
+        }
+    }
+
+    /// The points as a bonus awarded by a scoring rule, like "+4 parlay".
+    pub fn bonus(self, rule: &str) -> String {
+        format!("+{} {rule}", self.0)
+    }
+}
+
+impl fmt::Display for Points {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<u16> for Points {
+    fn from(points: u16) -> Self {
+        Self(points)
+    }
+}
+
+impl PartialEq<u16> for Points {
+    fn eq(&self, other: &u16) -> bool {
+        self.0 == *other
+    }
+}
+
+impl std::ops::Add for Points {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        match self.0.checked_add(other.0) {
+            Some(points) => Self(points),
+            None => {
+                warn!(
+                    "Points overflowed adding {self} and {other}, keeping {}.",
+                    u16::MAX
+                );
+                Self(u16::MAX)
+            }
+        }
+    }
+}
+
+impl std::ops::Mul<u16> for Points {
+    type Output = Self;
+
+    fn mul(self, multiplier: u16) -> Self {
+        match self.0.checked_mul(multiplier) {
+            Some(points) => Self(points),
+            None => {
+                warn!(
+                    "Points overflowed multiplying {self} by {multiplier}, keeping {}.",
+                    u16::MAX
+                );
+                Self(u16::MAX)
+            }
+        }
+    }
+}
+
+impl std::ops::AddAssign for Points {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl std::iter::Sum for Points {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::ZERO, |total, points| total + points)
+    }
+}
+
+impl<'a> std::iter::Sum<&'a Points> for Points {
+    fn sum<I: Iterator<Item = &'a Self>>(iter: I) -> Self {
+        iter.copied().sum()
+    }
+}
+
+/// Points of a guess split by the scoring rule that awarded them.
+#[derive(Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ScoreBreakdown {
+    pub podium: Points,
+    pub top_five: Points,
+    pub wrong_place: Points,
+    /// Consolation points for picks finishing from P6 to P10, when the scoring awards any.
+    pub top_ten: Points,
+    pub parlay: Points,
+}
+
+impl ScoreBreakdown {
+    pub fn total(&self) -> Points {
+        [
+            self.podium,
+            self.top_five,
+            self.wrong_place,
+            self.top_ten,
+            self.parlay,
+        ]
+        .into_iter()
+        .sum()
+    }
+
+    /// The points of every rule counted `multiplier` times, for races worth more than others.
+    pub fn times(self, multiplier: u16) -> Self {
+        Self {
+            podium: self.podium * multiplier,
+            top_five: self.top_five * multiplier,
+            wrong_place: self.wrong_place * multiplier,
+            top_ten: self.top_ten * multiplier,
+            parlay: self.parlay * multiplier,
+        }
+    }
+
+    /// Describes the rules that awarded points, like "+9 podium" or "+4 parlay".
+    pub fn bonuses(&self) -> Vec<String> {
+        [
+            (self.podium, "podium"),
+            (self.top_five, "top five"),
+            (self.wrong_place, "wrong place"),
+            (self.top_ten, "top ten"),
+            (self.parlay, "parlay"),
+        ]
+        .into_iter()
+        .filter(|(points, _)| *points > Points::ZERO)
+        .map(|(points, rule)| points.bonus(rule))
+        .collect()
+    }
+}
+
+/// Like `ScoredGuess`, but owning its guess, so it can outlive the guesses it was scored from.
+#[derive(Clone, Serialize)]
+pub struct ScoredGuessOwned {
+    pub guess: Guess,
+    pub points: Points,
+    pub breakdown: ScoreBreakdown,
+    /// The result of the race, or `None` while it's pending.
+    pub result: Option<RaceResult>,
+    /// Whether the picks are shown as `HIDDEN_PICK`, until the deadline of the event.
+    pub hidden: bool,
+    /// Whether no event matches the race, so the guess can't be sorted by date and comes last.
+    pub undated: bool,
+}
+
+impl RaceDated for ScoredGuessOwned {
+    fn race(&self) -> &str {
+        &self.guess.race
+    }
+
+    fn set_undated(&mut self, undated: bool) {
+        self.undated = undated;
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiScoredGuess {
+    pub guess: Guess,
+    pub points: Points,
+}
+
+/// The driver picked by most players for each position of a race, ties going to the first code
+/// alphabetically.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct CrowdGuess {
+    pub p1_popular: String,
+    pub p2_popular: String,
+    pub p3_popular: String,
+    pub p4_popular: String,
+    pub p5_popular: String,
+    /// Average over the positions of the share of players who picked the popular driver.
+    pub consensus_score: f32,
+}
+
+/// How often the picks of a user for one position were right, right but in another position of
+/// the top 5, or missed altogether. The rates are 0 while no guess was scored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct PositionAccuracy {
+    pub position: u8,
+    pub correct_rate: f32,
+    pub wrong_place_rate: f32,
+    pub miss_rate: f32,
+}
+
+/// The player closest in points to a user on the all-time leaderboard.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RivalInfo {
+    pub username: String,
+    pub points: Points,
+    /// Points of the rival minus those of the user, negative when the rival is behind.
+    pub points_difference: i32,
+}
+
+/// A leaderboard row with the guess of the user for the upcoming event, if any.
+#[derive(Serialize)]
+pub struct ApiLeaderboardEntry {
+    pub rank: usize,
+    pub username: String,
+    pub points: Points,
+    pub current_guess: Option<Guess>,
+}
+
+/// A ranked row of the leaderboard.
+#[derive(Serialize)]
+pub struct LeaderboardEntry {
+    pub rank: usize,
+    pub username: String,
+    /// Username followed by the country flag and preferred driver of the user.
+    pub name: String,
+    pub points: Points,
+    /// Races where nobody scored more than the user.
+    pub wins: u32,
+    /// Whether this is the row of the user viewing the leaderboard.
+    pub current_user: bool,
+    pub avatar: String,
+    /// Positions gained since before the latest scored race, negative when positions were lost.
+    pub rank_change: i32,
+    /// Whether the user wasn't on the leaderboard before the latest scored race.
+    pub is_new: bool,
+}
+
+impl LeaderboardEntry {
+    /// Ranks a leaderboard sorted by points, marking the row of `username` if given.
+    /// Avatars are looked up by lowercase username, falling back to the default one.
+    pub fn rank(
+        leaderboard: Vec<(String, Points, u32)>,
+        username: Option<&str>,
+        avatars: &HashMap<String, String>,
+    ) -> Vec<Self> {
+        leaderboard
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, points, wins))| {
+                let name_username = Self::username(&name);
+
+                Self {
+                    rank: index + 1,
+                    current_user: username
+                        .is_some_and(|username| name_username.eq_ignore_ascii_case(username)),
+                    avatar: avatars
+                        .get(&name_username)
+                        .map_or(DEFAULT_AVATAR.to_string(), String::clone),
+                    username: name_username,
+                    name,
+                    points,
+                    wins,
+                    rank_change: 0,
+                    is_new: false,
+                }
+            })
+            .collect()
+    }
+
+    /// Compares the ranks with a previous leaderboard sorted by points, where players missing
+    /// from it are new.
+    pub fn track_changes(entries: &mut [Self], previous: &[(String, Points, u32)]) {
+        let previous_ranks: HashMap<String, usize> = previous
+            .iter()
+            .enumerate()
+            .map(|(index, (name, ..))| (Self::username(name), index + 1))
+            .collect();
+
+        for entry in entries {
+            match previous_ranks.get(&Self::username(&entry.name)) {
+                Some(&previous_rank) => {
+                    entry.rank_change = previous_rank as i32 - entry.rank as i32
+                }
+                None => entry.is_new = true,
+            }
+        }
+    }
+
+    /// Whether the username a leaderboard name starts with contains `query`, ignoring case.
+    /// An empty query matches every name.
+    pub fn name_matches(name: &str, query: &str) -> bool {
+        Self::username(name).contains(&query.trim().to_lowercase())
+    }
+
+    /// The lowercase username a leaderboard name starts with, which can't contain spaces.
+    pub fn username(name: &str) -> String {
+        name.split(' ').next().unwrap_or_default().to_lowercase()
+    }
+}
+
+/// A ranked row of the leaderboard as served by the API.
+#[derive(Serialize)]
+pub struct LeaderboardRow {
+    pub rank: usize,
+    pub username: String,
+    pub points: Points,
+    pub wins: u32,
+    /// Username followed by the country flag and preferred driver, shown in the text format.
+    #[serde(skip)]
+    pub name: String,
+}
+
+impl LeaderboardRow {
+    /// Ranks a leaderboard in the order it's sorted, by points or by wins.
+    pub fn rank(leaderboard: Vec<(String, Points, u32)>) -> Vec<Self> {
+        leaderboard
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, points, wins))| Self {
+                rank: index + 1,
+                username: LeaderboardEntry::username(&name),
+                points,
+                wins,
+                name,
+            })
+            .collect()
+    }
+
+    /// Formats the row as a line of the text format, or as a short item of the IRC format, where
+    /// the username is cut to a 3 letter code.
+    pub fn format(&self, irc: bool) -> String {
+        if irc {
+            let code: String = self
+                .username
+                .chars()
+                .filter(|c| c.is_alphanumeric())
+                .take(3)
+                .collect();
+
+            format!("{}. {} {}", self.rank, code.to_uppercase(), self.points)
+        } else {
+            format!("{}. {} {}", self.rank, self.name, self.points)
+        }
+    }
+
+    /// Writes the rows separated by `delimiter`, with a header naming the columns. Fields
+    /// containing the delimiter or quotes are quoted as in RFC 4180.
+    pub fn to_delimited(rows: &[Self], delimiter: u8) -> Result<String, csv::Error> {
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(true)
+            .delimiter(delimiter)
+            .from_writer(Vec::new());
+
+        // The header is only written along with the first row, so an empty leaderboard needs it.
+        if rows.is_empty() {
+            writer.write_record(["rank", "username", "points", "wins"])?;
+        }
+
+        for row in rows {
+            writer.serialize(row)?;
+        }
+
+        let bytes = writer
+            .into_inner()
+            .map_err(|error| csv::Error::from(error.into_error()))?;
+
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+/// Points of every user in every race, where `scores[race_idx][user_idx]` is `None` when the user
+/// didn't guess that race.
+#[derive(Serialize)]
+pub struct ScoreMatrix {
+    pub races: Vec<String>,
+    pub users: Vec<String>,
+    pub scores: Vec<Vec<Option<Points>>>,
+}
+
+/// A user as stored, including their session token and password hash. It is only serialized to be
+/// written to the database: anything shown or returned to clients goes through [`PublicUser`].
+#[derive(Default, Deserialize, PartialEq, Serialize)]
+pub struct User {
+    pub token: String,
+    pub username: String,
+    pub password: String,
+    pub country: String,
+    #[serde(default)]
+    pub preferred_driver: Option<String>,
+    /// IANA time zone the user wants event times shown in, UTC when empty.
+    #[serde(default)]
+    pub timezone: String,
+    /// URL of the uploaded avatar, if any.
+    #[serde(default)]
+    pub avatar: Option<String>,
+    #[serde(default)]
+    pub dark_mode: bool,
+    #[serde(default)]
+    pub compact_tables: bool,
+    /// Incremented on every update, so an update based on an outdated profile can be rejected.
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl User {
+    pub fn ui_prefs(&self) -> UiPrefs {
+        UiPrefs {
+            dark_mode: self.dark_mode,
+            compact_tables: self.compact_tables,
+        }
+    }
+
+    pub fn set_ui_prefs(&mut self, prefs: UiPrefs) {
+        self.dark_mode = prefs.dark_mode;
+        self.compact_tables = prefs.compact_tables;
+    }
+
+    pub fn avatar_url(&self) -> &str {
+        self.avatar.as_deref().unwrap_or(DEFAULT_AVATAR)
+    }
+}
+
+/// A single use code letting someone register while registration is invite only.
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+pub struct InviteCode {
+    pub code: String,
+    /// Admin who created the code.
+    pub created_by: String,
+    pub used: bool,
+    pub used_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A country users can pick, stored by its ISO 3166-1 alpha-2 code.
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+pub struct Country {
+    pub code: String,
+    pub name: String,
+    #[serde(default)]
+    pub flag: String,
+}
+
+impl Country {
+    /// The countries the collection is seeded with, in the order they're listed.
+    const SEED: &str = include_str!("countries.csv");
+
+    pub fn seed() -> Result<Vec<Self>, csv::Error> {
+        csv::Reader::from_reader(Self::SEED.as_bytes())
+            .deserialize()
+            .map(|country| {
+                country.map(|country: Self| Self {
+                    flag: code_to_flag(&country.code).unwrap_or_default(),
+                    ..country
+                })
+            })
+            .collect()
+    }
+
+    /// Whether `code` is the code of one of the countries, ignoring case.
+    pub fn is_known(countries: &[Self], code: &str) -> bool {
+        countries.iter().any(|c| c.code.eq_ignore_ascii_case(code))
+    }
+}
+
+pub const AVATAR_DIR: &str = "static/avatars";
+pub const DEFAULT_AVATAR: &str = "/static/avatars/default.svg";
+pub const MAX_AVATAR_SIZE: u64 = 200 * 1024;
+
+/// Detects the image format of an avatar from its first bytes, regardless of the file name or the
+/// content type claimed by the browser. Only PNG, JPEG and WebP images are accepted.
+pub fn avatar_extension(header: &[u8]) -> Option<&'static str> {
+    match header {
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some("png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("jpg"),
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'W',
+            b'E',
+            b'B',
+            b'P',
+            ..,
+        ] => Some("webp"),
+        _ => None,
+    }
+}
+
+/// Name of an avatar file of a user, unique to the time it was uploaded, so a new avatar never
+/// overwrites the one still in use. Usernames aren't restricted on registration, so names that
+/// could escape the avatars directory get no file name at all.
+pub fn avatar_file_name(
+    username: &str,
+    uploaded_at: DateTime<Utc>,
+    extension: &str,
+) -> Option<String> {
+    let safe = !username.is_empty()
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    safe.then(|| {
+        format!(
+            "{}-{}.{extension}",
+            username.to_lowercase(),
+            uploaded_at.timestamp_millis()
+        )
+    })
+}
+
+/// Path of the file of an uploaded avatar, or `None` for any other URL, such as the default avatar.
+pub fn avatar_path(url: &str) -> Option<&str> {
+    url.strip_prefix('/')
+        .filter(|path| path.starts_with(AVATAR_DIR) && url != DEFAULT_AVATAR)
+}
+
+/// The subset of a user that is safe to expose publicly.
+#[derive(Serialize)]
+pub struct PublicUser {
+    pub username: String,
+    pub country: String,
+    pub preferred_driver: Option<String>,
+}
+
+impl From<&User> for PublicUser {
+    fn from(user: &User) -> Self {
+        Self {
+            username: user.username.clone(),
+            country: user.country.clone(),
+            preferred_driver: user.preferred_driver.clone(),
+        }
+    }
+}
+
+/// Replaces the password hash of a user in their data export.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// A user as included in their data export, without the token and with the password redacted.
+#[derive(Serialize)]
+pub struct ExportedUser {
+    pub username: String,
+    pub password: &'static str,
+    pub country: String,
+    pub preferred_driver: Option<String>,
+    pub timezone: String,
+    pub avatar: Option<String>,
+    pub dark_mode: bool,
+    pub compact_tables: bool,
+}
+
+impl From<User> for ExportedUser {
+    fn from(user: User) -> Self {
+        Self {
+            username: user.username,
+            password: REDACTED,
+            country: user.country,
+            preferred_driver: user.preferred_driver,
+            timezone: user.timezone,
+            avatar: user.avatar,
+            dark_mode: user.dark_mode,
+            compact_tables: user.compact_tables,
+        }
+    }
+}
+
+/// Everything stored about a user, for them to download.
+#[derive(Serialize)]
+pub struct UserDataExport {
+    pub user: ExportedUser,
+    pub guesses: Vec<Guess>,
+    pub constructor_guesses: Vec<ConstructorGuess>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let cookies = req.cookies();
+        let store = match req.guard::<&State<Store>>().await {
+            Outcome::Success(store) => store,
+            _ => {
+                return Outcome::Error((Status::InternalServerError, "Could not access database."));
+            }
+        };
+
+        // The session cookie is decrypted by get_private, whereas the API key is used as is.
+        let session = cookies.get_private("session");
+        let credential = match &session {
+            Some(token) => token.value().to_owned(),
+            None => match req.headers().get_one("x-api-key") {
+                Some(key) => key.to_owned(),
+                None => return Outcome::Error((Status::Unauthorized, "Unauthorized")),
+            },
+        };
+
+        match store.authenticate(&credential).await {
+            Some(user) => Outcome::Success(user),
+            None if session.is_some() => Outcome::Forward(Status::Unauthorized),
+            None => Outcome::Error((Status::Unauthorized, "Unauthorized")),
+        }
+    }
+}
+
+/// A user listed as an admin in the configuration.
+///
+/// Anonymous requests are forwarded like with `User`, so they end up on the login page, while
+/// authenticated users who aren't admins get a 403.
+pub struct Admin(pub User);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = match req.guard::<&State<Config>>().await {
+            Outcome::Success(config) => config,
+            _ => {
+                return Outcome::Error((
+                    Status::InternalServerError,
+                    "Could not access configuration.",
+                ));
+            }
+        };
+
+        match req.guard::<User>().await {
+            Outcome::Success(user) if config.is_admin(&user.username) => {
+                Outcome::Success(Admin(user))
+            }
+            Outcome::Success(_) => Outcome::Error((Status::Forbidden, "Forbidden")),
+            Outcome::Forward(status) => Outcome::Forward(status),
+            Outcome::Error(error) => Outcome::Error(error),
+        }
+    }
+}
+
+/// How pages are displayed, shared by every template through the base layout.
+///
+/// The preferences of users are stored with them and copied to a cookie when they login or change
+/// them, so every device they use gets them. Anonymous visitors only have the cookie.
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Serialize)]
+pub struct UiPrefs {
+    pub dark_mode: bool,
+    pub compact_tables: bool,
+}
+
+impl UiPrefs {
+    pub const COOKIE: &str = "ui_prefs";
+
+    /// Parses the value of the preferences cookie, falling back to the defaults when it's invalid.
+    pub fn from_cookie(value: &str) -> Self {
+        rocket::serde::json::from_str(value).unwrap_or_default()
+    }
+
+    pub fn from_cookies(cookies: &CookieJar<'_>) -> Self {
+        cookies
+            .get(Self::COOKIE)
+            .map(|cookie| Self::from_cookie(cookie.value()))
+            .unwrap_or_default()
+    }
+
+    pub fn to_cookie(self) -> String {
+        rocket::serde::json::to_string(&self).unwrap_or_default()
+    }
+}
+
+/// The preferences toggled from any page, returning to `next` afterwards.
+#[derive(FromForm)]
+pub struct PrefsForm {
+    #[field(name = "_csrf", default = String::new())]
+    pub csrf: String,
+    pub dark_mode: bool,
+    pub compact_tables: bool,
+    pub next: Option<String>,
+}
+
+impl From<&PrefsForm> for UiPrefs {
+    fn from(form: &PrefsForm) -> Self {
+        Self {
+            dark_mode: form.dark_mode,
+            compact_tables: form.compact_tables,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UiPrefs {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self::from_cookies(req.cookies()))
+    }
+}
+
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+pub struct Driver {
+    pub number: u8,
+    pub code: DriverCode,
+    pub name: String,
+    #[serde(default)]
+    pub category: String,
+    #[serde(default = "Driver::active_by_default")]
+    pub active: bool,
+    #[serde(default)]
+    pub team: String,
+    /// Colour of the team as `#rrggbb`, or empty for none.
+    #[serde(default)]
+    pub colour: String,
+}
+
+/// A driver code replaced by another one from a given date, such as after a number change. Guesses
+/// and results of races before that date keep using the old code.
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+pub struct DriverAlias {
+    pub old_code: DriverCode,
+    pub new_code: DriverCode,
+    pub effective_from: DateTime<Utc>,
+}
+
+impl DriverAlias {
+    /// The code a driver went by at `race_datetime`, given either its old or its new code.
+    /// Successive renames are followed, so every code of a driver resolves to the same one.
+    pub fn resolve(
+        aliases: &[Self],
+        code: &DriverCode,
+        race_datetime: DateTime<Utc>,
+    ) -> DriverCode {
+        let mut code = code.clone();
+
+        for alias in aliases.iter().sorted_by_key(|a| a.effective_from) {
+            if race_datetime >= alias.effective_from && code == alias.old_code {
+                code = alias.new_code.clone();
+            }
+        }
+
+        for alias in aliases.iter().sorted_by_key(|a| Reverse(a.effective_from)) {
+            if race_datetime < alias.effective_from && code == alias.new_code {
+                code = alias.old_code.clone();
+            }
+        }
+
+        code
+    }
+}
+
+/// Why a driver can't be stored, naming the driver already using its code or number.
+#[derive(Debug, PartialEq)]
+pub enum DriverConflict {
+    Code { code: DriverCode, name: String },
+    Number { number: u8, name: String },
+}
+
+impl fmt::Display for DriverConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Code { code, name } => write!(f, "code {code} already used by {name}"),
+            Self::Number { number, name } => write!(f, "number {number} already used by {name}"),
+        }
+    }
+}
+
+/// The drivers of a team, shown together on the play form.
+#[derive(Serialize)]
+pub struct TeamDrivers {
+    pub team: String,
+    pub colour: String,
+    pub drivers: Vec<Driver>,
+}
+
+impl Driver {
+    fn active_by_default() -> bool {
+        true
+    }
+
+    pub fn normalize(&mut self) {
+        self.name = self.name.trim().to_string();
+        self.category = self.category.trim().to_string();
+        self.team = self.team.trim().to_string();
+        self.colour = self.colour.trim().to_lowercase();
+    }
+
+    /// The active driver other than `exclude_code` racing with `number`, if any. Inactive drivers
+    /// don't count, so a reserve driver can take over the number of the driver they replace.
+    pub fn number_taken_by<'d>(
+        drivers: &'d [Driver],
+        number: u8,
+        exclude_code: Option<&DriverCode>,
+    ) -> Option<&'d Driver> {
+        drivers.iter().find(|d| {
+            d.active && d.number == number && exclude_code.is_none_or(|code| d.code != *code)
+        })
+    }
+
+    /// The first of `drivers`, other than `exclude_code`, this driver can't be stored alongside:
+    /// one with the same code, or an active one with the same number.
+    pub fn conflict(
+        &self,
+        drivers: &[Driver],
+        exclude_code: Option<&DriverCode>,
+    ) -> Option<DriverConflict> {
+        if let Some(other) = drivers
+            .iter()
+            .filter(|d| exclude_code.is_none_or(|code| d.code != *code))
+            .find(|d| d.code == self.code)
+        {
+            return Some(DriverConflict::Code {
+                code: other.code.clone(),
+                name: other.name.clone(),
+            });
+        }
+
+        Self::number_taken_by(drivers, self.number, exclude_code).map(|other| {
+            DriverConflict::Number {
+                number: other.number,
+                name: other.name.clone(),
+            }
+        })
+    }
+
+    /// Checks that the name is not empty and the colour, if any, is written as `#rrggbb`.
+    pub fn valid(&self) -> bool {
+        !self.name.is_empty()
+            && (self.colour.is_empty()
+                || self.colour.len() == 7
+                    && self
+                        .colour
+                        .strip_prefix('#')
+                        .is_some_and(|hex| hex.chars().all(|c| c.is_ascii_hexdigit())))
+    }
+
+    /// Groups drivers by team, ordering teams and their drivers by name. Drivers without a team
+    /// come last. The colour of a team is the first one set on any of its drivers.
+    pub fn by_team(drivers: Vec<Driver>) -> Vec<TeamDrivers> {
+        drivers
+            .into_iter()
+            .into_group_map_by(|d| d.team.clone())
+            .into_iter()
+            .sorted_by_key(|(team, _)| (team.is_empty(), team.to_lowercase()))
+            .map(|(team, drivers)| TeamDrivers {
+                colour: drivers
+                    .iter()
+                    .map(|d| d.colour.clone())
+                    .find(|colour| !colour.is_empty())
+                    .unwrap_or_default(),
+                drivers: drivers
+                    .into_iter()
+                    .sorted_by(|a, b| a.name.cmp(&b.name))
+                    .collect(),
+                team,
+            })
+            .collect()
+    }
+}
+
+#[derive(Default, FromForm, Serialize)]
+pub struct DriverForm {
+    pub original_code: String,
+    pub number: String,
+    pub code: String,
+    pub name: String,
+    pub category: String,
+    pub active: bool,
+    pub team: String,
+    pub colour: String,
+}
+
+impl From<&Driver> for DriverForm {
+    fn from(driver: &Driver) -> Self {
+        Self {
+            original_code: driver.code.to_string(),
+            number: driver.number.to_string(),
+            code: driver.code.to_string(),
+            name: driver.name.clone(),
+            category: driver.category.clone(),
+            active: driver.active,
+            team: driver.team.clone(),
+            colour: driver.colour.clone(),
+        }
+    }
+}
+
+#[derive(FromForm)]
+pub struct DriverKey {
+    pub code: DriverCode,
+}
+
+/// Events are also written by another tool, which can leave out `tags` and `notify` and writes
+/// datetimes without seconds or time zone, which are taken as UTC.
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+pub struct Event {
+    pub category: String,
+    pub name: String,
+    pub description: String,
+    #[serde(deserialize_with = "lenient_datetime")]
+    pub datetime: DateTime<Utc>,
+    pub channel: String,
+    #[serde(default, deserialize_with = "empty_as_default")]
+    pub tags: String,
+    #[serde(default, deserialize_with = "empty_as_default")]
+    pub notify: bool,
+    /// Slug of the name the event was created with, which guesses and results refer to. It's kept
+    /// when the event is renamed, and shared by the sessions of a weekend.
+    #[serde(default)]
+    pub id: String,
+}
+
+/// Accepts the datetimes chrono parses, like RFC 3339, as well as `YYYY-MM-DD HH:MM` in UTC.
+fn lenient_datetime<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<DateTime<Utc>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    let value = value.trim();
+
+    value
+        .parse::<DateTime<Utc>>()
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M").map(|n| n.and_utc()))
+        .map_err(|_| serde::de::Error::custom(format!("invalid datetime \"{value}\"")))
+}
+
+/// Reads an empty field as the default value rather than failing.
+fn empty_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(FromForm)]
+pub struct EventKey {
+    pub name: String,
+    pub description: String,
+}
+
+impl From<&Event> for EventForm {
+    fn from(event: &Event) -> Self {
+        Self {
+            original_name: event.name.clone(),
+            original_description: event.description.clone(),
+            category: event.category.clone(),
+            name: event.name.clone(),
+            description: event.description.clone(),
+            datetime: event.datetime.format("%Y-%m-%dT%H:%M").to_string(),
+            timezone: "UTC".to_string(),
+            channel: event.channel.clone(),
+            tags: event.tags.clone(),
+            notify: event.notify,
+        }
+    }
+}
+
+impl Event {
+    /// Marks an event that isn't played, like a test session sharing the channel of the races.
+    pub const NO_GAME_TAG: &str = "no-game";
+    /// Marks a race whose points count `double_points_multiplier` times.
+    pub const DOUBLE_POINTS_TAG: &str = "double-points";
+    /// Marks a sprint race, whose points count `sprint_multiplier` times.
+    pub const SPRINT_TAG: &str = "sprint";
+
+    /// Whether the event has a tag, ignoring case. Tags are separated by semicolons, and events
+    /// tagged before that convention separate them by spaces.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags
+            .split(|c: char| c == ';' || c.is_whitespace())
+            .any(|t| !t.is_empty() && t.eq_ignore_ascii_case(tag))
+    }
+
+    /// The categories of the event, separated by semicolons and optionally wrapped in brackets,
+    /// like "[Formula 1; Formula 2]".
+    pub fn categories(&self) -> Vec<&str> {
+        self.category
+            .split(';')
+            .map(|c| c.trim().trim_matches(['[', ']']).trim())
+            .filter(|c| !c.is_empty())
+            .collect()
+    }
+
+    pub fn in_category(&self, category: &str) -> bool {
+        self.categories()
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(category))
+    }
+
+    /// Turns a name into an id, lowercasing it and joining its words with dashes, like
+    /// "bahrain-grand-prix".
+    pub fn slug(name: &str) -> String {
+        name.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .join("-")
+    }
+
+    /// Gives an event stored without an id, by the other tool or before ids existed, the slug of
+    /// its name.
+    pub fn fill_id(&mut self) {
+        if self.id.is_empty() {
+            self.id = Self::slug(&self.name);
+        }
+    }
+
+    /// The slug of `name`, numbered from 2 when an event under another name already uses it, like a
+    /// renamed one, so a new event never inherits the guesses and results of another. Sessions of
+    /// a weekend still share their id.
+    pub fn unique_id(name: &str, events: &[Event]) -> String {
+        let slug = Self::slug(name);
+        let taken = |id: &str| {
+            events.iter().any(|e| {
+                let e_id = if e.id.is_empty() {
+                    Self::slug(&e.name)
+                } else {
+                    e.id.clone()
+                };
+
+                e_id == id && !e.name.eq_ignore_ascii_case(name)
+            })
+        };
+
+        (1..)
+            .map(|n| {
+                if n == 1 {
+                    slug.clone()
+                } else {
+                    format!("{slug}-{n}")
+                }
+            })
+            .find(|id| !taken(id))
+            .unwrap_or(slug)
+    }
+
+    /// Events are identified by their name and description, since every session of a weekend
+    /// shares the same name.
+    pub fn is(&self, name: &str, description: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) && self.description.eq_ignore_ascii_case(description)
+    }
+
+    /// Converts the value of a `datetime-local` input, in the given IANA time zone, to UTC.
+    pub fn parse_local_datetime(value: &str, timezone: &str) -> Option<DateTime<Utc>> {
+        let timezone: Tz = timezone.parse().ok()?;
+        let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M").ok()?;
+
+        Some(timezone.from_local_datetime(&naive).single()?.to_utc())
+    }
+
+    /// Formats the start of the event in an IANA time zone, falling back to UTC when the time
+    /// zone is empty or unknown.
+    pub fn local_datetime(&self, timezone: &str) -> String {
+        let timezone: Tz = timezone.parse().unwrap_or(Tz::UTC);
+
+        self.datetime
+            .with_timezone(&timezone)
+            .format("%Y-%m-%d %H:%M %Z")
+            .to_string()
+    }
+}
+
+#[derive(Default, FromForm, Serialize)]
+pub struct EventForm {
+    /// Name and description of the event being edited, empty when creating a new one.
+    pub original_name: String,
+    pub original_description: String,
+    pub category: String,
+    pub name: String,
+    pub description: String,
+    pub datetime: String,
+    pub timezone: String,
+    pub channel: String,
+    pub tags: String,
+    pub notify: bool,
+}
+
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+pub struct RaceResult {
+    pub race: String,
+    pub p1: DriverCode,
+    pub p2: DriverCode,
+    pub p3: DriverCode,
+    pub p4: DriverCode,
+    pub p5: DriverCode,
+    /// The rest of the top 10, `None` when not entered.
+    #[serde(default)]
+    pub p6: Option<DriverCode>,
+    #[serde(default)]
+    pub p7: Option<DriverCode>,
+    #[serde(default)]
+    pub p8: Option<DriverCode>,
+    #[serde(default)]
+    pub p9: Option<DriverCode>,
+    #[serde(default)]
+    pub p10: Option<DriverCode>,
+    #[serde(default)]
+    pub c1: Option<String>,
+    #[serde(default)]
+    pub c2: Option<String>,
+    /// When the result was last entered, unknown for results stored before this was tracked.
+    #[serde(default)]
+    pub entered_at: Option<DateTime<Utc>>,
+    /// Id of the event of the race, like `Guess::race_id`.
+    #[serde(default)]
+    pub race_id: String,
+}
+
+/// A result as entered on the admin form, before its codes are parsed. It's also rendered back into
+/// the form, to enter a new result or correct one that can't be parsed.
+#[derive(Default, FromForm, Serialize)]
+pub struct RaceResultForm {
+    pub race: String,
+    pub p1: String,
+    pub p2: String,
+    pub p3: String,
+    pub p4: String,
+    pub p5: String,
+    #[field(default = String::new())]
+    pub p6: String,
+    #[field(default = String::new())]
+    pub p7: String,
+    #[field(default = String::new())]
+    pub p8: String,
+    #[field(default = String::new())]
+    pub p9: String,
+    #[field(default = String::new())]
+    pub p10: String,
+    pub c1: Option<String>,
+    pub c2: Option<String>,
+}
+
+impl TryFrom<&RaceResultForm> for RaceResult {
+    type Error = GuessValidationError;
+
+    fn try_from(form: &RaceResultForm) -> Result<Self, Self::Error> {
+        let [p1, p2, p3, p4, p5] =
+            parse_picks([&form.p1, &form.p2, &form.p3, &form.p4, &form.p5].map(String::as_str))?;
+        // The rest of the top 10 is optional, so only the positions entered are parsed.
+        let top_ten = |position, pick: &str| {
+            (!pick.trim().is_empty())
+                .then(|| parse_pick(position, pick))
+                .transpose()
+        };
+
+        Ok(Self {
+            race: form.race.clone(),
+            p1,
+            p2,
+            p3,
+            p4,
+            p5,
+            p6: top_ten(6, &form.p6)?,
+            p7: top_ten(7, &form.p7)?,
+            p8: top_ten(8, &form.p8)?,
+            p9: top_ten(9, &form.p9)?,
+            p10: top_ten(10, &form.p10)?,
+            c1: form.c1.clone(),
+            c2: form.c2.clone(),
+            entered_at: None,
+            race_id: String::new(),
+        })
+    }
+}
+
+impl From<&RaceResult> for RaceResultForm {
+    fn from(result: &RaceResult) -> Self {
+        let code = |code: &Option<DriverCode>| {
+            code.as_ref().map(DriverCode::to_string).unwrap_or_default()
+        };
+
+        Self {
+            race: result.race.clone(),
+            p1: result.p1.to_string(),
+            p2: result.p2.to_string(),
+            p3: result.p3.to_string(),
+            p4: result.p4.to_string(),
+            p5: result.p5.to_string(),
+            p6: code(&result.p6),
+            p7: code(&result.p7),
+            p8: code(&result.p8),
+            p9: code(&result.p9),
+            p10: code(&result.p10),
+            c1: result.c1.clone(),
+            c2: result.c2.clone(),
+        }
+    }
+}
+
+/// Describes how long ago `then` was, relative to `now`, such as "2 hours ago".
+pub fn time_ago(then: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let elapsed = now - then;
+
+    let (amount, unit) = if elapsed.num_days() > 0 {
+        (elapsed.num_days(), "day")
+    } else if elapsed.num_hours() > 0 {
+        (elapsed.num_hours(), "hour")
+    } else if elapsed.num_minutes() > 0 {
+        (elapsed.num_minutes(), "minute")
+    } else {
+        return "just now".to_string();
+    };
+
+    if amount == 1 {
+        format!("1 {unit} ago")
+    } else {
+        format!("{amount} {unit}s ago")
+    }
+}
+
+/// Uppercases a race name, trimming it and collapsing any runs of whitespace inside it.
+fn normalize_race_name(race: &str) -> String {
+    race.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_uppercase()
+}
+
+impl RaceResult {
+    /// What the result is keyed by in the normalized results: the id of its event, or its name
+    /// when it has none.
+    pub fn key(&self) -> &str {
+        if self.race_id.is_empty() {
+            &self.race
+        } else {
+            &self.race_id
+        }
+    }
+
+    /// Looks a result up in the normalized results by the id of its event, falling back to the
+    /// race name for rows stored before events had ids.
+    pub fn find<'r>(
+        results: &'r HashMap<String, RaceResult>,
+        race_id: &str,
+        race: &str,
+    ) -> Option<&'r RaceResult> {
+        (!race_id.is_empty())
+            .then(|| results.get(race_id))
+            .flatten()
+            .or_else(|| results.get(race))
+            .or_else(|| results.values().find(|r| r.race.eq_ignore_ascii_case(race)))
+    }
+
+    /// Driver codes are normalized when parsed, leaving the race and constructors.
+    pub fn normalize(&mut self) {
+        self.race = normalize_race_name(&self.race);
+
+        self.c1 = self
+            .c1
+            .take()
+            .map(|c| c.trim().to_uppercase())
+            .filter(|c| !c.is_empty());
+        self.c2 = self
+            .c2
+            .take()
+            .map(|c| c.trim().to_uppercase())
+            .filter(|c| !c.is_empty());
+    }
+
+    /// The drivers classified from P1 to P5.
+    pub fn top_five(&self) -> [&DriverCode; 5] {
+        [&self.p1, &self.p2, &self.p3, &self.p4, &self.p5]
+    }
+
+    /// The drivers classified from P6 to P10, leaving out the positions not entered.
+    pub fn top_ten(&self) -> impl Iterator<Item = &DriverCode> {
+        [&self.p6, &self.p7, &self.p8, &self.p9, &self.p10]
+            .into_iter()
+            .flatten()
+    }
+
+    /// A result is valid when it contains 5 different known driver codes, and any of P6 to P10
+    /// entered are known drivers not classified elsewhere. Inactive drivers are accepted, since a
+    /// driver may have raced before being replaced.
+    pub fn valid(&self, drivers: &[Driver]) -> bool {
+        let guess = Guess {
+            race: self.race.clone(),
+            positions: self.top_five().map(DriverCode::clone).to_vec(),
+            ..Default::default()
+        };
+        let known = |code: &DriverCode| drivers.iter().any(|d| d.code == *code);
+
+        guess.valid(drivers).is_ok()
+            && self.top_ten().all(known)
+            && self
+                .top_five()
+                .into_iter()
+                .chain(self.top_ten())
+                .all_unique()
+    }
+}
+
+#[derive(Clone, Default, Deserialize, FromForm, PartialEq, Serialize)]
+pub struct ConstructorGuess {
+    pub race: String,
+    pub username: String,
+    pub c1: String,
+    pub c2: String,
+    /// Id of the event guessed, which outlives a rename of the race. Empty for guesses stored before
+    /// events had ids and not matching any event.
+    #[serde(default)]
+    #[field(default = String::new())]
+    pub race_id: String,
+}
+
+impl ConstructorGuess {
+    pub fn normalize(&mut self) {
+        self.race = self.race.to_uppercase();
+        self.username = self.username.to_lowercase();
+        self.c1 = self.c1.trim().to_uppercase();
+        self.c2 = self.c2.trim().to_uppercase();
+    }
+
+    pub fn valid(&self) -> bool {
+        !self.c1.is_empty() && !self.c2.is_empty() && self.c1 != self.c2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+    use csv_db::Database;
+    use rocket::{http::Header, local::asynchronous::Client};
+
+    fn driver_code(code: &str) -> DriverCode {
+        DriverCode::new(code).expect("The test driver code should be valid")
+    }
+
+    fn race_result(race: &str, top_five: [&str; 5]) -> RaceResult {
+        let [p1, p2, p3, p4, p5] = top_five.map(driver_code);
+
+        RaceResult {
+            race: race.to_string(),
+            p1,
+            p2,
+            p3,
+            p4,
+            p5,
+            p6: None,
+            p7: None,
+            p8: None,
+            p9: None,
+            p10: None,
+            c1: None,
+            c2: None,
+            entered_at: None,
+            race_id: String::new(),
+        }
+    }
+
+    #[get("/whoami")]
+    fn whoami(user: User) -> String {
+        user.username
+    }
+
+    async fn client(path: &'static str) -> Client {
+        let db = Database::new(path, None);
+
+        assert!(db.delete("users", |_: &&User| true).await.is_ok());
+        assert!(
+            db.insert(
+                "users",
+                User {
+                    token: "test-token".to_string(),
+                    username: "test".to_string(),
+                    password: "".to_string(),
+                    country: "PT".to_string(),
+                    preferred_driver: None,
+                    timezone: String::new(),
+                    avatar: None,
+                    ..Default::default()
+                },
+            )
+            .await
+            .is_ok()
+        );
+
+        let rocket = rocket::build()
+            .mount("/", routes![whoami])
+            .manage(Store::new(path));
+
+        Client::tracked(rocket)
+            .await
+            .expect("The test rocket instance should be valid")
+    }
+
+    #[rocket::async_test]
+    async fn authenticate_session_cookie() {
+        let client = client("test_data/authenticate_session_cookie/").await;
+
+        let response = client
+            .get("/whoami")
+            .private_cookie(("session", "test-token"))
+            .dispatch()
+            .await;
+
+        assert!(response.status() == Status::Ok);
+        assert!(response.into_string().await.unwrap_or_default() == "test");
+    }
+
+    #[rocket::async_test]
+    async fn authenticate_api_key() {
+        let client = client("test_data/authenticate_api_key/").await;
+
+        let response = client
+            .get("/whoami")
+            .header(Header::new("x-api-key", "test-token"))
+            .dispatch()
+            .await;
+
+        assert!(response.status() == Status::Ok);
+        assert!(response.into_string().await.unwrap_or_default() == "test");
+
+        let response = client
+            .get("/whoami")
+            .header(Header::new("x-api-key", "wrong-token"))
+            .dispatch()
+            .await;
+
+        assert!(response.status() == Status::Unauthorized);
+    }
+
+    #[test]
+    fn guess_changes_from() {
+        let previous = Guess::default();
+        let mut guess = previous.clone();
+
+        assert!(guess.changes_from(&previous).is_empty());
+
+        guess.positions.swap(1, 2);
+
+        assert!(guess.changes_from(&previous) == ["P2: VER → PIA", "P3: PIA → VER"]);
+
+        guess.positions[4] = driver_code(" lec");
+
+        assert!(guess.changes_from(&previous).len() == 2);
+    }
+
+    #[test]
+    fn guess_validation() {
+        let drivers: Vec<Driver> = ["NOR", "VER", "PIA", "RUS", "LEC"]
+            .into_iter()
+            .map(|code| Driver {
+                number: 1,
+                code: driver_code(code),
+                name: code.to_string(),
+                category: String::new(),
+                active: true,
+                team: String::new(),
+                colour: String::new(),
+            })
+            .collect();
+        let form = |picks: [&str; 5]| {
+            let [p1, p2, p3, p4, p5] = picks.map(String::from);
+
+            GuessForm {
+                p1,
+                p2,
+                p3,
+                p4,
+                p5,
+                ..Default::default()
+            }
+        };
+        let check = |picks: [&str; 5]| -> Result<(), GuessValidationError> {
+            Guess::try_from(&form(picks))?.valid(&drivers)
+        };
+
+        assert!(check(["NOR", "VER", "PIA", "RUS", "LEC"]).is_ok());
+        assert!(
+            check(["NOR", "VER", "", "RUS", "LEC"])
+                == Err(GuessValidationError::EmptyPosition { position: 3 })
+        );
+        assert!(
+            check(["NOR", "VER", "XXX", "RUS", "LEC"])
+                == Err(GuessValidationError::UnknownDriver {
+                    position: 3,
+                    code: "XXX".to_string()
+                })
+        );
+        assert!(
+            check(["NOR", "VER", "PIA", "ver", "LEC"])
+                == Err(GuessValidationError::DuplicateDriver {
+                    code: "VER".to_string()
+                })
+        );
+        assert!(
+            GuessValidationError::UnknownDriver {
+                position: 3,
+                code: "XXX".to_string()
+            }
+            .to_string()
+                == "P3 contains 'XXX' which is not a valid driver code."
+        );
+        assert!(
+            check(["NOR", "VE1", "PIA", "RUS", "LEC"])
+                == Err(GuessValidationError::InvalidCode {
+                    position: 2,
+                    code: "VE1".to_string()
+                })
+        );
+        assert!(
+            check(["NOR", "VERS", "PIA", "RUS", "LEC"])
+                == Err(GuessValidationError::InvalidCode {
+                    position: 2,
+                    code: "VERS".to_string()
+                })
+        );
+
+        let mut padded = Guess::try_from(&GuessForm {
+            race: "  test   gp ".to_string(),
+            username: " Tester ".to_string(),
+            ..form([" nor", "ver ", " pia ", "rus", "\tlec"])
+        })
+        .expect("Padded picks should be parsed");
+        padded.normalize();
+
+        assert!(padded.race == "TEST GP");
+        assert!(padded.username == "tester");
+        assert!(padded.picks() == ["NOR", "VER", "PIA", "RUS", "LEC"]);
+        assert!(padded.valid(&drivers).is_ok());
+
+        padded.positions.truncate(4);
+
+        assert!(padded.valid(&drivers) == Err(GuessValidationError::EmptyPosition { position: 5 }));
+
+        padded.positions = vec![driver_code("NOR"); 11];
+
+        assert!(padded.valid(&drivers) == Err(GuessValidationError::TooManyPicks { count: 11 }));
+    }
+
+    #[test]
+    fn guess_record_round_trip() {
+        let round_trip = |csv: &str| -> String {
+            let guesses: Vec<Guess> = csv::Reader::from_reader(csv.as_bytes())
+                .deserialize()
+                .collect::<Result<_, _>>()
+                .expect("The fixture rows should be valid guesses");
+            let mut writer = csv::Writer::from_writer(Vec::new());
+
+            for guess in &guesses {
+                assert!(writer.serialize(guess).is_ok());
+            }
+
+            String::from_utf8(writer.into_inner().unwrap_or_default()).unwrap_or_default()
+        };
+
+        // Rows as currently stored keep their values, with every position up to P10.
+        let current = "race,username,p1,p2,p3,p4,p5,p6,p7,p8,p9,p10,category,submitted_at,created_at,updated_at,version,race_id\n\
+                       TEST GP,test,NOR,VER,PIA,RUS,LEC,,,,,,formula 1,2026-03-08T04:00:00Z,2026-03-01T12:00:00Z,2026-03-08T04:00:00Z,2,test-gp\n\
+                       TEST GP,other,VER,NOR,LEC,PIA,RUS,,,,,,,1970-01-01T00:00:00Z,,,0,\n";
+
+        assert!(round_trip(current) == current);
+
+        // Rows stored before the other columns existed only gain them.
+        let legacy: Guess = csv::Reader::from_reader(
+            "race,username,p1,p2,p3,p4,p5\nTEST GP,test,NOR,VER,PIA,RUS,LEC\n".as_bytes(),
+        )
+        .deserialize()
+        .next()
+        .and_then(Result::ok)
+        .expect("A legacy row should be a valid guess");
+
+        assert!(legacy.picks() == ["NOR", "VER", "PIA", "RUS", "LEC"]);
+
+        // Longer guesses have their picks after P5 read back in order, in rows as long as the others.
+        let longer = "race,username,p1,p2,p3,p4,p5,p6,p7,p8,p9,p10,category,submitted_at,created_at,updated_at,version,race_id\n\
+                      TEST GP,test,NOR,VER,PIA,RUS,LEC,HAM,ALO,,,,,1970-01-01T00:00:00Z,,,0,\n\
+                      TEST GP,other,VER,NOR,LEC,PIA,RUS,,,,,,,1970-01-01T00:00:00Z,,,0,\n";
+
+        assert!(round_trip(longer) == longer);
+
+        // The API and the templates still see the picks as p1 to p5.
+        let json = rocket::serde::json::to_value(&legacy).unwrap_or_default();
+
+        assert!(json["p1"] == "NOR" && json["p5"] == "LEC" && json["p6"].is_null());
+    }
+
+    #[test]
+    fn parse_driver_code() {
+        assert!(DriverCode::new(" ver\t").is_ok_and(|code| code == "VER"));
+        assert!(DriverCode::new("Nor") == DriverCode::new("NOR"));
+        assert!(
+            DriverCode::new("VE1")
+                == Err(InvalidDriverCode {
+                    code: "VE1".to_string()
+                })
+        );
+        assert!(DriverCode::new(" VERS ").is_err_and(|error| error.code == "VERS"));
+        assert!(DriverCode::new("").is_err());
+        assert!(
+            DriverCode::new("é")
+                .is_err_and(|error| error.to_string() == "'é' is not a 3 letter driver code.")
+        );
+
+        let json = rocket::serde::json::to_string(&driver_code("lec")).unwrap_or_default();
+
+        assert!(json == "\"LEC\"");
+        assert!(
+            rocket::serde::json::from_str::<DriverCode>("\" pia\"").is_ok_and(|code| code == "PIA")
+        );
+        assert!(rocket::serde::json::from_str::<DriverCode>("\"P1A\"").is_err());
+    }
+
+    #[test]
+    fn hidden_before_deadline() {
+        let now = Utc::now();
+        let event = Event {
+            category: "[Formula 1]".to_string(),
+            name: "NEXT GP".to_string(),
+            description: "Race".to_string(),
+            datetime: now + TimeDelta::hours(1),
+            channel: "#formula1".to_string(),
+            tags: "".to_string(),
+            notify: false,
+            id: String::new(),
+        };
+        let guesses = [
+            Guess {
+                race: "PAST GP".to_string(),
+                username: "test".to_string(),
+                ..Default::default()
+            },
+            Guess {
+                race: "NEXT GP".to_string(),
+                username: "test".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        let hidden: Vec<bool> = guesses
+            .iter()
+            .map(|g| g.hidden_before_deadline(&event, now))
+            .collect();
+
+        assert!(hidden == [false, true]);
+        assert!(
+            guesses
+                .iter()
+                .all(|g| !g.hidden_before_deadline(&event, event.datetime))
+        );
+    }
+
+    #[test]
+    fn categories() {
+        let event = |category: &str| Event {
+            category: category.to_string(),
+            name: "Test GP".to_string(),
+            description: "Race".to_string(),
+            datetime: Utc::now(),
+            channel: "#formula1".to_string(),
+            tags: String::new(),
+            notify: true,
+            id: String::new(),
+        };
+
+        assert!(event("[Formula 1]").categories() == ["Formula 1"]);
+        assert!(event("[Formula 1; Formula 2]").categories() == ["Formula 1", "Formula 2"]);
+        assert!(event("[Formula 1];[Formula 2];").categories() == ["Formula 1", "Formula 2"]);
+        assert!(event("[Formula 1; Formula 2]").in_category("formula 2"));
+        assert!(!event("[Formula 1 Academy]").in_category("formula 1"));
+        assert!(event("").categories().is_empty());
+    }
+
+    #[test]
+    fn event_slug() {
+        assert!(Event::slug("Bahrain Grand Prix") == "bahrain-grand-prix");
+        assert!(Event::slug("  SÃO PAULO GP 2025 ") == "são-paulo-gp-2025");
+        assert!(Event::slug("Emilia-Romagna  /  Imola") == "emilia-romagna-imola");
+        assert!(Event::slug("").is_empty());
+    }
+
+    #[test]
+    fn drivers_by_team() {
+        let driver = |code: &str, name: &str, team: &str, colour: &str| Driver {
+            number: 1,
+            code: driver_code(code),
+            name: name.to_string(),
+            category: String::new(),
+            active: true,
+            team: team.to_string(),
+            colour: colour.to_string(),
+        };
+
+        let teams = Driver::by_team(vec![
+            driver("VER", "Max Verstappen", "Red Bull", "#3671c6"),
+            driver("OWA", "Pato O'Ward", "", ""),
+            driver("PIA", "Oscar Piastri", "McLaren", ""),
+            driver("NOR", "Lando Norris", "McLaren", "#ff8000"),
+        ]);
+
+        assert!(
+            teams
+                .iter()
+                .map(|t| (t.team.as_str(), t.colour.as_str(), t.drivers.len()))
+                .eq([
+                    ("McLaren", "#ff8000", 2),
+                    ("Red Bull", "#3671c6", 1),
+                    ("", "", 1)
+                ])
+        );
+        assert!(teams[0].drivers[0].code == "NOR");
+
+        assert!(driver("NOR", "Lando Norris", "McLaren", "#FF8000").valid());
+        assert!(!driver("NOR", "Lando Norris", "McLaren", "orange").valid());
+        assert!(!driver("NOR", "Lando Norris", "McLaren", "#ff80001").valid());
+    }
+
+    #[test]
+    fn has_tag() {
+        let event = |tags: &str| Event {
+            category: "[Formula 1]".to_string(),
+            name: "Test GP".to_string(),
+            description: "Race".to_string(),
+            datetime: Utc::now(),
+            channel: "#formula1".to_string(),
+            tags: tags.to_string(),
+            notify: true,
+            id: String::new(),
+        };
+
+        assert!(event("sprint;no-game").has_tag(Event::NO_GAME_TAG));
+        assert!(event(" Sprint ; double-points ").has_tag("sprint"));
+        assert!(event("f1 formula1").has_tag("formula1"));
+        assert!(!event("no-games").has_tag(Event::NO_GAME_TAG));
+        assert!(!event("").has_tag(""));
+    }
+
+    #[test]
+    fn parse_local_datetime() {
+        assert!(
+            Event::parse_local_datetime("2026-07-05T15:00", "Europe/London")
+                .is_some_and(|datetime| datetime.to_string() == "2026-07-05 14:00:00 UTC")
+        );
+        assert!(
+            Event::parse_local_datetime("2026-07-05T15:00", "UTC")
+                .is_some_and(|datetime| datetime.to_string() == "2026-07-05 15:00:00 UTC")
+        );
+        assert!(Event::parse_local_datetime("05/07/2026 15:00", "UTC").is_none());
+        assert!(Event::parse_local_datetime("2026-07-05T15:00", "Nowhere/City").is_none());
+    }
+
+    #[test]
+    fn local_datetime() {
+        let event = |datetime: &str| Event {
+            category: "[Formula 1]".to_string(),
+            name: "Test GP".to_string(),
+            description: "Race".to_string(),
+            datetime: datetime.parse().unwrap_or_default(),
+            channel: "#formula1".to_string(),
+            tags: String::new(),
+            notify: true,
+            id: String::new(),
+        };
+
+        assert!(
+            event("2026-07-05T12:00:00Z").local_datetime("America/New_York")
+                == "2026-07-05 08:00 EDT"
+        );
+        assert!(
+            event("2026-01-04T12:00:00Z").local_datetime("America/New_York")
+                == "2026-01-04 07:00 EST"
+        );
+        assert!(event("2026-01-04T12:00:00Z").local_datetime("") == "2026-01-04 12:00 UTC");
+    }
+
+    #[test]
+    fn user_history_summary() {
+        let guess = Guess {
+            race: "Test GP".to_string(),
+            ..Default::default()
+        };
+        let other_guess = Guess {
+            race: "Other GP".to_string(),
+            ..Default::default()
+        };
+
+        let summary = UserHistorySummary::new(&[
+            ScoredGuess {
+                guess: &guess,
+                points: Points(25),
+                result: None,
+                undated: false,
+            },
+            ScoredGuess {
+                guess: &other_guess,
+                points: Points(4),
+                result: None,
+                undated: false,
+            },
+            ScoredGuess {
+                guess: &other_guess,
+                points: Points(0),
+                result: None,
+                undated: false,
+            },
+        ]);
+
+        assert!(summary.total_races == 3 && summary.total_points == 29);
+        assert!(summary.average_points == 29.0 / 3.0);
+        assert!(summary.best_race.as_deref() == Some("Test GP") && summary.best_score == 25);
+        assert!(summary.perfect_count == 1);
+    }
+
+    #[test]
+    fn season_summary() {
+        let history_event = |name: &str, p1: Option<&str>, points, pending| HistoryEvent {
+            name: name.to_string(),
+            datetime: DateTime::default(),
+            guess: p1.map(|p1| Guess {
+                race: name.to_uppercase(),
+                positions: [p1, "VER", "PIA", "RUS", "LEC"].map(driver_code).to_vec(),
+                ..Default::default()
+            }),
+            points,
+            pending,
+        };
+        let result = |race: &str, p1: &str| {
+            race_result(&race.to_uppercase(), [p1, "VER", "PIA", "RUS", "LEC"])
+        };
+
+        let history = [
+            history_event("First GP", Some("NOR"), Some(Points(25)), false),
+            history_event("Second GP", Some("HAM"), Some(Points(10)), false),
+            history_event("Third GP", Some("NOR"), Some(Points(25)), false),
+            history_event("Fourth GP", Some("NOR"), Some(Points(12)), false),
+            history_event("Fifth GP", Some("NOR"), None, true),
+        ];
+        let results = HashMap::from(
+            [
+                ("First GP", "NOR"),
+                ("Second GP", "NOR"),
+                ("Third GP", "NOR"),
+                ("Fourth GP", "NOR"),
+            ]
+            .map(|(race, p1)| (race.to_uppercase(), result(race, p1))),
+        );
+        let entry = LeaderboardEntry {
+            rank: 3,
+            username: "test".to_string(),
+            name: "test".to_string(),
+            points: Points(72),
+            wins: 0,
+            current_user: true,
+            avatar: DEFAULT_AVATAR.to_string(),
+            rank_change: 0,
+            is_new: false,
+        };
+
+        let summary = SeasonSummary::new(Some(&entry), &history, &results, false);
+
+        assert!(summary.rank == Some(3) && summary.points == 72);
+        assert!(summary.last_race.as_deref() == Some("Fourth GP"));
+        assert!(summary.last_race_points == Some(Points(12)));
+        assert!(summary.p1_streak == 2 && !summary.submitted);
+
+        let summary = SeasonSummary::new(None, &[], &HashMap::new(), true);
+
+        assert!(summary.rank.is_none() && summary.last_race.is_none());
+        assert!(summary.p1_streak == 0 && summary.submitted);
+    }
+
+    #[test]
+    fn site_stats() {
+        let scored_guess =
+            |race: &str, username: &str, picks: [&str; 5], points, parlay| ScoredGuessOwned {
+                result: None,
+                guess: Guess {
+                    race: race.to_string(),
+                    username: username.to_string(),
+                    positions: picks.map(driver_code).to_vec(),
+                    ..Default::default()
+                },
+                points: Points(points),
+                breakdown: ScoreBreakdown {
+                    parlay: Points(parlay),
+                    ..Default::default()
+                },
+                hidden: false,
+                undated: false,
+            };
+        let results = HashMap::from([
+            (
+                "FIRST GP".to_string(),
+                race_result("FIRST GP", ["NOR", "VER", "PIA", "RUS", "LEC"]),
+            ),
+            (
+                "SECOND GP".to_string(),
+                race_result("SECOND GP", ["NOR", "VER", "PIA", "RUS", "LEC"]),
+            ),
+        ]);
+
+        let stats = SiteStats::new(
+            Some(2025),
+            &[
+                scored_guess(
+                    "FIRST GP",
+                    "alice",
+                    ["NOR", "VER", "PIA", "RUS", "LEC"],
+                    25,
+                    5,
+                ),
+                scored_guess("FIRST GP", "bob", ["VER", "NOR", "PIA", "RUS", "HAM"], 9, 0),
+                scored_guess(
+                    "SECOND GP",
+                    "alice",
+                    ["NOR", "VER", "PIA", "RUS", "LEC"],
+                    4,
+                    0,
+                ),
+                scored_guess(
+                    "SECOND GP",
+                    "bob",
+                    ["VER", "NOR", "PIA", "RUS", "HAM"],
+                    2,
+                    0,
+                ),
+                scored_guess("THIRD GP", "bob", ["VER", "NOR", "PIA", "RUS", "ALO"], 0, 0),
+            ],
+            &results,
+        );
+
+        assert!(stats.guesses_count == 5);
+        assert!(stats.average_points == 10.0);
+        assert!(stats.best_score == 25 && stats.best_username.as_deref() == Some("alice"));
+        assert!(stats.best_race.as_deref() == Some("FIRST GP"));
+        assert!(stats.parlay_count == 1);
+        assert!(stats.most_picked.as_deref() == Some("NOR") && stats.most_picked_count == 5);
+        assert!(stats.least_picked.as_deref() == Some("ALO") && stats.least_picked_count == 1);
+        assert!(
+            stats.worst_race.as_deref() == Some("SECOND GP") && stats.worst_race_average == 3.0
+        );
+    }
+
+    #[test]
+    fn driver_stats() {
+        let guess = |username: &str, picks: [&str; 5]| Guess {
+            username: username.to_string(),
+            positions: picks.map(driver_code).to_vec(),
+            ..Default::default()
+        };
+        let driver = Driver {
+            number: 4,
+            code: driver_code("NOR"),
+            name: "Lando Norris".to_string(),
+            category: String::new(),
+            active: true,
+            team: String::new(),
+            colour: String::new(),
+        };
+        let result = race_result("", ["PIA", "NOR", "VER", "RUS", "LEC"]);
+
+        let stats = DriverStats::new(
+            driver,
+            Some(2025),
+            &[
+                guess("alice", ["NOR", "VER", "PIA", "RUS", "LEC"]),
+                guess("alice", ["NOR", "VER", "PIA", "RUS", "LEC"]),
+                guess("bob", ["VER", "NOR", "PIA", "RUS", "LEC"]),
+                guess("carol", ["VER", "PIA", "RUS", "LEC", "HAM"]),
+            ],
+            &[result],
+        );
+
+        assert!(stats.picks == [2, 1, 0, 0, 0]);
+        assert!(stats.finishes == [0, 1, 0, 0, 0] && stats.top_five_count == 1);
+        assert!(stats.top_pickers == [("alice".to_string(), 2), ("bob".to_string(), 1)]);
+    }
+
+    #[test]
+    fn user_history_summary_awaiting_results() {
+        let guess = Guess::default();
+
+        let summary = UserHistorySummary::new(&[
+            ScoredGuess {
+                guess: &guess,
+                points: Points(0),
+                result: None,
+                undated: false,
+            },
+            ScoredGuess {
+                guess: &guess,
+                points: Points(0),
+                result: None,
+                undated: false,
+            },
+        ]);
+
+        assert!(summary.total_races == 2 && summary.total_points == 0);
+        assert!(summary.average_points == 0.0);
+        assert!(summary.best_race.is_none() && summary.best_score == 0);
+        assert!(summary.perfect_count == 0);
+    }
+
+    #[test]
+    fn user_history_summary_single_guess() {
+        let guess = Guess {
+            race: "Test GP".to_string(),
+            ..Default::default()
+        };
+
+        let summary = UserHistorySummary::new(&[ScoredGuess {
+            guess: &guess,
+            points: Points(7),
+            result: None,
+            undated: false,
+        }]);
+
+        assert!(summary.total_races == 1 && summary.total_points == 7);
+        assert!(summary.average_points == 7.0);
+        assert!(summary.best_race.as_deref() == Some("Test GP") && summary.best_score == 7);
+        assert!(UserHistorySummary::new(&[]) == UserHistorySummary::default());
+    }
+
+    #[test]
+    fn rank_leaderboard() {
+        let leaderboard = vec![
+            ("alice 🇵🇹".to_string(), Points(50), 2),
+            ("bob 🇪🇸 VER".to_string(), Points(40), 1),
+            ("bobby".to_string(), Points(30), 0),
+        ];
+
+        let avatars = HashMap::from([("bob".to_string(), "/static/avatars/bob.png".to_string())]);
+
+        let entries = LeaderboardEntry::rank(leaderboard, Some("BOB"), &avatars);
+
+        assert!(entries.iter().map(|e| e.rank).eq([1, 2, 3]));
+        assert!(
+            entries
+                .iter()
+                .map(|e| e.username.as_str())
+                .eq(["alice", "bob", "bobby"])
+        );
+        assert!(
+            entries
+                .iter()
+                .map(|e| e.current_user)
+                .eq([false, true, false])
+        );
+        assert!(entries.iter().map(|e| e.avatar.as_str()).eq([
+            DEFAULT_AVATAR,
+            "/static/avatars/bob.png",
+            DEFAULT_AVATAR
+        ]));
+
+        assert!(LeaderboardEntry::name_matches("bob 🇪🇸 VER", "BO"));
+        assert!(LeaderboardEntry::name_matches("bob 🇪🇸 VER", ""));
+        assert!(!LeaderboardEntry::name_matches("bob 🇪🇸 VER", "ver"));
+    }
+
+    #[test]
+    fn guess_history_csv() {
+        let row = |race: &str, points| GuessHistoryRow {
+            race: race.to_string(),
+            date: None,
+            p1: Some(driver_code("NOR")),
+            p2: Some(driver_code("VER")),
+            p3: Some(driver_code("PIA")),
+            p4: Some(driver_code("RUS")),
+            p5: Some(driver_code("LEC")),
+            points,
+            scored: points.is_some(),
+        };
+
+        let csv = GuessHistoryRow::to_csv(&[
+            row("Grand Prix, Monaco", Some(Points(25))),
+            row("Next GP", None),
+        ])
+        .unwrap_or_default();
+
+        assert!(csv.lines().eq([
+            "race,date,p1,p2,p3,p4,p5,points,scored",
+            "\"Grand Prix, Monaco\",,NOR,VER,PIA,RUS,LEC,25,yes",
+            "Next GP,,NOR,VER,PIA,RUS,LEC,,no",
+        ]));
+    }
+
+    #[test]
+    fn points() {
+        assert!(Points(25) + Points(4) == 29);
+        assert!([Points(9), Points(12), Points(4)].iter().sum::<Points>() == 25);
+        assert!(Points(u16::MAX - 1) + Points(4) == u16::MAX);
+        assert!(Points(13) * 2 == 26 && Points(u16::MAX / 2 + 1) * 2 == u16::MAX);
+        assert!(Points(25).to_string() == "25");
+        assert!(Points(25).label() == "25 pts" && Points(1).label() == "1 pt");
+        assert!(Points(4).bonus("parlay") == "+4 parlay");
+        assert!(rocket::serde::json::to_string(&Points(25)).is_ok_and(|json| json == "25"));
+        assert!(
+            ScoreBreakdown {
+                podium: Points(9),
+                parlay: Points(4),
+                ..Default::default()
+            }
+            .bonuses()
+                == ["+9 podium", "+4 parlay"]
+        );
+        assert!(
+            ScoreBreakdown {
+                podium: Points(9),
+                parlay: Points(4),
+                ..Default::default()
+            }
+            .times(2)
+            .bonuses()
+                == ["+18 podium", "+8 parlay"]
+        );
+    }
+
+    #[test]
+    fn leaderboard_delimited() {
+        let row = |rank, username: &str, points, wins| LeaderboardRow {
+            rank,
+            username: username.to_string(),
+            points: Points(points),
+            wins,
+            name: username.to_string(),
+        };
+        let rows = [
+            row(1, "smith,john", 50, 2),
+            row(2, "bob\"the\"builder", 42, 0),
+        ];
+
+        let csv = LeaderboardRow::to_delimited(&rows, b',').unwrap_or_default();
+
+        assert!(csv.lines().eq([
+            "rank,username,points,wins",
+            "1,\"smith,john\",50,2",
+            "2,\"bob\"\"the\"\"builder\",42,0",
+        ]));
+
+        let tsv = LeaderboardRow::to_delimited(&rows, b'\t').unwrap_or_default();
+
+        assert!(tsv.lines().eq([
+            "rank\tusername\tpoints\twins",
+            "1\tsmith,john\t50\t2",
+            "2\t\"bob\"\"the\"\"builder\"\t42\t0",
+        ]));
+    }
+
+    #[test]
+    fn leaderboard_row_format() {
+        let rows = LeaderboardRow::rank(vec![
+            ("alice 🇵🇹".to_string(), Points(50), 1),
+            ("b_o 🇪🇸 VER".to_string(), Points(40), 0),
+        ]);
+
+        assert!(
+            rows.iter()
+                .map(|r| r.username.as_str())
+                .eq(["alice", "b_o"])
+        );
+        assert!(
+            rows.iter()
+                .map(|r| r.format(false))
+                .eq(["1. alice 🇵🇹 50", "2. b_o 🇪🇸 VER 40"])
+        );
+        assert!(
+            rows.iter()
+                .map(|r| r.format(true))
+                .eq(["1. ALI 50", "2. BO 40"])
+        );
+    }
+
+    #[test]
+    fn ui_prefs_cookie() {
+        let prefs = UiPrefs {
+            dark_mode: true,
+            compact_tables: false,
+        };
+
+        assert!(UiPrefs::from_cookie(&prefs.to_cookie()) == prefs);
+        assert!(UiPrefs::from_cookie("not json") == UiPrefs::default());
+    }
+
+    #[test]
+    fn form_timestamp() {
+        let form_timestamp = FormTimestamp::default();
+        let now = Utc::now();
+        let issued = form_timestamp.issue(now);
+        let after = |seconds: f64| now + TimeDelta::milliseconds((seconds * 1000.0) as i64);
+
+        assert!(!form_timestamp.validate(&issued, after(0.5)));
+        assert!(form_timestamp.validate(&issued, after(3.0)));
+        assert!(form_timestamp.validate(&issued, after(3600.0)));
+        assert!(!form_timestamp.validate(&issued, after(3602.0)));
+        // Clocks that disagree by less than a second are tolerated at both ends.
+        assert!(form_timestamp.validate(&issued, after(2.5)));
+        assert!(form_timestamp.validate(&issued, after(3600.5)));
+        // A timestamp issued in the future, beyond the skew, is rejected.
+        assert!(!form_timestamp.validate(&issued, after(-5.0)));
+
+        // Tampering with the timestamp or using another key breaks the signature.
+        let (timestamp, signature) = issued.split_once('.').unwrap_or_default();
+        let earlier = format!(
+            "{}.{signature}",
+            timestamp.parse::<i64>().unwrap_or_default() - 60_000
+        );
+        assert!(!form_timestamp.validate(&earlier, after(3.0)));
+        assert!(!FormTimestamp::default().validate(&issued, after(3.0)));
+        assert!(!form_timestamp.validate("", after(3.0)));
+        assert!(!form_timestamp.validate("1.é", after(3.0)));
+    }
+
+    #[test]
+    fn registration_looks_automated() {
+        let form_timestamp = FormTimestamp::default();
+        let now = Utc::now();
+        let registration = |website: &str, issued: String| Registration {
+            username: "test".to_string(),
+            password: "secret123".to_string(),
+            country: None,
+            invite_code: String::new(),
+            csrf: String::new(),
+            website: website.to_string(),
+            issued,
+        };
+
+        assert!(
+            !registration("", form_timestamp.issue(now))
+                .looks_automated(&form_timestamp, now + TimeDelta::seconds(5))
+        );
+        assert!(
+            registration("http://spam.example", form_timestamp.issue(now))
+                .looks_automated(&form_timestamp, now + TimeDelta::seconds(5))
+        );
+        assert!(registration("", form_timestamp.issue(now)).looks_automated(&form_timestamp, now));
+        assert!(registration("", String::new()).looks_automated(&form_timestamp, now));
+    }
+
+    #[test]
+    fn registration_validation() {
+        let errors = |username: &str, password: &str| {
+            form::Form::<Registration>::parse(&format!("username={username}&password={password}"))
+                .err()
+                .map(|errors| {
+                    errors
+                        .iter()
+                        .filter_map(|error| error.name.as_ref().map(|name| name.to_string()))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+
+        assert!(errors("test_user1", "secret123").is_empty());
+        assert!(errors("abc", "12345678").is_empty());
+        assert!(errors("ab", "secret123") == ["username"]);
+        assert!(errors(&"a".repeat(21), "secret123") == ["username"]);
+        assert!(errors("test+user", "secret123") == ["username"]);
+        assert!(errors("test-user", "secret123") == ["username"]);
+        assert!(errors("tëst", "secret123") == ["username"]);
+        assert!(errors("test", "secret1") == ["password"]);
+        assert!(errors("test", "") == ["password"]);
+        assert!(errors("a b", "short") == ["username", "password"]);
+    }
+
+    #[test]
+    fn public_user_hides_password() {
+        let user = User {
+            token: "secret-token".to_string(),
+            username: "test".to_string(),
+            password: "$argon2id$v=19$m=19456,t=2,p=1$secret-hash".to_string(),
+            country: "PT".to_string(),
+            ..Default::default()
+        };
+        let json = rocket::serde::json::to_string(&PublicUser::from(&user)).unwrap_or_default();
+
+        assert!(json.contains("\"username\":\"test\""));
+        assert!(!json.contains("secret-hash"));
+        assert!(!json.contains("argon2"));
+        assert!(!json.contains("secret-token"));
+    }
+
+    #[test]
+    fn country_seed() {
+        let countries = Country::seed().unwrap_or_default();
+
+        assert!(countries.len() == 252);
+        assert!(countries.iter().map(|c| &c.code).all_unique());
+        assert!(
+            countries
+                .iter()
+                .any(|c| c.code == "PT" && c.name == "Portugal" && c.flag == "🇵🇹")
+        );
+        assert!(countries.iter().any(|c| c.name == "Korea, South"));
+        assert!(Country::is_known(&countries, "pt"));
+        assert!(!Country::is_known(&countries, "XX"));
+    }
+
+    #[test]
+    fn csrf_token() {
+        let token = CsrfToken::generate();
+
+        assert!(token != CsrfToken::generate());
+        assert!(CsrfToken::validate(&token, &token));
+        assert!(!CsrfToken::validate(&token, &CsrfToken::generate()));
+        assert!(!CsrfToken::validate(&token, ""));
+        assert!(!CsrfToken::validate("", ""));
+    }
+
+    #[test]
+    fn avatar_file() {
+        assert!(avatar_extension(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR") == Some("png"));
+        assert!(avatar_extension(b"\xff\xd8\xff\xe0\0\x10JFIF") == Some("jpg"));
+        assert!(avatar_extension(b"RIFF\x24\0\0\0WEBPVP8 ") == Some("webp"));
+        assert!(avatar_extension(b"GIF89a").is_none());
+        assert!(avatar_extension(b"<svg></svg>").is_none());
+
+        let uploaded_at = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap_or_default();
+
+        assert!(
+            avatar_file_name("Vasco", uploaded_at, "png").as_deref()
+                == Some("vasco-1700000000000.png")
+        );
+        assert!(avatar_file_name("../../etc/passwd", uploaded_at, "png").is_none());
+        assert!(avatar_file_name("a/b", uploaded_at, "png").is_none());
+        assert!(avatar_file_name("", uploaded_at, "png").is_none());
+
+        assert!(avatar_path("/static/avatars/vasco.png") == Some("static/avatars/vasco.png"));
+        assert!(avatar_path(DEFAULT_AVATAR).is_none());
+        assert!(avatar_path("https://example.com/vasco.png").is_none());
+    }
+
+    #[test]
+    fn describe_time_ago() {
+        let now = Utc::now();
+
+        assert!(time_ago(now, now) == "just now");
+        assert!(time_ago(now - TimeDelta::seconds(59), now) == "just now");
+        assert!(time_ago(now - TimeDelta::minutes(1), now) == "1 minute ago");
+        assert!(time_ago(now - TimeDelta::minutes(125), now) == "2 hours ago");
+        assert!(time_ago(now - TimeDelta::days(3), now) == "3 days ago");
+        assert!(time_ago(now + TimeDelta::minutes(5), now) == "just now");
+    }
 }
@@ -1,483 +1,6415 @@
 use std::{
-    collections::HashMap,
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fmt,
     io::{Error, ErrorKind},
+    ops::{ControlFlow, Deref},
+    path::Path,
+    sync::Arc,
 };
 
 use argon2::{
-    Argon2, PasswordHasher,
+    Algorithm, Argon2, Params, PasswordHasher, Version,
     password_hash::{PasswordHash, PasswordVerifier, SaltString, rand_core::OsRng},
 };
-use chrono::Utc;
+use chrono::{DateTime, Datelike, TimeDelta, Utc};
 use country_emoji::code_to_flag;
 use csv_db::{Database, DbError};
 use itertools::Itertools;
-use rocket::{State, form::validate::Contains, futures::future::join_all, tokio::sync::Mutex};
+#[cfg(feature = "parallel-scoring")]
+use rayon::prelude::*;
+use rocket::tokio::task;
+use rocket::{
+    form::validate::Contains,
+    serde::{Serialize, de::DeserializeOwned},
+    tokio::{
+        fs,
+        sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, mpsc},
+    },
+};
 use uuid::Uuid;
 
-use crate::models::{Driver, Event, Guess, RaceResult, ScoredGuess, User};
+use crate::config::{Argon2Config, Category, ScoringConfig};
+use crate::models::{
+    ConstructorGuess, Country, CrowdGuess, Driver, DriverAlias, DriverCode, DriverConflict,
+    DriverStats, Event, Guess, GuessHistoryRow, HistoryEvent, InviteCode, ParticipationStat,
+    Points, PositionAccuracy, RaceDated, RaceResult, RivalInfo, ScoreBreakdown, ScoreMatrix,
+    ScoredGuess, ScoredGuessOwned, SiteStats, User, UserDataExport,
+};
 
-const CATEGORY: &str = "formula 1";
-const CHANNEL: &str = "#formula1";
 pub const CORRECT_PODIUM: u16 = 3;
 pub const CORRECT_FIVE: u16 = 6;
 pub const WRONG_PLACE: u16 = 1;
 pub const PARLAY: u16 = 4;
+pub const CONSTRUCTOR_CORRECT: u16 = 4;
+/// Rows read ahead of the one being looked at by `Db::scan`.
+const SCAN_BUFFER: usize = 64;
 
-pub struct Store<'a> {
-    db: &'a State<Mutex<Database<&'static str>>>,
+#[derive(Debug)]
+pub enum StoreError {
+    Db(DbError),
+    AlreadyExists,
+    InUse,
+    /// The row was updated since it was read.
+    Conflict,
+    /// The invite code was already used to register.
+    Used,
+    /// Another driver already uses the code or number of the one being stored.
+    DuplicateDriver(DriverConflict),
+    /// A row of a table couldn't be parsed.
+    InvalidRow {
+        table: &'static str,
+        line: u64,
+        reason: String,
+    },
 }
 
-impl<'a> Store<'a> {
-    pub fn new(db: &'a State<Mutex<Database<&'static str>>>) -> Self {
-        Self { db }
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Db(error) => write!(f, "{error}"),
+            Self::AlreadyExists => write!(f, "Already exists"),
+            Self::InUse => write!(f, "Still in use"),
+            Self::Conflict => write!(f, "Modified concurrently"),
+            Self::Used => write!(f, "Already used"),
+            Self::DuplicateDriver(conflict) => write!(f, "Duplicate driver: {conflict}"),
+            Self::InvalidRow {
+                table,
+                line,
+                reason,
+            } => write!(f, "Invalid row on line {line} of {table}: {reason}"),
+        }
     }
+}
 
-    pub async fn add_user(
-        &self,
-        username: &str,
-        password: &str,
-        country: Option<String>,
-    ) -> Result<(), DbError> {
-        let db_lock = self.db.lock().await;
+impl std::error::Error for StoreError {}
 
-        let users = db_lock
-            .find("users", |u: &User| {
-                u.username.eq_ignore_ascii_case(username)
-            })
-            .await?;
+impl StoreError {
+    /// Tells which row of `table` failed to parse when that's why reading it failed.
+    pub fn in_table(table: &'static str, error: DbError) -> Self {
+        match error {
+            DbError::Csv(csv_error) => match csv_error.kind() {
+                csv::ErrorKind::Deserialize {
+                    pos: Some(pos),
+                    err,
+                } => Self::InvalidRow {
+                    table,
+                    line: pos.line(),
+                    reason: err.to_string(),
+                },
+                _ => Self::Db(DbError::Csv(csv_error)),
+            },
+            error => Self::Db(error),
+        }
+    }
+}
 
-        if users.is_empty() {
-            let user = User {
-                token: Uuid::new_v4().to_string(),
-                username: username.to_lowercase(),
-                password: Self::hash_password(password)
-                    .await
-                    .map_err(|_| DbError::NoMatch)?,
-                country: country.unwrap_or_default(),
-            };
+impl From<DbError> for StoreError {
+    fn from(error: DbError) -> Self {
+        Self::Db(error)
+    }
+}
 
-            db_lock.insert("users", user).await
-        } else {
-            Err(DbError::Io(Error::from(ErrorKind::AlreadyExists)))
+/// The CSV database along with the folder of its files, which csv_db doesn't tell, so that single
+/// rows can be looked up without reading a whole collection.
+pub struct Db {
+    database: Database<&'static str>,
+    dir: &'static str,
+    /// How long every read waits once it has the lock, standing in for slow storage in tests.
+    #[cfg(test)]
+    read_delay: std::time::Duration,
+}
+
+impl Db {
+    fn new(dir: &'static str) -> Self {
+        Self {
+            database: Database::new(dir, None),
+            dir,
+            #[cfg(test)]
+            read_delay: std::time::Duration::ZERO,
         }
     }
 
-    pub async fn update_user(&self, user: User, token: &str) -> Result<(), DbError> {
-        self.db
-            .lock()
-            .await
-            .update("users", user, |u: &&User| u.token == token)
-            .await
+    /// Finds the first row of a collection matching `predicate`, such as a user by token, reading
+    /// no further than it.
+    async fn find_first<T, P>(&self, collection: &str, predicate: P) -> Result<Option<T>, DbError>
+    where
+        T: DeserializeOwned + Send + 'static,
+        P: FnMut(&T) -> bool,
+    {
+        Ok(self.find_limited(collection, predicate, 1).await?.pop())
     }
 
-    pub async fn get_user(token: &str, db: &State<Mutex<Database<&str>>>) -> Option<User> {
-        db.lock()
-            .await
-            .find("users", |u: &User| u.token == token)
-            .await
-            .ok()?
-            .into_iter()
-            .next()
-    }
+    /// Finds at most `limit` rows of a collection matching `predicate`, in the order of its file,
+    /// reading no further than the last of them.
+    async fn find_limited<T, P>(
+        &self,
+        collection: &str,
+        mut predicate: P,
+        limit: usize,
+    ) -> Result<Vec<T>, DbError>
+    where
+        T: DeserializeOwned + Send + 'static,
+        P: FnMut(&T) -> bool,
+    {
+        let mut rows = Vec::new();
 
-    pub async fn validate_user(&self, username: &str, password: &str) -> Option<String> {
-        let users = self
-            .db
-            .lock()
-            .await
-            .find("users", |u: &User| {
-                u.username.eq_ignore_ascii_case(username)
-            })
-            .await
-            .ok()?;
+        if limit == 0 {
+            return Ok(rows);
+        }
 
-        if let Some(user) = users.first() {
-            let parsed_hash = PasswordHash::new(&user.password).ok()?;
+        self.scan(collection, |row| {
+            if predicate(&row) {
+                rows.push(row);
+            }
 
-            if Argon2::default()
-                .verify_password(password.as_bytes(), &parsed_hash)
-                .is_ok()
-            {
-                Some(user.token.clone())
+            if rows.len() < limit {
+                ControlFlow::Continue(())
             } else {
-                None
+                ControlFlow::Break(())
+            }
+        })
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Hands the rows of a collection to `visit` one at a time until it breaks. They are read on a
+    /// blocking thread, which stops reading once `visit` breaks, so the rest of the file is never
+    /// loaded, and only a few rows are held at any time.
+    async fn scan<T, V>(&self, collection: &str, mut visit: V) -> Result<(), DbError>
+    where
+        T: DeserializeOwned + Send + 'static,
+        V: FnMut(T) -> ControlFlow<()>,
+    {
+        let path = Path::new(self.dir).join(format!("{collection}.csv"));
+        let (sender, mut receiver) = mpsc::channel(SCAN_BUFFER);
+
+        let reader = task::spawn_blocking(move || {
+            let mut reader = match csv::Reader::from_path(path) {
+                Ok(reader) => reader,
+                Err(error) => match error.kind() {
+                    // Like csv_db, a collection without a file yet has no rows.
+                    csv::ErrorKind::Io(io_error) if io_error.kind() == ErrorKind::NotFound => {
+                        return Ok(());
+                    }
+                    _ => return Err(error),
+                },
+            };
+
+            for row in reader.deserialize::<T>() {
+                // The receiver is gone once it has seen enough rows.
+                if sender.blocking_send(row?).is_err() {
+                    break;
+                }
+            }
+
+            Ok::<(), csv::Error>(())
+        });
+
+        while let Some(row) = receiver.recv().await {
+            if visit(row).is_break() {
+                return Ok(());
             }
-        } else {
-            None
         }
+
+        reader.await??;
+
+        Ok(())
     }
+}
 
-    pub async fn hash_password(password: &str) -> Result<String, &'static str> {
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
+impl Deref for Db {
+    type Target = Database<&'static str>;
 
-        Ok(argon2
-            .hash_password(password.as_bytes(), &salt)
-            .map_err(|_| "Could not hash password")?
-            .to_string())
+    fn deref(&self) -> &Self::Target {
+        &self.database
     }
+}
 
-    pub async fn all_drivers(&self) -> Result<Vec<Driver>, DbError> {
-        self.db.lock().await.find("drivers", |_| true).await
+/// A table stored in a CSV file, whose older files may lack the columns added since.
+pub trait Migrator {
+    const TABLE: &'static str;
+    /// Columns added after the table was created, in order, with the value given to existing rows.
+    const ADDED_COLUMNS: &'static [(&'static str, &'static str)];
+}
+
+impl Migrator for User {
+    const TABLE: &'static str = "users";
+    const ADDED_COLUMNS: &'static [(&'static str, &'static str)] = &[
+        ("preferred_driver", ""),
+        ("timezone", ""),
+        ("avatar", ""),
+        ("dark_mode", "false"),
+        ("compact_tables", "false"),
+        ("version", "0"),
+    ];
+}
+
+impl Migrator for Guess {
+    const TABLE: &'static str = "guesses";
+    const ADDED_COLUMNS: &'static [(&'static str, &'static str)] = &[
+        ("category", ""),
+        ("submitted_at", "1970-01-01T00:00:00Z"),
+        ("version", "0"),
+        ("created_at", ""),
+        ("updated_at", ""),
+        ("race_id", ""),
+        ("p6", ""),
+        ("p7", ""),
+        ("p8", ""),
+        ("p9", ""),
+        ("p10", ""),
+    ];
+}
+
+impl Migrator for ConstructorGuess {
+    const TABLE: &'static str = "constructor_guesses";
+    const ADDED_COLUMNS: &'static [(&'static str, &'static str)] = &[("race_id", "")];
+}
+
+impl Migrator for Driver {
+    const TABLE: &'static str = "drivers";
+    const ADDED_COLUMNS: &'static [(&'static str, &'static str)] = &[
+        ("category", ""),
+        ("active", "true"),
+        ("team", ""),
+        ("colour", ""),
+    ];
+}
+
+impl Migrator for RaceResult {
+    const TABLE: &'static str = "results";
+    const ADDED_COLUMNS: &'static [(&'static str, &'static str)] = &[
+        ("c1", ""),
+        ("c2", ""),
+        ("entered_at", ""),
+        ("p6", ""),
+        ("p7", ""),
+        ("p8", ""),
+        ("p9", ""),
+        ("p10", ""),
+        ("race_id", ""),
+    ];
+}
+
+/// In-memory copy of the drivers, which rarely change but are needed on every guess.
+#[derive(Default)]
+struct DriverCache {
+    drivers: Mutex<Option<Vec<Driver>>>,
+}
+
+impl DriverCache {
+    async fn clear(&self) {
+        *self.drivers.lock().await = None;
     }
+}
 
-    pub async fn get_guesses(
-        &self,
-        username: Option<&str>,
-        race: Option<&str>,
-    ) -> Result<Vec<Guess>, DbError> {
-        self.db
-            .lock()
-            .await
-            .find("guesses", |g: &Guess| {
-                (if let Some(username) = username {
-                    g.username.eq_ignore_ascii_case(&username)
-                } else {
-                    true
-                }) && (if let Some(race) = race {
-                    g.race.eq_ignore_ascii_case(&race)
-                } else {
-                    true
-                })
-            })
-            .await
+/// In-memory copy of every scored guess, so the pages showing standings don't score the whole
+/// season on every view. It's filled on the first read, then kept up to date as guesses and
+/// results are stored, rescoring only what changed. Edits to events, which may rename races,
+/// empty it instead.
+///
+/// Guesses and results edited by hand in the CSV files are only picked up after a restart.
+#[derive(Default)]
+struct ScoreCache {
+    /// Shared with the readers, so a read doesn't copy every guess.
+    scored_guesses: Mutex<Option<Arc<Vec<ScoredGuessOwned>>>>,
+    /// Running totals of the players by lowercase category and season, for the leaderboards read
+    /// so far. Always locked after `scored_guesses`, which they're kept in step with.
+    totals: Mutex<HashMap<(String, Option<u16>), SeasonTotals>>,
+}
+
+impl ScoreCache {
+    async fn clear(&self) {
+        *self.scored_guesses.lock().await = None;
+        self.totals.lock().await.clear();
     }
+}
 
-    pub async fn update_guess(&self, guess: Guess, current_race: &str) -> Result<(), DbError> {
-        let username = guess.username.to_lowercase();
+/// Points and race wins of the players over the races of a category in one season, or in every
+/// season, updated a guess at a time so reading them doesn't go through every guess.
+struct SeasonTotals {
+    /// Datetime of every race counted, by uppercase name.
+    races: HashMap<String, DateTime<Utc>>,
+    category: String,
+    /// Whether guesses stored without a category count, which belong to the primary category.
+    primary: bool,
+    /// Points of the players in every race counted, by uppercase race name then username.
+    race_points: HashMap<String, HashMap<String, Points>>,
+    /// Points and race wins of every player with a guess counted, by username.
+    players: HashMap<String, (Points, u32)>,
+    /// The totals before the latest race with a result, kept until the next update.
+    previous: Option<Option<HashMap<String, (Points, u32)>>>,
+}
 
-        let db_lock = self.db.lock().await;
+impl SeasonTotals {
+    fn new(
+        races: HashMap<String, DateTime<Utc>>,
+        category: &Category,
+        primary: bool,
+        scored_guesses: &[ScoredGuessOwned],
+    ) -> Self {
+        let mut totals = Self {
+            races,
+            category: category.name.clone(),
+            primary,
+            race_points: HashMap::new(),
+            players: HashMap::new(),
+            previous: None,
+        };
 
-        if let Err(e) = db_lock
-            .update("guesses", guess.clone(), |g: &&Guess| {
-                g.username.to_lowercase() == username && g.race.eq_ignore_ascii_case(current_race)
-            })
-            .await
-        {
-            match e {
-                DbError::NoMatch => match db_lock.insert("guesses", guess).await {
-                    Ok(_) => return Ok(()),
-                    Err(_) => return Err(DbError::Io(Error::from(ErrorKind::Other))),
-                },
-                _ => return Err(DbError::Io(Error::from(ErrorKind::Other))),
-            }
+        for scored_guess in scored_guesses {
+            totals.update(scored_guess);
         }
 
-        Ok(())
+        totals
     }
 
-    pub async fn next_event(&self) -> Result<Event, DbError> {
-        self.db
-            .lock()
-            .await
-            .find("events", |e: &Event| {
-                e.datetime > Utc::now()
-                    && e.channel.eq_ignore_ascii_case(CHANNEL)
-                    && e.category.to_lowercase().contains(CATEGORY)
-                    && e.description.eq_ignore_ascii_case("race")
-            })
-            .await?
-            .into_iter()
-            .sorted_by(|a, b| a.datetime.cmp(&b.datetime))
-            .next()
-            .ok_or(DbError::NoMatch)
+    fn counts(&self, guess: &Guess) -> bool {
+        let in_category = if guess.category.is_empty() {
+            self.primary
+        } else {
+            guess.category.eq_ignore_ascii_case(&self.category)
+        };
+
+        in_category && self.races.contains_key(&guess.race.to_uppercase())
     }
 
-    pub async fn scored_guesses(
-        &self,
-        guesses: &'a [Guess],
-        normalized_results: &HashMap<String, RaceResult>,
-    ) -> Vec<ScoredGuess<'a>> {
-        let futures: Vec<_> = guesses
-            .iter()
-            .map(|g| async move {
-                ScoredGuess {
-                    guess: g,
-                    points: self.score_guess(g, normalized_results).await,
-                }
-            })
-            .collect();
+    /// Counts the new points of a scored guess, replacing those of its previous version, and moves
+    /// the win of its race along.
+    fn update(&mut self, scored_guess: &ScoredGuessOwned) {
+        if !self.counts(&scored_guess.guess) {
+            return;
+        }
 
-        join_all(futures).await
-    }
+        let username = &scored_guess.guess.username;
+        let scores = self
+            .race_points
+            .entry(scored_guess.guess.race.to_uppercase())
+            .or_default();
+        let winners_before = race_winners(scores);
 
-    async fn score_guess(
-        &self,
-        guess: &Guess,
-        normalized_results: &HashMap<String, RaceResult>,
-    ) -> u16 {
-        let result = match normalized_results.get(&guess.race) {
-            Some(result) => result,
-            None => return 0,
-        };
+        scores.insert(username.clone(), scored_guess.points);
 
-        let guess_positions = [&guess.p1, &guess.p2, &guess.p3, &guess.p4, &guess.p5];
-        let result_positions = [&result.p1, &result.p2, &result.p3, &result.p4, &result.p5];
+        let winners_after = race_winners(scores);
 
-        let mut score = 0;
+        self.players.entry(username.clone()).or_default().0 = self
+            .race_points
+            .values()
+            .filter_map(|scores| scores.get(username))
+            .copied()
+            .sum();
 
-        for (pos, guess_driver) in guess_positions.iter().enumerate() {
-            if guess_driver.eq_ignore_ascii_case(&result_positions[pos]) {
-                score += if pos < 3 {
-                    CORRECT_PODIUM
-                } else {
-                    CORRECT_FIVE
-                };
-            } else if result_positions
-                .iter()
-                .any(|result_driver| guess_driver.eq_ignore_ascii_case(&result_driver))
-            {
-                score += WRONG_PLACE;
+        for winner in winners_before.difference(&winners_after) {
+            if let Some((_, wins)) = self.players.get_mut(winner) {
+                *wins = wins.saturating_sub(1);
             }
         }
-
-        if score == 3 * CORRECT_PODIUM + 2 * CORRECT_FIVE {
-            score += PARLAY;
+        for winner in winners_after.difference(&winners_before) {
+            if let Some((_, wins)) = self.players.get_mut(winner) {
+                *wins += 1;
+            }
         }
 
-        score
+        self.previous = None;
     }
 
-    pub async fn normalized_results(&self) -> Result<HashMap<String, RaceResult>, DbError> {
-        let results = self.results().await?;
-
-        Ok(results.into_iter().map(|r| (r.race.clone(), r)).collect())
+    /// Datetime of the latest race counted with a result.
+    fn latest_scored(&self, results: &HashMap<String, RaceResult>) -> Option<DateTime<Utc>> {
+        results
+            .values()
+            .filter_map(|result| self.races.get(&result.race.to_uppercase()))
+            .max()
+            .copied()
     }
 
-    pub async fn results(&self) -> Result<Vec<RaceResult>, DbError> {
-        self.db
-            .lock()
-            .await
-            .find("results", |_: &RaceResult| true)
-            .await
-    }
+    /// The totals over the races before the latest one with a result, or `None` while none has.
+    fn previous(
+        &mut self,
+        results: &HashMap<String, RaceResult>,
+    ) -> Option<&HashMap<String, (Points, u32)>> {
+        if self.previous.is_none() {
+            self.previous = Some(self.latest_scored(results).map(|latest_scored| {
+                let mut players: HashMap<String, (Points, u32)> = HashMap::new();
 
-    pub async fn get_users(&self, username: Option<&str>) -> Result<Vec<User>, DbError> {
-        self.db
-            .lock()
-            .await
-            .find("users", |u: &User| {
-                if let Some(username) = username {
-                    u.username.to_lowercase().contains(username)
-                } else {
-                    true
+                for (race, scores) in &self.race_points {
+                    if self
+                        .races
+                        .get(race)
+                        .is_some_and(|datetime| *datetime < latest_scored)
+                    {
+                        let winners = race_winners(scores);
+
+                        for (username, points) in scores {
+                            let player = players.entry(username.clone()).or_default();
+                            player.0 += *points;
+                            player.1 += u32::from(winners.contains(username));
+                        }
+                    }
                 }
-            })
-            .await
-    }
 
-    pub async fn leaderboard(
-        &self,
-        grouped_guesses: HashMap<&String, Vec<&ScoredGuess<'_>>>,
-    ) -> Vec<(String, u16)> {
-        let users: HashMap<String, String> = self
-            .db
-            .lock()
-            .await
-            .find("users", |_: &User| true)
-            .await
-            .unwrap_or_default()
-            .into_iter()
-            .map(|u| (u.username, u.country))
-            .collect();
+                players
+            }));
+        }
 
-        grouped_guesses
-            .into_iter()
-            .map(|(username, group)| {
-                let total_points: u16 = group.into_iter().map(|g| g.points).sum();
-                let user_str = format!(
-                    "{} {}",
-                    username,
-                    code_to_flag(users.get(username).unwrap_or(&"".to_string()))
-                        .unwrap_or_default()
-                );
-                (user_str, total_points)
-            })
-            .sorted_by(|a, b| b.1.cmp(&a.1))
-            .collect()
+        self.previous.as_ref().and_then(Option::as_ref)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rocket::tokio;
+/// The players with the best score of a race, unless nobody scored at all.
+fn race_winners(scores: &HashMap<String, Points>) -> HashSet<String> {
+    let best = scores.values().max().copied().unwrap_or_default();
 
-    fn normalized_results() -> HashMap<String, RaceResult> {
-        HashMap::from([(
-            "Test GP".to_string(),
-            RaceResult {
-                race: "Test GP".to_string(),
-                p1: "NOR".to_string(),
-                p2: "VER".to_string(),
-                p3: "PIA".to_string(),
-                p4: "RUS".to_string(),
-                p5: "LEC".to_string(),
-            },
-        )])
-    }
+    scores
+        .iter()
+        .filter(|(_, points)| **points > Points::ZERO && **points == best)
+        .map(|(username, _)| username.clone())
+        .collect()
+}
 
-    fn perfect_guess() -> Guess {
-        Guess {
-            race: "Test GP".to_string(),
-            username: "test".to_string(),
-            p1: "NOR".to_string(),
-            p2: "VER".to_string(),
-            p3: "PIA".to_string(),
-            p4: "RUS".to_string(),
-            p5: "LEC".to_string(),
-        }
+/// Renamed driver codes, along with the datetime of every race to resolve them at. The datetimes
+/// also tell the season a guess is scored with, and the multipliers how many times the points of
+/// a tagged race count.
+#[derive(Clone, Default)]
+pub struct DriverRenames {
+    aliases: Vec<DriverAlias>,
+    races: HashMap<String, DateTime<Utc>>,
+    /// Keyed by uppercase race name, leaving out the races counted once.
+    multipliers: HashMap<String, u16>,
+}
+
+impl DriverRenames {
+    /// How many times the points of a race count, from the tags of its event.
+    fn multiplier(&self, race: &str) -> u16 {
+        self.multipliers
+            .get(&race.to_uppercase())
+            .copied()
+            .unwrap_or(1)
     }
+}
 
-    fn mixed_guess() -> Guess {
-        Guess {
-            race: "Test GP".to_string(),
-            username: "test".to_string(),
-            p1: "VER".to_string(),
-            p2: "NOR".to_string(),
-            p3: "PIA".to_string(),
-            p4: "LEC".to_string(),
-            p5: "RUS".to_string(),
-        }
+/// Access to the data, shared by every request through managed state along with its caches.
+pub struct Store {
+    db: Arc<RwLock<Db>>,
+    driver_cache: Option<DriverCache>,
+    score_cache: Option<ScoreCache>,
+    scoring: ScoringConfig,
+    /// Variant new password hashes are made with.
+    password_algorithm: Algorithm,
+    /// Read on first use, then kept until the events or drivers are edited. Aliases edited by hand
+    /// in the CSV file are only picked up after a restart.
+    driver_renames: Mutex<Option<Arc<DriverRenames>>>,
+}
+
+impl Store {
+    /// A store on the CSV files in `dir`.
+    pub fn new(dir: &'static str) -> Self {
+        Self::with_db(Arc::new(RwLock::new(Db::new(dir))))
     }
 
-    fn partial_guess() -> Guess {
-        Guess {
-            race: "Test GP".to_string(),
-            username: "test".to_string(),
-            p1: "NOR".to_string(),
-            p2: "HAM".to_string(),
-            p3: "PIA".to_string(),
-            p4: "ANT".to_string(),
-            p5: "LEC".to_string(),
+    fn with_db(db: Arc<RwLock<Db>>) -> Self {
+        Self {
+            db,
+            driver_cache: None,
+            score_cache: None,
+            scoring: ScoringConfig::default(),
+            password_algorithm: Algorithm::default(),
+            driver_renames: Mutex::new(None),
         }
     }
 
-    #[tokio::test]
-    async fn score_guess() {
-        let db = Mutex::new(Database::new("test_data/score_guess/", None));
-        let store = Store::new(State::from(&db));
+    /// Takes the database for reading. Reads share it with each other, only waiting for writes,
+    /// which take it alone.
+    async fn read_db(&self) -> RwLockReadGuard<'_, Db> {
+        let db_lock = self.db.read().await;
 
-        let perfect_score = store
-            .score_guess(&perfect_guess(), &normalized_results())
-            .await;
-        let mixed_score = store
-            .score_guess(&mixed_guess(), &normalized_results())
-            .await;
-        let partial_score = store
-            .score_guess(&partial_guess(), &normalized_results())
-            .await;
+        #[cfg(test)]
+        rocket::tokio::time::sleep(db_lock.read_delay).await;
 
-        assert!(perfect_score == 25);
-        assert!(mixed_score == 7);
-        assert!(partial_score == 12);
+        db_lock
     }
 
-    #[tokio::test]
-    async fn scored_guesses() {
-        let db = Mutex::new(Database::new("test_data/scored_guesses/", None));
-        let store = Store::new(State::from(&db));
+    /// Another store on the same database, without caches or settings, for background tasks that
+    /// can't borrow the managed one.
+    pub fn detached(&self) -> Self {
+        Self::with_db(Arc::clone(&self.db))
+    }
 
-        let guesses = [perfect_guess(), mixed_guess(), partial_guess()];
-        let scored_guesses = store.scored_guesses(&guesses, &normalized_results()).await;
+    /// Serves the drivers from a cache, reading the database only when it's empty.
+    pub fn with_driver_cache(mut self) -> Self {
+        self.driver_cache = Some(DriverCache::default());
+        self
+    }
 
-        assert!(
-            scored_guesses[0].points + scored_guesses[1].points + scored_guesses[2].points == 44
-        )
+    /// Serves the scored guesses from a cache, scoring them all only when it's empty.
+    ///
+    /// The cache is only kept up to date by this store, so every write must go through it.
+    pub fn with_score_cache(mut self) -> Self {
+        self.score_cache = Some(ScoreCache::default());
+        self
     }
 
-    #[tokio::test]
-    async fn get_update_guesses() {
-        let db = Mutex::new(Database::new("test_data/update_guess/", None));
-        let store = Store::new(State::from(&db));
+    /// Scores guesses with the configured scoring, rather than without its optional rules.
+    pub fn with_scoring(mut self, scoring: ScoringConfig) -> Self {
+        self.scoring = scoring;
+        self
+    }
 
-        let result = store.update_guess(perfect_guess(), "Test GP").await;
-        let guess = store
-            .get_guesses(Some("test"), Some("Test GP"))
-            .await
-            .unwrap_or_default();
+    /// Hashes passwords with the configured Argon2 variant, rather than Argon2id.
+    pub fn with_argon2(mut self, argon2: &Argon2Config) -> Self {
+        self.password_algorithm = argon2.algorithm();
+        self
+    }
 
-        assert!(!guess.is_empty());
+    /// Adds the columns missing from the CSV file of every table under `path`, so files written by
+    /// older versions have the same columns as new ones. Running it again changes nothing.
+    pub async fn run_migrations(&self, path: impl AsRef<Path>) -> Result<(), DbError> {
+        // Hold the lock so no request reads a table while its file is rewritten.
+        let _db_lock = self.db.write().await;
 
-        assert!(
-            result.is_ok()
-                && guess[0].race == "Test GP"
-                && guess[0].username == "test"
-                && guess[0].p1 == "NOR"
-                && guess[0].p2 == "VER"
-                && guess[0].p3 == "PIA"
-                && guess[0].p4 == "RUS"
-                && guess[0].p5 == "LEC"
-        )
+        Self::migrate::<User>(path.as_ref()).await?;
+        Self::migrate::<Guess>(path.as_ref()).await?;
+        Self::migrate::<ConstructorGuess>(path.as_ref()).await?;
+        Self::migrate::<Driver>(path.as_ref()).await?;
+        Self::migrate::<RaceResult>(path.as_ref()).await?;
+        Self::backfill_race_ids(path.as_ref()).await?;
+
+        Ok(())
+    }
+
+    /// Looks for data the admin forms would have rejected, like drivers edited by hand in the CSV
+    /// file into sharing a code or an active number. Describes each problem found.
+    pub async fn verify(&self) -> Result<Vec<String>, DbError> {
+        let drivers: Vec<Driver> = self
+            .read_db()
+            .await
+            .find("drivers", |_: &Driver| true)
+            .await?;
+
+        Ok(drivers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, driver)| {
+                driver
+                    .conflict(&drivers[..i], None)
+                    .filter(|conflict| {
+                        driver.active || matches!(conflict, DriverConflict::Code { .. })
+                    })
+                    .map(|conflict| format!("{} in drivers.csv: {conflict}", driver.name))
+            })
+            .collect())
+    }
+
+    /// Appends the missing columns of a table to its header and rows. Rows are split on line
+    /// breaks, which is fine as long as no stored value spans several lines.
+    async fn migrate<M: Migrator>(path: &Path) -> Result<(), DbError> {
+        let file = path.join(format!("{}.csv", M::TABLE));
+
+        let content = match fs::read_to_string(&file).await {
+            Ok(content) => content,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(DbError::Io(error)),
+        };
+        let mut lines = content.lines().filter(|line| !line.is_empty());
+        let Some(header) = lines.next() else {
+            return Ok(());
+        };
+
+        let columns: Vec<&str> = header.split(',').collect();
+        let missing: Vec<&(&str, &str)> = M::ADDED_COLUMNS
+            .iter()
+            .filter(|(column, _)| !columns.contains(column))
+            .collect();
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let header_suffix: String = missing
+            .iter()
+            .map(|(column, _)| format!(",{column}"))
+            .collect();
+        let row_suffix: String = missing
+            .iter()
+            .map(|(_, value)| format!(",{value}"))
+            .collect();
+
+        let mut migrated = format!("{header}{header_suffix}\n");
+
+        for row in lines {
+            migrated.push_str(&format!("{row}{row_suffix}\n"));
+        }
+
+        fs::write(&file, migrated).await?;
+
+        info!(
+            "Migrated the {} table, adding the columns {}.",
+            M::TABLE,
+            missing.iter().map(|(column, _)| column).join(", ")
+        );
+
+        Ok(())
+    }
+
+    /// Gives the events without an id the slug of their name, then links the guesses and results
+    /// stored by race name to the id of the event with that name.
+    async fn backfill_race_ids(path: &Path) -> Result<(), DbError> {
+        let Some(mut events) = Self::read_table::<Event>(path, "events").await? else {
+            return Ok(());
+        };
+
+        if events.iter().any(|e| e.id.is_empty()) {
+            events.iter_mut().for_each(Event::fill_id);
+            Self::write_table(path, "events", &events).await?;
+        }
+
+        let ids: HashMap<String, String> = events
+            .into_iter()
+            .map(|e| (e.name.to_uppercase(), e.id))
+            .collect();
+
+        if let Some(mut guesses) = Self::read_table::<Guess>(path, "guesses").await? {
+            let mut linked = 0;
+
+            for guess in guesses.iter_mut().filter(|g| g.race_id.is_empty()) {
+                if let Some(id) = ids.get(&guess.race.to_uppercase()) {
+                    guess.race_id.clone_from(id);
+                    linked += 1;
+                }
+            }
+
+            if linked > 0 {
+                Self::write_table(path, "guesses", &guesses).await?;
+                info!("Linked {linked} guesses to the id of their race.");
+            }
+        }
+
+        if let Some(mut guesses) =
+            Self::read_table::<ConstructorGuess>(path, "constructor_guesses").await?
+        {
+            let mut linked = 0;
+
+            for guess in guesses.iter_mut().filter(|g| g.race_id.is_empty()) {
+                if let Some(id) = ids.get(&guess.race.to_uppercase()) {
+                    guess.race_id.clone_from(id);
+                    linked += 1;
+                }
+            }
+
+            if linked > 0 {
+                Self::write_table(path, "constructor_guesses", &guesses).await?;
+                info!("Linked {linked} constructor guesses to the id of their race.");
+            }
+        }
+
+        if let Some(mut results) = Self::read_table::<RaceResult>(path, "results").await? {
+            let mut linked = 0;
+
+            for result in results.iter_mut().filter(|r| r.race_id.is_empty()) {
+                if let Some(id) = ids.get(&result.race.to_uppercase()) {
+                    result.race_id.clone_from(id);
+                    linked += 1;
+                }
+            }
+
+            if linked > 0 {
+                Self::write_table(path, "results", &results).await?;
+                info!("Linked {linked} results to the id of their race.");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads every row of a table straight from its file, or `None` when there is no file yet.
+    async fn read_table<T: DeserializeOwned>(
+        path: &Path,
+        table: &str,
+    ) -> Result<Option<Vec<T>>, DbError> {
+        let content = match fs::read_to_string(path.join(format!("{table}.csv"))).await {
+            Ok(content) => content,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(DbError::Io(error)),
+        };
+
+        csv::Reader::from_reader(content.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .map(Some)
+            .map_err(DbError::Csv)
+    }
+
+    /// Rewrites a table with the given rows, in one go rather than row by row.
+    async fn write_table<T: Serialize>(
+        path: &Path,
+        table: &str,
+        rows: &[T],
+    ) -> Result<(), DbError> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+
+        for row in rows {
+            writer.serialize(row).map_err(DbError::Csv)?;
+        }
+
+        let content = writer
+            .into_inner()
+            .map_err(|error| DbError::Csv(csv::Error::from(error.into_error())))?;
+
+        Ok(fs::write(path.join(format!("{table}.csv")), content).await?)
+    }
+
+    pub async fn add_user(
+        &self,
+        username: &str,
+        password: &str,
+        country: Option<String>,
+    ) -> Result<(), DbError> {
+        // SAFETY: The guard is held until the end of this method, across the await points of
+        // both find and insert, so no other registration can slip in between the existence check
+        // and the insert of a user with the same username.
+        let db_lock = self.db.write().await;
+
+        self.insert_user(&db_lock, username, password, country)
+            .await
+    }
+
+    /// Adds a user registering with an invite code, marking the code as used by them. The code is
+    /// only used up once the user is stored, and both happen under a single lock, so a failed
+    /// registration keeps the code and two registrations can't share it.
+    pub async fn add_invited_user(
+        &self,
+        username: &str,
+        password: &str,
+        country: Option<String>,
+        code: &str,
+    ) -> Result<(), StoreError> {
+        let db_lock = self.db.write().await;
+
+        let mut invite_code = Self::unused_invite_code(&db_lock, code).await?;
+
+        self.insert_user(&db_lock, username, password, country)
+            .await?;
+
+        invite_code.used = true;
+        invite_code.used_by = Some(username.to_lowercase());
+
+        db_lock
+            .update("invite_codes", invite_code, |c: &&InviteCode| {
+                c.code == code.trim()
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Inserts a new user unless the username is taken, under the lock of the caller.
+    async fn insert_user(
+        &self,
+        db_lock: &Database<&'static str>,
+        username: &str,
+        password: &str,
+        country: Option<String>,
+    ) -> Result<(), DbError> {
+        let users = db_lock
+            .find("users", |u: &User| {
+                u.username.eq_ignore_ascii_case(username)
+            })
+            .await?;
+
+        if users.is_empty() {
+            let user = User {
+                token: Uuid::new_v4().to_string(),
+                username: username.to_lowercase(),
+                password: self
+                    .hash_password(password)
+                    .await
+                    .map_err(|_| DbError::NoMatch)?,
+                country: country.unwrap_or_default(),
+                preferred_driver: None,
+                timezone: String::new(),
+                avatar: None,
+                dark_mode: false,
+                compact_tables: false,
+                version: 0,
+            };
+
+            db_lock.insert("users", user).await
+        } else {
+            Err(DbError::Io(Error::from(ErrorKind::AlreadyExists)))
+        }
+    }
+
+    /// Stores the user with the given token, as long as the update is based on its latest version.
+    /// Otherwise it returns a `StoreError::Conflict`.
+    pub async fn update_user(&self, mut user: User, token: &str) -> Result<(), StoreError> {
+        let db_lock = self.db.write().await;
+
+        let stored = db_lock
+            .find_first("users", |u: &User| u.token == token)
+            .await?
+            .ok_or(DbError::NoMatch)?;
+
+        if stored.version != user.version {
+            return Err(StoreError::Conflict);
+        }
+
+        user.version = stored.version + 1;
+
+        Ok(db_lock
+            .update("users", user, |u: &&User| u.token == token)
+            .await?)
+    }
+
+    pub async fn get_user(&self, token: &str) -> Option<User> {
+        self.read_db()
+            .await
+            .find_first("users", |u: &User| u.token == token)
+            .await
+            .ok()?
+    }
+
+    /// Authenticates a credential against the stored user tokens.
+    ///
+    /// Both the session cookie and the `X-API-Key` header end up here. The session cookie is
+    /// private, so it is already decrypted by `get_private` before reaching this function, while the
+    /// API key is sent in plain text. Either way the credential is the user's token.
+    pub async fn authenticate(&self, credential: &str) -> Option<User> {
+        self.get_user(credential).await
+    }
+
+    /// Checks the credentials of a user and, if they are valid, rotates their token so that a
+    /// previously leaked token can't be used anymore. Returns the new token.
+    ///
+    /// Hashes name their variant, so they verify whichever one they were made with. Since the
+    /// password is known at this point, a hash made with another variant than the configured one
+    /// is replaced.
+    pub async fn validate_user(&self, username: &str, password: &str) -> Option<String> {
+        // Hold the lock until the new token is stored, so no other login can rotate it meanwhile.
+        let db_lock = self.db.write().await;
+
+        let mut user = db_lock
+            .find_first("users", |u: &User| {
+                u.username.eq_ignore_ascii_case(username)
+            })
+            .await
+            .ok()
+            .flatten()?;
+
+        let parsed_hash = PasswordHash::new(&user.password).ok()?;
+
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .ok()?;
+
+        if parsed_hash.algorithm != self.password_algorithm.ident() {
+            user.password = self.hash_password(password).await.ok()?;
+        }
+
+        let old_token = user.token.clone();
+        user.token = Uuid::new_v4().to_string();
+        let token = user.token.clone();
+
+        // If the new token can't be stored, the login fails rather than handing out a token
+        // that doesn't authenticate.
+        db_lock
+            .update("users", user, |u: &&User| u.token == old_token)
+            .await
+            .ok()?;
+
+        Some(token)
+    }
+
+    /// Gives the user a new token, so that every session and API key using the old one stops
+    /// authenticating, such as after a password change. Returns the new token.
+    pub async fn invalidate_sessions(&self, username: &str) -> Result<String, StoreError> {
+        let db_lock = self.db.write().await;
+
+        let mut user = db_lock
+            .find_first("users", |u: &User| {
+                u.username.eq_ignore_ascii_case(username)
+            })
+            .await?
+            .ok_or(DbError::NoMatch)?;
+
+        let old_token = user.token.clone();
+        user.token = Uuid::new_v4().to_string();
+        let token = user.token.clone();
+
+        db_lock
+            .update("users", user, |u: &&User| u.token == old_token)
+            .await?;
+
+        Ok(token)
+    }
+
+    /// Creates a new invite code on behalf of an admin.
+    pub async fn create_invite_code(&self, admin_username: &str) -> Result<InviteCode, DbError> {
+        let invite_code = InviteCode {
+            code: Uuid::new_v4().simple().to_string(),
+            created_by: admin_username.to_lowercase(),
+            used: false,
+            used_by: None,
+            created_at: Utc::now(),
+        };
+
+        self.db
+            .write()
+            .await
+            .insert("invite_codes", invite_code.clone())
+            .await?;
+
+        Ok(invite_code)
+    }
+
+    /// Finds an invite code that wasn't used yet, under the lock of the caller. Unknown codes fail
+    /// with `DbError::NoMatch` and used ones with `StoreError::Used`.
+    async fn unused_invite_code(db_lock: &Db, code: &str) -> Result<InviteCode, StoreError> {
+        let invite_code = db_lock
+            .find_first("invite_codes", |c: &InviteCode| c.code == code.trim())
+            .await?
+            .ok_or(DbError::NoMatch)?;
+
+        if invite_code.used {
+            return Err(StoreError::Used);
+        }
+
+        Ok(invite_code)
+    }
+
+    pub async fn hash_password(&self, password: &str) -> Result<String, &'static str> {
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2 = Argon2::new(
+            self.password_algorithm,
+            Version::default(),
+            Params::default(),
+        );
+
+        Ok(argon2
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|_| "Could not hash password")?
+            .to_string())
+    }
+
+    /// The users whose password hash wasn't made with the configured variant, or can't be parsed.
+    /// Only their next login can convert them, since that needs the password.
+    pub async fn outdated_password_hashes(&self) -> Result<Vec<String>, DbError> {
+        let algorithm = self.password_algorithm.ident();
+
+        Ok(self
+            .read_db()
+            .await
+            .find("users", |u: &User| {
+                PasswordHash::new(&u.password).map_or(true, |hash| hash.algorithm != algorithm)
+            })
+            .await?
+            .into_iter()
+            .map(|u| u.username)
+            .collect())
+    }
+
+    pub async fn all_drivers(&self) -> Result<Vec<Driver>, DbError> {
+        let Some(cache) = &self.driver_cache else {
+            return self.read_db().await.find("drivers", |_| true).await;
+        };
+
+        let mut cached_drivers = cache.drivers.lock().await;
+
+        if let Some(drivers) = cached_drivers.as_ref() {
+            return Ok(drivers.clone());
+        }
+
+        let drivers: Vec<Driver> = self.read_db().await.find("drivers", |_| true).await?;
+        *cached_drivers = Some(drivers.clone());
+
+        Ok(drivers)
+    }
+
+    /// The countries users can pick, in the order they were seeded.
+    pub async fn all_countries(&self) -> Result<Vec<Country>, DbError> {
+        self.read_db()
+            .await
+            .find("countries", |_: &Country| true)
+            .await
+    }
+
+    /// Fills the countries collection from the embedded list the first time the server runs.
+    pub async fn seed_countries(&self) -> Result<(), DbError> {
+        if !self.all_countries().await?.is_empty() {
+            return Ok(());
+        }
+
+        let countries = Country::seed().map_err(DbError::Csv)?;
+        let db_lock = self.db.write().await;
+
+        for country in countries {
+            db_lock.insert("countries", country).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every code belongs to an active driver, returning the codes that don't.
+    pub async fn validate_drivers_exist(
+        &self,
+        codes: &[&DriverCode],
+    ) -> Result<(), Vec<DriverCode>> {
+        let drivers = self.all_drivers().await.unwrap_or_default();
+
+        let invalid_codes: Vec<DriverCode> = codes
+            .iter()
+            .filter(|code| !drivers.iter().any(|d| d.active && d.code == ***code))
+            .map(|code| (*code).clone())
+            .collect();
+
+        if invalid_codes.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid_codes)
+        }
+    }
+
+    /// The renamed driver codes, read the first time they're needed.
+    pub async fn driver_renames(&self) -> Arc<DriverRenames> {
+        let mut cached = self.driver_renames.lock().await;
+
+        if let Some(renames) = cached.as_ref() {
+            return Arc::clone(renames);
+        }
+
+        let aliases: Vec<DriverAlias> = self
+            .read_db()
+            .await
+            .find("driver_aliases", |_: &DriverAlias| true)
+            .await
+            .unwrap_or_default();
+        // The race is the last session of an event, so its datetime and tags are the ones of the
+        // latest session with the name.
+        let mut races: HashMap<String, (DateTime<Utc>, u16)> = HashMap::new();
+
+        for event in self.events().await.unwrap_or_default() {
+            let multiplier = self.scoring.multiplier(&event);
+
+            races
+                .entry(event.name.to_uppercase())
+                .and_modify(|race| {
+                    if event.datetime > race.0 {
+                        *race = (event.datetime, multiplier);
+                    }
+                })
+                .or_insert((event.datetime, multiplier));
+        }
+
+        let renames = Arc::new(DriverRenames {
+            aliases,
+            multipliers: races
+                .iter()
+                .filter(|(_, (_, multiplier))| *multiplier != 1)
+                .map(|(race, (_, multiplier))| (race.clone(), *multiplier))
+                .collect(),
+            races: races
+                .into_iter()
+                .map(|(race, (datetime, _))| (race, datetime))
+                .collect(),
+        });
+        *cached = Some(Arc::clone(&renames));
+
+        renames
+    }
+
+    async fn clear_driver_cache(&self) {
+        if let Some(cache) = &self.driver_cache {
+            cache.clear().await;
+        }
+    }
+
+    /// Adds a driver, unless another one already uses the same code or an active one the same
+    /// number.
+    pub async fn add_driver(&self, driver: Driver) -> Result<(), StoreError> {
+        let db_lock = self.db.write().await;
+
+        let drivers: Vec<Driver> = db_lock.find("drivers", |_: &Driver| true).await?;
+
+        if let Some(conflict) = driver.conflict(&drivers, None) {
+            return Err(StoreError::DuplicateDriver(conflict));
+        }
+
+        db_lock.insert("drivers", driver).await?;
+        // Never wait for the cache lock while holding the db lock, as readers of the cache take
+        // them the other way round.
+        drop(db_lock);
+        self.clear_driver_cache().await;
+        self.clear_score_cache().await;
+
+        Ok(())
+    }
+
+    /// Replaces the driver identified by `code`, unless another driver already uses the new code
+    /// or another active one the new number.
+    pub async fn update_driver(&self, code: &DriverCode, driver: Driver) -> Result<(), StoreError> {
+        let db_lock = self.db.write().await;
+
+        let drivers: Vec<Driver> = db_lock.find("drivers", |_: &Driver| true).await?;
+
+        if let Some(conflict) = driver.conflict(&drivers, Some(code)) {
+            return Err(StoreError::DuplicateDriver(conflict));
+        }
+
+        db_lock
+            .update("drivers", driver, |d: &&Driver| d.code == *code)
+            .await?;
+        // Never wait for the cache lock while holding the db lock, as readers of the cache take
+        // them the other way round.
+        drop(db_lock);
+        self.clear_driver_cache().await;
+        self.clear_score_cache().await;
+
+        Ok(())
+    }
+
+    /// Flips the active flag of a driver, for instance when a reserve driver replaces a regular one.
+    /// A driver can't be activated while another active driver uses the same number.
+    pub async fn toggle_driver(&self, code: &DriverCode) -> Result<(), StoreError> {
+        let db_lock = self.db.write().await;
+
+        let drivers: Vec<Driver> = db_lock.find("drivers", |_: &Driver| true).await?;
+        let mut driver = drivers
+            .iter()
+            .find(|d| d.code == *code)
+            .cloned()
+            .ok_or(DbError::NoMatch)?;
+
+        driver.active = !driver.active;
+
+        if driver.active
+            && let Some(conflict) = driver.conflict(&drivers, Some(code))
+        {
+            return Err(StoreError::DuplicateDriver(conflict));
+        }
+
+        db_lock
+            .update("drivers", driver, |d: &&Driver| d.code == *code)
+            .await?;
+        drop(db_lock);
+        self.clear_driver_cache().await;
+
+        Ok(())
+    }
+
+    pub async fn get_guesses(
+        &self,
+        username: Option<&str>,
+        race: Option<&str>,
+    ) -> Result<Vec<Guess>, DbError> {
+        let race_names = self.race_names().await?;
+        let mut guesses: Vec<Guess> = self
+            .read_db()
+            .await
+            .find("guesses", |g: &Guess| filter_by_username(g, username))
+            .await?;
+
+        // Filter by race once the guesses go by the current names of their races.
+        for guess in &mut guesses {
+            current_race_name(&mut guess.race, &guess.race_id, &race_names);
+        }
+        guesses.retain(|g| race.is_none_or(|race| g.race.eq_ignore_ascii_case(race)));
+
+        Ok(guesses)
+    }
+
+    /// The guesses for the upcoming event of the category, keyed by lowercase username. Without an
+    /// upcoming event there are none.
+    pub async fn get_current_guesses(
+        &self,
+        category: &Category,
+    ) -> Result<HashMap<String, Guess>, StoreError> {
+        let current_event = match self.next_event(category).await? {
+            Some(current_event) => current_event,
+            None => return Ok(HashMap::new()),
+        };
+
+        Ok(self
+            .get_guesses(None, Some(&current_event.name))
+            .await?
+            .into_iter()
+            .map(|g| (g.username.to_lowercase(), g))
+            .collect())
+    }
+
+    /// The guess of the user for the most recent race of the category, by race date.
+    pub async fn latest_guess(
+        &self,
+        username: &str,
+        category: &Category,
+    ) -> Result<Option<Guess>, DbError> {
+        let race_datetimes = self.race_datetimes(category).await?;
+
+        Ok(self
+            .get_guesses(Some(username), None)
+            .await?
+            .into_iter()
+            .filter_map(|g| {
+                race_datetimes
+                    .get(&g.race.to_uppercase())
+                    .map(|dt| (*dt, g))
+            })
+            .max_by_key(|(datetime, _)| *datetime)
+            .map(|(_, g)| g))
+    }
+
+    /// Whether the stored guess of the user for the same race is identical to this one.
+    pub async fn is_stored_guess(&self, guess: &Guess) -> Result<bool, DbError> {
+        Ok(self
+            .get_guesses(Some(&guess.username), Some(&guess.race))
+            .await?
+            .iter()
+            .any(|g| g.picks() == guess.picks() && g.category == guess.category))
+    }
+
+    /// Stores the guess of the user for the current race, replacing the previous one only if the
+    /// guess is based on its latest version. Otherwise it returns a `StoreError::Conflict`.
+    /// The timestamps of the guess are set here, ignoring any given by the client.
+    pub async fn update_guess(
+        &self,
+        mut guess: Guess,
+        current_race: &str,
+    ) -> Result<Guess, StoreError> {
+        let now = Utc::now();
+
+        guess.submitted_at = now;
+        guess.updated_at = Some(now);
+
+        let username = guess.username.to_lowercase();
+        let race_id = guess.race_id.clone();
+        // Stored guesses go by the name their race had then, unless they have its id.
+        let is_current = |g: &Guess| {
+            g.username.to_lowercase() == username
+                && same_race(&g.race_id, &g.race, &race_id, current_race)
+        };
+
+        let db_lock = self.db.write().await;
+
+        let stored = db_lock
+            .find_first("guesses", |g: &Guess| is_current(g))
+            .await?;
+
+        match stored {
+            Some(stored) if stored.version != guess.version => return Err(StoreError::Conflict),
+            Some(stored) => {
+                guess.version = stored.version + 1;
+                guess.created_at = stored.created_at;
+
+                db_lock
+                    .update("guesses", guess.clone(), |g: &&Guess| is_current(g))
+                    .await
+                    .map_err(|_| DbError::Io(Error::from(ErrorKind::Other)))?;
+            }
+            None => {
+                guess.version = 1;
+                guess.created_at = Some(now);
+
+                db_lock
+                    .insert("guesses", guess.clone())
+                    .await
+                    .map_err(|_| DbError::Io(Error::from(ErrorKind::Other)))?;
+            }
+        }
+
+        // The cache reads the database while locked, so it's only updated once this is unlocked.
+        drop(db_lock);
+        self.cache_guess(&guess).await?;
+
+        Ok(guess)
+    }
+
+    /// Scores a guess just stored into the score cache, replacing its previous version.
+    async fn cache_guess(&self, guess: &Guess) -> Result<(), DbError> {
+        let Some(cache) = &self.score_cache else {
+            return Ok(());
+        };
+
+        let race_names = self.race_names().await?;
+        let normalized_results = self.normalized_results().await?;
+        let mut guess = guess.clone();
+        current_race_name(&mut guess.race, &guess.race_id, &race_names);
+
+        let mut cached = cache.scored_guesses.lock().await;
+
+        let Some(scored_guesses) = cached.as_mut() else {
+            return Ok(());
+        };
+
+        let scored_guess = self.score_owned(guess, &normalized_results).await;
+
+        for totals in cache.totals.lock().await.values_mut() {
+            totals.update(&scored_guess);
+        }
+
+        // Readers still holding the previous guesses keep them, only this copy is updated.
+        let scored_guesses = Arc::make_mut(scored_guesses);
+        let stored = scored_guesses.iter_mut().find(|sg| {
+            sg.guess
+                .username
+                .eq_ignore_ascii_case(&scored_guess.guess.username)
+                && same_race(
+                    &sg.guess.race_id,
+                    &sg.guess.race,
+                    &scored_guess.guess.race_id,
+                    &scored_guess.guess.race,
+                )
+        });
+
+        match stored {
+            Some(stored) => *stored = scored_guess,
+            None => scored_guesses.push(scored_guess),
+        }
+
+        Ok(())
+    }
+
+    /// Rescores the cached guesses of a race whose result was just stored.
+    async fn cache_result(&self, race_id: &str, race: &str) -> Result<(), DbError> {
+        let Some(cache) = &self.score_cache else {
+            return Ok(());
+        };
+
+        let normalized_results = self.normalized_results().await?;
+        let race_names = self.race_names().await?;
+        let mut race = race.to_string();
+        current_race_name(&mut race, race_id, &race_names);
+
+        let mut cached = cache.scored_guesses.lock().await;
+
+        let Some(scored_guesses) = cached.as_mut() else {
+            return Ok(());
+        };
+
+        let mut totals = cache.totals.lock().await;
+
+        for scored_guess in Arc::make_mut(scored_guesses)
+            .iter_mut()
+            .filter(|sg| same_race(&sg.guess.race_id, &sg.guess.race, race_id, &race))
+        {
+            *scored_guess = self
+                .score_owned(scored_guess.guess.clone(), &normalized_results)
+                .await;
+
+            for totals in totals.values_mut() {
+                totals.update(scored_guess);
+            }
+        }
+
+        // The previous standings move along with the latest race with a result.
+        for totals in totals.values_mut() {
+            totals.previous = None;
+        }
+
+        Ok(())
+    }
+
+    /// Empties the score cache along with the driver renames, which resolve at the race dates.
+    async fn clear_score_cache(&self) {
+        *self.driver_renames.lock().await = None;
+
+        if let Some(cache) = &self.score_cache {
+            cache.clear().await;
+        }
+    }
+
+    pub async fn get_constructor_guesses(
+        &self,
+        username: Option<&str>,
+        race: Option<&str>,
+    ) -> Result<Vec<ConstructorGuess>, DbError> {
+        let race_names = self.race_names().await?;
+        let mut guesses: Vec<ConstructorGuess> = self
+            .read_db()
+            .await
+            .find("constructor_guesses", |g: &ConstructorGuess| {
+                username.is_none_or(|username| g.username.eq_ignore_ascii_case(username))
+            })
+            .await?;
+
+        // Filter by race once the guesses go by the current names of their races, like guesses.
+        for guess in &mut guesses {
+            current_race_name(&mut guess.race, &guess.race_id, &race_names);
+        }
+        guesses.retain(|g| race.is_none_or(|race| g.race.eq_ignore_ascii_case(race)));
+
+        Ok(guesses)
+    }
+
+    pub async fn update_constructor_guess(
+        &self,
+        guess: ConstructorGuess,
+        current_race: &str,
+    ) -> Result<(), DbError> {
+        let username = guess.username.to_lowercase();
+        let race_id = guess.race_id.clone();
+
+        let db_lock = self.db.write().await;
+
+        match db_lock
+            .update(
+                "constructor_guesses",
+                guess.clone(),
+                |g: &&ConstructorGuess| {
+                    g.username.to_lowercase() == username
+                        && same_race(&g.race_id, &g.race, &race_id, current_race)
+                },
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(DbError::NoMatch) => db_lock.insert("constructor_guesses", guess).await,
+            Err(_) => Err(DbError::Io(Error::from(ErrorKind::Other))),
+        }
+    }
+
+    /// Sums the constructor guess points of every user, keyed by username.
+    pub async fn constructor_points(
+        &self,
+        normalized_results: &HashMap<String, RaceResult>,
+    ) -> Result<HashMap<String, Points>, DbError> {
+        let mut points = HashMap::new();
+
+        for guess in self.get_constructor_guesses(None, None).await? {
+            *points.entry(guess.username.clone()).or_default() += self
+                .score_constructor_guess(&guess, normalized_results)
+                .await;
+        }
+
+        Ok(points)
+    }
+
+    pub async fn score_constructor_guess(
+        &self,
+        guess: &ConstructorGuess,
+        normalized_results: &HashMap<String, RaceResult>,
+    ) -> Points {
+        let result = match RaceResult::find(normalized_results, &guess.race_id, &guess.race) {
+            Some(result) => result,
+            None => return Points::ZERO,
+        };
+
+        let points: Points = [(&guess.c1, &result.c1), (&guess.c2, &result.c2)]
+            .into_iter()
+            .filter(|(guess, result)| {
+                result
+                    .as_ref()
+                    .is_some_and(|result| guess.eq_ignore_ascii_case(result))
+            })
+            .map(|_| Points::from(CONSTRUCTOR_CORRECT))
+            .sum();
+
+        points * self.driver_renames().await.multiplier(&guess.race)
+    }
+
+    /// The upcoming race of the category to guess, skipping races tagged as not played. Returns
+    /// `None` once the season is over.
+    pub async fn next_event(&self, category: &Category) -> Result<Option<Event>, DbError> {
+        let now = Utc::now();
+        let mut next_event: Option<Event> = None;
+
+        // Only the earliest race so far is kept while the calendar is read.
+        self.read_db()
+            .await
+            .scan("events", |e: Event| {
+                if e.datetime > now
+                    && Self::is_race(&e, category)
+                    && !e.has_tag(Event::NO_GAME_TAG)
+                    && next_event
+                        .as_ref()
+                        .is_none_or(|next| e.datetime < next.datetime)
+                {
+                    next_event = Some(e);
+                }
+
+                ControlFlow::Continue(())
+            })
+            .await
+            .map_err(Self::events_error)?;
+
+        Ok(next_event.map(|mut e| {
+            e.fill_id();
+            e
+        }))
+    }
+
+    /// Counts the races of the category on the calendar of the season, skipping races tagged as not
+    /// played, like `next_event` does.
+    pub async fn upcoming_events_count(
+        &self,
+        category: &Category,
+        season: u16,
+    ) -> Result<usize, DbError> {
+        Ok(self
+            .find_events(|e| {
+                Self::is_race(e, category)
+                    && !e.has_tag(Event::NO_GAME_TAG)
+                    && e.datetime.year() as u16 == season
+            })
+            .await?
+            .len())
+    }
+
+    /// Counts the races of the category left in the season of `from_datetime`, including a race
+    /// starting right then, so the round of the next race is the season total minus this plus 1.
+    pub async fn events_remaining_count(
+        &self,
+        category: &Category,
+        from_datetime: DateTime<Utc>,
+    ) -> Result<usize, DbError> {
+        Ok(self
+            .find_events(|e| {
+                Self::is_race(e, category)
+                    && !e.has_tag(Event::NO_GAME_TAG)
+                    && e.datetime >= from_datetime
+                    && e.datetime.year() == from_datetime.year()
+            })
+            .await?
+            .len())
+    }
+
+    /// Maps the name of every race, past or upcoming, to its date and time.
+    pub async fn race_datetimes(
+        &self,
+        category: &Category,
+    ) -> Result<HashMap<String, DateTime<Utc>>, DbError> {
+        Ok(self
+            .find_events(|e| Self::is_race(e, category))
+            .await?
+            .into_iter()
+            .map(|e| (e.name.to_uppercase(), e.datetime))
+            .collect())
+    }
+
+    /// Keeps at most `limit` scored guesses, from the most recent race to the oldest and, within
+    /// each race, from the latest updated, then by points. Only the guesses kept are copied, so
+    /// the cost in memory follows the limit rather than the number of guesses.
+    pub async fn latest_scored_guesses<'g>(
+        &self,
+        scored_guesses: impl IntoIterator<Item = &'g ScoredGuessOwned>,
+        limit: usize,
+    ) -> Result<Vec<ScoredGuessOwned>, DbError> {
+        let races = self.race_names_and_dates().await?;
+        let race_datetime = |race: &str| {
+            races
+                .get(&race.to_uppercase())
+                .map(|(_, datetime)| *datetime)
+        };
+
+        // The index keeps the order of guesses that are otherwise equal, and `None` is the
+        // smallest date, so the undated guesses come last in reverse.
+        Ok(scored_guesses
+            .into_iter()
+            .enumerate()
+            .k_smallest_by_key(limit, |(index, sg)| {
+                (
+                    Reverse(race_datetime(sg.race())),
+                    Reverse(sg.guess.updated_at),
+                    Reverse(sg.points),
+                    *index,
+                )
+            })
+            .map(|(_, sg)| {
+                let mut sg = sg.clone();
+                sg.set_undated(race_datetime(sg.race()).is_none());
+                sg
+            })
+            .collect())
+    }
+
+    pub fn is_race(event: &Event, category: &Category) -> bool {
+        event.channel.eq_ignore_ascii_case(&category.channel)
+            && event.in_category(&category.name)
+            && event.description.eq_ignore_ascii_case("race")
+    }
+
+    pub async fn events(&self) -> Result<Vec<Event>, DbError> {
+        self.find_events(|_| true).await
+    }
+
+    /// The events listing `category` among their categories, ignoring case.
+    pub async fn events_by_category(&self, category: &str) -> Result<Vec<Event>, DbError> {
+        self.find_events(|e| e.in_category(category)).await
+    }
+
+    /// Reads the events matching `predicate`. As the events come from another tool, the row that
+    /// fails to parse is logged.
+    async fn find_events(
+        &self,
+        predicate: impl FnMut(&Event) -> bool,
+    ) -> Result<Vec<Event>, DbError> {
+        let mut events = self
+            .read_db()
+            .await
+            .find("events", predicate)
+            .await
+            .map_err(Self::events_error)?;
+
+        events.iter_mut().for_each(Event::fill_id);
+
+        Ok(events)
+    }
+
+    /// Logs the row of the events that failed to parse, since the error alone doesn't tell.
+    fn events_error(error: DbError) -> DbError {
+        match StoreError::in_table("events", error) {
+            StoreError::Db(error) => error,
+            invalid_row => {
+                error!("{invalid_row}");
+                DbError::Io(Error::new(ErrorKind::InvalidData, invalid_row))
+            }
+        }
+    }
+
+    /// Maps the id of every event to its current name, normalized like the race of a guess.
+    async fn race_names(&self) -> Result<HashMap<String, String>, DbError> {
+        Ok(self
+            .events()
+            .await?
+            .into_iter()
+            .map(|e| (e.id, e.name.to_uppercase()))
+            .collect())
+    }
+
+    pub async fn add_event(&self, mut event: Event) -> Result<(), StoreError> {
+        let db_lock = self.db.write().await;
+
+        let events: Vec<Event> = db_lock.find("events", |_: &Event| true).await?;
+
+        if events.iter().any(|e| e.is(&event.name, &event.description)) {
+            return Err(StoreError::AlreadyExists);
+        }
+
+        if event.id.is_empty() {
+            event.id = Event::unique_id(&event.name, &events);
+        }
+
+        db_lock.insert("events", event).await?;
+        drop(db_lock);
+        self.clear_score_cache().await;
+
+        Ok(())
+    }
+
+    /// Replaces the event identified by `name` and `description`, keeping its id so the guesses
+    /// and results of a renamed race stay linked to it.
+    pub async fn update_event(
+        &self,
+        name: &str,
+        description: &str,
+        mut event: Event,
+    ) -> Result<(), StoreError> {
+        let db_lock = self.db.write().await;
+
+        let mut stored = db_lock
+            .find_first("events", |e: &Event| e.is(name, description))
+            .await?
+            .ok_or(DbError::NoMatch)?;
+        stored.fill_id();
+        event.id = stored.id;
+
+        let renamed = !event.is(name, description);
+        let existing = db_lock
+            .find("events", |e: &Event| e.is(&event.name, &event.description))
+            .await?;
+
+        if renamed && !existing.is_empty() {
+            return Err(StoreError::AlreadyExists);
+        }
+
+        db_lock
+            .update("events", event, |e: &&Event| e.is(name, description))
+            .await?;
+        drop(db_lock);
+        self.clear_score_cache().await;
+
+        Ok(())
+    }
+
+    /// Deletes an event, unless it is a race someone already guessed.
+    pub async fn delete_event(&self, name: &str, description: &str) -> Result<(), StoreError> {
+        let db_lock = self.db.write().await;
+
+        let id = db_lock
+            .find_first("events", |e: &Event| e.is(name, description))
+            .await?
+            .map(|mut e| {
+                e.fill_id();
+                e.id
+            })
+            .unwrap_or_default();
+        let guesses = db_lock
+            .find("guesses", |g: &Guess| {
+                same_race(&g.race_id, &g.race, &id, name)
+            })
+            .await?;
+
+        if description.eq_ignore_ascii_case("race") && !guesses.is_empty() {
+            return Err(StoreError::InUse);
+        }
+
+        db_lock
+            .delete("events", |e: &&Event| e.is(name, description))
+            .await?;
+        drop(db_lock);
+        self.clear_score_cache().await;
+
+        Ok(())
+    }
+
+    /// Lists the events flagged with `notify` that start within the next `lookahead_minutes`.
+    pub async fn events_needing_notification(
+        &self,
+        lookahead_minutes: i64,
+    ) -> Result<Vec<Event>, DbError> {
+        let now = Utc::now();
+        let until = now + TimeDelta::minutes(lookahead_minutes);
+
+        self.find_events(|e| e.notify && e.datetime >= now && e.datetime <= until)
+            .await
+    }
+
+    /// Lists the races of a category that already took place but have no result yet, oldest first.
+    pub async fn races_missing_results(&self, category: &Category) -> Result<Vec<Event>, DbError> {
+        let normalized_results = self.normalized_results().await?;
+
+        Ok(self
+            .find_events(|e| {
+                e.datetime <= Utc::now()
+                    && Self::is_race(e, category)
+                    && RaceResult::find(&normalized_results, &e.id, &e.name).is_none()
+            })
+            .await?
+            .into_iter()
+            .sorted_by(|a, b| a.datetime.cmp(&b.datetime))
+            .collect())
+    }
+
+    /// Lists the races of a category that already took place, oldest first, along with the guess of
+    /// the user for each of them and its points once the race has a result.
+    pub async fn event_history(
+        &self,
+        username: &str,
+        category: &Category,
+    ) -> Result<Vec<HistoryEvent>, DbError> {
+        let normalized_results = self.normalized_results().await?;
+        let mut guesses: HashMap<String, Guess> = self
+            .get_guesses(Some(username), None)
+            .await?
+            .into_iter()
+            .map(|g| (g.race.to_uppercase(), g))
+            .collect();
+
+        let events = self
+            .events()
+            .await?
+            .into_iter()
+            .filter(|e| e.datetime <= Utc::now() && Self::is_race(e, category))
+            .sorted_by_key(|e| e.datetime);
+
+        let renames = self.driver_renames().await;
+        let mut history = Vec::new();
+
+        for event in events {
+            let pending = RaceResult::find(&normalized_results, &event.id, &event.name).is_none();
+            let guess = guesses.remove(&event.name.to_uppercase());
+            let points = match &guess {
+                Some(guess) if !pending => Some(score_guess(
+                    guess,
+                    &normalized_results,
+                    &renames,
+                    &self.scoring,
+                )),
+                _ => None,
+            };
+
+            history.push(HistoryEvent {
+                name: event.name,
+                datetime: event.datetime,
+                guess,
+                points,
+                pending,
+            });
+        }
+
+        Ok(history)
+    }
+
+    #[cfg(not(feature = "parallel-scoring"))]
+    pub async fn scored_guesses<'g>(
+        &self,
+        guesses: &'g [Guess],
+        normalized_results: &'g HashMap<String, RaceResult>,
+    ) -> Vec<ScoredGuess<'g>> {
+        let renames = self.driver_renames().await;
+
+        guesses
+            .iter()
+            .map(|g| ScoredGuess {
+                guess: g,
+                points: score_guess(g, normalized_results, &renames, &self.scoring),
+                result: g.result_in(normalized_results),
+                undated: false,
+            })
+            .collect()
+    }
+
+    /// Scores the guesses in parallel on the rayon thread pool, off the async runtime. They are
+    /// copied there along with the results, which only pays off with several cores.
+    #[cfg(feature = "parallel-scoring")]
+    pub async fn scored_guesses<'g>(
+        &self,
+        guesses: &'g [Guess],
+        normalized_results: &'g HashMap<String, RaceResult>,
+    ) -> Vec<ScoredGuess<'g>> {
+        let owned_guesses = guesses.to_vec();
+        let results = normalized_results.clone();
+        let renames = self.driver_renames().await;
+        let pool_renames = Arc::clone(&renames);
+        let scoring = self.scoring.clone();
+
+        let points = match task::spawn_blocking(move || {
+            owned_guesses
+                .par_iter()
+                .map(|g| score_guess(g, &results, &pool_renames, &scoring))
+                .collect::<Vec<Points>>()
+        })
+        .await
+        {
+            Ok(points) => points,
+            Err(error) => match error.try_into_panic() {
+                Ok(panic) => std::panic::resume_unwind(panic),
+                // Only cancelled when the runtime shuts down, in which case the guesses are scored
+                // here instead, one after the other.
+                Err(_) => guesses
+                    .iter()
+                    .map(|g| score_guess(g, normalized_results, &renames, &self.scoring))
+                    .collect(),
+            },
+        };
+
+        guesses
+            .iter()
+            .zip(points)
+            .map(|(g, points)| ScoredGuess {
+                guess: g,
+                points,
+                result: g.result_in(normalized_results),
+                undated: false,
+            })
+            .collect()
+    }
+
+    /// Sorts scored guesses from the most recent race to the oldest, by the date of their event
+    /// rather than the order in which they were stored, keeping the order of those of the same race.
+    /// Guesses whose race matches no event come last, flagged as undated.
+    pub async fn scored_guesses_by_date<G: RaceDated>(
+        &self,
+        mut scored_guesses: Vec<G>,
+    ) -> Result<Vec<G>, DbError> {
+        let races = self.race_names_and_dates().await?;
+        let race_datetime = |race: &str| {
+            races
+                .get(&race.to_uppercase())
+                .map(|(_, datetime)| *datetime)
+        };
+
+        for scored_guess in &mut scored_guesses {
+            scored_guess.set_undated(race_datetime(scored_guess.race()).is_none());
+        }
+        // `None` is the smallest, so the undated guesses come last in reverse.
+        scored_guesses.sort_by_key(|sg| Reverse(race_datetime(sg.race())));
+
+        Ok(scored_guesses)
+    }
+
+    /// Scores every guess, or serves them from the score cache when there is one. Only the first
+    /// read scores them all, the cache being kept up to date afterwards.
+    pub async fn get_all_scored_guesses(&self) -> Result<Arc<Vec<ScoredGuessOwned>>, StoreError> {
+        let Some(cache) = &self.score_cache else {
+            return Ok(Arc::new(self.score_all_guesses().await?));
+        };
+
+        let (_, scored_guesses) = self.lock_scored_guesses(cache).await?;
+
+        Ok(scored_guesses)
+    }
+
+    /// Locks the cached scored guesses, scoring them all first if the cache is empty.
+    async fn lock_scored_guesses<'a>(
+        &self,
+        cache: &'a ScoreCache,
+    ) -> Result<
+        (
+            MutexGuard<'a, Option<Arc<Vec<ScoredGuessOwned>>>>,
+            Arc<Vec<ScoredGuessOwned>>,
+        ),
+        StoreError,
+    > {
+        let mut cached = cache.scored_guesses.lock().await;
+
+        let scored_guesses = match cached.as_ref() {
+            Some(scored_guesses) => Arc::clone(scored_guesses),
+            None => {
+                let scored_guesses = Arc::new(self.score_all_guesses().await?);
+                *cached = Some(Arc::clone(&scored_guesses));
+                scored_guesses
+            }
+        };
+
+        Ok((cached, scored_guesses))
+    }
+
+    /// Fetches every guess and result under a single lock and scores all the guesses.
+    async fn score_all_guesses(&self) -> Result<Vec<ScoredGuessOwned>, StoreError> {
+        let race_names = self.race_names().await?;
+        let (mut guesses, mut results) = {
+            let db_lock = self.read_db().await;
+
+            let guesses: Vec<Guess> = db_lock.find("guesses", |_: &Guess| true).await?;
+            let results: Vec<RaceResult> = db_lock.find("results", |_: &RaceResult| true).await?;
+
+            (guesses, results)
+        };
+
+        for guess in &mut guesses {
+            current_race_name(&mut guess.race, &guess.race_id, &race_names);
+        }
+        for result in &mut results {
+            current_race_name(&mut result.race, &result.race_id, &race_names);
+        }
+
+        let normalized_results: HashMap<String, RaceResult> = results
+            .into_iter()
+            .map(|r| (r.key().to_string(), r))
+            .collect();
+
+        let mut scored_guesses = Vec::with_capacity(guesses.len());
+
+        for guess in guesses {
+            scored_guesses.push(self.score_owned(guess, &normalized_results).await);
+        }
+
+        Ok(scored_guesses)
+    }
+
+    async fn score_owned(
+        &self,
+        guess: Guess,
+        normalized_results: &HashMap<String, RaceResult>,
+    ) -> ScoredGuessOwned {
+        let breakdown = self.score_breakdown(&guess, normalized_results).await;
+
+        ScoredGuessOwned {
+            result: guess.result_in(normalized_results).cloned(),
+            guess,
+            points: breakdown.total(),
+            breakdown,
+            hidden: false,
+            undated: false,
+        }
+    }
+
+    /// Owns a scored guess along with the breakdown of its points.
+    pub async fn scored_guess_owned(
+        &self,
+        scored_guess: &ScoredGuess<'_>,
+        normalized_results: &HashMap<String, RaceResult>,
+    ) -> ScoredGuessOwned {
+        let breakdown = self
+            .score_breakdown(scored_guess.guess, normalized_results)
+            .await;
+
+        ScoredGuessOwned {
+            guess: scored_guess.guess.clone(),
+            points: breakdown.total(),
+            breakdown,
+            result: scored_guess.result.cloned(),
+            hidden: false,
+            undated: scored_guess.undated,
+        }
+    }
+
+    /// Scores a guess with the renamed drivers and scoring of the store.
+    pub async fn score_breakdown(
+        &self,
+        guess: &Guess,
+        normalized_results: &HashMap<String, RaceResult>,
+    ) -> ScoreBreakdown {
+        let renames = self.driver_renames().await;
+
+        score_breakdown(guess, normalized_results, &renames, &self.scoring)
+    }
+
+    /// The results keyed by the id of their event, or by their name without one. Look them up with
+    /// `RaceResult::find`, which handles both.
+    pub async fn normalized_results(&self) -> Result<HashMap<String, RaceResult>, DbError> {
+        let results = self.results().await?;
+
+        Ok(results
+            .into_iter()
+            .map(|r| (r.key().to_string(), r))
+            .collect())
+    }
+
+    /// Whether the result of `race` was entered, ignoring case.
+    pub async fn race_has_result(&self, race: &str) -> Result<bool, DbError> {
+        Ok(self
+            .results()
+            .await?
+            .iter()
+            .any(|r| r.race.eq_ignore_ascii_case(race)))
+    }
+
+    pub async fn results(&self) -> Result<Vec<RaceResult>, DbError> {
+        let race_names = self.race_names().await?;
+        let mut results: Vec<RaceResult> = self
+            .read_db()
+            .await
+            .find("results", |_: &RaceResult| true)
+            .await?;
+
+        for result in &mut results {
+            current_race_name(&mut result.race, &result.race_id, &race_names);
+        }
+
+        Ok(results)
+    }
+
+    /// Results in the order their races took place, since they aren't necessarily entered in order.
+    /// Results of races without an event come first, as there's no way to tell when they happened.
+    pub async fn results_sorted(&self) -> Result<Vec<RaceResult>, StoreError> {
+        let race_datetimes: HashMap<String, DateTime<Utc>> = self
+            .events()
+            .await?
+            .into_iter()
+            .filter(|e| e.description.eq_ignore_ascii_case("race"))
+            .map(|e| (e.name.to_lowercase(), e.datetime))
+            .collect();
+
+        Ok(self
+            .results()
+            .await?
+            .into_iter()
+            .sorted_by_key(|r| race_datetimes.get(&r.race.to_lowercase()).copied())
+            .collect())
+    }
+
+    /// Stores the result of a race, replacing any previous one, and records when it was entered.
+    pub async fn upsert_result(&self, mut result: RaceResult) -> Result<(), DbError> {
+        if result.race_id.is_empty()
+            && let Some(event) = self
+                .find_events(|e| e.name.eq_ignore_ascii_case(&result.race))
+                .await?
+                .into_iter()
+                .next()
+        {
+            result.race_id = event.id;
+        }
+
+        let race = result.race.clone();
+        let race_id = result.race_id.clone();
+        result.entered_at = Some(Utc::now());
+
+        let db_lock = self.db.write().await;
+
+        match db_lock
+            .update("results", result.clone(), |r: &&RaceResult| {
+                same_race(&r.race_id, &r.race, &race_id, &race)
+            })
+            .await
+        {
+            Ok(_) => {}
+            Err(DbError::NoMatch) => db_lock.insert("results", result).await?,
+            Err(e) => return Err(e),
+        }
+
+        drop(db_lock);
+        self.cache_result(&race_id, &race).await
+    }
+
+    /// When a result was last entered, or `None` if no result records it.
+    pub async fn results_updated_at(&self) -> Result<Option<DateTime<Utc>>, DbError> {
+        Ok(self
+            .results()
+            .await?
+            .into_iter()
+            .filter_map(|r| r.entered_at)
+            .max())
+    }
+
+    pub async fn get_users(&self, username: Option<&str>) -> Result<Vec<User>, DbError> {
+        self.read_db()
+            .await
+            .find("users", |u: &User| {
+                if let Some(username) = username {
+                    u.username.to_lowercase().contains(username)
+                } else {
+                    true
+                }
+            })
+            .await
+    }
+
+    /// Collects everything stored about a user, so they can download it.
+    pub async fn export_user_data(&self, username: &str) -> Result<UserDataExport, StoreError> {
+        let user = self
+            .read_db()
+            .await
+            .find_first("users", |u: &User| {
+                u.username.eq_ignore_ascii_case(username)
+            })
+            .await?
+            .ok_or(DbError::NoMatch)?;
+
+        Ok(UserDataExport {
+            user: user.into(),
+            guesses: self.get_guesses(Some(username), None).await?,
+            constructor_guesses: self.get_constructor_guesses(Some(username), None).await?,
+        })
+    }
+
+    /// Avatar URLs of the users who uploaded one, by lowercase username.
+    pub async fn avatars(&self) -> Result<HashMap<String, String>, DbError> {
+        Ok(self
+            .get_users(None)
+            .await?
+            .into_iter()
+            .filter_map(|u| Some((u.username.to_lowercase(), u.avatar?)))
+            .collect())
+    }
+
+    /// Counts the players who guessed each race, keyed by the uppercase race name.
+    pub async fn guess_count_per_round(&self) -> Result<HashMap<String, usize>, StoreError> {
+        let guesses = self.get_guesses(None, None).await?;
+
+        Ok(guesses
+            .iter()
+            .map(|g| (g.race.to_uppercase(), g.username.to_lowercase()))
+            .unique()
+            .counts_by(|(race, _)| race))
+    }
+
+    /// Participation in every race with guesses, sorted by the date of the race. Races no longer
+    /// in the calendar come last.
+    pub async fn participation(&self) -> Result<Vec<ParticipationStat>, StoreError> {
+        let counts = self.guess_count_per_round().await?;
+        let users_count = self.users_count().await?;
+        let races = self.race_names_and_dates().await?;
+
+        Ok(counts
+            .into_iter()
+            .map(|(race, guesses)| {
+                let (name, datetime) = races
+                    .get(&race)
+                    .map_or((race.clone(), None), |(name, datetime)| {
+                        (name.clone(), Some(*datetime))
+                    });
+                let participation_rate = if users_count == 0 {
+                    0.0
+                } else {
+                    guesses as f32 / users_count as f32
+                };
+
+                (
+                    datetime,
+                    ParticipationStat {
+                        race: name,
+                        guesses,
+                        participation_rate,
+                    },
+                )
+            })
+            .sorted_by_key(|(datetime, stat)| (datetime.is_none(), *datetime, stat.race.clone()))
+            .map(|(_, stat)| stat)
+            .collect())
+    }
+
+    /// Every guess of the user, scored when the race has a result, sorted by the date of the race.
+    /// Races no longer in the calendar come last.
+    pub async fn guess_history(&self, username: &str) -> Result<Vec<GuessHistoryRow>, StoreError> {
+        let scored_guesses = self.get_all_scored_guesses().await?;
+        let normalized_results = self.normalized_results().await?;
+        let races = self.race_names_and_dates().await?;
+
+        Ok(scored_guesses
+            .iter()
+            .filter(|sg| sg.guess.username.eq_ignore_ascii_case(username))
+            .cloned()
+            .map(|sg| {
+                let race = sg.guess.race.to_uppercase();
+                let scored =
+                    RaceResult::find(&normalized_results, &sg.guess.race_id, &race).is_some();
+                let date = races.get(&race).map(|(_, datetime)| *datetime);
+                let mut picks = sg.guess.positions.into_iter();
+
+                GuessHistoryRow {
+                    race: sg.guess.race,
+                    date,
+                    p1: picks.next(),
+                    p2: picks.next(),
+                    p3: picks.next(),
+                    p4: picks.next(),
+                    p5: picks.next(),
+                    points: scored.then_some(sg.points),
+                    scored,
+                }
+            })
+            .sorted_by_key(|row| (row.date.is_none(), row.date, row.race.clone()))
+            .collect())
+    }
+
+    /// Maps the uppercase name of every event to its name and the datetime of its race. The race
+    /// is the last session of an event, so that's the latest datetime with the name.
+    async fn race_names_and_dates(
+        &self,
+    ) -> Result<HashMap<String, (String, DateTime<Utc>)>, DbError> {
+        let mut races: HashMap<String, (String, DateTime<Utc>)> = HashMap::new();
+
+        for event in self.events().await? {
+            races
+                .entry(event.name.to_uppercase())
+                .and_modify(|(_, datetime)| *datetime = (*datetime).max(event.datetime))
+                .or_insert((event.name, event.datetime));
+        }
+
+        Ok(races)
+    }
+
+    pub async fn users_count(&self) -> Result<usize, DbError> {
+        Ok(self.get_users(None).await?.len())
+    }
+
+    /// Computes the leaderboard of a single season, or the all-time leaderboard when `season` is
+    /// `None`. The season of a race is the year of its event. `primary` tells whether `category`
+    /// is the primary category, which the guesses stored without one belong to.
+    pub async fn leaderboard_for_season(
+        &self,
+        category: &Category,
+        primary: bool,
+        season: Option<u16>,
+        include_constructor: bool,
+    ) -> Result<Vec<(String, Points, u32)>, StoreError> {
+        let (races, players) = self
+            .with_season_totals(category, primary, season, |totals| {
+                (totals.races.clone(), totals.players.clone())
+            })
+            .await?;
+
+        self.leaderboard_for_totals(players, include_constructor, |race| {
+            races.contains_key(&race.to_uppercase())
+        })
+        .await
+    }
+
+    /// Computes the leaderboard of a season as it was before the latest race of the season with a
+    /// result, or returns `None` while none of its races has a result.
+    pub async fn previous_leaderboard_for_season(
+        &self,
+        category: &Category,
+        primary: bool,
+        season: Option<u16>,
+        include_constructor: bool,
+    ) -> Result<Option<Vec<(String, Points, u32)>>, StoreError> {
+        let normalized_results = self.normalized_results().await?;
+        let previous = self
+            .with_season_totals(category, primary, season, |totals| {
+                let latest_scored = totals.latest_scored(&normalized_results);
+                let players = totals.previous(&normalized_results).cloned();
+
+                latest_scored.zip(players).map(|(latest_scored, players)| {
+                    let races: HashSet<String> = totals
+                        .races
+                        .iter()
+                        .filter(|(_, datetime)| **datetime < latest_scored)
+                        .map(|(race, _)| race.clone())
+                        .collect();
+
+                    (races, players)
+                })
+            })
+            .await?;
+
+        let Some((races, players)) = previous else {
+            return Ok(None);
+        };
+
+        self.leaderboard_for_totals(players, include_constructor, |race| {
+            races.contains(&race.to_uppercase())
+        })
+        .await
+        .map(Some)
+    }
+
+    /// Runs `f` on the running totals of a season, kept in the score cache from the first read on.
+    /// Without a cache they're summed from every scored guess instead.
+    async fn with_season_totals<T>(
+        &self,
+        category: &Category,
+        primary: bool,
+        season: Option<u16>,
+        f: impl FnOnce(&mut SeasonTotals) -> T,
+    ) -> Result<T, StoreError> {
+        let key = (category.name.to_lowercase(), season);
+
+        let Some(cache) = &self.score_cache else {
+            let races = self.season_races(category, season).await?;
+            let scored_guesses = self.score_all_guesses().await?;
+
+            return Ok(f(&mut SeasonTotals::new(
+                races,
+                category,
+                primary,
+                &scored_guesses,
+            )));
+        };
+
+        // The scored guesses stay locked until the totals are, so no update goes in between.
+        let (_scored_lock, scored_guesses) = self.lock_scored_guesses(cache).await?;
+        let mut totals = cache.totals.lock().await;
+
+        if let Some(totals) = totals.get_mut(&key) {
+            return Ok(f(totals));
+        }
+
+        let races = self.season_races(category, season).await?;
+        let totals = totals
+            .entry(key)
+            .or_insert_with(|| SeasonTotals::new(races, category, primary, &scored_guesses));
+
+        Ok(f(totals))
+    }
+
+    /// Datetimes of the races of a category in one season, or in every season, by uppercase name.
+    async fn season_races(
+        &self,
+        category: &Category,
+        season: Option<u16>,
+    ) -> Result<HashMap<String, DateTime<Utc>>, DbError> {
+        Ok(self
+            .race_datetimes(category)
+            .await?
+            .into_iter()
+            .filter(|(_, datetime)| season.is_none_or(|season| datetime.year() == season as i32))
+            .collect())
+    }
+
+    /// Turns the points and wins of the players into a leaderboard, adding the constructor guess
+    /// points of the races `included` when they count.
+    async fn leaderboard_for_totals(
+        &self,
+        players: HashMap<String, (Points, u32)>,
+        include_constructor: bool,
+        included: impl Fn(&str) -> bool,
+    ) -> Result<Vec<(String, Points, u32)>, StoreError> {
+        let constructor_points = if include_constructor {
+            let included_results: HashMap<String, RaceResult> = self
+                .normalized_results()
+                .await?
+                .into_iter()
+                .filter(|(_, result)| included(&result.race))
+                .collect();
+
+            self.constructor_points(&included_results).await?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(self.leaderboard_rows(players, &constructor_points).await)
+    }
+
+    /// Builds the leaderboard of the races of a category taking place between `from` and `to`,
+    /// both inclusive, for competitions covering only some rounds of a season.
+    pub async fn leaderboard_for_date_range(
+        &self,
+        category: &Category,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        scored_guesses: &[ScoredGuessOwned],
+        include_constructor: bool,
+    ) -> Result<Vec<(String, Points, u32)>, DbError> {
+        let race_datetimes = self.race_datetimes(category).await?;
+        let in_range = |race: &str| {
+            race_datetimes
+                .get(&race.to_uppercase())
+                .is_some_and(|datetime| (from..=to).contains(datetime))
+        };
+
+        self.leaderboard_for_races(scored_guesses, include_constructor, in_range)
+            .await
+    }
+
+    async fn leaderboard_for_races(
+        &self,
+        scored_guesses: &[ScoredGuessOwned],
+        include_constructor: bool,
+        included: impl Fn(&str) -> bool,
+    ) -> Result<Vec<(String, Points, u32)>, DbError> {
+        let grouped_guesses = scored_guesses
+            .iter()
+            .filter(|g| included(&g.guess.race))
+            .into_group_map_by(|g| &g.guess.username);
+
+        let constructor_points = if include_constructor {
+            let included_results: HashMap<String, RaceResult> = self
+                .normalized_results()
+                .await?
+                .into_iter()
+                .filter(|(_, result)| included(&result.race))
+                .collect();
+
+            self.constructor_points(&included_results).await?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(self.leaderboard(grouped_guesses, &constructor_points).await)
+    }
+
+    /// Finds the player right above `username` on the all-time leaderboard, or right below when
+    /// the user leads it. Returns `None` when the user hasn't played or is the only player.
+    pub async fn find_rival(
+        &self,
+        username: &str,
+        normalized_results: &HashMap<String, RaceResult>,
+    ) -> Result<Option<RivalInfo>, StoreError> {
+        let guesses = self.get_guesses(None, None).await?;
+        let leaderboard: Vec<(String, Points)> = self
+            .scored_guesses(&guesses, normalized_results)
+            .await
+            .into_iter()
+            .into_grouping_map_by(|sg| sg.guess.username.to_lowercase())
+            .fold(Points::ZERO, |points, _, sg| points + sg.points)
+            .into_iter()
+            .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+            .collect();
+
+        let Some(position) = leaderboard
+            .iter()
+            .position(|(name, _)| name.eq_ignore_ascii_case(username))
+        else {
+            return Ok(None);
+        };
+
+        let points = leaderboard[position].1;
+        let rival_position = if position > 0 {
+            position - 1
+        } else {
+            position + 1
+        };
+
+        Ok(leaderboard
+            .get(rival_position)
+            .map(|(rival, rival_points)| RivalInfo {
+                username: rival.clone(),
+                points: *rival_points,
+                points_difference: rival_points.get() as i32 - points.get() as i32,
+            }))
+    }
+
+    /// For each of the 5 positions, the share of the guesses of the user for races with a result
+    /// where the pick was exact, in the top 5 but elsewhere, or not in the top 5.
+    pub async fn guess_accuracy_by_position(
+        &self,
+        username: &str,
+        normalized_results: &HashMap<String, RaceResult>,
+    ) -> Result<[PositionAccuracy; 5], StoreError> {
+        // Exact, wrong place and missed picks of every position.
+        let mut counts = [[0u32; 3]; 5];
+
+        for guess in self.get_guesses(Some(username), None).await? {
+            let Some(result) = guess.result_in(normalized_results) else {
+                continue;
+            };
+            let result_positions = result.top_five();
+
+            // Picks after P5 have no accuracy of their own.
+            for (pos, guess_driver) in guess.positions.iter().take(5).enumerate() {
+                let outcome = if guess_driver == result_positions[pos] {
+                    0
+                } else if result_positions.contains(&guess_driver) {
+                    1
+                } else {
+                    2
+                };
+
+                counts[pos][outcome] += 1;
+            }
+        }
+
+        Ok(std::array::from_fn(|pos| {
+            let [correct, wrong_place, missed] = counts[pos];
+            let total = (correct + wrong_place + missed).max(1) as f32;
+
+            PositionAccuracy {
+                position: pos as u8 + 1,
+                correct_rate: correct as f32 / total,
+                wrong_place_rate: wrong_place as f32 / total,
+                miss_rate: missed as f32 / total,
+            }
+        }))
+    }
+
+    /// The most popular pick of every position among the guesses for `race`, with how much the
+    /// players agreed on them. Without any guess, every pick is empty and the score 0.
+    pub async fn most_popular_guesses_for_race(
+        &self,
+        race: &str,
+    ) -> Result<CrowdGuess, StoreError> {
+        let guesses = self.get_guesses(None, Some(race)).await?;
+
+        if guesses.is_empty() {
+            return Ok(CrowdGuess::default());
+        }
+
+        let popular: [(String, f32); 5] = std::array::from_fn(|pos| {
+            guesses
+                .iter()
+                .filter_map(|g| g.positions.get(pos).map(DriverCode::to_string))
+                .counts()
+                .into_iter()
+                .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+                .map(|(code, count)| (code, count as f32 / guesses.len() as f32))
+                .next()
+                .unwrap_or_default()
+        });
+        let consensus_score = popular.iter().map(|(_, rate)| rate).sum::<f32>() / 5.0;
+        let [p1, p2, p3, p4, p5] = popular.map(|(code, _)| code);
+
+        Ok(CrowdGuess {
+            p1_popular: p1,
+            p2_popular: p2,
+            p3_popular: p3,
+            p4_popular: p4,
+            p5_popular: p5,
+            consensus_score,
+        })
+    }
+
+    /// Counts the guesses of the user that earned the parlay bonus, with all 5 positions right.
+    pub async fn parlay_count(
+        &self,
+        username: &str,
+        normalized_results: &HashMap<String, RaceResult>,
+    ) -> Result<u32, StoreError> {
+        let mut count = 0;
+
+        for guess in self.get_guesses(Some(username), None).await? {
+            if self
+                .score_breakdown(&guess, normalized_results)
+                .await
+                .parlay
+                > Points::ZERO
+            {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Every player who earned the parlay bonus with how many times they did, most first.
+    pub async fn parlay_leaderboard(&self) -> Result<Vec<(String, u32)>, StoreError> {
+        let mut counts: HashMap<String, u32> = HashMap::new();
+
+        for scored_guess in self.get_all_scored_guesses().await?.iter() {
+            if scored_guess.breakdown.parlay > Points::ZERO {
+                *counts
+                    .entry(scored_guess.guess.username.to_lowercase())
+                    .or_default() += 1;
+            }
+        }
+
+        Ok(counts
+            .into_iter()
+            .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+            .collect())
+    }
+
+    /// Counts the past races of the category the user guessed in a row, from the latest one back
+    /// to the first race they missed. Races tagged as not played don't break the streak.
+    pub async fn guess_streak(
+        &self,
+        username: &str,
+        category: &Category,
+    ) -> Result<u32, StoreError> {
+        let guessed_races: Vec<String> = self
+            .get_guesses(Some(username), None)
+            .await?
+            .into_iter()
+            .map(|g| g.race.to_uppercase())
+            .collect();
+
+        Ok(self
+            .events_by_category(&category.name)
+            .await?
+            .into_iter()
+            .filter(|e| {
+                e.datetime <= Utc::now()
+                    && Self::is_race(e, category)
+                    && !e.has_tag(Event::NO_GAME_TAG)
+            })
+            .sorted_by_key(|e| Reverse(e.datetime))
+            .take_while(|e| guessed_races.contains(&e.name.to_uppercase()))
+            .count() as u32)
+    }
+
+    /// Aggregates the guesses of every player in the latest season of the category.
+    pub async fn site_stats(&self, category: &Category) -> Result<SiteStats, StoreError> {
+        let (season, races) = self.latest_season_races(category).await?;
+
+        let scored_guesses: Vec<ScoredGuessOwned> = self
+            .get_all_scored_guesses()
+            .await?
+            .iter()
+            .filter(|sg| races.contains(&sg.guess.race.to_uppercase()))
+            .cloned()
+            .collect();
+
+        Ok(SiteStats::new(
+            season,
+            &scored_guesses,
+            &self.normalized_results().await?,
+        ))
+    }
+
+    /// Aggregates the picks and finishes of a driver in the latest season of the category, or
+    /// returns `None` when no driver has the code.
+    pub async fn driver_stats(
+        &self,
+        category: &Category,
+        code: &DriverCode,
+    ) -> Result<Option<DriverStats>, StoreError> {
+        let Some(driver) = self
+            .all_drivers()
+            .await?
+            .into_iter()
+            .find(|d| d.code == *code)
+        else {
+            return Ok(None);
+        };
+        let (season, races) = self.latest_season_races(category).await?;
+
+        let guesses = self
+            .get_guesses(None, None)
+            .await?
+            .into_iter()
+            .filter(|g| races.contains(&g.race.to_uppercase()))
+            .collect::<Vec<Guess>>();
+        let results = self
+            .results()
+            .await?
+            .into_iter()
+            .filter(|r| races.contains(&r.race.to_uppercase()))
+            .collect::<Vec<RaceResult>>();
+
+        Ok(Some(DriverStats::new(driver, season, &guesses, &results)))
+    }
+
+    /// The latest season of the category along with the names of its races, in uppercase.
+    async fn latest_season_races(
+        &self,
+        category: &Category,
+    ) -> Result<(Option<u16>, HashSet<String>), DbError> {
+        let season = self.seasons(category).await?.first().copied();
+
+        let races = self
+            .race_datetimes(category)
+            .await?
+            .into_iter()
+            .filter(|(_, datetime)| season.is_some_and(|season| datetime.year() == season as i32))
+            .map(|(race, _)| race)
+            .collect();
+
+        Ok((season, races))
+    }
+
+    /// Builds the race × user score matrix of a season, or of every season when `season` is `None`.
+    /// Races are sorted by date and users alphabetically. Returns `None` when the matrix would
+    /// have more than `max_cells` cells.
+    pub async fn score_matrix(
+        &self,
+        category: &Category,
+        season: Option<u16>,
+        guesses: &[Guess],
+        normalized_results: &HashMap<String, RaceResult>,
+        max_cells: usize,
+    ) -> Result<Option<ScoreMatrix>, DbError> {
+        let race_datetimes = self.race_datetimes(category).await?;
+        let race_datetime = |race: &str| race_datetimes.get(&race.to_uppercase()).copied();
+
+        let season_guesses: Vec<Guess> = guesses
+            .iter()
+            .filter(|g| {
+                season.is_none_or(|season| {
+                    race_datetime(&g.race).is_some_and(|datetime| datetime.year() == season as i32)
+                })
+            })
+            .cloned()
+            .collect();
+
+        let races: Vec<String> = season_guesses
+            .iter()
+            .map(|g| g.race.clone())
+            .unique()
+            .sorted_by_key(|race| race_datetime(race).unwrap_or(DateTime::<Utc>::MAX_UTC))
+            .collect();
+        let users: Vec<String> = season_guesses
+            .iter()
+            .map(|g| g.username.clone())
+            .unique()
+            .sorted()
+            .collect();
+
+        if races.len() * users.len() > max_cells {
+            return Ok(None);
+        }
+
+        let mut scores = vec![vec![None; users.len()]; races.len()];
+
+        for scored_guess in self
+            .scored_guesses(&season_guesses, normalized_results)
+            .await
+        {
+            let race_idx = races.iter().position(|r| *r == scored_guess.guess.race);
+            let user_idx = users.iter().position(|u| *u == scored_guess.guess.username);
+
+            if let (Some(race_idx), Some(user_idx)) = (race_idx, user_idx) {
+                scores[race_idx][user_idx] = Some(scored_guess.points);
+            }
+        }
+
+        Ok(Some(ScoreMatrix {
+            races,
+            users,
+            scores,
+        }))
+    }
+
+    /// Lists every season with at least one race, most recent first.
+    pub async fn seasons(&self, category: &Category) -> Result<Vec<u16>, DbError> {
+        Ok(self
+            .race_datetimes(category)
+            .await?
+            .values()
+            .map(|datetime| datetime.year() as u16)
+            .unique()
+            .sorted_by(|a, b| b.cmp(a))
+            .collect())
+    }
+
+    pub async fn leaderboard(
+        &self,
+        grouped_guesses: HashMap<&String, Vec<&ScoredGuessOwned>>,
+        constructor_points: &HashMap<String, Points>,
+    ) -> Vec<(String, Points, u32)> {
+        // The best score of every race with a result. Everyone who scored it wins the race, unless
+        // nobody scored at all.
+        let best_scores: HashMap<String, Points> = grouped_guesses
+            .values()
+            .flatten()
+            .filter(|g| g.result.is_some())
+            .into_grouping_map_by(|g| g.guess.race.to_uppercase())
+            .fold(Points::ZERO, |best, _, g| best.max(g.points));
+
+        let players = grouped_guesses
+            .into_iter()
+            .map(|(username, group)| {
+                let wins = group
+                    .iter()
+                    .filter(|g| {
+                        g.points > Points::ZERO
+                            && best_scores.get(&g.guess.race.to_uppercase()) == Some(&g.points)
+                    })
+                    .count() as u32;
+                let points: Points = group.into_iter().map(|g| g.points).sum();
+
+                (username.clone(), (points, wins))
+            })
+            .collect();
+
+        self.leaderboard_rows(players, constructor_points).await
+    }
+
+    /// Sorts the players by points, adding their constructor guess points and labelling each with
+    /// the flag of their country and their preferred driver.
+    async fn leaderboard_rows(
+        &self,
+        players: HashMap<String, (Points, u32)>,
+        constructor_points: &HashMap<String, Points>,
+    ) -> Vec<(String, Points, u32)> {
+        let users: HashMap<String, User> = self
+            .read_db()
+            .await
+            .find("users", |_: &User| true)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|u| (u.username.clone(), u))
+            .collect();
+
+        players
+            .into_iter()
+            .map(|(username, (points, wins))| {
+                let total_points = points
+                    + constructor_points
+                        .get(&username)
+                        .copied()
+                        .unwrap_or_default();
+                let user = users.get(&username);
+                let user_str = format!(
+                    "{} {}{}",
+                    username,
+                    code_to_flag(user.map(|u| u.country.as_str()).unwrap_or_default())
+                        .unwrap_or_default(),
+                    user.and_then(|u| u.preferred_driver.as_ref())
+                        .map(|code| format!(" {code}"))
+                        .unwrap_or_default()
+                );
+                (user_str, total_points, wins)
+            })
+            .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+            .collect()
+    }
+}
+
+/// Whether the guess belongs to the given username, ignoring case. No username matches every
+/// guess.
+fn filter_by_username(g: &Guess, filter: Option<&str>) -> bool {
+    filter.is_none_or(|username| g.username.eq_ignore_ascii_case(username))
+}
+
+/// Scores a guess against the result of its race, or scores nothing while the race has none.
+pub fn score_guess(
+    guess: &Guess,
+    normalized_results: &HashMap<String, RaceResult>,
+    renames: &DriverRenames,
+    scoring: &ScoringConfig,
+) -> Points {
+    score_breakdown(guess, normalized_results, renames, scoring).total()
+}
+
+/// Like `score_guess`, telling which rules the points come from.
+pub fn score_breakdown(
+    guess: &Guess,
+    normalized_results: &HashMap<String, RaceResult>,
+    renames: &DriverRenames,
+    scoring: &ScoringConfig,
+) -> ScoreBreakdown {
+    match guess.result_in(normalized_results) {
+        Some(result) => {
+            let season = renames
+                .races
+                .get(&guess.race.to_uppercase())
+                .map(|datetime| datetime.year() as u16);
+
+            breakdown(
+                guess,
+                result,
+                renames,
+                Points::from(scoring.top_ten_points(season)),
+            )
+            .times(renames.multiplier(&guess.race))
+        }
+        None => ScoreBreakdown::default(),
+    }
+}
+
+/// Scores a guess against the result of its race. It doesn't touch the store, so guesses can be
+/// scored on any thread.
+fn breakdown(
+    guess: &Guess,
+    result: &RaceResult,
+    renames: &DriverRenames,
+    top_ten_points: Points,
+) -> ScoreBreakdown {
+    // Compare the codes the drivers went by at the race, in case any of them was renamed.
+    let race_datetime = renames.races.get(&guess.race.to_uppercase()).copied();
+    let resolve = |code: &DriverCode| match race_datetime {
+        Some(race_datetime) => DriverAlias::resolve(&renames.aliases, code, race_datetime),
+        None => code.clone(),
+    };
+    let guess_positions: Vec<DriverCode> = guess.picks().iter().map(resolve).collect();
+    let result_positions: Vec<DriverCode> = result.top_five().into_iter().map(resolve).collect();
+    let top_ten: Vec<DriverCode> = result.top_ten().map(resolve).collect();
+    // P6 to P10 by position, to score the picks after P5 of longer guesses.
+    let after_five: Vec<Option<DriverCode>> =
+        [&result.p6, &result.p7, &result.p8, &result.p9, &result.p10]
+            .into_iter()
+            .map(|code| code.as_ref().map(resolve))
+            .collect();
+
+    let mut breakdown = ScoreBreakdown::default();
+
+    for (pos, guess_driver) in guess_positions.iter().enumerate() {
+        // A pick after P5 only scores when it's exactly where the driver finished.
+        if pos >= 5 {
+            if after_five
+                .get(pos - 5)
+                .is_some_and(|code| code.as_ref() == Some(guess_driver))
+            {
+                breakdown.top_ten += top_ten_points;
+            }
+        } else if *guess_driver == result_positions[pos] {
+            if pos < 3 {
+                breakdown.podium += Points::from(CORRECT_PODIUM);
+            } else {
+                breakdown.top_five += Points::from(CORRECT_FIVE);
+            }
+        } else if result_positions.contains(guess_driver) {
+            breakdown.wrong_place += Points::from(WRONG_PLACE);
+        } else if top_ten.contains(guess_driver) {
+            breakdown.top_ten += top_ten_points;
+        }
+    }
+
+    if breakdown.podium + breakdown.top_five == 3 * CORRECT_PODIUM + 2 * CORRECT_FIVE {
+        breakdown.parlay = Points::from(PARLAY);
+    }
+
+    breakdown
+}
+
+/// Whether a stored guess or result is for the given race, by the id of its event when both have
+/// one, otherwise by name.
+fn same_race(stored_id: &str, stored_race: &str, race_id: &str, race: &str) -> bool {
+    if stored_id.is_empty() || race_id.is_empty() {
+        stored_race.eq_ignore_ascii_case(race)
+    } else {
+        stored_id == race_id
+    }
+}
+
+/// Renames the race of a guess or result to the current name of its event, which may have been
+/// renamed since. Rows without the id of an event keep their name.
+fn current_race_name(race: &mut String, race_id: &str, race_names: &HashMap<String, String>) {
+    if let Some(name) = race_names.get(race_id) {
+        race.clone_from(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SeasonScoringConfig;
+    use crate::models::LeaderboardEntry;
+    use rocket::tokio;
+
+    fn driver_code(code: &str) -> DriverCode {
+        DriverCode::new(code).expect("The test driver code should be valid")
+    }
+
+    fn normalized_results() -> HashMap<String, RaceResult> {
+        HashMap::from([(
+            "Test GP".to_string(),
+            RaceResult {
+                race: "Test GP".to_string(),
+                p1: driver_code("NOR"),
+                p2: driver_code("VER"),
+                p3: driver_code("PIA"),
+                p4: driver_code("RUS"),
+                p5: driver_code("LEC"),
+                p6: Some(driver_code("HAM")),
+                p7: Some(driver_code("ALO")),
+                p8: Some(driver_code("GAS")),
+                p9: Some(driver_code("SAI")),
+                p10: Some(driver_code("ALB")),
+                c1: Some("MCL".to_string()),
+                c2: Some("MER".to_string()),
+                entered_at: None,
+                race_id: String::new(),
+            },
+        )])
+    }
+
+    fn perfect_guess() -> Guess {
+        Guess {
+            race: "Test GP".to_string(),
+            race_id: String::new(),
+            username: "test".to_string(),
+            positions: ["NOR", "VER", "PIA", "RUS", "LEC"]
+                .map(driver_code)
+                .to_vec(),
+            category: "".to_string(),
+            submitted_at: DateTime::default(),
+            created_at: None,
+            updated_at: None,
+            version: 0,
+        }
+    }
+
+    fn mixed_guess() -> Guess {
+        Guess {
+            race: "Test GP".to_string(),
+            race_id: String::new(),
+            username: "test".to_string(),
+            positions: ["VER", "NOR", "PIA", "LEC", "RUS"]
+                .map(driver_code)
+                .to_vec(),
+            category: "".to_string(),
+            submitted_at: DateTime::default(),
+            created_at: None,
+            updated_at: None,
+            version: 0,
+        }
+    }
+
+    fn partial_guess() -> Guess {
+        Guess {
+            race: "Test GP".to_string(),
+            race_id: String::new(),
+            username: "test".to_string(),
+            positions: ["NOR", "HAM", "PIA", "ANT", "LEC"]
+                .map(driver_code)
+                .to_vec(),
+            category: "".to_string(),
+            submitted_at: DateTime::default(),
+            created_at: None,
+            updated_at: None,
+            version: 0,
+        }
+    }
+
+    #[test]
+    fn score_guess() {
+        let score = |guess: &Guess| {
+            super::score_guess(
+                guess,
+                &normalized_results(),
+                &DriverRenames::default(),
+                &ScoringConfig::default(),
+            )
+        };
+
+        let perfect_score = score(&perfect_guess());
+        let mixed_score = score(&mixed_guess());
+        let partial_score = score(&partial_guess());
+
+        assert!(perfect_score == 25);
+        assert!(mixed_score == 7);
+        assert!(partial_score == 12);
+    }
+
+    #[test]
+    fn score_guess_top_ten() {
+        let scoring = ScoringConfig {
+            top_ten_points: 1,
+            ..Default::default()
+        };
+        let score = |guess: &Guess| {
+            super::score_guess(
+                guess,
+                &normalized_results(),
+                &DriverRenames::default(),
+                &scoring,
+            )
+        };
+
+        // Only HAM, picked P2 by the partial guess, finished from P6 to P10.
+        let perfect_score = score(&perfect_guess());
+        let mixed_score = score(&mixed_guess());
+        let partial_score = score(&partial_guess());
+
+        assert!(perfect_score == 25);
+        assert!(mixed_score == 7);
+        assert!(partial_score == 13);
+
+        // Picks after P5 only score when the driver finished exactly there.
+        let mut longer_guess = perfect_guess();
+        longer_guess
+            .positions
+            .extend(["HAM", "GAS", "ALO"].map(driver_code));
+        let longer_score = score(&longer_guess);
+
+        assert!(longer_score == 26);
+
+        // Seasons scored differently keep their own consolation points.
+        let scoring_2024 = ScoringConfig {
+            seasons: vec![SeasonScoringConfig {
+                season: 2024,
+                include_constructor: false,
+                top_ten_points: 0,
+            }],
+            ..scoring.clone()
+        };
+        let renames = |year| DriverRenames {
+            aliases: Vec::new(),
+            races: HashMap::from([(
+                "TEST GP".to_string(),
+                DateTime::from_naive_utc_and_offset(
+                    chrono::NaiveDate::from_ymd_opt(year, 6, 1)
+                        .and_then(|date| date.and_hms_opt(12, 0, 0))
+                        .unwrap_or_default(),
+                    Utc,
+                ),
+            )]),
+            multipliers: HashMap::new(),
+        };
+
+        for (year, partial_score) in [(2024, 12), (2025, 13)] {
+            assert!(
+                super::score_guess(
+                    &partial_guess(),
+                    &normalized_results(),
+                    &renames(year),
+                    &scoring_2024
+                ) == partial_score
+            );
+        }
+
+        // Generous consolation points add up to the most points a guess can have, not past it.
+        let scoring = ScoringConfig {
+            top_ten_points: u16::MAX,
+            ..Default::default()
+        };
+        let outside_top_five = Guess {
+            positions: ["HAM", "ALO", "GAS", "SAI", "ALB"]
+                .map(driver_code)
+                .to_vec(),
+            ..perfect_guess()
+        };
+        let breakdown = super::score_breakdown(
+            &outside_top_five,
+            &normalized_results(),
+            &DriverRenames::default(),
+            &scoring,
+        );
+
+        assert!(breakdown.top_ten == u16::MAX && breakdown.total() == u16::MAX);
+    }
+
+    /// Scores the same guesses on a single thread and on every core. Both must agree, and with
+    /// more than one core the parallel scoring has to be the faster. Run with
+    /// `cargo test --release --features parallel-scoring parallel_scoring -- --nocapture`.
+    #[cfg(feature = "parallel-scoring")]
+    #[test]
+    fn parallel_scoring() {
+        let guesses: Vec<Guess> = (0..50_000)
+            .map(|i| match i % 3 {
+                0 => perfect_guess(),
+                1 => mixed_guess(),
+                _ => partial_guess(),
+            })
+            .collect();
+        let results = normalized_results();
+        let renames = DriverRenames::default();
+        let scoring = ScoringConfig::default();
+
+        let sequential: Vec<Points> = guesses
+            .iter()
+            .map(|g| super::score_guess(g, &results, &renames, &scoring))
+            .collect();
+        // Four threads, so the guesses are split between workers even on a single core.
+        let Ok(pool) = rayon::ThreadPoolBuilder::new().num_threads(4).build() else {
+            panic!("The thread pool should be built");
+        };
+        let parallel: Vec<Points> = pool.install(|| {
+            guesses
+                .par_iter()
+                .map(|g| super::score_guess(g, &results, &renames, &scoring))
+                .collect()
+        });
+
+        assert!(parallel == sequential);
+    }
+
+    #[test]
+    fn filter_by_username() {
+        let guess = perfect_guess();
+
+        assert!(super::filter_by_username(&guess, Some("TEST")));
+        assert!(super::filter_by_username(&guess, Some("test")));
+        assert!(super::filter_by_username(&guess, None));
+        assert!(!super::filter_by_username(&guess, Some("other")));
+    }
+
+    #[tokio::test]
+    async fn get_guesses_ignores_username_case() {
+        let path = "test_data/get_guesses_ignores_username_case/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category\n\
+                 Test GP,test,NOR,VER,PIA,RUS,LEC,\n\
+                 Test GP,other,VER,NOR,PIA,RUS,LEC,\n",
+            )
+            .is_ok()
+        );
+
+        let guesses = store.get_guesses(Some("TEST"), None).await;
+
+        assert!(guesses.is_ok_and(|guesses| guesses.len() == 1 && guesses[0].username == "test"));
+    }
+
+    #[test]
+    fn score_padded_guess() {
+        let mut result = RaceResult {
+            race: " test  gp".to_string(),
+            p1: driver_code("nor "),
+            p2: driver_code(" ver"),
+            p3: driver_code(" pia "),
+            p4: driver_code("Rus"),
+            p5: driver_code("lec\t"),
+            ..normalized_results().remove("Test GP").unwrap()
+        };
+        result.normalize();
+        let results = HashMap::from([(result.race.clone(), result)]);
+
+        let mut guess = Guess {
+            race: "Test   GP ".to_string(),
+            positions: [" nor", "ver ", "pia", " rus ", "LEC"]
+                .map(driver_code)
+                .to_vec(),
+            ..perfect_guess()
+        };
+        guess.normalize();
+
+        assert!(guess.race == "TEST GP");
+        assert!(
+            super::score_guess(
+                &guess,
+                &results,
+                &DriverRenames::default(),
+                &ScoringConfig::default()
+            ) == 25
+        );
+    }
+
+    #[tokio::test]
+    async fn resolve_driver_code() {
+        let path = "test_data/resolve_driver_code/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],Early GP,Race,2025-03-01 12:00:00 UTC,#formula1,f1,true\n\
+                 [Formula 1],Late GP,Race,2025-09-01 12:00:00 UTC,#formula1,f1,true\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}driver_aliases.csv"),
+                "old_code,new_code,effective_from\nHAM,HMT,2025-06-01T00:00:00Z\n",
+            )
+            .is_ok()
+        );
+
+        let early = "2025-03-01T12:00:00Z".parse().unwrap_or_default();
+        let late = "2025-09-01T12:00:00Z".parse().unwrap_or_default();
+
+        let (ham, hmt, ver) = (driver_code("HAM"), driver_code("HMT"), driver_code("VER"));
+
+        let aliases = &store.driver_renames().await.aliases;
+
+        assert!(DriverAlias::resolve(aliases, &ham, early) == ham);
+        assert!(DriverAlias::resolve(aliases, &hmt, early) == ham);
+        assert!(DriverAlias::resolve(aliases, &ham, late) == hmt);
+        assert!(DriverAlias::resolve(aliases, &ver, late) == ver);
+
+        let result = |race: &str, p1: &str| RaceResult {
+            race: race.to_string(),
+            p1: driver_code(p1),
+            ..normalized_results()["Test GP"].clone()
+        };
+        let normalized_results = HashMap::from([
+            ("EARLY GP".to_string(), result("EARLY GP", "HAM")),
+            ("LATE GP".to_string(), result("LATE GP", "HMT")),
+        ]);
+        let guess = |race: &str, p1: &str| Guess {
+            race: race.to_string(),
+            positions: [p1, "VER", "PIA", "RUS", "LEC"].map(driver_code).to_vec(),
+            ..perfect_guess()
+        };
+
+        // A guess with the old code still scores once the result uses the new one, and the other
+        // way around.
+        for guess in [
+            guess("EARLY GP", "HAM"),
+            guess("EARLY GP", "HMT"),
+            guess("LATE GP", "HAM"),
+            guess("LATE GP", "HMT"),
+        ] {
+            assert!(
+                store
+                    .score_breakdown(&guess, &normalized_results)
+                    .await
+                    .total()
+                    == 25
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn guess_accuracy_by_position() {
+        let store = Store::new("test_data/guess_accuracy_by_position/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("guesses", |_: &&Guess| true)
+                .await
+                .is_ok()
+        );
+
+        let mut normalized_results = normalized_results();
+        normalized_results.insert(
+            "Other GP".to_string(),
+            RaceResult {
+                race: "Other GP".to_string(),
+                ..normalized_results["Test GP"].clone()
+            },
+        );
+
+        // Against NOR VER PIA RUS LEC, then a guess for a race without a result, which is left out.
+        for guess in [
+            perfect_guess(),
+            Guess {
+                race: "Other GP".to_string(),
+                positions: ["NOR", "PIA", "VER", "HAM", "ALO"]
+                    .map(driver_code)
+                    .to_vec(),
+                ..perfect_guess()
+            },
+            Guess {
+                race: "Next GP".to_string(),
+                ..perfect_guess()
+            },
+        ] {
+            let race = guess.race.clone();
+            assert!(store.update_guess(guess, &race).await.is_ok());
+        }
+
+        let accuracy = store
+            .guess_accuracy_by_position("test", &normalized_results)
+            .await;
+
+        assert!(accuracy.as_ref().is_ok_and(|accuracy| {
+            accuracy.iter().all(|a| {
+                (a.correct_rate + a.wrong_place_rate + a.miss_rate - 1.0).abs() < f32::EPSILON
+            })
+        }));
+        assert!(accuracy.is_ok_and(|accuracy| {
+            accuracy.map(|a| (a.position, a.correct_rate, a.wrong_place_rate, a.miss_rate))
+                == [
+                    (1, 1.0, 0.0, 0.0),
+                    (2, 0.5, 0.5, 0.0),
+                    (3, 0.5, 0.5, 0.0),
+                    (4, 0.5, 0.0, 0.5),
+                    (5, 0.5, 0.0, 0.5),
+                ]
+        }));
+    }
+
+    #[tokio::test]
+    async fn seed_countries() {
+        let path = "test_data/seed_countries/";
+        let store = Store::new(path);
+
+        let _ = std::fs::remove_file(format!("{path}countries.csv"));
+
+        assert!(store.seed_countries().await.is_ok());
+        assert!(
+            store
+                .all_countries()
+                .await
+                .is_ok_and(|countries| countries.len() == 252 && countries[0].code == "AF")
+        );
+
+        // Seeding again keeps the collection as it is.
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("countries", |c: &&Country| c.code != "PT")
+                .await
+                .is_ok()
+        );
+        assert!(store.seed_countries().await.is_ok());
+        assert!(
+            store
+                .all_countries()
+                .await
+                .is_ok_and(|countries| countries.len() == 1 && countries[0].flag == "🇵🇹")
+        );
+    }
+
+    #[tokio::test]
+    async fn most_popular_guesses_for_race() {
+        let store = Store::new("test_data/most_popular_guesses_for_race/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("guesses", |_: &&Guess| true)
+                .await
+                .is_ok()
+        );
+
+        assert!(
+            store
+                .most_popular_guesses_for_race("TEST GP")
+                .await
+                .is_ok_and(|crowd| crowd == CrowdGuess::default())
+        );
+
+        for (username, picks) in [
+            ("alice", ["NOR", "VER", "PIA", "RUS", "LEC"]),
+            ("bob", ["NOR", "PIA", "VER", "RUS", "HAM"]),
+            ("carol", ["VER", "NOR", "PIA", "ALO", "LEC"]),
+        ] {
+            let guess = Guess {
+                username: username.to_string(),
+                positions: picks.map(driver_code).to_vec(),
+                ..perfect_guess()
+            };
+            assert!(store.update_guess(guess, "Test GP").await.is_ok());
+        }
+
+        // Every P2 pick is different, so the first one alphabetically wins with a third of the
+        // players, while two thirds agree on the other positions.
+        let crowd = store.most_popular_guesses_for_race("TEST GP").await;
+
+        assert!(crowd.as_ref().is_ok_and(|crowd| {
+            [
+                &crowd.p1_popular,
+                &crowd.p2_popular,
+                &crowd.p3_popular,
+                &crowd.p4_popular,
+                &crowd.p5_popular,
+            ] == ["NOR", "NOR", "PIA", "RUS", "LEC"]
+        }));
+        assert!(crowd.is_ok_and(|crowd| (crowd.consensus_score - 0.6).abs() < 1e-6));
+    }
+
+    #[tokio::test]
+    async fn parlay_count() {
+        let store = Store::new("test_data/parlay_count/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("guesses", |_: &&Guess| true)
+                .await
+                .is_ok()
+        );
+
+        // Swapping the last two drivers keeps every driver in the top 5, but loses the parlay.
+        let near_perfect = Guess {
+            username: "near".to_string(),
+            positions: ["NOR", "VER", "PIA", "LEC", "RUS"]
+                .map(driver_code)
+                .to_vec(),
+            ..perfect_guess()
+        };
+        let second_perfect = Guess {
+            race: "Other GP".to_string(),
+            ..perfect_guess()
+        };
+        let mut normalized_results = normalized_results();
+        normalized_results.insert(
+            "Other GP".to_string(),
+            RaceResult {
+                race: "Other GP".to_string(),
+                ..normalized_results["Test GP"].clone()
+            },
+        );
+
+        for guess in [perfect_guess(), second_perfect, near_perfect] {
+            let race = guess.race.clone();
+            assert!(store.update_guess(guess, &race).await.is_ok());
+        }
+        // The parlay leaderboard reads the stored results.
+        assert!(
+            std::fs::write(
+                "test_data/parlay_count/results.csv",
+                "race,p1,p2,p3,p4,p5\n"
+            )
+            .is_ok()
+        );
+        for result in normalized_results.values() {
+            assert!(store.upsert_result(result.clone()).await.is_ok());
+        }
+
+        assert!(
+            store
+                .parlay_count("TEST", &normalized_results)
+                .await
+                .is_ok_and(|count| count == 2)
+        );
+        assert!(
+            store
+                .parlay_count("near", &normalized_results)
+                .await
+                .is_ok_and(|count| count == 0)
+        );
+        assert!(
+            store
+                .parlay_leaderboard()
+                .await
+                .is_ok_and(|leaderboard| leaderboard == [("test".to_string(), 2)])
+        );
+    }
+
+    #[tokio::test]
+    async fn scored_guesses() {
+        let store = Store::new("test_data/scored_guesses/");
+
+        let guesses = [perfect_guess(), mixed_guess(), partial_guess()];
+        let normalized_results = normalized_results();
+        let scored_guesses = store.scored_guesses(&guesses, &normalized_results).await;
+
+        assert!(
+            scored_guesses[0].points + scored_guesses[1].points + scored_guesses[2].points == 44
+        );
+        assert!(
+            scored_guesses
+                .iter()
+                .all(|sg| sg.result == normalized_results.get("Test GP"))
+        );
+
+        let pending = Guess {
+            race: "Next GP".to_string(),
+            ..perfect_guess()
+        };
+        let pending_guesses = [pending];
+        let scored_guesses = store
+            .scored_guesses(&pending_guesses, &normalized_results)
+            .await;
+
+        assert!(scored_guesses[0].points == 0);
+        assert!(scored_guesses[0].result.is_none());
+    }
+
+    #[tokio::test]
+    async fn get_update_guesses() {
+        let store = Store::new("test_data/update_guess/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("guesses", |_: &&Guess| true)
+                .await
+                .is_ok()
+        );
+
+        let result = store.update_guess(perfect_guess(), "Test GP").await;
+        let guess = store
+            .get_guesses(Some("test"), Some("Test GP"))
+            .await
+            .unwrap_or_default();
+
+        assert!(!guess.is_empty());
+
+        assert!(
+            result.is_ok()
+                && guess[0].race == "Test GP"
+                && guess[0].username == "test"
+                && guess[0].picks() == ["NOR", "VER", "PIA", "RUS", "LEC"]
+        )
+    }
+
+    #[tokio::test]
+    async fn latest_guess() {
+        let path = "test_data/latest_guess/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],Second GP,Race,2025-04-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],First GP,Race,2025-03-01 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category\n\
+                 SECOND GP,test,VER,NOR,PIA,RUS,LEC,\n\
+                 FIRST GP,test,NOR,VER,PIA,RUS,LEC,\n\
+                 SECOND GP,other,LEC,NOR,PIA,RUS,VER,\n",
+            )
+            .is_ok()
+        );
+
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+
+        assert!(store.latest_guess("test", &category).await.is_ok_and(|g| {
+            g.is_some_and(|g| g.race == "SECOND GP" && g.p1().is_some_and(|p1| p1 == "VER"))
+        }));
+        assert!(
+            store
+                .latest_guess("nobody", &category)
+                .await
+                .is_ok_and(|g| g.is_none())
+        );
+    }
+
+    #[tokio::test]
+    async fn add_update_user() {
+        let store = Store::new("test_data/add_update_user/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("users", |_: &&User| true)
+                .await
+                .is_ok()
+        );
+
+        assert!(
+            store
+                .add_user("test", "********", Some("PT".to_string()))
+                .await
+                .is_ok()
+        );
+
+        let token = store
+            .db
+            .read()
+            .await
+            .find("users", |u: &User| u.username.eq_ignore_ascii_case("test"))
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .unwrap_or_default()
+            .token;
+
+        assert!(
+            store
+                .update_user(
+                    User {
+                        token: token.clone(),
+                        username: "test".to_string(),
+                        password: "new_password".to_string(),
+                        country: "PT".to_string(),
+                        preferred_driver: None,
+                        timezone: String::new(),
+                        avatar: None,
+                        ..Default::default()
+                    },
+                    &token
+                )
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn invalidate_sessions_after_password_change() {
+        let store = Store::new("test_data/invalidate_sessions_after_password_change/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("users", |_: &&User| true)
+                .await
+                .is_ok()
+        );
+        assert!(
+            store
+                .add_user("test", "secret123", Some("PT".to_string()))
+                .await
+                .is_ok()
+        );
+
+        let old_token = store
+            .validate_user("test", "secret123")
+            .await
+            .unwrap_or_default();
+        let mut user = store.get_user(&old_token).await.unwrap_or_default();
+
+        user.password = store.hash_password("new-secret").await.unwrap_or_default();
+
+        assert!(store.update_user(user, &old_token).await.is_ok());
+
+        let new_token = store.invalidate_sessions("TEST").await.unwrap_or_default();
+
+        assert!(new_token != old_token);
+        assert!(store.get_user(&old_token).await.is_none());
+        assert!(
+            store
+                .get_user(&new_token)
+                .await
+                .is_some_and(|u| u.username == "test")
+        );
+        assert!(store.validate_user("test", "new-secret").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn add_invited_user() {
+        let path = "test_data/add_invited_user/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}users.csv"),
+                "token,username,password,country,preferred_driver,timezone,avatar,dark_mode,compact_tables,version\n\
+                 token,taken,secret,,,,,false,false,0\n"
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}invite_codes.csv"),
+                "code,created_by,created_at,used,used_by\n"
+            )
+            .is_ok()
+        );
+
+        let Ok(invite_code) = store.create_invite_code("Admin").await else {
+            panic!("The invite code should be created");
+        };
+        let stored = || async {
+            store
+                .db
+                .read()
+                .await
+                .find("invite_codes", |_: &InviteCode| true)
+                .await
+                .unwrap_or_default()
+        };
+
+        assert!(invite_code.created_by == "admin" && !invite_code.used);
+        assert!(matches!(
+            store
+                .add_invited_user("test", "secret", None, "unknown")
+                .await,
+            Err(StoreError::Db(DbError::NoMatch))
+        ));
+
+        // A failed registration leaves the code for another try.
+        assert!(
+            store
+                .add_invited_user("Taken", "secret", None, &invite_code.code)
+                .await
+                .is_err()
+        );
+        assert!(stored().await.iter().all(|code| !code.used));
+
+        assert!(
+            store
+                .add_invited_user("Test", "secret", None, &format!(" {} ", invite_code.code))
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            store
+                .add_invited_user("other", "secret", None, &invite_code.code)
+                .await,
+            Err(StoreError::Used)
+        ));
+
+        let codes = stored().await;
+
+        assert!(codes.len() == 1 && codes[0].used && codes[0].used_by.as_deref() == Some("test"));
+        assert!(store.get_users(None).await.is_ok_and(|users| {
+            users
+                .iter()
+                .map(|u| u.username.as_str())
+                .eq(["taken", "test"])
+        }));
+    }
+
+    #[tokio::test]
+    async fn validate_user_rehashes_password() {
+        let store = Store::new("test_data/validate_user_rehashes_password/");
+        let argon2i = Argon2Config {
+            variant: "argon2i".to_string(),
+        };
+        let old_store = store.detached().with_argon2(&argon2i);
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("users", |_: &&User| true)
+                .await
+                .is_ok()
+        );
+        assert!(
+            old_store
+                .add_user("test", "secret123", Some("PT".to_string()))
+                .await
+                .is_ok()
+        );
+
+        let password = || async {
+            store
+                .get_users(Some("test"))
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .next()
+                .unwrap_or_default()
+                .password
+        };
+
+        assert!(password().await.starts_with("$argon2i$"));
+        assert!(
+            old_store
+                .outdated_password_hashes()
+                .await
+                .is_ok_and(|u| u.is_empty())
+        );
+        assert!(
+            store
+                .outdated_password_hashes()
+                .await
+                .is_ok_and(|u| u == ["test"])
+        );
+
+        // The old hash still verifies, and is replaced now that the password is known.
+        assert!(store.validate_user("test", "wrong").await.is_none());
+        assert!(password().await.starts_with("$argon2i$"));
+        assert!(store.validate_user("test", "secret123").await.is_some());
+        assert!(password().await.starts_with("$argon2id$"));
+        assert!(store.validate_user("test", "secret123").await.is_some());
+        assert!(
+            store
+                .outdated_password_hashes()
+                .await
+                .is_ok_and(|u| u.is_empty())
+        );
+    }
+
+    #[tokio::test]
+    async fn add_user_concurrently() {
+        let store = Store::new("test_data/add_user_concurrently/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("users", |_: &&User| true)
+                .await
+                .is_ok()
+        );
+
+        let (first, second) = tokio::join!(
+            store.add_user("test", "********", Some("PT".to_string())),
+            store.add_user("TEST", "********", Some("PT".to_string())),
+        );
+
+        assert!(first.is_ok() != second.is_ok());
+
+        let users = store
+            .db
+            .read()
+            .await
+            .find("users", |u: &User| u.username.eq_ignore_ascii_case("test"))
+            .await
+            .unwrap_or_default();
+
+        assert!(users.len() == 1);
+    }
+
+    #[tokio::test]
+    async fn update_user_concurrently() {
+        let store = Store::new("test_data/update_user_concurrently/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("users", |_: &&User| true)
+                .await
+                .is_ok()
+        );
+        assert!(
+            store
+                .add_user("test", "********", Some("PT".to_string()))
+                .await
+                .is_ok()
+        );
+
+        let stored = || async {
+            store
+                .db
+                .read()
+                .await
+                .find("users", |u: &User| u.username == "test")
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .next()
+                .unwrap_or_default()
+        };
+
+        // Both tabs were opened on the same version of the profile.
+        let first_tab = User {
+            country: "GB".to_string(),
+            ..stored().await
+        };
+        let second_tab = User {
+            country: "NL".to_string(),
+            ..stored().await
+        };
+        let token = first_tab.token.clone();
+
+        let (first, second) = tokio::join!(
+            store.update_user(first_tab, &token),
+            store.update_user(second_tab, &token),
+        );
+
+        assert!(first.is_ok() != second.is_ok());
+        assert!(matches!(first.and(second), Err(StoreError::Conflict)));
+
+        let user = stored().await;
+
+        assert!(user.version == 1);
+        assert!(user.country == "GB" || user.country == "NL");
+    }
+
+    #[tokio::test]
+    async fn add_update_driver() {
+        let store = Store::new("test_data/add_update_driver/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("drivers", |_: &&Driver| true)
+                .await
+                .is_ok()
+        );
+
+        let driver = Driver {
+            number: 1,
+            code: driver_code("NOR"),
+            name: "Lando Norris".to_string(),
+            category: String::new(),
+            active: true,
+            team: String::new(),
+            colour: String::new(),
+        };
+
+        assert!(store.add_driver(driver.clone()).await.is_ok());
+        assert!(matches!(
+            store
+                .add_driver(Driver {
+                    code: driver_code("ABC"),
+                    ..driver.clone()
+                })
+                .await,
+            Err(StoreError::DuplicateDriver(DriverConflict::Number {
+                number: 1,
+                ..
+            }))
+        ));
+        assert!(
+            store
+                .add_driver(Driver {
+                    number: 81,
+                    code: driver_code("nor"),
+                    ..driver.clone()
+                })
+                .await
+                .is_err_and(|error| error.to_string()
+                    == "Duplicate driver: code NOR already used by Lando Norris")
+        );
+        assert!(
+            store
+                .update_driver(
+                    &driver_code("NOR"),
+                    Driver {
+                        number: 4,
+                        ..driver.clone()
+                    }
+                )
+                .await
+                .is_ok()
+        );
+        assert!(store.toggle_driver(&driver_code("nor")).await.is_ok());
+
+        let drivers = store.all_drivers().await.unwrap_or_default();
+
+        assert!(drivers.len() == 1 && drivers[0].number == 4 && !drivers[0].active);
+    }
+
+    #[tokio::test]
+    async fn verify_duplicate_drivers() {
+        let path = "test_data/verify_duplicate_drivers/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}drivers.csv"),
+                "number,code,name,category,active,team,colour\n\
+                 1,VER,Max Verstappen,,true,,\n\
+                 4,NOR,Lando Norris,,true,,\n\
+                 3,ver,Max V.,,true,,\n\
+                 1,LAW,Liam Lawson,,true,,\n\
+                 4,OWA,Pato O'Ward,,false,,\n",
+            )
+            .is_ok()
+        );
+
+        assert!(store.verify().await.is_ok_and(|problems| problems
+            == [
+                "Max V. in drivers.csv: code VER already used by Max Verstappen",
+                "Liam Lawson in drivers.csv: number 1 already used by Max Verstappen",
+            ]));
+    }
+
+    #[tokio::test]
+    async fn driver_number_conflict() {
+        let store = Store::new("test_data/driver_number_conflict/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("drivers", |_: &&Driver| true)
+                .await
+                .is_ok()
+        );
+
+        let driver = Driver {
+            number: 1,
+            code: driver_code("NOR"),
+            name: "Lando Norris".to_string(),
+            category: String::new(),
+            active: true,
+            team: String::new(),
+            colour: String::new(),
+        };
+        let reserve = Driver {
+            code: driver_code("OWA"),
+            name: "Pato O'Ward".to_string(),
+            ..driver.clone()
+        };
+
+        // A clean insert, then a second active driver with the same number.
+        assert!(store.add_driver(driver.clone()).await.is_ok());
+        assert!(matches!(
+            store.add_driver(reserve.clone()).await,
+            Err(StoreError::DuplicateDriver(DriverConflict::Number {
+                number: 1,
+                ..
+            }))
+        ));
+
+        // A driver keeps its own number when updated.
+        assert!(
+            store
+                .update_driver(
+                    &driver_code("NOR"),
+                    Driver {
+                        name: "L. Norris".to_string(),
+                        ..driver.clone()
+                    }
+                )
+                .await
+                .is_ok()
+        );
+
+        // The number is free again once its driver is inactive, but then can't be reactivated.
+        assert!(store.toggle_driver(&driver_code("NOR")).await.is_ok());
+        assert!(store.add_driver(reserve).await.is_ok());
+        assert!(matches!(
+            store.toggle_driver(&driver_code("NOR")).await,
+            Err(StoreError::DuplicateDriver(DriverConflict::Number { .. }))
+        ));
+        assert!(matches!(
+            store
+                .update_driver(
+                    &driver_code("NOR"),
+                    Driver {
+                        active: true,
+                        ..driver.clone()
+                    }
+                )
+                .await,
+            Err(StoreError::DuplicateDriver(DriverConflict::Number { .. }))
+        ));
+
+        let drivers = store.all_drivers().await.unwrap_or_default();
+
+        assert!(Driver::number_taken_by(&drivers, 1, Some(&driver_code("owa"))).is_none());
+        assert!(Driver::number_taken_by(&drivers, 1, None).is_some_and(|d| d.code == "OWA"));
+    }
+
+    #[tokio::test]
+    async fn driver_cache() {
+        let store = Store::new("test_data/driver_cache/").with_driver_cache();
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("drivers", |_: &&Driver| true)
+                .await
+                .is_ok()
+        );
+
+        let driver = |number, code| Driver {
+            number,
+            code: driver_code(code),
+            name: String::new(),
+            category: String::new(),
+            active: true,
+            team: String::new(),
+            colour: String::new(),
+        };
+
+        assert!(store.add_driver(driver(1, "NOR")).await.is_ok());
+        assert!(store.all_drivers().await.is_ok_and(|d| d.len() == 1));
+
+        // Written behind the back of the cache, so it only shows up once the cache is cleared.
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .insert("drivers", driver(81, "PIA"))
+                .await
+                .is_ok()
+        );
+
+        for _ in 0..1000 {
+            assert!(store.all_drivers().await.is_ok_and(|d| d.len() == 1));
+        }
+
+        assert!(store.toggle_driver(&driver_code("NOR")).await.is_ok());
+        assert!(
+            store
+                .all_drivers()
+                .await
+                .is_ok_and(|d| d.len() == 2 && d.iter().any(|d| d.code == "NOR" && !d.active))
+        );
+
+        // Readers filling the cache while drivers are edited must not wait on each other forever.
+        let edits = async {
+            for _ in 0..50 {
+                assert!(store.toggle_driver(&driver_code("NOR")).await.is_ok());
+            }
+        };
+        let reads = async {
+            for _ in 0..50 {
+                assert!(store.all_drivers().await.is_ok());
+            }
+        };
+
+        assert!(
+            rocket::tokio::time::timeout(std::time::Duration::from_secs(30), async {
+                rocket::tokio::join!(edits, reads)
+            })
+            .await
+            .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn score_cache() {
+        let path = "test_data/score_cache/";
+        let store = Store::new(path).with_score_cache();
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify,id\n\
+                 [Formula 1],Test GP,Race,2025-03-01 12:00:00 UTC,#formula1,,true,test-gp\n\
+                 [Formula 1],Other GP,Race,2025-03-08 12:00:00 UTC,#formula1,,true,other-gp\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category,submitted_at\n"
+            )
+            .is_ok()
+        );
+        assert!(std::fs::write(format!("{path}results.csv"), "race,p1,p2,p3,p4,p5\n").is_ok());
+
+        // Stored before the cache is filled, so it only scores them when first read.
+        for (username, race, race_id) in [
+            ("first", "Test GP", "test-gp"),
+            ("second", "Test GP", "test-gp"),
+            ("third", "Test GP", "test-gp"),
+            ("first", "Other GP", "other-gp"),
+            ("second", "Other GP", "other-gp"),
+        ] {
+            let guess = Guess {
+                username: username.to_string(),
+                race: race.to_string(),
+                race_id: race_id.to_string(),
+                ..perfect_guess()
+            };
+
+            assert!(store.update_guess(guess, race).await.is_ok());
+        }
+
+        let cached = store.get_all_scored_guesses().await.unwrap_or_default();
+
+        assert!(cached.len() == 5 && cached.iter().all(|sg| sg.points == 0));
+        // Reads share the cached guesses rather than copying them.
+        assert!(
+            store
+                .get_all_scored_guesses()
+                .await
+                .is_ok_and(|sg| Arc::ptr_eq(&sg, &cached))
+        );
+        assert!(
+            store
+                .leaderboard_for_season(&category, true, Some(2025), false)
+                .await
+                .is_ok_and(|leaderboard| leaderboard.len() == 3
+                    && leaderboard.iter().all(|(_, points, _)| *points == 0))
+        );
+
+        // Written behind the back of the cache, so it stays unseen until the cache is emptied.
+        let hidden = Guess {
+            username: "fourth".to_string(),
+            race: "Other GP".to_string(),
+            race_id: "other-gp".to_string(),
+            ..perfect_guess()
+        };
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .insert("guesses", hidden)
+                .await
+                .is_ok()
+        );
+
+        // A new version of a guess replaces that guess only.
+        let guess = store
+            .get_guesses(Some("first"), Some("Test GP"))
+            .await
+            .unwrap_or_default()
+            .remove(0);
+        let guess = Guess {
+            positions: mixed_guess().positions,
+            ..guess
+        };
+
+        assert!(store.update_guess(guess, "Test GP").await.is_ok());
+        assert!(store.get_all_scored_guesses().await.is_ok_and(|sg| {
+            sg.len() == 5
+                && sg
+                    .iter()
+                    .filter(|sg| sg.guess.picks() == mixed_guess().picks())
+                    .count()
+                    == 1
+        }));
+        // Readers holding the previous guesses keep them as they were.
+        assert!(
+            cached
+                .iter()
+                .all(|sg| sg.guess.picks() == perfect_guess().picks())
+        );
+
+        // A result only rescores the guesses of its race, and the totals follow.
+        for result in normalized_results().into_values() {
+            assert!(store.upsert_result(result).await.is_ok());
+        }
+
+        let scored_guesses = store.get_all_scored_guesses().await.unwrap_or_default();
+
+        assert!(
+            scored_guesses.len() == 5
+                && scored_guesses
+                    .iter()
+                    .filter(|sg| sg.guess.race.eq_ignore_ascii_case("Test GP"))
+                    .all(|sg| sg.points > Points::ZERO)
+        );
+
+        let leaderboard = store
+            .leaderboard_for_season(&category, true, Some(2025), false)
+            .await
+            .unwrap_or_default();
+
+        for (name, points, wins) in &leaderboard {
+            let username = LeaderboardEntry::username(name);
+            let guesses = scored_guesses
+                .iter()
+                .filter(|sg| sg.guess.username == username);
+
+            assert!(*points == guesses.map(|sg| sg.points).sum::<Points>());
+            assert!(*wins == u32::from(username != "first"));
+        }
+
+        // Events can rename races, so editing them scores everything again on the next read.
+        let renamed = Event {
+            name: "RENAMED GP".to_string(),
+            ..store.events().await.unwrap_or_default().remove(0)
+        };
+
+        assert!(store.update_event("Test GP", "Race", renamed).await.is_ok());
+        assert!(store.get_all_scored_guesses().await.is_ok_and(|sg| {
+            sg.len() == 6 && sg.iter().filter(|sg| sg.guess.race == "RENAMED GP").count() == 3
+        }));
+        assert!(
+            store
+                .leaderboard_for_season(&category, true, Some(2025), false)
+                .await
+                .is_ok_and(|leaderboard| leaderboard.len() == 4)
+        );
+    }
+
+    #[tokio::test]
+    async fn score_cache_scales() {
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+
+        // The reads of the front page once the cache is warm, timed over a few views.
+        let index_reads = |store: Store| {
+            let category = category.clone();
+
+            async move {
+                assert!(store.get_all_scored_guesses().await.is_ok());
+                assert!(
+                    store
+                        .leaderboard_for_season(&category, true, Some(2025), false)
+                        .await
+                        .is_ok()
+                );
+
+                let start = std::time::Instant::now();
+
+                for _ in 0..20 {
+                    assert!(
+                        store
+                            .leaderboard_for_season(&category, true, Some(2025), false)
+                            .await
+                            .is_ok_and(|leaderboard| leaderboard.len() == 3)
+                    );
+                    assert!(
+                        store
+                            .previous_leaderboard_for_season(&category, true, Some(2025), false)
+                            .await
+                            .is_ok()
+                    );
+                    assert!(store.get_all_scored_guesses().await.is_ok());
+                    assert!(store.parlay_leaderboard().await.is_ok());
+                }
+
+                start.elapsed()
+            }
+        };
+
+        let mut elapsed = Vec::new();
+
+        // The same season, once alone and once after 20000 guesses of past seasons.
+        for (path, past_guesses) in [
+            ("test_data/score_cache_scales/small/", 0),
+            ("test_data/score_cache_scales/large/", 20_000),
+        ] {
+            let mut events = "category,name,description,datetime,channel,tags,notify,id\n\
+                 [Formula 1],Test GP,Race,2025-03-01 12:00:00 UTC,#formula1,,true,test-gp\n"
+                .to_string();
+            let mut guesses = "race,username,p1,p2,p3,p4,p5,category\n\
+                 Test GP,first,NOR,VER,PIA,RUS,LEC,\n\
+                 Test GP,second,NOR,VER,PIA,LEC,RUS,\n\
+                 Test GP,third,HAM,VER,PIA,RUS,LEC,\n"
+                .to_string();
+
+            for round in 1..=20 {
+                events.push_str(&format!(
+                    "[Formula 1],Old GP {round},Race,2024-{:02}-01 12:00:00 UTC,#formula1,,true,old-gp-{round}\n",
+                    round % 12 + 1
+                ));
+            }
+            for i in 0..past_guesses {
+                guesses.push_str(&format!(
+                    "Old GP {},player{},NOR,VER,PIA,RUS,LEC,\n",
+                    i % 20 + 1,
+                    i / 20
+                ));
+            }
+
+            assert!(std::fs::create_dir_all(path).is_ok());
+            assert!(std::fs::write(format!("{path}events.csv"), events).is_ok());
+            assert!(std::fs::write(format!("{path}guesses.csv"), guesses).is_ok());
+            assert!(
+                std::fs::write(
+                    format!("{path}results.csv"),
+                    "race,p1,p2,p3,p4,p5,c1,c2,entered_at\n\
+                     Test GP,NOR,VER,PIA,RUS,LEC,,,\n\
+                     Old GP 1,NOR,VER,PIA,RUS,LEC,,,\n",
+                )
+                .is_ok()
+            );
+
+            let store = Store::new(path).with_score_cache();
+
+            elapsed.push(index_reads(store).await);
+        }
+
+        // Reading the cache doesn't go through every guess again, so the past seasons barely show.
+        assert!(elapsed[1] < elapsed[0] * 4 + std::time::Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn add_event_unique_id() {
+        let path = "test_data/add_event_unique_id/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify,id\n"
+            )
+            .is_ok()
+        );
+
+        let event = |name: &str, description: &str| Event {
+            category: "[Formula 1]".to_string(),
+            name: name.to_string(),
+            description: description.to_string(),
+            datetime: DateTime::default(),
+            channel: String::new(),
+            tags: String::new(),
+            notify: false,
+            id: String::new(),
+        };
+        let id = |name: &str, description: &str| {
+            let store = &store;
+            let (name, description) = (name.to_string(), description.to_string());
+
+            async move {
+                store
+                    .events()
+                    .await
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|e| e.is(&name, &description))
+                    .map(|e| e.id)
+                    .unwrap_or_default()
+            }
+        };
+
+        assert!(store.add_event(event("Test GP", "Race")).await.is_ok());
+        assert!(store.add_event(event("Test GP", "Race")).await.is_err());
+        assert!(
+            store
+                .update_event("Test GP", "Race", event("Renamed GP", "Race"))
+                .await
+                .is_ok()
+        );
+
+        // The renamed race keeps its id, which a new race under its old name doesn't inherit.
+        assert!(store.add_event(event("Test GP", "Race")).await.is_ok());
+        assert!(
+            store
+                .add_event(event("Test GP", "Qualifying"))
+                .await
+                .is_ok()
+        );
+        assert!(store.add_event(event("Test-GP", "Race")).await.is_ok());
+        assert!(id("Renamed GP", "Race").await == "test-gp");
+        assert!(id("Test GP", "Race").await == "test-gp-2");
+        assert!(id("Test GP", "Qualifying").await == "test-gp-2");
+        assert!(id("Test-GP", "Race").await == "test-gp-3");
+    }
+
+    #[tokio::test]
+    async fn validate_drivers_exist() {
+        let store = Store::new("test_data/validate_drivers_exist/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("drivers", |_: &&Driver| true)
+                .await
+                .is_ok()
+        );
+
+        let driver = Driver {
+            number: 1,
+            code: driver_code("NOR"),
+            name: "Lando Norris".to_string(),
+            category: String::new(),
+            active: true,
+            team: String::new(),
+            colour: String::new(),
+        };
+
+        assert!(store.add_driver(driver.clone()).await.is_ok());
+        assert!(
+            store
+                .add_driver(Driver {
+                    number: 3,
+                    code: driver_code("VER"),
+                    active: false,
+                    ..driver
+                })
+                .await
+                .is_ok()
+        );
+
+        assert!(
+            store
+                .validate_drivers_exist(&[&driver_code("nor")])
+                .await
+                .is_ok()
+        );
+        assert!(
+            store
+                .validate_drivers_exist(&[
+                    &driver_code("NOR"),
+                    &driver_code("VER"),
+                    &driver_code("XXX")
+                ])
+                .await
+                .is_err_and(|codes| codes == ["VER", "XXX"])
+        );
+    }
+
+    #[tokio::test]
+    async fn find_limited_reads_only_what_it_needs() {
+        use rocket::serde::{Deserialize, Deserializer};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static ROWS_READ: AtomicUsize = AtomicUsize::new(0);
+
+        /// A user counting how many rows were read into memory.
+        struct CountedUser(User);
+
+        impl<'de> Deserialize<'de> for CountedUser {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                ROWS_READ.fetch_add(1, Ordering::Relaxed);
+                User::deserialize(deserializer).map(Self)
+            }
+        }
+
+        let path = "test_data/find_limited_reads_only_what_it_needs/";
+        let store = Store::new(path);
+        let users: String = (0..50_000)
+            .map(|i| format!("token-{i},user{i},hash-{i},PT\n"))
+            .collect();
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}users.csv"),
+                format!("token,username,password,country\n{users}"),
+            )
+            .is_ok()
+        );
+
+        // Without a match every row is read, one at a time.
+        assert!(
+            store
+                .read_db()
+                .await
+                .find_first("users", |u: &CountedUser| u.0.token == "token-50000")
+                .await
+                .is_ok_and(|user| user.is_none())
+        );
+        assert!(ROWS_READ.swap(0, Ordering::Relaxed) == 50_000);
+
+        // The third match is the twelfth row, so reading stops long before the 50,000th.
+        let found = store
+            .read_db()
+            .await
+            .find_limited(
+                "users",
+                |u: &CountedUser| u.0.username.starts_with("user1"),
+                3,
+            )
+            .await
+            .unwrap_or_default();
+
+        assert!(
+            found
+                .iter()
+                .map(|u| u.0.username.as_str())
+                .eq(["user1", "user10", "user11"])
+        );
+        assert!(ROWS_READ.load(Ordering::Relaxed) <= 12 + SCAN_BUFFER + 1);
+    }
+
+    #[tokio::test]
+    async fn find_first_open_errors() {
+        let path = "test_data/find_first_open_errors/";
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(std::fs::write(format!("{path}not_a_dir"), "").is_ok());
+
+        // A collection without a file has no rows, but a file that can't be opened is an error.
+        assert!(
+            Store::new(path)
+                .read_db()
+                .await
+                .find_first("users", |_: &User| true)
+                .await
+                .is_ok_and(|user| user.is_none())
+        );
+        assert!(
+            Store::new("test_data/find_first_open_errors/not_a_dir/")
+                .read_db()
+                .await
+                .find_first("users", |_: &User| true)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn get_user_among_many() {
+        let path = "test_data/get_user_among_many/";
+        let store = Store::new(path);
+
+        let users: String = (0..50_000)
+            .map(|i| format!("token-{i},user{i},hash-{i},PT\n"))
+            .collect();
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}users.csv"),
+                format!("token,username,password,country\n{users}"),
+            )
+            .is_ok()
+        );
+
+        for (token, username) in [("token-0", "user0"), ("token-49999", "user49999")] {
+            assert!(
+                store
+                    .get_user(token)
+                    .await
+                    .is_some_and(|user| user.username == username)
+            );
+        }
+
+        assert!(store.get_user("token-50000").await.is_none());
+
+        // A broken last row fails any read of the whole file, but a lookup stops reading at its
+        // match, long before it.
+        let broken = format!("token,username,password,country\n{users}token-broken\n");
+
+        assert!(std::fs::write(format!("{path}users.csv"), broken).is_ok());
+        assert!(
+            store
+                .db
+                .read()
+                .await
+                .find("users", |u: &User| u.token == "token-0")
+                .await
+                .is_err()
+        );
+        assert!(
+            store
+                .get_user("token-0")
+                .await
+                .is_some_and(|user| user.username == "user0")
+        );
+        assert!(
+            store
+                .db
+                .read()
+                .await
+                .find_first("users", |u: &User| u.token == "token-50000")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn validate_user() {
+        let store = Store::new("test_data/validate_user/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("users", |_: &&User| true)
+                .await
+                .is_ok()
+        );
+
+        assert!(
+            store
+                .add_user("test", "********", Some("PT".to_string()))
+                .await
+                .is_ok()
+        );
+
+        let first_token = store.validate_user("test", "********").await;
+        let second_token = store.validate_user("test", "********").await;
+
+        assert!(first_token.is_some() && second_token.is_some());
+        assert!(first_token != second_token);
+        assert!(store.validate_user("test", "wrong").await.is_none());
+
+        let first_token = first_token.unwrap_or_default();
+        let second_token = second_token.unwrap_or_default();
+
+        assert!(store.authenticate(&first_token).await.is_none());
+        assert!(store.authenticate(&second_token).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn score_constructor_guess() {
+        let store = Store::new("test_data/score_constructor_guess/");
+
+        let guess = |c1: &str, c2: &str| ConstructorGuess {
+            race: "Test GP".to_string(),
+            username: "test".to_string(),
+            c1: c1.to_string(),
+            c2: c2.to_string(),
+            race_id: String::new(),
+        };
+
+        let results = normalized_results();
+
+        assert!(
+            store
+                .score_constructor_guess(&guess("MCL", "MER"), &results)
+                .await
+                == 2 * CONSTRUCTOR_CORRECT
+        );
+        assert!(
+            store
+                .score_constructor_guess(&guess("MCL", "RBR"), &results)
+                .await
+                == CONSTRUCTOR_CORRECT
+        );
+        assert!(
+            store
+                .score_constructor_guess(&guess("MER", "MCL"), &results)
+                .await
+                == 0
+        );
+    }
+
+    #[tokio::test]
+    async fn score_matrix() {
+        let path = "test_data/score_matrix/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],Test GP,Race,2025-03-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],First GP,Race,2025-02-01 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+        let guesses = [
+            Guess {
+                username: "zed".to_string(),
+                ..perfect_guess()
+            },
+            Guess {
+                race: "First GP".to_string(),
+                ..mixed_guess()
+            },
+        ];
+
+        let matrix = store
+            .score_matrix(&category, None, &guesses, &normalized_results(), 100)
+            .await
+            .unwrap_or_default()
+            .expect("The matrix should be within the cell limit");
+
+        assert!(matrix.races == ["First GP", "Test GP"]);
+        assert!(matrix.users == ["test", "zed"]);
+        assert!(matrix.scores == [[Some(Points::ZERO), None], [None, Some(Points::from(25))]]);
+
+        assert!(
+            store
+                .score_matrix(&category, None, &guesses, &normalized_results(), 3)
+                .await
+                .is_ok_and(|matrix| matrix.is_none())
+        );
+    }
+
+    #[tokio::test]
+    async fn latest_scored_guesses() {
+        let path = "test_data/latest_scored_guesses/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2025-02-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Second GP,Race,2025-03-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Third GP,Race,2025-04-01 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+
+        // Stored oldest race last, the opposite of the order they took place in.
+        let scored_guesses: Vec<ScoredGuessOwned> = ["Third GP", "Second GP", "First GP"]
+            .into_iter()
+            .rev()
+            .map(|race| ScoredGuessOwned {
+                result: None,
+                guess: Guess {
+                    race: race.to_string(),
+                    ..perfect_guess()
+                },
+                points: Points::ZERO,
+                breakdown: ScoreBreakdown::default(),
+                hidden: false,
+                undated: false,
+            })
+            .collect();
+
+        let latest = store
+            .latest_scored_guesses(&scored_guesses, 2)
+            .await
+            .unwrap_or_default();
+        let races: Vec<&str> = latest.iter().map(|sg| sg.guess.race.as_str()).collect();
+
+        assert!(races == ["Third GP", "Second GP"]);
+    }
+
+    #[tokio::test]
+    async fn scored_guesses_by_date() {
+        let path = "test_data/scored_guesses_by_date/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2025-02-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Second GP,Race,2025-03-01 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+
+        // Stored in neither chronological nor reverse order, with a race that has no event.
+        let guesses: Vec<Guess> = ["Second GP", "Unknown GP", "First GP"]
+            .into_iter()
+            .map(|race| Guess {
+                race: race.to_string(),
+                ..perfect_guess()
+            })
+            .collect();
+        let normalized_results = HashMap::new();
+
+        let scored_guesses = store
+            .scored_guesses_by_date(store.scored_guesses(&guesses, &normalized_results).await)
+            .await
+            .unwrap_or_default();
+        let races: Vec<(&str, bool)> = scored_guesses
+            .iter()
+            .map(|sg| (sg.guess.race.as_str(), sg.undated))
+            .collect();
+
+        assert!(
+            races
+                == [
+                    ("Second GP", false),
+                    ("First GP", false),
+                    ("Unknown GP", true)
+                ]
+        );
+    }
+
+    #[tokio::test]
+    async fn next_event_off_season() {
+        let path = "test_data/next_event_off_season/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2025-02-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Last GP,Race,2025-12-07 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+
+        assert!(store.next_event(&category).await.is_ok_and(|e| e.is_none()));
+        assert!(store.seasons(&category).await.is_ok_and(|s| s == [2025]));
+    }
+
+    #[tokio::test]
+    async fn events_by_category() {
+        let path = "test_data/events_by_category/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2099-03-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1; Formula 2],Second GP,Race,2099-04-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1 Academy],Third GP,Race,2099-05-01 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+
+        let names = |events: Result<Vec<Event>, DbError>| -> Vec<String> {
+            events
+                .unwrap_or_default()
+                .into_iter()
+                .map(|e| e.name)
+                .collect()
+        };
+
+        assert!(names(store.events_by_category("formula 1").await) == ["First GP", "Second GP"]);
+        assert!(names(store.events_by_category("Formula 2").await) == ["Second GP"]);
+        assert!(names(store.events_by_category("formula").await).is_empty());
+    }
+
+    #[tokio::test]
+    async fn next_event_no_game() {
+        let path = "test_data/next_event_no_game/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],Test Days,Race,2099-02-01 12:00:00 UTC,#formula1,f1;no-game,true\n\
+                 [Formula 1],First GP,Race,2099-03-01 12:00:00 UTC,#formula1,f1,true\n",
+            )
+            .is_ok()
+        );
+
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+
+        assert!(
+            store
+                .next_event(&category)
+                .await
+                .is_ok_and(|e| e.is_some_and(|e| e.name == "First GP"))
+        );
+    }
+
+    #[tokio::test]
+    async fn events_remaining_count() {
+        let path = "test_data/events_remaining_count/";
+        let store = Store::new(path);
+
+        // A race on the first of each month from January to October, then one the next season.
+        let events = (1..=10)
+            .map(|month| {
+                format!(
+                    "[Formula 1],Round {month} GP,Race,2025-{month:02}-01 12:00:00 UTC,#formula1,f1,true\n"
+                )
+            })
+            .collect::<String>();
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                format!(
+                    "category,name,description,datetime,channel,tags,notify\n{events}\
+                     [Formula 1],Next GP,Race,2026-03-01 12:00:00 UTC,#formula1,f1,true\n"
+                ),
+            )
+            .is_ok()
+        );
+
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+        let after_round_three = "2025-03-15T12:00:00Z".parse().unwrap_or_default();
+        let round_four = "2025-04-01T12:00:00Z".parse().unwrap_or_default();
+
+        assert!(
+            store
+                .events_remaining_count(&category, after_round_three)
+                .await
+                .is_ok_and(|count| count == 7)
+        );
+        assert!(
+            store
+                .events_remaining_count(&category, round_four)
+                .await
+                .is_ok_and(|count| count == 7)
+        );
+        assert!(
+            store
+                .upcoming_events_count(&category, 2025)
+                .await
+                .is_ok_and(|count| count == 10)
+        );
+    }
+
+    #[tokio::test]
+    async fn guess_streak() {
+        let path = "test_data/guess_streak/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2025-03-01 12:00:00 UTC,#formula1,f1,true\n\
+                 [Formula 1],Second GP,Race,2025-04-01 12:00:00 UTC,#formula1,f1,true\n\
+                 [Formula 1],Third GP,Qualifying,2025-05-01 12:00:00 UTC,#formula1,f1,true\n\
+                 [Formula 1],Third GP,Race,2025-05-02 12:00:00 UTC,#formula1,f1,true\n\
+                 [Formula 1],Test Days,Race,2025-05-10 12:00:00 UTC,#formula1,f1;no-game,true\n\
+                 [Formula 1],Fourth GP,Race,2025-06-01 12:00:00 UTC,#formula1,f1,true\n\
+                 [Formula 1],Next GP,Race,2099-03-01 12:00:00 UTC,#formula1,f1,true\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5\n\
+                 FIRST GP,alice,NOR,VER,PIA,RUS,LEC\n\
+                 SECOND GP,alice,NOR,VER,PIA,RUS,LEC\n\
+                 THIRD GP,alice,NOR,VER,PIA,RUS,LEC\n\
+                 FOURTH GP,alice,NOR,VER,PIA,RUS,LEC\n\
+                 NEXT GP,alice,NOR,VER,PIA,RUS,LEC\n\
+                 FIRST GP,bob,NOR,VER,PIA,RUS,LEC\n\
+                 THIRD GP,bob,NOR,VER,PIA,RUS,LEC\n\
+                 FOURTH GP,bob,NOR,VER,PIA,RUS,LEC\n\
+                 FIRST GP,carol,NOR,VER,PIA,RUS,LEC\n",
+            )
+            .is_ok()
+        );
+
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+
+        // Upcoming races and races tagged as not played are left out of the streak.
+        assert!(
+            store
+                .guess_streak("alice", &category)
+                .await
+                .is_ok_and(|streak| streak == 4)
+        );
+        // Missing the second race breaks the streak of bob.
+        assert!(
+            store
+                .guess_streak("bob", &category)
+                .await
+                .is_ok_and(|streak| streak == 2)
+        );
+        assert!(
+            store
+                .guess_streak("carol", &category)
+                .await
+                .is_ok_and(|streak| streak == 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn get_all_scored_guesses() {
+        let path = "test_data/get_all_scored_guesses/";
+        let store = Store::new(path);
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("guesses", |_: &&Guess| true)
+                .await
+                .is_ok()
+        );
+        assert!(store.update_guess(perfect_guess(), "Test GP").await.is_ok());
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("results", |_: &&RaceResult| true)
+                .await
+                .is_ok()
+        );
+        for result in normalized_results().into_values() {
+            assert!(
+                store
+                    .db
+                    .write()
+                    .await
+                    .insert("results", result)
+                    .await
+                    .is_ok()
+            );
+        }
+
+        let scored_guesses = store.get_all_scored_guesses().await.unwrap_or_default();
+
+        assert!(scored_guesses.len() == 1);
+        assert!(scored_guesses[0].points == 25);
+        assert!(
+            scored_guesses[0].breakdown
+                == ScoreBreakdown {
+                    podium: Points::from(3 * CORRECT_PODIUM),
+                    top_five: Points::from(2 * CORRECT_FIVE),
+                    wrong_place: Points::ZERO,
+                    top_ten: Points::ZERO,
+                    parlay: Points::from(PARLAY),
+                }
+        );
+        assert!(scored_guesses[0].result.as_ref() == normalized_results().get("Test GP"));
+    }
+
+    #[tokio::test]
+    async fn upsert_result() {
+        let store = Store::new("test_data/upsert_result/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("results", |_: &&RaceResult| true)
+                .await
+                .is_ok()
+        );
+
+        let mut result = normalized_results()
+            .remove("Test GP")
+            .expect("The test result should exist");
+
+        assert!(store.upsert_result(result.clone()).await.is_ok());
+
+        result.p1 = driver_code("VER");
+        result.p2 = driver_code("NOR");
+
+        assert!(store.upsert_result(result).await.is_ok());
+
+        let results = store.results().await.unwrap_or_default();
+
+        assert!(results.len() == 1);
+        assert!(results[0].p1 == "VER" && results[0].p2 == "NOR");
+        assert!(results[0].entered_at.is_some());
+        assert!(
+            store
+                .results_updated_at()
+                .await
+                .is_ok_and(|updated_at| updated_at == results[0].entered_at)
+        );
+    }
+
+    #[tokio::test]
+    async fn events_needing_notification() {
+        let path = "test_data/events_needing_notification/";
+        let store = Store::new(path);
+
+        let at = |minutes| (Utc::now() + TimeDelta::minutes(minutes)).format("%F %T UTC");
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                format!(
+                    "category,name,description,datetime,channel,tags,notify\n\
+                     [Formula 1],Past GP,Race,{},#formula1,,true\n\
+                     [Formula 1],Soon GP,Race,{},#formula1,,true\n\
+                     [Formula 1],Soon GP,Qualifying,{},#formula1,,false\n\
+                     [Formula 1],Later GP,Race,{},#formula1,,true\n",
+                    at(-10),
+                    at(30),
+                    at(20),
+                    at(120),
+                ),
+            )
+            .is_ok()
+        );
+
+        let events = store
+            .events_needing_notification(60)
+            .await
+            .unwrap_or_default();
+
+        assert!(events.len() == 1);
+        assert!(events[0].is("Soon GP", "Race"));
+    }
+
+    #[tokio::test]
+    async fn leaderboard_for_date_range() {
+        let path = "test_data/leaderboard_for_date_range/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2025-06-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Second GP,Race,2025-06-15 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Third GP,Race,2025-07-06 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Fourth GP,Race,2025-07-20 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category\n\
+                 FIRST GP,first,NOR,VER,PIA,RUS,LEC,\n\
+                 SECOND GP,second,NOR,VER,PIA,RUS,LEC,\n\
+                 THIRD GP,third,NOR,VER,PIA,RUS,LEC,\n\
+                 FOURTH GP,fourth,NOR,VER,PIA,RUS,LEC,\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}results.csv"),
+                "race,p1,p2,p3,p4,p5,c1,c2,entered_at\n",
+            )
+            .is_ok()
+        );
+
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+        let scored_guesses = store.get_all_scored_guesses().await.unwrap_or_default();
+        let from = "2025-06-01T00:00:00Z".parse().unwrap_or_default();
+        let to = "2025-06-30T23:59:59Z".parse().unwrap_or_default();
+
+        let leaderboard = store
+            .leaderboard_for_date_range(&category, from, to, &scored_guesses, false)
+            .await
+            .unwrap_or_default();
+
+        assert!(leaderboard.len() == 2);
+        assert!(
+            leaderboard
+                .iter()
+                .all(|(name, ..)| name.starts_with("first") || name.starts_with("second"))
+        );
+    }
+
+    #[tokio::test]
+    async fn leaderboard_wins() {
+        let path = "test_data/leaderboard_wins/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2025-06-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Second GP,Race,2025-06-15 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Third GP,Race,2025-07-06 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Fourth GP,Race,2025-07-20 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+        // Alice wins the first round, ties with Bob in the second and Carol wins the third. Nobody
+        // wins the fourth, which has no result yet.
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category\n\
+                 FIRST GP,alice,NOR,VER,PIA,RUS,LEC,\n\
+                 FIRST GP,bob,VER,NOR,PIA,LEC,RUS,\n\
+                 SECOND GP,alice,NOR,VER,PIA,RUS,LEC,\n\
+                 SECOND GP,bob,NOR,VER,PIA,RUS,LEC,\n\
+                 SECOND GP,carol,NOR,HAM,PIA,ANT,LEC,\n\
+                 THIRD GP,alice,VER,NOR,PIA,LEC,RUS,\n\
+                 THIRD GP,bob,NOR,HAM,PIA,ANT,LEC,\n\
+                 THIRD GP,carol,NOR,VER,PIA,RUS,LEC,\n\
+                 FOURTH GP,alice,NOR,VER,PIA,RUS,LEC,\n\
+                 FOURTH GP,bob,NOR,VER,PIA,RUS,LEC,\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}results.csv"),
+                "race,p1,p2,p3,p4,p5,c1,c2,entered_at\n\
+                 FIRST GP,NOR,VER,PIA,RUS,LEC,,,\n\
+                 SECOND GP,NOR,VER,PIA,RUS,LEC,,,\n\
+                 THIRD GP,NOR,VER,PIA,RUS,LEC,,,\n",
+            )
+            .is_ok()
+        );
+
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+        let wins: HashMap<String, u32> = store
+            .leaderboard_for_season(&category, true, None, false)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, _, wins)| (LeaderboardEntry::username(&name), wins))
+            .collect();
+
+        assert!(
+            wins == HashMap::from([
+                ("alice".to_string(), 2),
+                ("bob".to_string(), 1),
+                ("carol".to_string(), 1),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn previous_leaderboard_for_season() {
+        let path = "test_data/previous_leaderboard_for_season/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify,id\n\
+                 [Formula 1],First GP,Race,2025-06-01 12:00:00 UTC,#formula1,,true,first-gp\n\
+                 [Formula 1],Second GP,Race,2025-06-15 12:00:00 UTC,#formula1,,true,second-gp\n\
+                 [Formula 1],Third GP,Race,2025-07-06 12:00:00 UTC,#formula1,,true,third-gp\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category\n\
+                 FIRST GP,first,NOR,VER,PIA,RUS,LEC,\n\
+                 FIRST GP,second,HAM,VER,PIA,RUS,LEC,\n\
+                 SECOND GP,first,HAM,RUS,ALO,SAI,STR,\n\
+                 SECOND GP,second,NOR,VER,PIA,RUS,LEC,\n\
+                 SECOND GP,third,NOR,VER,PIA,LEC,RUS,\n\
+                 THIRD GP,first,NOR,VER,PIA,RUS,LEC,\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}results.csv"),
+                "race,p1,p2,p3,p4,p5,c1,c2,entered_at,race_id\n\
+                 FIRST GP,NOR,VER,PIA,RUS,LEC,,,,first-gp\n\
+                 SECOND GP,NOR,VER,PIA,RUS,LEC,,,,second-gp\n",
+            )
+            .is_ok()
+        );
+
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+        let previous = store
+            .previous_leaderboard_for_season(&category, true, Some(2025), false)
+            .await
+            .unwrap_or_default()
+            .unwrap_or_default();
+
+        assert!(
+            previous
+                .iter()
+                .map(|(name, ..)| LeaderboardEntry::username(name))
+                .eq(["first", "second"])
+        );
+
+        let current = store
+            .leaderboard_for_season(&category, true, Some(2025), false)
+            .await
+            .unwrap_or_default();
+        let mut entries = LeaderboardEntry::rank(current, None, &HashMap::new());
+        LeaderboardEntry::track_changes(&mut entries, &previous);
+
+        // The second player overtook the first one, whose guess for the second race was poor.
+        assert!(
+            entries
+                .iter()
+                .map(|e| (e.name.as_str(), e.rank_change, e.is_new))
+                .eq([
+                    ("second ", 1, false),
+                    ("first ", -1, false),
+                    ("third ", 0, true)
+                ])
+        );
+
+        assert!(
+            store
+                .previous_leaderboard_for_season(&category, true, Some(2024), false)
+                .await
+                .is_ok_and(|previous| previous.is_none())
+        );
+    }
+
+    #[tokio::test]
+    async fn tagged_race_multiplier() {
+        let path = "test_data/tagged_race_multiplier/";
+        let store = Store::new(path).with_scoring(ScoringConfig {
+            sprint_multiplier: 3,
+            ..Default::default()
+        });
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],Plain GP,Race,2025-06-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Double GP,Qualifying,2025-06-14 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Double GP,Race,2025-06-15 12:00:00 UTC,#formula1,double-points,true\n\
+                 [Formula 1],Sprint GP,Race,2025-06-29 12:00:00 UTC,#formula1,sprint,true\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category\n\
+                 PLAIN GP,test,NOR,VER,PIA,RUS,LEC,\n\
+                 DOUBLE GP,test,NOR,VER,PIA,RUS,LEC,\n\
+                 SPRINT GP,test,NOR,VER,PIA,RUS,LEC,\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}results.csv"),
+                "race,p1,p2,p3,p4,p5,c1,c2,entered_at\n\
+                 PLAIN GP,NOR,VER,PIA,RUS,LEC,MCL,RBR,\n\
+                 DOUBLE GP,NOR,VER,PIA,RUS,LEC,MCL,RBR,\n\
+                 SPRINT GP,NOR,VER,PIA,RUS,LEC,MCL,RBR,\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}constructor_guesses.csv"),
+                "race,username,c1,c2\nPLAIN GP,test,MCL,RBR\nDOUBLE GP,test,MCL,RBR\n",
+            )
+            .is_ok()
+        );
+
+        // A perfect guess scores 25 points, counted as many times as the tags of its race say.
+        let scored_guesses = store.get_all_scored_guesses().await.unwrap_or_default();
+
+        assert!(
+            scored_guesses
+                .iter()
+                .map(|sg| (sg.guess.race.as_str(), sg.points.get()))
+                .eq([("PLAIN GP", 25), ("DOUBLE GP", 50), ("SPRINT GP", 75)])
+        );
+        assert!(
+            scored_guesses
+                .iter()
+                .all(|sg| sg.breakdown.total() == sg.points)
+        );
+
+        let normalized_results = store.normalized_results().await.unwrap_or_default();
+
+        assert!(
+            store
+                .constructor_points(&normalized_results)
+                .await
+                .is_ok_and(|points| points.get("test")
+                    == Some(&Points::from(
+                        2 * CONSTRUCTOR_CORRECT + 4 * CONSTRUCTOR_CORRECT
+                    )))
+        );
+    }
+
+    #[tokio::test]
+    async fn tied_players_keep_their_rank() {
+        let path = "test_data/tied_players_keep_their_rank/";
+        let store = Store::new(path);
+        let players = ["fay", "ann", "eve", "ben", "dan", "cat"];
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2025-06-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Second GP,Race,2025-06-15 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+        let guesses: String = ["FIRST GP", "SECOND GP"]
+            .iter()
+            .flat_map(|race| {
+                players
+                    .iter()
+                    .map(move |player| format!("{race},{player},NOR,VER,PIA,RUS,LEC,\n"))
+            })
+            .collect();
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                format!("race,username,p1,p2,p3,p4,p5,category\n{guesses}"),
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}results.csv"),
+                "race,p1,p2,p3,p4,p5,c1,c2,entered_at\n\
+                 FIRST GP,NOR,VER,PIA,RUS,LEC,,,\n\
+                 SECOND GP,NOR,VER,PIA,RUS,LEC,,,\n",
+            )
+            .is_ok()
+        );
+
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+        let previous = store
+            .previous_leaderboard_for_season(&category, true, Some(2025), false)
+            .await
+            .unwrap_or_default()
+            .unwrap_or_default();
+        let current = store
+            .leaderboard_for_season(&category, true, Some(2025), false)
+            .await
+            .unwrap_or_default();
+        let mut entries = LeaderboardEntry::rank(current, None, &HashMap::new());
+        LeaderboardEntry::track_changes(&mut entries, &previous);
+
+        // Players tied on points are listed by username, so neither leaderboard reorders them.
+        assert!(
+            entries
+                .iter()
+                .map(|e| e.username.as_str())
+                .eq(["ann", "ben", "cat", "dan", "eve", "fay"])
+        );
+        assert!(entries.iter().all(|e| e.rank_change == 0 && !e.is_new));
+    }
+
+    #[tokio::test]
+    async fn joined_reads_overlap() {
+        let path = "test_data/joined_reads_overlap/";
+        let store = Store::with_db(Arc::new(RwLock::new(Db {
+            read_delay: std::time::Duration::from_millis(100),
+            ..Db::new(path)
+        })));
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2025-06-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Future GP,Race,2999-01-01 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category\n\
+                 FIRST GP,test,NOR,VER,PIA,RUS,LEC,\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}results.csv"),
+                "race,p1,p2,p3,p4,p5,c1,c2,entered_at\n\
+                 FIRST GP,NOR,VER,PIA,RUS,LEC,,,\n",
+            )
+            .is_ok()
+        );
+
+        // Scored once beforehand, so the driver renames are read and kept before anything is timed.
+        assert!(store.get_all_scored_guesses().await.is_ok());
+
+        // The same fetches the front page joins, timed one after the other first.
+        let start = std::time::Instant::now();
+        assert!(store.get_all_scored_guesses().await.is_ok());
+        let guesses = start.elapsed();
+        let start = std::time::Instant::now();
+        assert!(store.next_event(&category).await.is_ok_and(|e| e.is_some()));
+        let event = start.elapsed();
+        let start = std::time::Instant::now();
+        assert!(store.seasons(&category).await.is_ok());
+        let seasons = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let (scored_guesses, next_event, all_seasons) = tokio::join!(
+            store.get_all_scored_guesses(),
+            store.next_event(&category),
+            store.seasons(&category),
+        );
+        let joined = start.elapsed();
+
+        assert!(scored_guesses.is_ok() && next_event.is_ok() && all_seasons.is_ok());
+
+        // Reads share the lock, so the joined fetches take about as long as the slowest of them
+        // rather than all of them added up.
+        let sum = guesses + event + seasons;
+        let max = guesses.max(event).max(seasons);
+
+        assert!(joined < max + (sum - max) / 2);
+    }
+
+    #[tokio::test]
+    async fn event_history() {
+        let path = "test_data/event_history/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify,id\n\
+                 [Formula 1],Third GP,Race,2025-05-01 12:00:00 UTC,#formula1,,true,third-gp\n\
+                 [Formula 1],First GP,Race,2025-03-01 12:00:00 UTC,#formula1,,true,first-gp\n\
+                 [Formula 1],First GP,Qualifying,2025-02-28 12:00:00 UTC,#formula1,,true,first-q\n\
+                 [Formula 1],Second GP,Race,2025-04-01 12:00:00 UTC,#formula1,,true,second-gp\n\
+                 [Formula 1],Future GP,Race,2999-01-01 12:00:00 UTC,#formula1,,true,future-gp\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category\n\
+                 FIRST GP,test,NOR,VER,PIA,RUS,LEC,\n\
+                 THIRD GP,test,NOR,VER,PIA,RUS,LEC,\n\
+                 SECOND GP,other,NOR,VER,PIA,RUS,LEC,\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}results.csv"),
+                "race,p1,p2,p3,p4,p5,c1,c2,entered_at,race_id\n\
+                 FIRST GP,NOR,VER,PIA,RUS,LEC,,,,first-gp\n\
+                 SECOND GP,NOR,VER,PIA,RUS,LEC,,,,\n",
+            )
+            .is_ok()
+        );
+
+        let category = Category {
+            name: "formula 1".to_string(),
+            channel: "#formula1".to_string(),
+        };
+
+        let history = store
+            .event_history("test", &category)
+            .await
+            .unwrap_or_default();
+
+        assert!(
+            history
+                .iter()
+                .map(|e| e.name.as_str())
+                .eq(["First GP", "Second GP", "Third GP"])
+        );
+        assert!(history[0].points == Some(Points::from(25)) && !history[0].pending);
+        assert!(history[1].guess.is_none() && history[1].points.is_none());
+        assert!(history[2].guess.is_some() && history[2].pending && history[2].points.is_none());
+
+        // Results keyed by the id of their event count as much as those keyed by its name.
+        assert!(
+            store
+                .races_missing_results(&category)
+                .await
+                .is_ok_and(|events| events.iter().map(|e| e.name.as_str()).eq(["Third GP"]))
+        );
+        assert!(store.guess_history("test").await.is_ok_and(|rows| {
+            rows.iter()
+                .map(|row| (row.race.as_str(), row.scored))
+                .eq([("FIRST GP", true), ("THIRD GP", false)])
+        }));
     }
 
     #[tokio::test]
-    async fn add_update_user() {
-        let db = Mutex::new(Database::new("test_data/add_update_user/", None));
-        let store = Store::new(State::from(&db));
+    async fn events_mixed_formats() {
+        let path = "test_data/events_mixed_formats/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2025-03-01 12:00:00 UTC,#formula1,f1,true\n\
+                 [Formula 1],Second GP,Race,2025-04-01T12:00:00+02:00,#formula1,,\n\
+                 [Formula 1],Third GP,Race,2025-05-01 12:30,#formula1,,false\n",
+            )
+            .is_ok()
+        );
+
+        let Ok(events) = store.events().await else {
+            panic!("the events should parse");
+        };
+
+        assert!(
+            events
+                .iter()
+                .map(|e| (e.datetime.to_rfc3339(), e.tags.as_str(), e.notify))
+                .eq([
+                    ("2025-03-01T12:00:00+00:00".to_string(), "f1", true),
+                    ("2025-04-01T10:00:00+00:00".to_string(), "", false),
+                    ("2025-05-01T12:30:00+00:00".to_string(), "", false),
+                ])
+        );
 
+        // The other tool may also leave the optional columns out entirely.
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel\n\
+                 [Formula 1],First GP,Race,2025-03-01 12:00,#formula1\n",
+            )
+            .is_ok()
+        );
         assert!(
-            db.lock()
+            store
+                .events()
                 .await
-                .delete("users", |_: &&User| true)
+                .is_ok_and(|events| events.len() == 1 && !events[0].notify)
+        );
+
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2025-03-01 12:00,#formula1,,true\n\
+                 [Formula 1],Second GP,Race,next sunday,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+
+        let error = store
+            .db
+            .read()
+            .await
+            .find("events", |_: &Event| true)
+            .await
+            .err()
+            .map(|error| StoreError::in_table("events", error));
+
+        assert!(matches!(
+            error,
+            Some(StoreError::InvalidRow {
+                table: "events",
+                line: 3,
+                ..
+            })
+        ));
+        assert!(store.events().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn export_user_data() {
+        let path = "test_data/export_user_data/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}users.csv"),
+                "token,username,password,country\n\
+                 secret-token,test,secret-hash,PT\n\
+                 other-token,other,other-hash,PT\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category\n\
+                 Test GP,test,NOR,VER,PIA,RUS,LEC,\n\
+                 Test GP,other,NOR,VER,PIA,RUS,LEC,\n",
+            )
+            .is_ok()
+        );
+
+        let Ok(export) = store.export_user_data("Test").await else {
+            panic!("the user should be exported");
+        };
+        let json = rocket::serde::json::to_value(&export).unwrap_or_default();
+
+        assert!(json["user"]["password"] == crate::models::REDACTED);
+        assert!(json["user"].get("token").is_none());
+        assert!(!json.to_string().contains("secret"));
+        assert!(export.guesses.len() == 1);
+        assert!(store.export_user_data("nobody").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn find_rival() {
+        let path = "test_data/find_rival/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category\n\
+                 Test GP,first,NOR,VER,PIA,RUS,LEC,\n\
+                 Test GP,second,NOR,VER,PIA,LEC,RUS,\n\
+                 Test GP,third,LEC,RUS,PIA,VER,NOR,\n",
+            )
+            .is_ok()
+        );
+
+        let results = normalized_results();
+        let rival = |username| store.find_rival(username, &results);
+
+        let Ok(Some(leader_rival)) = rival("first").await else {
+            panic!("the leader should have a rival");
+        };
+
+        assert!(leader_rival.username == "second");
+        assert!(leader_rival.points_difference < 0);
+
+        let Ok(Some(last_rival)) = rival("Third").await else {
+            panic!("the last player should have a rival");
+        };
+
+        assert!(last_rival.username == "second");
+        assert!(last_rival.points_difference > 0);
+
+        assert!(
+            rival("second")
                 .await
-                .is_ok()
+                .is_ok_and(|r| r.is_some_and(|r| r.username == "first"))
         );
+        assert!(rival("nobody").await.is_ok_and(|r| r.is_none()));
+
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category\n\
+                 Test GP,first,NOR,VER,PIA,RUS,LEC,\n",
+            )
+            .is_ok()
+        );
+
+        assert!(rival("first").await.is_ok_and(|r| r.is_none()));
+    }
+
+    #[tokio::test]
+    async fn participation() {
+        let path = "test_data/participation/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],Third GP,Race,2025-05-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],First GP,Race,2025-03-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Second GP,Qualifying,2025-03-31 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Second GP,Race,2025-04-01 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}users.csv"),
+                "token,username,password,country\n\
+                 token-1,first,,PT\n\
+                 token-2,second,,PT\n\
+                 token-3,third,,PT\n\
+                 token-4,fourth,,PT\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category\n\
+                 THIRD GP,first,NOR,VER,PIA,RUS,LEC,\n\
+                 FIRST GP,first,NOR,VER,PIA,RUS,LEC,\n\
+                 FIRST GP,second,NOR,VER,PIA,RUS,LEC,\n\
+                 FIRST GP,third,NOR,VER,PIA,RUS,LEC,\n\
+                 FIRST GP,fourth,NOR,VER,PIA,RUS,LEC,\n\
+                 SECOND GP,first,NOR,VER,PIA,RUS,LEC,\n\
+                 SECOND GP,First,VER,NOR,PIA,RUS,LEC,\n\
+                 SECOND GP,second,NOR,VER,PIA,RUS,LEC,\n",
+            )
+            .is_ok()
+        );
+
+        let counts = store.guess_count_per_round().await.unwrap_or_default();
+
+        assert!(counts.len() == 3);
+        assert!(counts.get("FIRST GP") == Some(&4));
+        assert!(counts.get("SECOND GP") == Some(&2));
+        assert!(counts.get("THIRD GP") == Some(&1));
+
+        let participation = store.participation().await.unwrap_or_default();
+
+        assert!(
+            participation
+                .iter()
+                .map(|p| (p.race.as_str(), p.guesses, p.participation_rate))
+                .eq([
+                    ("First GP", 4, 1.0),
+                    ("Second GP", 2, 0.5),
+                    ("Third GP", 1, 0.25)
+                ])
+        );
+    }
+
+    #[tokio::test]
+    async fn run_migrations() {
+        let path = "test_data/run_migrations/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}users.csv"),
+                "token,username,password,country\r\n\
+                 test-token,test,,PT\r\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}drivers.csv"),
+                "number,code,name\n4,NOR,Lando Norris\n",
+            )
+            .is_ok()
+        );
+
+        assert!(store.run_migrations(path).await.is_ok());
+
+        let users = std::fs::read_to_string(format!("{path}users.csv")).unwrap_or_default();
+
+        assert!(
+            users.lines().next()
+                == Some(
+                    "token,username,password,country,preferred_driver,timezone,avatar,\
+                     dark_mode,compact_tables,version"
+                )
+        );
+
+        // Running the migrations again leaves the files as they are.
+        assert!(store.run_migrations(path).await.is_ok());
+        assert!(std::fs::read_to_string(format!("{path}users.csv")).is_ok_and(|u| u == users));
 
         assert!(
             store
-                .add_user("test", "********", Some("PT".to_string()))
+                .get_users(Some("test"))
                 .await
-                .is_ok()
+                .is_ok_and(|u| u.len() == 1 && u[0].timezone.is_empty() && u[0].avatar.is_none())
+        );
+        assert!(
+            store
+                .all_drivers()
+                .await
+                .is_ok_and(|d| d.len() == 1 && d[0].active && d[0].category.is_empty())
         );
+    }
 
-        let token = db
-            .lock()
-            .await
-            .find("users", |u: &User| u.username.eq_ignore_ascii_case("test"))
+    #[tokio::test]
+    async fn guesses_of_mixed_lengths() {
+        let path = "test_data/guesses_of_mixed_lengths/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(std::fs::write(format!("{path}guesses.csv"), "").is_ok());
+
+        let longer = Guess {
+            username: "other".to_string(),
+            positions: ["NOR", "VER", "PIA", "RUS", "LEC", "HAM", "ALO"]
+                .map(driver_code)
+                .to_vec(),
+            ..perfect_guess()
+        };
+
+        for guess in [perfect_guess(), longer.clone(), perfect_guess()] {
+            assert!(
+                store
+                    .db
+                    .write()
+                    .await
+                    .insert("guesses", guess)
+                    .await
+                    .is_ok()
+            );
+        }
+
+        let guesses = store.get_guesses(None, None).await.unwrap_or_default();
+
+        assert!(guesses.len() == 3);
+        assert!(guesses[0].positions == perfect_guess().positions);
+        assert!(guesses[1].positions == longer.positions);
+        assert!(guesses[2].positions == perfect_guess().positions);
+    }
+
+    #[tokio::test]
+    async fn backfill_race_ids() {
+        let path = "test_data/backfill_race_ids/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],Test GP,Race,2025-03-01 12:00:00 UTC,#formula1,f1,true\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5\n\
+                 TEST GP,test,NOR,VER,PIA,RUS,LEC\n\
+                 GONE GP,test,NOR,VER,PIA,RUS,LEC\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}results.csv"),
+                "race,p1,p2,p3,p4,p5,c1,c2\nTEST GP,NOR,VER,PIA,RUS,LEC,MCL,RBR\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}constructor_guesses.csv"),
+                "race,username,c1,c2\nTEST GP,test,MCL,RBR\n",
+            )
+            .is_ok()
+        );
+
+        assert!(store.run_migrations(path).await.is_ok());
+        assert!(
+            store
+                .events()
+                .await
+                .is_ok_and(|e| e.len() == 1 && e[0].id == "test-gp")
+        );
+
+        let guesses = store.get_guesses(None, None).await.unwrap_or_default();
+
+        assert!(guesses.len() == 2);
+        assert!(guesses[0].race_id == "test-gp" && guesses[1].race_id.is_empty());
+        assert!(
+            store
+                .results()
+                .await
+                .is_ok_and(|r| r.len() == 1 && r[0].race_id == "test-gp")
+        );
+
+        // Renaming the event leaves the guesses alone, which still score under the new name.
+        let stored_guesses =
+            std::fs::read_to_string(format!("{path}guesses.csv")).unwrap_or_default();
+        let renamed = Event {
+            name: "RENAMED GP".to_string(),
+            ..store.events().await.unwrap_or_default().remove(0)
+        };
+
+        assert!(store.update_event("Test GP", "Race", renamed).await.is_ok());
+        assert!(
+            std::fs::read_to_string(format!("{path}guesses.csv"))
+                .is_ok_and(|guesses| guesses == stored_guesses)
+        );
+
+        let scored_guesses = store.get_all_scored_guesses().await.unwrap_or_default();
+
+        assert!(scored_guesses[0].guess.race == "RENAMED GP" && scored_guesses[0].points == 25);
+        assert!(scored_guesses[1].guess.race == "GONE GP" && scored_guesses[1].points == 0);
+        assert!(
+            store
+                .get_guesses(None, Some("renamed gp"))
+                .await
+                .is_ok_and(|g| g.len() == 1)
+        );
+
+        // So do the constructor guesses.
+        let constructor_guesses = store
+            .get_constructor_guesses(None, Some("renamed gp"))
             .await
-            .unwrap_or_default()
-            .into_iter()
-            .next()
-            .unwrap_or_default()
-            .token;
+            .unwrap_or_default();
+        let normalized_results = store.normalized_results().await.unwrap_or_default();
 
+        assert!(constructor_guesses.len() == 1 && constructor_guesses[0].race_id == "test-gp");
         assert!(
             store
-                .update_user(
-                    User {
-                        token: token.clone(),
-                        username: "test".to_string(),
-                        password: "new_password".to_string(),
-                        country: "PT".to_string(),
-                    },
-                    &token
-                )
+                .score_constructor_guess(&constructor_guesses[0], &normalized_results)
+                .await
+                == Points::from(2 * CONSTRUCTOR_CORRECT)
+        );
+    }
+
+    #[tokio::test]
+    async fn results_sorted() {
+        let path = "test_data/results_sorted/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}events.csv"),
+                "category,name,description,datetime,channel,tags,notify\n\
+                 [Formula 1],First GP,Race,2025-03-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Third GP,Qualifying,2025-01-04 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Third GP,Race,2025-05-01 12:00:00 UTC,#formula1,,true\n\
+                 [Formula 1],Second GP,Race,2025-04-01 12:00:00 UTC,#formula1,,true\n",
+            )
+            .is_ok()
+        );
+        assert!(
+            std::fs::write(
+                format!("{path}results.csv"),
+                "race,p1,p2,p3,p4,p5,c1,c2,entered_at\n\
+                 THIRD GP,VER,NOR,PIA,RUS,LEC,,,\n\
+                 SECOND GP,VER,NOR,PIA,RUS,LEC,,,\n\
+                 FIRST GP,VER,NOR,PIA,RUS,LEC,,,\n",
+            )
+            .is_ok()
+        );
+
+        let results = store.results_sorted().await.unwrap_or_default();
+
+        assert!(store.results().await.is_ok_and(|r| r[0].race == "THIRD GP"));
+        assert!(
+            results
+                .iter()
+                .map(|r| r.race.as_str())
+                .eq(["FIRST GP", "SECOND GP", "THIRD GP"])
+        );
+    }
+
+    #[tokio::test]
+    async fn update_guess_submitted_at() {
+        let store = Store::new("test_data/update_guess_submitted_at/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("guesses", |_: &&Guess| true)
                 .await
                 .is_ok()
         );
+
+        let submitted_at = |guesses: Result<Vec<Guess>, DbError>| {
+            guesses.unwrap_or_default().first().map(|g| g.submitted_at)
+        };
+
+        assert!(store.update_guess(perfect_guess(), "Test GP").await.is_ok());
+        let first = submitted_at(store.get_guesses(Some("test"), Some("Test GP")).await);
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let mixed_guess = Guess {
+            version: 1,
+            ..mixed_guess()
+        };
+
+        assert!(store.update_guess(mixed_guess, "Test GP").await.is_ok());
+        let second = submitted_at(store.get_guesses(Some("test"), Some("Test GP")).await);
+
+        assert!(first.is_some_and(|first| first > DateTime::<Utc>::default()));
+        assert!(first < second);
+    }
+
+    #[tokio::test]
+    async fn update_guess_timestamps() {
+        let path = "test_data/update_guess_timestamps/";
+        let store = Store::new(path);
+
+        assert!(std::fs::create_dir_all(path).is_ok());
+        assert!(
+            std::fs::write(
+                format!("{path}guesses.csv"),
+                "race,username,p1,p2,p3,p4,p5,category,submitted_at,version,created_at,updated_at\n\
+                 Old GP,test,NOR,VER,PIA,RUS,LEC,,1970-01-01T00:00:00Z,0,,\n",
+            )
+            .is_ok()
+        );
+
+        let timestamps = |guesses: Result<Vec<Guess>, DbError>| {
+            guesses
+                .unwrap_or_default()
+                .first()
+                .map(|g| (g.created_at, g.updated_at))
+        };
+
+        assert!(
+            timestamps(store.get_guesses(Some("test"), Some("Old GP")).await) == Some((None, None))
+        );
+
+        assert!(store.update_guess(perfect_guess(), "Test GP").await.is_ok());
+        let Some((Some(created), Some(updated))) =
+            timestamps(store.get_guesses(Some("test"), Some("Test GP")).await)
+        else {
+            panic!("a new guess should have both timestamps");
+        };
+
+        assert!(created == updated);
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let mixed_guess = Guess {
+            version: 1,
+            created_at: None,
+            ..mixed_guess()
+        };
+
+        assert!(store.update_guess(mixed_guess, "Test GP").await.is_ok());
+        assert!(
+            timestamps(store.get_guesses(Some("test"), Some("Test GP")).await)
+                .is_some_and(|(c, u)| c == Some(created) && u > Some(updated))
+        );
     }
 
     #[tokio::test]
-    async fn validate_user() {
-        let db = Mutex::new(Database::new("test_data/validate_user/", None));
-        let store = Store::new(State::from(&db));
+    async fn update_guess_version() {
+        let store = Store::new("test_data/update_guess_version/");
 
         assert!(
-            db.lock()
+            store
+                .db
+                .write()
                 .await
-                .delete("users", |_: &&User| true)
+                .delete("guesses", |_: &&Guess| true)
                 .await
                 .is_ok()
         );
 
         assert!(
             store
-                .add_user("test", "********", Some("PT".to_string()))
+                .update_guess(perfect_guess(), "Test GP")
+                .await
+                .is_ok_and(|g| g.version == 1)
+        );
+
+        // Another tab still shows the form without any guess.
+        assert!(matches!(
+            store.update_guess(mixed_guess(), "Test GP").await,
+            Err(StoreError::Conflict)
+        ));
+
+        let mixed_guess = Guess {
+            version: 1,
+            ..mixed_guess()
+        };
+
+        assert!(
+            store
+                .update_guess(mixed_guess.clone(), "Test GP")
+                .await
+                .is_ok_and(|g| g.version == 2)
+        );
+        assert!(matches!(
+            store.update_guess(mixed_guess, "Test GP").await,
+            Err(StoreError::Conflict)
+        ));
+        assert!(
+            store
+                .get_guesses(Some("test"), Some("Test GP"))
+                .await
+                .is_ok_and(|g| g.len() == 1 && g[0].version == 2)
+        );
+    }
+
+    #[tokio::test]
+    async fn is_stored_guess() {
+        let store = Store::new("test_data/is_stored_guess/");
+
+        assert!(
+            store
+                .db
+                .write()
+                .await
+                .delete("guesses", |_: &&Guess| true)
                 .await
                 .is_ok()
         );
 
-        assert!(store.validate_user("test", "********").await.is_some());
+        assert!(
+            store
+                .is_stored_guess(&perfect_guess())
+                .await
+                .is_ok_and(|stored| !stored)
+        );
+        assert!(store.update_guess(perfect_guess(), "Test GP").await.is_ok());
+        assert!(
+            store
+                .is_stored_guess(&perfect_guess())
+                .await
+                .is_ok_and(|stored| stored)
+        );
+        assert!(
+            store
+                .is_stored_guess(&mixed_guess())
+                .await
+                .is_ok_and(|stored| !stored)
+        );
     }
 }